@@ -1,8 +1,10 @@
 use anyhow::Result;
 use clap::Parser;
 use cpal::traits::HostTrait;
+use simple_webrtc::media::SinkTrack;
 use simple_webrtc::testing::*;
-use simple_webrtc::{Controller, EmittedEvents};
+use simple_webrtc::{Controller, EmittedEvents, RejectReason};
+use std::collections::HashMap;
 use std::io::Write;
 use std::sync::Arc;
 use std::time::Duration;
@@ -47,10 +49,10 @@ async fn main() -> Result<()> {
     let (client_event_tx, client_event_rx) = mpsc::unbounded_channel::<EmittedEvents>();
 
     // SimpleWebRTC instance
-    let swrtc = simple_webrtc::Controller::init(simple_webrtc::InitArgs {
-        id: cli.local.clone(),
-        emitted_event_chan: client_event_tx,
-    })?;
+    let swrtc = simple_webrtc::Controller::init(simple_webrtc::InitArgs::new(
+        cli.local.clone(),
+        client_event_tx,
+    ))?;
     let swrtc: Arc<Mutex<Controller>> = Arc::new(Mutex::new(swrtc));
 
     // hook up signaling
@@ -111,7 +113,7 @@ async fn handle_swrtc(
 }
 
 async fn handle_signals(
-    _client_address: String,
+    client_address: String,
     _peer_address: String,
     swrtc: Arc<Mutex<Controller>>,
     mut server_signal_rx: mpsc::UnboundedReceiver<PeerSignal>,
@@ -138,19 +140,26 @@ async fn handle_signals(
 
                 if let Err(e) = s.accept_call(&sig.src, sig.sdp).await {
                     log::error!("failed to accept call: {}", e);
-                    s.hang_up(&sig.src).await;
-                    //send_disconnect(&sig.src, &client_address).await;
+                    // the rejection tells the remote to hang up
+                    s.recv_hang_up(&sig.src).await;
+                    let reject = SigReject {
+                        src: client_address.clone(),
+                        reason: RejectReason::from_error(&e),
+                    };
+                    if let Err(e) = send_reject(&sig.src, reject).await {
+                        log::error!("failed to send rejection: {}", e);
+                    }
                 }
             }
             PeerSignal::CallTerminated(src) => {
                 log::debug!("signal: CallTerminated");
                 let mut s = swrtc.lock().await;
-                s.hang_up(&src).await;
+                s.recv_hang_up(&src).await;
             }
-            PeerSignal::CallRejected(src) => {
+            PeerSignal::CallRejected(sig) => {
                 log::debug!("signal: CallRejected");
                 let mut s = swrtc.lock().await;
-                s.hang_up(&src).await;
+                s.recv_rejection(&sig.src, sig.reason).await;
             }
         }
     }
@@ -165,7 +174,8 @@ async fn handle_events(
 ) -> Result<()> {
     // want to send RTP packets to CPAL
 
-    let mut sink_tracks = vec![];
+    // dropping a sink track stops its decode task
+    let mut sink_tracks: HashMap<String, Vec<Box<dyn SinkTrack>>> = HashMap::new();
 
     while let Some(evt) = client_event_rx.recv().await {
         match evt {
@@ -202,12 +212,31 @@ async fn handle_events(
                 )
                 .await?;
             }
+            // the remote hung up: it mustn't be signaled back
+            EmittedEvents::CallTerminated {
+                by_remote: true, ..
+            } => {
+                log::debug!("event: CallTerminated by the remote");
+            }
+            EmittedEvents::CallTerminated { dest, .. } => {
+                log::debug!("event: CallTerminated");
+                // the remote may be unreachable already, ex: after Disconnected
+                if let Err(e) = send_disconnect(&dest, &client_address).await {
+                    log::warn!("failed to send CallTerminated to {}: {}", dest, e);
+                }
+            }
+            EmittedEvents::CallRejected { peer, reason } => {
+                log::info!("call rejected by {}: {:?}", peer, reason);
+            }
             EmittedEvents::Disconnected { peer } => {
                 log::debug!("event: Disconnected");
                 let mut s = swrtc.lock().await;
-                s.hang_up(&peer).await;
+                if let Err(e) = s.hang_up(&peer).await {
+                    log::warn!("failed to hang up on {}: {}", peer, e);
+                }
+                sink_tracks.remove(&peer);
             }
-            EmittedEvents::TrackAdded { peer: _, track } => {
+            EmittedEvents::TrackAdded { peer, track } => {
                 log::debug!("event: TrackAdded");
                 let host = cpal::default_host();
                 // todo: allow switching the output device during the call.
@@ -220,8 +249,11 @@ async fn handle_events(
                     simple_webrtc::media::create_sink_track(output_device, track, codec)?;
                 //simple_webrtc::media::OpusSink::init(output_device, track, codec)?;
                 sink_track.play()?;
-                sink_tracks.push(sink_track);
+                let peer_sinks = sink_tracks.entry(peer).or_default();
+                peer_sinks.retain(|s| s.is_decoding());
+                peer_sinks.push(sink_track);
             }
+            _ => {}
         }
     }
     Ok(())
@@ -2,7 +2,7 @@ use anyhow::Result;
 use clap::Parser;
 use cpal::traits::HostTrait;
 use simple_webrtc::testing::*;
-use simple_webrtc::{Controller, EmittedEvents, MimeType, RTCRtpCodecCapability};
+use simple_webrtc::{Controller, EmittedEvents, MimeType, RTCRtpCodecCapability, RejectReason};
 use std::io::Write;
 use std::sync::Arc;
 use std::time::Duration;
@@ -44,10 +44,10 @@ async fn main() -> Result<()> {
     let (client_event_tx, client_event_rx) = mpsc::unbounded_channel::<EmittedEvents>();
 
     // SimpleWebRTC instance
-    let swrtc = simple_webrtc::Controller::init(simple_webrtc::InitArgs {
-        id: cli.local.clone(),
-        emitted_event_chan: client_event_tx,
-    })?;
+    let swrtc = simple_webrtc::Controller::init(simple_webrtc::InitArgs::new(
+        cli.local.clone(),
+        client_event_tx,
+    ))?;
     let swrtc: Arc<Mutex<Controller>> = Arc::new(Mutex::new(swrtc));
 
     // hook up signaling
@@ -139,7 +139,7 @@ async fn handle_swrtc(
 }
 
 async fn handle_signals(
-    _client_address: String,
+    client_address: String,
     _peer_address: String,
     swrtc: Arc<Mutex<Controller>>,
     mut server_signal_rx: mpsc::UnboundedReceiver<PeerSignal>,
@@ -165,19 +165,26 @@ async fn handle_signals(
                 let mut s = swrtc.lock().await;
                 if let Err(e) = s.accept_call(&sig.src, sig.sdp).await {
                     log::error!("failed to accept call: {}", e);
-                    s.hang_up(&sig.src).await;
-                    //send_disconnect(&sig.src, &client_address).await;
+                    // the rejection tells the remote to hang up
+                    s.recv_hang_up(&sig.src).await;
+                    let reject = SigReject {
+                        src: client_address.clone(),
+                        reason: RejectReason::from_error(&e),
+                    };
+                    if let Err(e) = send_reject(&sig.src, reject).await {
+                        log::error!("failed to send rejection: {}", e);
+                    }
                 }
             }
             PeerSignal::CallTerminated(src) => {
                 log::debug!("signal: CallTerminated");
                 let mut s = swrtc.lock().await;
-                s.hang_up(&src).await;
+                s.recv_hang_up(&src).await;
             }
-            PeerSignal::CallRejected(src) => {
+            PeerSignal::CallRejected(sig) => {
                 log::debug!("signal: CallRejected");
                 let mut s = swrtc.lock().await;
-                s.hang_up(&src).await;
+                s.recv_rejection(&sig.src, sig.reason).await;
             }
         }
     }
@@ -225,10 +232,28 @@ async fn handle_events(
                 )
                 .await?;
             }
+            // the remote hung up: it mustn't be signaled back
+            EmittedEvents::CallTerminated {
+                by_remote: true, ..
+            } => {
+                log::debug!("event: CallTerminated by the remote");
+            }
+            EmittedEvents::CallTerminated { dest, .. } => {
+                log::debug!("event: CallTerminated");
+                // the remote may be unreachable already, ex: after Disconnected
+                if let Err(e) = send_disconnect(&dest, &client_address).await {
+                    log::warn!("failed to send CallTerminated to {}: {}", dest, e);
+                }
+            }
+            EmittedEvents::CallRejected { peer, reason } => {
+                log::info!("call rejected by {}: {:?}", peer, reason);
+            }
             EmittedEvents::Disconnected { peer } => {
                 log::debug!("event: Disconnected");
                 let mut s = swrtc.lock().await;
-                s.hang_up(&peer).await;
+                if let Err(e) = s.hang_up(&peer).await {
+                    log::warn!("failed to hang up on {}: {}", peer, e);
+                }
             }
             _ => {}
         }
@@ -0,0 +1,79 @@
+use bytes::{Buf, BufMut};
+use std::time::SystemTime;
+use webrtc::rtp::extension::abs_send_time_extension::{ntp2unix, unix2ntp};
+use webrtc::rtp::packet::Packet;
+use webrtc::track::track_remote::TrackRemote;
+use webrtc::util::marshal::{Marshal, MarshalSize, Unmarshal};
+
+/// the absolute-capture-time RTP header extension. carries the wall clock time at which the
+/// media in the packet was captured, which allows separately received audio and video to be
+/// synchronized. registered for audio and video by the Controller
+pub const ABS_CAPTURE_TIME_URI: &str =
+    "http://www.webrtc.org/experiments/rtp-hdrext/abs-capture-time";
+
+// only the capture timestamp is sent. the spec allows 8 more bytes for the estimated clock offset
+const ABS_CAPTURE_TIME_SIZE: usize = 8;
+
+/// the payload of the absolute-capture-time extension
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AbsCaptureTime {
+    /// NTP time, in UQ32.32 format
+    pub timestamp: u64,
+}
+
+impl AbsCaptureTime {
+    pub fn new(capture_time: SystemTime) -> Self {
+        Self {
+            timestamp: unix2ntp(capture_time),
+        }
+    }
+
+    pub fn capture_time(&self) -> SystemTime {
+        ntp2unix(self.timestamp)
+    }
+
+    /// reads the extension from a packet received on `track`, if the sender included it
+    pub async fn from_packet(track: &TrackRemote, packet: &Packet) -> Option<Self> {
+        let id = track
+            .params()
+            .await
+            .header_extensions
+            .iter()
+            .find(|ext| ext.uri == ABS_CAPTURE_TIME_URI)?
+            .id;
+        let mut payload = packet.header.get_extension(id as u8)?;
+        Self::unmarshal(&mut payload).ok()
+    }
+}
+
+impl MarshalSize for AbsCaptureTime {
+    fn marshal_size(&self) -> usize {
+        ABS_CAPTURE_TIME_SIZE
+    }
+}
+
+impl Marshal for AbsCaptureTime {
+    fn marshal_to(&self, mut buf: &mut [u8]) -> Result<usize, webrtc::util::Error> {
+        if buf.remaining_mut() < ABS_CAPTURE_TIME_SIZE {
+            return Err(webrtc::util::Error::ErrBufferShort);
+        }
+        buf.put_u64(self.timestamp);
+        Ok(ABS_CAPTURE_TIME_SIZE)
+    }
+}
+
+impl Unmarshal for AbsCaptureTime {
+    fn unmarshal<B>(buf: &mut B) -> Result<Self, webrtc::util::Error>
+    where
+        Self: Sized,
+        B: Buf,
+    {
+        // the clock offset, if present, is ignored
+        if buf.remaining() < ABS_CAPTURE_TIME_SIZE {
+            return Err(webrtc::util::Error::ErrBufferShort);
+        }
+        Ok(Self {
+            timestamp: buf.get_u64(),
+        })
+    }
+}
@@ -0,0 +1,171 @@
+use std::collections::HashMap;
+
+use crate::internal::data_types::{ContentHint, MediaKind, MediaSourceId};
+
+/// the most an audio source gets from `Controller::set_total_bandwidth`, in bits per second.
+/// Opus is transparent for stereo music at about this bitrate, so more would be wasted
+pub const MAX_AUDIO_BANDWIDTH: u32 = 128_000;
+
+// the shares of the bandwidth left after the audio. a camera gets twice as much as a screen
+// share, which can lower its frame rate instead (see `ContentHint::keep_resolution`)
+const CAMERA_WEIGHT: u64 = 2;
+const SCREEN_WEIGHT: u64 = 1;
+
+/// what a media source carries, which decides its share of the bandwidth. in order of priority
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum StreamClass {
+    Audio,
+    Camera,
+    Screen,
+}
+
+impl StreamClass {
+    /// a video source is a screen share if its content hint says so
+    pub(crate) fn new(kind: MediaKind, hint: Option<ContentHint>) -> Self {
+        match (kind, hint) {
+            (MediaKind::Audio, _) => StreamClass::Audio,
+            (MediaKind::Video, Some(ContentHint::Detail | ContentHint::Text)) => {
+                StreamClass::Screen
+            }
+            (MediaKind::Video, _) => StreamClass::Camera,
+        }
+    }
+}
+
+/// splits `total` bits per second between the sources. the audio sources are served first, an
+/// equal share each, up to `MAX_AUDIO_BANDWIDTH`. the rest is split between the video sources in
+/// proportion to their weight. a source gets 0 if nothing is left for it
+pub(crate) fn allocate(
+    total: u32,
+    sources: &[(MediaSourceId, StreamClass)],
+) -> HashMap<MediaSourceId, u32> {
+    let mut allocation = HashMap::new();
+    let num_audio = sources
+        .iter()
+        .filter(|(_, class)| *class == StreamClass::Audio)
+        .count() as u32;
+    let audio_share = match num_audio {
+        0 => 0,
+        n => (total / n).min(MAX_AUDIO_BANDWIDTH),
+    };
+    let video_total = (total - audio_share * num_audio) as u64;
+
+    let weight = |class: StreamClass| match class {
+        StreamClass::Audio => 0,
+        StreamClass::Camera => CAMERA_WEIGHT,
+        StreamClass::Screen => SCREEN_WEIGHT,
+    };
+    let total_weight: u64 = sources.iter().map(|(_, class)| weight(*class)).sum();
+    for (source_id, class) in sources {
+        let bitrate = match class {
+            StreamClass::Audio => audio_share,
+            _ => (video_total * weight(*class) / total_weight) as u32,
+        };
+        allocation.insert(source_id.clone(), bitrate);
+    }
+    allocation
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn allocated(total: u32, sources: &[(&str, StreamClass)]) -> Vec<(String, u32)> {
+        let sources: Vec<(MediaSourceId, StreamClass)> = sources
+            .iter()
+            .map(|(id, class)| (id.to_string(), *class))
+            .collect();
+        let allocation = allocate(total, &sources);
+        assert!(allocation.values().map(|b| *b as u64).sum::<u64>() <= total as u64);
+        let mut allocated: Vec<(String, u32)> = allocation.into_iter().collect();
+        allocated.sort();
+        allocated
+    }
+
+    fn bitrates(allocated: &[(&str, u32)]) -> Vec<(String, u32)> {
+        allocated
+            .iter()
+            .map(|(id, bitrate)| (id.to_string(), *bitrate))
+            .collect()
+    }
+
+    #[test]
+    fn audio_is_served_first_then_video_by_weight() {
+        let sources = [
+            ("camera", StreamClass::Camera),
+            ("mic", StreamClass::Audio),
+            ("screen", StreamClass::Screen),
+        ];
+        assert_eq!(
+            allocated(1_000_000, &sources),
+            bitrates(&[("camera", 581_333), ("mic", 128_000), ("screen", 290_666)])
+        );
+    }
+
+    #[test]
+    fn the_audio_sources_share_a_small_budget() {
+        let sources = [
+            ("camera", StreamClass::Camera),
+            ("mic", StreamClass::Audio),
+            ("music", StreamClass::Audio),
+        ];
+        assert_eq!(
+            allocated(100_000, &sources),
+            bitrates(&[("camera", 0), ("mic", 50_000), ("music", 50_000)])
+        );
+    }
+
+    #[test]
+    fn audio_is_capped_without_video() {
+        let sources = [("mic", StreamClass::Audio)];
+        assert_eq!(
+            allocated(1_000_000, &sources),
+            bitrates(&[("mic", MAX_AUDIO_BANDWIDTH)])
+        );
+    }
+
+    #[test]
+    fn video_only_is_split_by_weight() {
+        let sources = [
+            ("camera", StreamClass::Camera),
+            ("screen", StreamClass::Screen),
+        ];
+        assert_eq!(
+            allocated(900_000, &sources),
+            bitrates(&[("camera", 600_000), ("screen", 300_000)])
+        );
+    }
+
+    #[test]
+    fn nothing_to_allocate() {
+        assert!(allocated(1_000_000, &[]).is_empty());
+        let sources = [("camera", StreamClass::Camera), ("mic", StreamClass::Audio)];
+        assert_eq!(
+            allocated(0, &sources),
+            bitrates(&[("camera", 0), ("mic", 0)])
+        );
+    }
+
+    #[test]
+    fn screen_shares_are_told_apart_by_their_content_hint() {
+        assert_eq!(StreamClass::new(MediaKind::Audio, None), StreamClass::Audio);
+        assert_eq!(
+            StreamClass::new(MediaKind::Audio, Some(ContentHint::Detail)),
+            StreamClass::Audio
+        );
+        assert_eq!(
+            StreamClass::new(MediaKind::Video, None),
+            StreamClass::Camera
+        );
+        assert_eq!(
+            StreamClass::new(MediaKind::Video, Some(ContentHint::Motion)),
+            StreamClass::Camera
+        );
+        for hint in [ContentHint::Detail, ContentHint::Text] {
+            assert_eq!(
+                StreamClass::new(MediaKind::Video, Some(hint)),
+                StreamClass::Screen
+            );
+        }
+    }
+}
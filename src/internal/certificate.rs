@@ -0,0 +1,25 @@
+use anyhow::Result;
+use webrtc::peer_connection::certificate::RTCCertificate;
+
+// todo: export the SRTP keying material (RFC 5764) so recording pipelines can decrypt a forked
+// stream. this isn't possible with webrtc-rs 0.6: the DTLSConn which implements
+// `export_keying_material` is crate-private in RTCDtlsTransport. once it's exposed, add the export
+// behind an opt-in feature flag, since anyone holding the keys can decrypt the call.
+
+/// generates a new DTLS certificate. the certificate can be persisted using `certificate_to_pem`
+/// and passed to `ControllerConfig::certificate` so that the remote sees the same fingerprint
+/// across sessions.
+pub fn generate_certificate() -> Result<RTCCertificate> {
+    let key_pair = rcgen::KeyPair::generate(&rcgen::PKCS_ECDSA_P256_SHA256)?;
+    Ok(RTCCertificate::from_key_pair(key_pair)?)
+}
+
+/// serializes the certificate, including the private key. store it securely.
+pub fn certificate_to_pem(certificate: &RTCCertificate) -> String {
+    certificate.serialize_pem()
+}
+
+/// parses a certificate created by `certificate_to_pem`
+pub fn certificate_from_pem(pem: &str) -> Result<RTCCertificate> {
+    Ok(RTCCertificate::from_pem(pem)?)
+}
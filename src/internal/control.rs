@@ -0,0 +1,316 @@
+use anyhow::{bail, Result};
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant};
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+use webrtc::data_channel::data_channel_init::RTCDataChannelInit;
+use webrtc::data_channel::data_channel_message::DataChannelMessage;
+use webrtc::data_channel::data_channel_state::RTCDataChannelState;
+use webrtc::data_channel::RTCDataChannel;
+use webrtc::peer_connection::RTCPeerConnection;
+
+use crate::internal::data_types::{HangupReason, LivenessCheck, Participant, PeerId};
+use crate::internal::emitter::EventEmitter;
+use crate::internal::events::EmittedEvents;
+use crate::internal::roster::Roster;
+
+// every peer connection has a reserved data channel, used by simple-webrtc to exchange messages
+// which aren't part of WebRTC's signaling. the channel is pre-negotiated: both sides create it
+// with the same id, so it doesn't show up in on_data_channel and doesn't require a round trip to
+// open. applications must not create a data channel with the same id or label.
+// a remote which isn't using simple-webrtc won't answer on this channel.
+
+/// the SCTP stream id of the reserved data channel
+pub const CONTROL_CHANNEL_ID: u16 = 1000;
+/// the label of the reserved data channel
+pub const CONTROL_CHANNEL_LABEL: &str = "simple-webrtc-control";
+
+/// messages sent over the control channel, serialized as JSON
+#[derive(Debug, Serialize, Deserialize)]
+pub enum ControlMessage {
+    Ping {
+        id: u64,
+    },
+    Pong {
+        id: u64,
+    },
+    /// the remote is about to close the connection
+    HangUp {
+        reason: HangupReason,
+    },
+    /// the remote put the call on hold
+    Hold,
+    /// the remote took the call off hold
+    Resume,
+    /// asks who is in the call. answered with `Participants` and the same id
+    QueryParticipants {
+        id: u64,
+    },
+    /// the sender and the peers it is connected to, with their media sources
+    Participants {
+        id: u64,
+        participants: Vec<Participant>,
+    },
+}
+
+pub struct ControlChannel {
+    channel: Arc<RTCDataChannel>,
+    /// the remote peer
+    peer_id: PeerId,
+    emitter: EventEmitter,
+    next_ping_id: AtomicU64,
+    /// pings which are waiting for a pong
+    pending_pings: Mutex<HashMap<u64, oneshot::Sender<()>>>,
+    /// answers the remote's participants queries
+    roster: Roster,
+    next_query_id: AtomicU64,
+    /// participants queries which are waiting for an answer
+    pending_queries: Mutex<HashMap<u64, oneshot::Sender<Vec<Participant>>>>,
+}
+
+impl ControlChannel {
+    /// creates the reserved data channel. must be called before the SDP is created
+    pub async fn new(
+        pc: &RTCPeerConnection,
+        peer_id: &PeerId,
+        emitter: EventEmitter,
+        roster: Roster,
+    ) -> Result<Arc<Self>> {
+        let channel = pc
+            .create_data_channel(
+                CONTROL_CHANNEL_LABEL,
+                Some(RTCDataChannelInit {
+                    negotiated: Some(CONTROL_CHANNEL_ID),
+                    ..Default::default()
+                }),
+            )
+            .await?;
+
+        let control = Arc::new(Self {
+            channel: channel.clone(),
+            peer_id: peer_id.clone(),
+            emitter,
+            next_ping_id: AtomicU64::new(0),
+            pending_pings: Mutex::new(HashMap::new()),
+            roster,
+            next_query_id: AtomicU64::new(0),
+            pending_queries: Mutex::new(HashMap::new()),
+        });
+
+        // the channel holds on to its handler, so only a weak reference goes in
+        let weak_control: Weak<Self> = Arc::downgrade(&control);
+        channel.on_message(Box::new(move |msg: DataChannelMessage| {
+            let weak_control = weak_control.clone();
+            Box::pin(async move {
+                if let Some(control) = weak_control.upgrade() {
+                    control.handle_message(msg).await;
+                }
+            })
+        }));
+
+        Ok(control)
+    }
+
+    pub async fn send(&self, msg: &ControlMessage) -> Result<()> {
+        if self.channel.ready_state() != RTCDataChannelState::Open {
+            bail!("control channel is not open");
+        }
+        let payload = serde_json::to_vec(msg)?;
+        self.channel.send(&Bytes::from(payload)).await?;
+        Ok(())
+    }
+
+    /// sends a ping and waits for the pong. returns the round trip time
+    pub async fn ping(&self, timeout: Duration) -> Result<Duration> {
+        let id = self.next_ping_id.fetch_add(1, Ordering::Relaxed);
+        let (tx, rx) = oneshot::channel();
+        self.lock_pending_pings()?.insert(id, tx);
+
+        let start = Instant::now();
+        let result = match self.send(&ControlMessage::Ping { id }).await {
+            Ok(_) => match tokio::time::timeout(timeout, rx).await {
+                Ok(Ok(_)) => Ok(start.elapsed()),
+                Ok(Err(_)) => Err(anyhow::anyhow!("control channel closed")),
+                Err(_) => Err(anyhow::anyhow!("ping timed out")),
+            },
+            Err(e) => Err(e),
+        };
+
+        self.lock_pending_pings()?.remove(&id);
+        result
+    }
+
+    /// asks the remote who is in the call, and waits for the answer
+    pub async fn query_participants(&self, timeout: Duration) -> Result<Vec<Participant>> {
+        let id = self.next_query_id.fetch_add(1, Ordering::Relaxed);
+        let (tx, rx) = oneshot::channel();
+        self.lock_pending_queries()?.insert(id, tx);
+
+        let result = match self.send(&ControlMessage::QueryParticipants { id }).await {
+            Ok(_) => match tokio::time::timeout(timeout, rx).await {
+                Ok(Ok(participants)) => Ok(participants),
+                Ok(Err(_)) => Err(anyhow::anyhow!("control channel closed")),
+                Err(_) => Err(anyhow::anyhow!("participants query timed out")),
+            },
+            Err(e) => Err(e),
+        };
+
+        self.lock_pending_queries()?.remove(&id);
+        result
+    }
+
+    /// pings the remote once per `check.interval`, after the channel first opens, and emits
+    /// `EmittedEvents::Disconnected` when a ping fails. see `PeerOptions::liveness_check`.
+    /// the task ends when the ControlChannel is dropped
+    pub fn spawn_liveness_check(self: &Arc<Self>, check: LivenessCheck) -> JoinHandle<()> {
+        let weak_control: Weak<Self> = Arc::downgrade(self);
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(check.interval);
+            ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            let mut was_open = false;
+            // only one event is emitted per outage
+            let mut reported = false;
+            loop {
+                ticker.tick().await;
+                let control = match weak_control.upgrade() {
+                    Some(c) => c,
+                    None => break,
+                };
+                // the connection is still being established
+                if !was_open && control.channel.ready_state() != RTCDataChannelState::Open {
+                    continue;
+                }
+                was_open = true;
+                match control.ping(check.timeout).await {
+                    Ok(_) => reported = false,
+                    Err(e) if !reported => {
+                        reported = true;
+                        log::warn!("liveness check failed for peer {}: {}", control.peer_id, e);
+                        if let Err(e) = control.emitter.send(EmittedEvents::Disconnected {
+                            peer: control.peer_id.clone(),
+                        }) {
+                            log::error!("failed to send disconnect event: {}", e);
+                        }
+                    }
+                    Err(_) => {}
+                }
+            }
+        })
+    }
+
+    /// tells the remote why the call is ending. waits (up to `timeout`) for the message to be
+    /// sent, so that it isn't lost when the connection is closed right after
+    pub async fn send_hang_up(&self, reason: HangupReason, timeout: Duration) -> Result<()> {
+        self.send(&ControlMessage::HangUp { reason }).await?;
+        let flushed = async {
+            while self.channel.buffered_amount().await > 0 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        };
+        if tokio::time::timeout(timeout, flushed).await.is_err() {
+            bail!("timed out sending hang up");
+        }
+        Ok(())
+    }
+
+    async fn handle_message(&self, msg: DataChannelMessage) {
+        let msg: ControlMessage = match serde_json::from_slice(&msg.data) {
+            Ok(m) => m,
+            Err(e) => {
+                log::error!("failed to deserialize control message: {}", e);
+                return;
+            }
+        };
+
+        match msg {
+            ControlMessage::Ping { id } => {
+                if let Err(e) = self.send(&ControlMessage::Pong { id }).await {
+                    log::error!("failed to send pong: {}", e);
+                }
+            }
+            ControlMessage::Pong { id } => {
+                let tx = match self.lock_pending_pings() {
+                    Ok(mut pending) => pending.remove(&id),
+                    Err(e) => {
+                        log::error!("{}", e);
+                        return;
+                    }
+                };
+                match tx {
+                    // the receiver is gone if the ping timed out
+                    Some(tx) => {
+                        let _ = tx.send(());
+                    }
+                    None => log::warn!("received pong for unknown ping {}", id),
+                }
+            }
+            ControlMessage::HangUp { reason } => {
+                if let Err(e) = self.emitter.send(EmittedEvents::RemoteHangUp {
+                    peer: self.peer_id.clone(),
+                    reason,
+                }) {
+                    log::error!("failed to send remote hang up event: {}", e);
+                }
+            }
+            ControlMessage::Hold => {
+                if let Err(e) = self.emitter.send(EmittedEvents::OnHold {
+                    peer: self.peer_id.clone(),
+                }) {
+                    log::error!("failed to send on hold event: {}", e);
+                }
+            }
+            ControlMessage::QueryParticipants { id } => {
+                let participants = self.roster.participants();
+                let answer = ControlMessage::Participants { id, participants };
+                if let Err(e) = self.send(&answer).await {
+                    log::error!("failed to send participants: {}", e);
+                }
+            }
+            ControlMessage::Participants { id, participants } => {
+                let tx = match self.lock_pending_queries() {
+                    Ok(mut pending) => pending.remove(&id),
+                    Err(e) => {
+                        log::error!("{}", e);
+                        return;
+                    }
+                };
+                match tx {
+                    // the receiver is gone if the query timed out
+                    Some(tx) => {
+                        let _ = tx.send(participants);
+                    }
+                    None => log::warn!("received participants for unknown query {}", id),
+                }
+            }
+            ControlMessage::Resume => {
+                if let Err(e) = self.emitter.send(EmittedEvents::Resumed {
+                    peer: self.peer_id.clone(),
+                }) {
+                    log::error!("failed to send resumed event: {}", e);
+                }
+            }
+        }
+    }
+
+    fn lock_pending_pings(
+        &self,
+    ) -> Result<std::sync::MutexGuard<'_, HashMap<u64, oneshot::Sender<()>>>> {
+        match self.pending_pings.lock() {
+            Ok(p) => Ok(p),
+            Err(e) => bail!("failed to lock pending pings: {}", e),
+        }
+    }
+
+    fn lock_pending_queries(
+        &self,
+    ) -> Result<std::sync::MutexGuard<'_, HashMap<u64, oneshot::Sender<Vec<Participant>>>>> {
+        match self.pending_queries.lock() {
+            Ok(p) => Ok(p),
+            Err(e) => bail!("failed to lock pending queries: {}", e),
+        }
+    }
+}
@@ -0,0 +1,205 @@
+use async_trait::async_trait;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use webrtc::interceptor::stream_info::StreamInfo;
+use webrtc::interceptor::{
+    self, Attributes, Interceptor, InterceptorBuilder, RTCPReader, RTCPWriter, RTPReader, RTPWriter,
+};
+use webrtc::rtp::packet::Packet;
+use webrtc::util::MarshalSize;
+
+use crate::internal::data_types::MediaSourceId;
+
+// the packets are counted by an interceptor, which wraps the RTP writer of every local stream.
+// it's registered first, so that it sees the packets last, right before they're encrypted,
+// including the retransmissions of the NACK responder.
+// the interceptor only knows a stream by its SSRC. the Controller registers the SSRC of each
+// sender it creates; the interceptor looks it up once, when webrtc-rs binds the stream, and
+// afterwards only updates atomics.
+
+/// the RTP packets sent for a media source, summed over every peer it's sent to. the counts are
+/// cumulative: they start at 0 when the source is added and are never reset. see
+/// `Controller::source_counters`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SourceCounters {
+    pub packets_sent: u64,
+    /// the size of the RTP packets, including the headers, before encryption
+    pub bytes_sent: u64,
+    /// packets which webrtc-rs failed to send, ex: because the connection is closing
+    pub send_errors: u64,
+}
+
+#[derive(Default)]
+struct Counters {
+    packets_sent: AtomicU64,
+    bytes_sent: AtomicU64,
+    send_errors: AtomicU64,
+}
+
+/// the counters of every media source. shared by the Controller and the interceptor of every
+/// connection
+#[derive(Clone, Default)]
+pub(crate) struct SendCounters {
+    inner: Arc<Mutex<Inner>>,
+}
+
+#[derive(Default)]
+struct Inner {
+    by_source: HashMap<MediaSourceId, Arc<Counters>>,
+    by_ssrc: HashMap<u32, Arc<Counters>>,
+}
+
+impl SendCounters {
+    pub fn add_source(&self, source_id: &MediaSourceId) {
+        match self.inner.lock() {
+            Ok(mut inner) => {
+                inner.by_source.insert(source_id.clone(), Arc::default());
+            }
+            Err(e) => log::error!("failed to lock send counters: {}", e),
+        }
+    }
+
+    pub fn remove_source(&self, source_id: &MediaSourceId) {
+        match self.inner.lock() {
+            Ok(mut inner) => {
+                if let Some(counters) = inner.by_source.remove(source_id) {
+                    inner.by_ssrc.retain(|_, c| !Arc::ptr_eq(c, &counters));
+                }
+            }
+            Err(e) => log::error!("failed to lock send counters: {}", e),
+        }
+    }
+
+    /// must be called before the sender is negotiated, when webrtc-rs binds its stream
+    pub fn add_sender(&self, source_id: &MediaSourceId, ssrc: u32) {
+        match self.inner.lock() {
+            Ok(mut inner) => {
+                if let Some(counters) = inner.by_source.get(source_id).cloned() {
+                    inner.by_ssrc.insert(ssrc, counters);
+                }
+            }
+            Err(e) => log::error!("failed to lock send counters: {}", e),
+        }
+    }
+
+    pub fn get(&self, source_id: &MediaSourceId) -> Option<SourceCounters> {
+        let counters = match self.inner.lock() {
+            Ok(inner) => inner.by_source.get(source_id).cloned()?,
+            Err(e) => {
+                log::error!("failed to lock send counters: {}", e);
+                return None;
+            }
+        };
+        Some(SourceCounters {
+            packets_sent: counters.packets_sent.load(Ordering::Relaxed),
+            bytes_sent: counters.bytes_sent.load(Ordering::Relaxed),
+            send_errors: counters.send_errors.load(Ordering::Relaxed),
+        })
+    }
+
+    fn bind(&self, ssrc: u32) -> Option<Arc<Counters>> {
+        match self.inner.lock() {
+            Ok(inner) => inner.by_ssrc.get(&ssrc).cloned(),
+            Err(e) => {
+                log::error!("failed to lock send counters: {}", e);
+                None
+            }
+        }
+    }
+
+    fn unbind(&self, ssrc: u32) {
+        match self.inner.lock() {
+            Ok(mut inner) => {
+                inner.by_ssrc.remove(&ssrc);
+            }
+            Err(e) => log::error!("failed to lock send counters: {}", e),
+        }
+    }
+}
+
+impl InterceptorBuilder for SendCounters {
+    fn build(&self, _id: &str) -> Result<Arc<dyn Interceptor + Send + Sync>, interceptor::Error> {
+        Ok(Arc::new(CountingInterceptor {
+            counters: self.clone(),
+        }))
+    }
+}
+
+struct CountingInterceptor {
+    counters: SendCounters,
+}
+
+#[async_trait]
+impl Interceptor for CountingInterceptor {
+    async fn bind_rtcp_reader(
+        &self,
+        reader: Arc<dyn RTCPReader + Send + Sync>,
+    ) -> Arc<dyn RTCPReader + Send + Sync> {
+        reader
+    }
+
+    async fn bind_rtcp_writer(
+        &self,
+        writer: Arc<dyn RTCPWriter + Send + Sync>,
+    ) -> Arc<dyn RTCPWriter + Send + Sync> {
+        writer
+    }
+
+    async fn bind_local_stream(
+        &self,
+        info: &StreamInfo,
+        writer: Arc<dyn RTPWriter + Send + Sync>,
+    ) -> Arc<dyn RTPWriter + Send + Sync> {
+        match self.counters.bind(info.ssrc) {
+            Some(counters) => Arc::new(CountingWriter {
+                inner: writer,
+                counters,
+            }),
+            None => writer,
+        }
+    }
+
+    async fn unbind_local_stream(&self, info: &StreamInfo) {
+        self.counters.unbind(info.ssrc);
+    }
+
+    async fn bind_remote_stream(
+        &self,
+        _info: &StreamInfo,
+        reader: Arc<dyn RTPReader + Send + Sync>,
+    ) -> Arc<dyn RTPReader + Send + Sync> {
+        reader
+    }
+
+    async fn unbind_remote_stream(&self, _info: &StreamInfo) {}
+
+    async fn close(&self) -> Result<(), interceptor::Error> {
+        Ok(())
+    }
+}
+
+struct CountingWriter {
+    inner: Arc<dyn RTPWriter + Send + Sync>,
+    counters: Arc<Counters>,
+}
+
+#[async_trait]
+impl RTPWriter for CountingWriter {
+    async fn write(
+        &self,
+        pkt: &Packet,
+        attributes: &Attributes,
+    ) -> Result<usize, interceptor::Error> {
+        let result = self.inner.write(pkt, attributes).await;
+        if result.is_ok() {
+            self.counters.packets_sent.fetch_add(1, Ordering::Relaxed);
+            self.counters
+                .bytes_sent
+                .fetch_add(pkt.marshal_size() as u64, Ordering::Relaxed);
+        } else {
+            self.counters.send_errors.fetch_add(1, Ordering::Relaxed);
+        }
+        result
+    }
+}
@@ -1,22 +1,546 @@
 use anyhow::{bail, Result};
 use serde::{Deserialize, Serialize};
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Duration;
 use webrtc::api::media_engine::{
     MIME_TYPE_AV1, MIME_TYPE_G722, MIME_TYPE_H264, MIME_TYPE_OPUS, MIME_TYPE_PCMA, MIME_TYPE_PCMU,
     MIME_TYPE_VP8, MIME_TYPE_VP9,
 };
+use webrtc::ice_transport::ice_candidate::RTCIceCandidate;
+use webrtc::ice_transport::ice_candidate_type::RTCIceCandidateType;
+use webrtc::ice_transport::ice_credential_type::RTCIceCredentialType;
+use webrtc::ice_transport::ice_protocol::RTCIceProtocol;
+use webrtc::ice_transport::ice_server::RTCIceServer;
+use webrtc::rtp_transceiver::rtp_codec::RTCRtpCodecCapability;
+use webrtc::track::track_remote::TrackRemote;
+
+use crate::internal::error::Error;
+use crate::internal::red::MIME_TYPE_RED;
 
 /// uniquely identifies peers
+/// may contain ASCII letters, digits, and the characters `-`, `_`, `.`, `:`, `@`
+/// must not be empty or longer than `MAX_PEER_ID_LEN`. the Controller normalizes the ids it's
+/// given with `normalize_peer_id`, and reports the normalized id in the events
 pub type PeerId = String;
 
+pub const MAX_PEER_ID_LEN: usize = 256;
+
+/// rejects peer ids which would cause problems later on, such as while signaling.
+/// the id is checked as is. see `normalize_peer_id`
+pub fn validate_peer_id(peer_id: &str) -> Result<()> {
+    let allowed = |c: char| c.is_ascii_alphanumeric() || "-_.:@".contains(c);
+    if peer_id.is_empty() || peer_id.len() > MAX_PEER_ID_LEN || !peer_id.chars().all(allowed) {
+        return Err(Error::InvalidPeerId(peer_id.into()).into());
+    }
+    Ok(())
+}
+
+/// removes the whitespace around the id, which user input and signaling layers often add, then
+/// validates it with `validate_peer_id`. the case is kept: ids are compared exactly, and may be
+/// case-sensitive, ex: public keys
+pub fn normalize_peer_id(peer_id: &str) -> Result<PeerId> {
+    let peer_id = peer_id.trim();
+    validate_peer_id(peer_id)?;
+    Ok(peer_id.into())
+}
+
+/// a candidate with the usual extensions is around 100 bytes
+pub const MAX_CANDIDATE_LEN: usize = 1024;
+
+/// checks the fields of an ICE candidate attribute (RFC 8839 5.1), ex:
+/// "candidate:1 1 udp 2130706431 192.168.1.2 50000 typ host", up to the candidate type. the
+/// "candidate:" prefix is optional and the extensions which follow the type aren't checked, but
+/// the candidate can't be longer than `MAX_CANDIDATE_LEN`. fails with `Error::InvalidCandidate`
+pub fn validate_candidate(candidate: &str) -> Result<()> {
+    let invalid = |field: &'static str, value: &str| Error::InvalidCandidate {
+        field,
+        value: value.into(),
+    };
+    if candidate.len() > MAX_CANDIDATE_LEN {
+        return Err(invalid("length", &candidate.len().to_string()).into());
+    }
+    let candidate = candidate.trim();
+    let candidate = candidate.strip_prefix("candidate:").unwrap_or(candidate);
+    // the fields are separated by a single space, so that an empty field is reported as such
+    let mut fields = candidate.split(' ');
+    let mut next = |field: &'static str| {
+        fields
+            .next()
+            .filter(|f| !f.is_empty())
+            .ok_or_else(|| invalid(field, ""))
+    };
+
+    let foundation = next("foundation")?;
+    if !is_valid_foundation(foundation) {
+        return Err(invalid("foundation", foundation).into());
+    }
+    let component = next("component")?;
+    if !matches!(component.parse::<u16>(), Ok(1..=256)) {
+        return Err(invalid("component", component).into());
+    }
+    let protocol = next("protocol")?;
+    if !protocol.eq_ignore_ascii_case("udp") && !protocol.eq_ignore_ascii_case("tcp") {
+        return Err(invalid("protocol", protocol).into());
+    }
+    let priority = next("priority")?;
+    if priority.parse::<u32>().is_err() {
+        return Err(invalid("priority", priority).into());
+    }
+    let address = next("address")?;
+    if !is_valid_address(address) {
+        return Err(invalid("address", address).into());
+    }
+    let port = next("port")?;
+    if port.parse::<u16>().is_err() {
+        return Err(invalid("port", port).into());
+    }
+    let typ = next("typ")?;
+    if typ != "typ" {
+        return Err(invalid("typ", typ).into());
+    }
+    let candidate_type = next("candidate type")?;
+    if !["host", "srflx", "prflx", "relay"].contains(&candidate_type) {
+        return Err(invalid("candidate type", candidate_type).into());
+    }
+    Ok(())
+}
+
+/// checks the fields of a candidate received from the remote, like `validate_candidate` does for
+/// a candidate attribute. the port and priority are already numbers. the candidate can't be longer
+/// than `MAX_CANDIDATE_LEN` once serialized. fails with `Error::InvalidCandidate`
+pub fn validate_ice_candidate(candidate: &RTCIceCandidate) -> Result<()> {
+    let invalid = |field: &'static str, value: String| Error::InvalidCandidate { field, value };
+    if !is_valid_foundation(&candidate.foundation) {
+        return Err(invalid("foundation", candidate.foundation.clone()).into());
+    }
+    if !(1..=256).contains(&candidate.component) {
+        return Err(invalid("component", candidate.component.to_string()).into());
+    }
+    if candidate.protocol == RTCIceProtocol::Unspecified {
+        return Err(invalid("protocol", String::new()).into());
+    }
+    if !is_valid_address(&candidate.address) {
+        return Err(invalid("address", candidate.address.clone()).into());
+    }
+    if candidate.typ == RTCIceCandidateType::Unspecified {
+        return Err(invalid("candidate type", String::new()).into());
+    }
+    let len = candidate.to_json()?.candidate.len();
+    if len > MAX_CANDIDATE_LEN {
+        return Err(invalid("length", len.to_string()).into());
+    }
+    Ok(())
+}
+
+fn is_valid_foundation(foundation: &str) -> bool {
+    let ice_char = |c: char| c.is_ascii_alphanumeric() || c == '+' || c == '/';
+    !foundation.is_empty() && foundation.len() <= 32 && foundation.chars().all(ice_char)
+}
+
+/// an IP address, or a hostname, ex: an mDNS name ending in ".local"
+fn is_valid_address(address: &str) -> bool {
+    let hostname_char = |c: char| c.is_ascii_alphanumeric() || c == '-' || c == '.';
+    !address.is_empty() && (address.parse::<IpAddr>().is_ok() || address.chars().all(hostname_char))
+}
+
+/// settings which apply to a single peer. passed to `dial_with_options`/`accept_call_with_options`
+#[derive(Debug, Clone)]
+pub struct PeerOptions {
+    /// if true, ICE candidates are emitted individually via `EmittedEvents::Ice` as they are
+    /// found. if false, the SDP isn't emitted until gathering is complete and contains all of the
+    /// candidates; no `Ice` events are emitted. use false for peers which don't support trickle ICE
+    pub trickle: bool,
+    /// if false, the remote is told not to send audio. the transceivers are still created, so
+    /// local audio can be sent
+    pub receive_audio: bool,
+    /// same as `receive_audio`, for video
+    pub receive_video: bool,
+    /// if set, trickled candidates are collected for this long and emitted together via
+    /// `EmittedEvents::IceBatch`, to be passed to `Controller::recv_ice_batch`. reduces the number
+    /// of signaling messages on hosts with many interfaces. ex: 50ms. if None, each candidate is
+    /// emitted as soon as it's found
+    pub ice_batch_window: Option<Duration>,
+    /// if set, the peer is pinged over the reserved data channel and `EmittedEvents::Disconnected`
+    /// is emitted as soon as a ping goes unanswered, rather than after ICE gives up, which can
+    /// take tens of seconds. only for peers using simple-webrtc: other peers never answer
+    pub liveness_check: Option<LivenessCheck>,
+    /// if true, `Controller::measured_latency` compares the capture timestamps sent by the remote
+    /// with the local clock, rather than estimating the latency. only accurate if both clocks
+    /// are synchronized, ex: in a loopback test or between hosts synchronized with NTP
+    pub measure_capture_latency: bool,
+}
+
+impl Default for PeerOptions {
+    fn default() -> Self {
+        Self {
+            trickle: true,
+            receive_audio: true,
+            receive_video: true,
+            ice_batch_window: None,
+            liveness_check: None,
+            measure_capture_latency: false,
+        }
+    }
+}
+
+/// a TURN server, which relays the media when the peers can't reach each other directly, ex:
+/// behind symmetric NATs. see `ControllerConfig::turn_servers`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TurnConfig {
+    /// the server's TURN urls, ex: "turn:turn.example.com:3478?transport=tcp" or
+    /// "turns:turn.example.com:5349". must not be empty
+    pub urls: Vec<String>,
+    /// the long-term credentials (RFC 8489 9.2). leave them empty for an open relay, which
+    /// doesn't authenticate
+    pub username: String,
+    pub credential: String,
+}
+
+// webrtc-rs refuses TURN servers without credentials. an open relay never asks for them
+const OPEN_RELAY_CREDENTIAL: &str = "anonymous";
+
+impl TurnConfig {
+    /// fails if there is no url, or if a url isn't a TURN url
+    pub(crate) fn validate(&self) -> Result<()> {
+        if self.urls.is_empty() {
+            bail!("TURN server has no url");
+        }
+        for url in &self.urls {
+            if !url.starts_with("turn:") && !url.starts_with("turns:") {
+                bail!("not a TURN url: {}", url);
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn to_ice_server(&self) -> RTCIceServer {
+        let or_open = |s: &str| match s.is_empty() {
+            true => OPEN_RELAY_CREDENTIAL.to_string(),
+            false => s.to_string(),
+        };
+        RTCIceServer {
+            urls: self.urls.clone(),
+            username: or_open(&self.username),
+            credential: or_open(&self.credential),
+            credential_type: RTCIceCredentialType::Password,
+        }
+    }
+}
+
+/// see `PeerOptions::liveness_check`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LivenessCheck {
+    /// how often the peer is pinged once connected. must not be zero
+    pub interval: Duration,
+    /// how long to wait for each pong. the Disconnected event is emitted once per outage: after
+    /// a ping is answered again, the next unanswered ping emits another. if ICE fails too, a
+    /// second Disconnected is emitted for the same outage
+    pub timeout: Duration,
+}
+
+impl Default for LivenessCheck {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(1),
+            timeout: Duration::from_secs(2),
+        }
+    }
+}
+
+/// how a peer is reconnected after its connection fails. see `Controller::set_reconnect_policy`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReconnectPolicy {
+    /// attempts made before giving up and emitting `EmittedEvents::Disconnected`. must not be zero
+    pub max_attempts: u32,
+    /// the delay before the first attempt. doubled for each following attempt
+    pub initial_backoff: Duration,
+    /// the delay between attempts never exceeds this
+    pub max_backoff: Duration,
+    /// the first `ice_restarts` attempts restart ICE, which keeps the connection and its tracks.
+    /// the following attempts dial the peer again, with the options it was dialed or accepted
+    /// with, which the remote must answer like a new call
+    pub ice_restarts: u32,
+    /// how long an attempt may take to connect before the next one is started
+    pub attempt_timeout: Duration,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 5,
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(30),
+            ice_restarts: 2,
+            attempt_timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+impl ReconnectPolicy {
+    /// the delay before `attempt`, counting from 1
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let factor = 2u32.saturating_pow(attempt.saturating_sub(1));
+        self.initial_backoff
+            .saturating_mul(factor)
+            .min(self.max_backoff)
+    }
+}
+
+/// the IP versions used for ICE. see `ControllerConfig::address_family`.
+/// webrtc-rs ranks IPv4 and IPv6 candidates equally, so neither can merely be preferred:
+/// `Ipv4Only` and `Ipv6Only` are restrictions. no local or server reflexive candidate of the
+/// other version is gathered, and there is no fallback to it: if the remote can't be reached
+/// with the chosen version, the connection fails. relay candidates and the candidates sent by
+/// the remote are not filtered
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum AddressFamily {
+    /// gather IPv4 and IPv6 candidates
+    #[default]
+    Any,
+    /// only gather IPv4 candidates. for networks where IPv6 is broken
+    Ipv4Only,
+    /// only gather IPv6 candidates
+    Ipv6Only,
+}
+
+/// how `Controller::accept_call` and `Controller::recv_sdp` handle a remote SDP with lines
+/// webrtc-rs can't parse, ex: from an endpoint which adds its own line types
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SdpParsing {
+    /// reject the SDP
+    #[default]
+    Strict,
+    /// remove the lines which aren't of the form `<type>=<value>` with a type from RFC 4566, and
+    /// parse the rest. each removed line is logged. lines of a known type with an invalid value,
+    /// or out of order, are still rejected.
+    /// the remote isn't told which lines were ignored, so it may expect something which was
+    /// left out, ex: a line carrying a parameter it considers required. the media may then fail
+    /// or be misinterpreted. only for interop with endpoints known to send such lines
+    Tolerant,
+}
+
+/// why a call ended. sent to the remote by `Controller::hang_up_with_reason`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum HangupReason {
+    /// the user ended the call
+    HangUp,
+    /// the user is in another call
+    Busy,
+    /// the user didn't want to take the call
+    Declined,
+    /// the call was dropped because of a local network problem
+    NetworkError,
+    /// an application-defined reason
+    Other(String),
+}
+
+/// why a call wasn't accepted. the application sends it back to the caller in its rejection
+/// signal, which the caller passes to `Controller::recv_rejection`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RejectReason {
+    /// the callee can't take the call right now, ex: the user is in another call
+    Busy,
+    /// the callee can't accept the caller's offer, assuming it doesn't change. ex: the SDP is
+    /// invalid or too large, or no codec is shared
+    Incompatible,
+    /// the call failed for another reason, ex: a network error
+    Error,
+}
+
+impl RejectReason {
+    /// the reason to send back to the caller after `accept_call` failed with `error`. never
+    /// `Busy`, which is up to the application
+    pub fn from_error(error: &anyhow::Error) -> Self {
+        match error.downcast_ref::<Error>() {
+            Some(Error::IncompatibleOffer(_)) | Some(Error::SdpTooLarge(_)) => {
+                RejectReason::Incompatible
+            }
+            _ => RejectReason::Error,
+        }
+    }
+}
+
+/// why the DTLS handshake failed. see `EmittedEvents::DtlsFailed`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DtlsFailure {
+    /// both SDPs claim the same DTLS role (`a=setup`), so neither side can complete the
+    /// handshake, ex: after glare
+    RoleConflict,
+    /// the remote's certificate doesn't match the fingerprint in its SDP
+    FingerprintMismatch,
+    /// the handshake failed for another reason, ex: it timed out. webrtc-rs logs the error
+    Handshake,
+}
+
+/// the RTCP feedback used to ask for a keyframe. see `Controller::request_keyframe` and
+/// `EmittedEvents::KeyframeRequested`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyframeRequest {
+    /// Picture Loss Indication (RFC 4585)
+    Pli,
+    /// Full Intra Request (RFC 5104)
+    Fir,
+}
+
+/// the lifecycle of a peer, emitted with `EmittedEvents::PeerStateChanged`:
+/// `dial` -> WaitingForSdp -> (answer received) -> WaitingForIce -> Connected
+/// `accept_call` -> WaitingForIce -> Connected
+/// Connected -> Negotiating -> Connected while a renegotiation is in progress
+/// Connected -> Interrupted -> Connected if ICE recovers on its own
+/// any state -> Failed when ICE fails, or -> Reconnecting -> Connected or Failed if a
+/// `ReconnectPolicy` is set
+/// any state -> Disconnected when the connection is closed, ex: by `Controller::hang_up`
+/// a connection starts in WaitingForSdp, or in Reconnecting when a reconnect redials the peer,
+/// which isn't emitted: see `EmittedEvents::PeerAdded`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum PeerState {
+    /// the connection was closed
     Disconnected,
+    /// waiting for the remote's answer
     WaitingForSdp,
+    /// the SDP has been exchanged and the connection is being established
     WaitingForIce,
     Connected,
+    /// connected, with an offer/answer exchange in progress. see `Controller::is_negotiating`
+    Negotiating,
+    /// the connection failed and is being reestablished. see `EmittedEvents::Reconnecting`
+    Reconnecting,
+    /// ICE lost connectivity, which it may recover
+    Interrupted,
+    /// ICE failed, and the peer won't be reconnected. the application should hang up
+    Failed,
 }
 
 pub type MediaSourceId = String;
 
+/// whether a media source carries audio or video
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum MediaKind {
+    Audio,
+    Video,
+}
+
+impl MediaKind {
+    /// the kind of a codec, from the prefix of its MIME type. ex: "audio/opus" is `Audio`
+    pub fn from_mime_type(mime_type: &str) -> Result<Self> {
+        let mime_type = mime_type.to_lowercase();
+        if mime_type.starts_with("audio/") {
+            Ok(MediaKind::Audio)
+        } else if mime_type.starts_with("video/") {
+            Ok(MediaKind::Video)
+        } else {
+            bail!("unknown media kind for mime type: {}", mime_type)
+        }
+    }
+}
+
+/// a media source which a member of a call sends. see `Participant`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PublishedSource {
+    pub id: MediaSourceId,
+    pub kind: MediaKind,
+}
+
+/// a member of a call, as reported by one of its peers. see `Controller::query_participants`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Participant {
+    pub peer: PeerId,
+    /// for the peer which answered the query, its media sources. for the others, the tracks
+    /// they send to it, identified by their `MediaSourceId` if they use simple-webrtc
+    pub sources: Vec<PublishedSource>,
+}
+
+/// a track the remote sends. see `Controller::remote_tracks`
+#[derive(Debug, Clone)]
+pub struct RemoteTrackInfo {
+    /// identifies the media section (and transceiver) within the session
+    pub mid: String,
+    pub kind: MediaKind,
+    /// the id of the remote's track. sent by simple-webrtc peers as the `MediaSourceId`
+    pub track_id: String,
+    /// the id of the remote's media stream. sent by simple-webrtc peers as the CNAME
+    pub stream_id: String,
+    pub ssrc: u32,
+    /// the codec the remote sends. None until the first packet is received
+    pub codec: Option<RTCRtpCodecCapability>,
+    /// false if the last negotiation left the media section without the remote sending
+    pub active: bool,
+    /// the same track as in `EmittedEvents::TrackAdded`, ex: to create a sink for a track
+    /// whose event was missed
+    pub track: Arc<TrackRemote>,
+}
+
+/// describes a media source before it's added with `Controller::add_source`. the kind is
+/// checked against the codec when the descriptor is created, so that an audio codec can't be
+/// sent as a video source or vice versa
+#[derive(Debug, Clone)]
+pub struct MediaSource {
+    id: MediaSourceId,
+    kind: MediaKind,
+    codec: RTCRtpCodecCapability,
+}
+
+impl MediaSource {
+    /// fails if `codec` isn't of the given kind
+    pub fn new(id: MediaSourceId, kind: MediaKind, codec: RTCRtpCodecCapability) -> Result<Self> {
+        let codec_kind = MediaKind::from_mime_type(&codec.mime_type)?;
+        if codec_kind != kind {
+            bail!(
+                "media source {} is {:?}, but the codec {} is {:?}",
+                id,
+                kind,
+                codec.mime_type,
+                codec_kind
+            );
+        }
+        Ok(Self { id, kind, codec })
+    }
+
+    pub fn audio(id: MediaSourceId, codec: RTCRtpCodecCapability) -> Result<Self> {
+        Self::new(id, MediaKind::Audio, codec)
+    }
+
+    pub fn video(id: MediaSourceId, codec: RTCRtpCodecCapability) -> Result<Self> {
+        Self::new(id, MediaKind::Video, codec)
+    }
+
+    pub fn id(&self) -> &MediaSourceId {
+        &self.id
+    }
+
+    pub fn kind(&self) -> MediaKind {
+        self.kind
+    }
+
+    pub fn codec(&self) -> &RTCRtpCodecCapability {
+        &self.codec
+    }
+}
+
+/// what a video source contains, which determines how the encoder should degrade when
+/// bandwidth is short. set with `Controller::set_content_hint`.
+/// the crate doesn't encode video, and webrtc-rs has no equivalent of the browser's
+/// `MediaStreamTrack.contentHint`, so the hint isn't sent anywhere: the application's encoder
+/// (VP8, VP9, H264, AV1) must apply it, using `keep_resolution`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ContentHint {
+    /// camera video. smooth movement matters more than detail
+    Motion,
+    /// a screen share with images or video. detail matters more than frame rate
+    Detail,
+    /// a screen share with text. details must stay sharp, even at a very low frame rate
+    Text,
+}
+
+impl ContentHint {
+    /// true if the encoder should lower the frame rate rather than the resolution
+    /// (the maintain-resolution degradation preference)
+    pub fn keep_resolution(&self) -> bool {
+        !matches!(self, ContentHint::Motion)
+    }
+}
+
 /// represents the MIME types from webrtc::api::media_engine
 #[derive(Serialize, Deserialize)]
 pub enum MimeType {
@@ -53,10 +577,14 @@ pub enum MimeType {
     // https://en.wikipedia.org//wiki/G.711
     // also known as G.711 A-law
     PCMA,
+    // https://www.rfc-editor.org/rfc/rfc2198
+    // redundant audio: each packet also carries the previous frame, so that lost packets can be
+    // recovered. wraps Opus frames. see `ControllerConfig::red`
+    RED,
 }
 
-impl ToString for MimeType {
-    fn to_string(&self) -> String {
+impl std::fmt::Display for MimeType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let s = match self {
             MimeType::H264 => MIME_TYPE_H264,
             MimeType::VP8 => MIME_TYPE_VP8,
@@ -66,8 +594,9 @@ impl ToString for MimeType {
             MimeType::G722 => MIME_TYPE_G722,
             MimeType::PCMU => MIME_TYPE_PCMU,
             MimeType::PCMA => MIME_TYPE_PCMA,
+            MimeType::RED => MIME_TYPE_RED,
         };
-        s.into()
+        write!(f, "{}", s)
     }
 }
 
@@ -82,8 +611,276 @@ impl MimeType {
             MIME_TYPE_G722 => MimeType::G722,
             MIME_TYPE_PCMU => MimeType::PCMU,
             MIME_TYPE_PCMA => MimeType::PCMA,
+            MIME_TYPE_RED => MimeType::RED,
             _ => bail!(format! {"invalid mime type: {}", s}),
         };
         Ok(mime_type)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn is_invalid_peer_id(result: Result<impl std::fmt::Debug>) -> bool {
+        matches!(
+            result.map_err(|e| e.downcast::<Error>()),
+            Err(Ok(Error::InvalidPeerId(_)))
+        )
+    }
+
+    #[test]
+    fn validate_peer_id_accepts_the_allowed_characters() {
+        for id in ["a", "Bob-2", "user_1.example:5", "did:key:z6Mk@host", "0"] {
+            assert!(validate_peer_id(id).is_ok(), "{:?}", id);
+        }
+        assert!(validate_peer_id(&"a".repeat(MAX_PEER_ID_LEN)).is_ok());
+    }
+
+    #[test]
+    fn validate_peer_id_rejects_malformed_ids() {
+        let too_long = "a".repeat(MAX_PEER_ID_LEN + 1);
+        for id in [
+            "",
+            " ",
+            "a b",
+            "a/b",
+            "a?b",
+            "é",
+            "a\n",
+            " a",
+            too_long.as_str(),
+        ] {
+            assert!(is_invalid_peer_id(validate_peer_id(id)), "{:?}", id);
+        }
+    }
+
+    #[test]
+    fn normalize_peer_id_trims_whitespace() {
+        assert_eq!(normalize_peer_id("  Bob \r\n").unwrap(), "Bob");
+        assert_eq!(normalize_peer_id("bob").unwrap(), "bob");
+    }
+
+    #[test]
+    fn normalize_peer_id_rejects_what_remains_invalid() {
+        for id in ["", "  ", " a b ", "\ta/b"] {
+            assert!(is_invalid_peer_id(normalize_peer_id(id)), "{:?}", id);
+        }
+    }
+
+    /// the field reported by `validate_candidate`
+    fn invalid_field(candidate: &str) -> Option<&'static str> {
+        match validate_candidate(candidate).map_err(|e| e.downcast::<Error>()) {
+            Err(Ok(Error::InvalidCandidate { field, .. })) => Some(field),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn validate_candidate_accepts_well_formed_candidates() {
+        for candidate in [
+            "candidate:1 1 udp 2130706431 192.168.1.2 50000 typ host",
+            "1 1 UDP 2130706431 192.168.1.2 50000 typ host",
+            "candidate:842163049 1 udp 1677729535 203.0.113.7 61665 typ srflx raddr 0.0.0.0 \
+             rport 0 generation 0",
+            "candidate:2 1 tcp 1518280447 2001:db8::1 9 typ host tcptype active",
+            "candidate:a+b/c 2 udp 41885439 4f3c1b2a-5d6e.local 3478 typ relay",
+        ] {
+            assert_eq!(invalid_field(candidate), None, "{:?}", candidate);
+        }
+    }
+
+    #[test]
+    fn validate_candidate_reports_the_missing_field_of_a_truncated_candidate() {
+        let candidate = "candidate:1 1 udp 2130706431 192.168.1.2 50000 typ host";
+        let fields = [
+            "foundation",
+            "component",
+            "protocol",
+            "priority",
+            "address",
+            "port",
+            "typ",
+            "candidate type",
+        ];
+        let words: Vec<&str> = candidate.split(' ').collect();
+        for (len, field) in fields.iter().enumerate() {
+            let truncated = words[..len].join(" ");
+            assert_eq!(invalid_field(&truncated), Some(*field), "{:?}", truncated);
+        }
+    }
+
+    #[test]
+    fn validate_candidate_reports_the_malformed_field() {
+        for (candidate, field) in [
+            (
+                "candidate:1 0 udp 1 192.168.1.2 50000 typ host",
+                "component",
+            ),
+            (
+                "candidate:1 1 sctp 1 192.168.1.2 50000 typ host",
+                "protocol",
+            ),
+            (
+                "candidate:1 1 udp -1 192.168.1.2 50000 typ host",
+                "priority",
+            ),
+            ("candidate:1 1 udp 1 192.168.1.2 port typ host", "port"),
+            ("candidate:1 1 udp 1 192.168.1.2 65536 typ host", "port"),
+            ("candidate:1 1 udp 1 bad_host 50000 typ host", "address"),
+            ("candidate:1 1 udp 1 192.168.1.2 50000 type host", "typ"),
+            (
+                "candidate:1 1 udp 1 192.168.1.2 50000 typ peer",
+                "candidate type",
+            ),
+            (
+                "candidate:1  1 udp 1 192.168.1.2 50000 typ host",
+                "component",
+            ),
+        ] {
+            assert_eq!(invalid_field(candidate), Some(field), "{:?}", candidate);
+        }
+    }
+
+    #[test]
+    fn validate_candidate_rejects_oversized_candidates() {
+        let candidate = "candidate:1 1 udp 2130706431 192.168.1.2 50000 typ host";
+        let padding = |len: usize| " x".repeat((len - candidate.len()) / 2);
+        let longest = format!("{}{}", candidate, padding(MAX_CANDIDATE_LEN));
+        assert_eq!(invalid_field(&longest), None);
+        let oversized = format!("{}{}", candidate, padding(MAX_CANDIDATE_LEN + 2));
+        assert_eq!(invalid_field(&oversized), Some("length"));
+        let foundation = format!(
+            "candidate:{} 1 udp 1 192.168.1.2 50000 typ host",
+            "f".repeat(33)
+        );
+        assert_eq!(invalid_field(&foundation), Some("foundation"));
+    }
+
+    fn host_candidate() -> RTCIceCandidate {
+        RTCIceCandidate {
+            foundation: "1".into(),
+            priority: 2130706431,
+            address: "192.168.1.2".into(),
+            protocol: RTCIceProtocol::Udp,
+            port: 50000,
+            typ: RTCIceCandidateType::Host,
+            component: 1,
+            ..Default::default()
+        }
+    }
+
+    /// the field reported by `validate_ice_candidate`
+    fn invalid_ice_field(candidate: RTCIceCandidate) -> Option<&'static str> {
+        match validate_ice_candidate(&candidate).map_err(|e| e.downcast::<Error>()) {
+            Err(Ok(Error::InvalidCandidate { field, .. })) => Some(field),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn validate_ice_candidate_accepts_well_formed_candidates() {
+        assert_eq!(invalid_ice_field(host_candidate()), None);
+        let relay = RTCIceCandidate {
+            foundation: "a+b/c".into(),
+            address: "4f3c1b2a-5d6e.local".into(),
+            protocol: RTCIceProtocol::Tcp,
+            typ: RTCIceCandidateType::Relay,
+            component: 256,
+            ..host_candidate()
+        };
+        assert_eq!(invalid_ice_field(relay), None);
+    }
+
+    #[test]
+    fn validate_ice_candidate_reports_the_malformed_field() {
+        let cases = [
+            (
+                "foundation",
+                RTCIceCandidate {
+                    foundation: "not/a+foundation!".into(),
+                    ..host_candidate()
+                },
+            ),
+            (
+                "foundation",
+                RTCIceCandidate {
+                    foundation: "f".repeat(33),
+                    ..host_candidate()
+                },
+            ),
+            (
+                "component",
+                RTCIceCandidate {
+                    component: 0,
+                    ..host_candidate()
+                },
+            ),
+            (
+                "protocol",
+                RTCIceCandidate {
+                    protocol: RTCIceProtocol::Unspecified,
+                    ..host_candidate()
+                },
+            ),
+            (
+                "address",
+                RTCIceCandidate {
+                    address: String::new(),
+                    ..host_candidate()
+                },
+            ),
+            (
+                "address",
+                RTCIceCandidate {
+                    address: "not an address".into(),
+                    ..host_candidate()
+                },
+            ),
+            (
+                "candidate type",
+                RTCIceCandidate {
+                    typ: RTCIceCandidateType::Unspecified,
+                    ..host_candidate()
+                },
+            ),
+            (
+                "length",
+                RTCIceCandidate {
+                    address: format!("{}.local", "a".repeat(MAX_CANDIDATE_LEN)),
+                    ..host_candidate()
+                },
+            ),
+        ];
+        for (field, candidate) in cases {
+            assert_eq!(invalid_ice_field(candidate), Some(field));
+        }
+    }
+
+    #[test]
+    fn backoff_doubles_from_the_initial_backoff() {
+        let policy = ReconnectPolicy {
+            initial_backoff: Duration::from_millis(250),
+            max_backoff: Duration::from_secs(60),
+            ..Default::default()
+        };
+        let backoffs: Vec<Duration> = (1..=4).map(|attempt| policy.backoff(attempt)).collect();
+        assert_eq!(backoffs, [250, 500, 1000, 2000].map(Duration::from_millis));
+        // an attempt counted from 0 is treated as the first
+        assert_eq!(policy.backoff(0), policy.initial_backoff);
+    }
+
+    #[test]
+    fn backoff_is_capped_at_the_max_backoff() {
+        let policy = ReconnectPolicy::default();
+        assert_eq!(policy.backoff(6), Duration::from_secs(30));
+        // doesn't overflow
+        assert_eq!(policy.backoff(40), policy.max_backoff);
+        assert_eq!(policy.backoff(u32::MAX), policy.max_backoff);
+        let policy = ReconnectPolicy {
+            initial_backoff: Duration::MAX,
+            ..Default::default()
+        };
+        assert_eq!(policy.backoff(2), policy.max_backoff);
+    }
+}
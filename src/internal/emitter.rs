@@ -0,0 +1,187 @@
+use anyhow::{bail, Result};
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use tokio::sync::mpsc;
+use webrtc::peer_connection::RTCPeerConnection;
+
+use crate::internal::data_types::PeerId;
+use crate::internal::events::EmittedEvents;
+
+/// what the Controller does once `EmittedEvents` can't be delivered, which happens when the
+/// application drops the receiving end of `emitted_event_chan` (ex: its event loop crashed).
+/// the channel is unbounded, so a failed send means the receiver is gone for good.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OnEventChanClosed {
+    /// close every peer connection and refuse new ones. nothing is left to handle their events
+    #[default]
+    Shutdown,
+    /// log the failure and keep running. the events are dropped, except for the ones kept for
+    /// `Controller::attach_event_chan`, and the calls which emit them still succeed
+    Ignore,
+}
+
+/// wraps `emitted_event_chan`. cloned into the PeerConnection callbacks, which have no access to
+/// the Controller but still need to be able to shut it down
+#[derive(Clone)]
+pub struct EventEmitter {
+    /// shared, so that `attach` replaces the channel of every clone
+    chan: Arc<Mutex<EventChan>>,
+    /// the connections to close on shutdown. weak, because the Controller owns the connections
+    connections: Arc<Mutex<HashMap<PeerId, Weak<RTCPeerConnection>>>>,
+    shut_down: Arc<AtomicBool>,
+}
+
+struct EventChan {
+    tx: mpsc::UnboundedSender<EmittedEvents>,
+    /// the most recent events, oldest first. see `ControllerConfig::event_replay_len`
+    replay: VecDeque<EmittedEvents>,
+    replay_len: usize,
+    on_closed: OnEventChanClosed,
+}
+
+impl EventEmitter {
+    pub fn new(
+        tx: mpsc::UnboundedSender<EmittedEvents>,
+        on_closed: OnEventChanClosed,
+        replay_len: usize,
+    ) -> Self {
+        Self {
+            chan: Arc::new(Mutex::new(EventChan {
+                tx,
+                replay: VecDeque::with_capacity(replay_len),
+                replay_len,
+                on_closed,
+            })),
+            connections: Arc::new(Mutex::new(HashMap::new())),
+            shut_down: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// the number of clones, held by the Controller, its tasks and the connection handlers
+    #[cfg(test)]
+    pub fn clones(&self) -> usize {
+        Arc::strong_count(&self.chan)
+    }
+
+    /// sends the event to the application. if the receiver is gone, applies `OnEventChanClosed`:
+    /// fails only if the Controller shuts down
+    pub fn send(&self, event: EmittedEvents) -> Result<()> {
+        // the lock is held while sending, so that events can't be reordered by `attach`
+        let (result, on_closed) = match self.chan.lock() {
+            Ok(mut chan) => {
+                if chan.replay_len > 0 {
+                    if chan.replay.len() == chan.replay_len {
+                        chan.replay.pop_front();
+                    }
+                    chan.replay.push_back(event.clone());
+                }
+                (chan.tx.send(event), chan.on_closed)
+            }
+            Err(e) => bail!("failed to lock event channel: {}", e),
+        };
+        if let Err(e) = result {
+            if on_closed == OnEventChanClosed::Ignore {
+                log::warn!("failed to emit event: {}", e);
+                return Ok(());
+            }
+            self.on_send_failed();
+            bail!("failed to emit event: {}", e);
+        }
+        Ok(())
+    }
+
+    /// replaces the channel the events are sent to. the buffered events are replayed to it first
+    pub fn attach(&self, tx: mpsc::UnboundedSender<EmittedEvents>) -> Result<()> {
+        if self.is_shut_down() {
+            bail!("the Controller shut down after its event channel closed");
+        }
+        let mut chan = match self.chan.lock() {
+            Ok(c) => c,
+            Err(e) => bail!("failed to lock event channel: {}", e),
+        };
+        for event in &chan.replay {
+            if tx.send(event.clone()).is_err() {
+                bail!("the new event channel is closed");
+            }
+        }
+        chan.tx = tx;
+        Ok(())
+    }
+
+    pub fn is_shut_down(&self) -> bool {
+        self.shut_down.load(Ordering::SeqCst)
+    }
+
+    /// the connection is closed if the Controller shuts down
+    pub fn register(&self, peer_id: &PeerId, connection: &Arc<RTCPeerConnection>) {
+        match self.connections.lock() {
+            Ok(mut c) => {
+                c.insert(peer_id.clone(), Arc::downgrade(connection));
+            }
+            Err(e) => log::error!("failed to lock connections: {}", e),
+        }
+    }
+
+    pub fn unregister(&self, peer_id: &PeerId) {
+        match self.connections.lock() {
+            Ok(mut c) => {
+                c.remove(peer_id);
+            }
+            Err(e) => log::error!("failed to lock connections: {}", e),
+        }
+    }
+
+    /// the registered connections which haven't been dropped
+    pub fn connections(&self) -> Vec<(PeerId, Arc<RTCPeerConnection>)> {
+        match self.connections.lock() {
+            Ok(c) => c
+                .iter()
+                .filter_map(|(id, pc)| Some((id.clone(), pc.upgrade()?)))
+                .collect(),
+            Err(e) => {
+                log::error!("failed to lock connections: {}", e);
+                vec![]
+            }
+        }
+    }
+
+    /// replaces the settings passed to `new`. the oldest buffered events are dropped if
+    /// `replay_len` is smaller
+    pub fn set_options(&self, on_closed: OnEventChanClosed, replay_len: usize) {
+        match self.chan.lock() {
+            Ok(mut chan) => {
+                chan.on_closed = on_closed;
+                chan.replay_len = replay_len;
+                while chan.replay.len() > replay_len {
+                    chan.replay.pop_front();
+                }
+            }
+            Err(e) => log::error!("failed to lock event channel: {}", e),
+        }
+    }
+
+    fn on_send_failed(&self) {
+        // only the first failure shuts down
+        if self.shut_down.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        log::warn!("the event receiver was dropped. closing all peer connections");
+
+        let connections: Vec<Arc<RTCPeerConnection>> = match self.connections.lock() {
+            Ok(mut c) => c.drain().filter_map(|(_, pc)| pc.upgrade()).collect(),
+            Err(e) => {
+                log::error!("failed to lock connections: {}", e);
+                return;
+            }
+        };
+        // send is called from synchronous callbacks
+        tokio::spawn(async move {
+            for pc in connections {
+                if let Err(e) = pc.close().await {
+                    log::error!("failed to close peer connection: {}", e);
+                }
+            }
+        });
+    }
+}
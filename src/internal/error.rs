@@ -0,0 +1,47 @@
+use std::fmt;
+
+/// errors which callers may want to handle individually.
+/// functions still return `anyhow::Result`; use `downcast_ref::<Error>()` to inspect them
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+    /// the peer id is empty, too long, or contains a character outside of the allowed set.
+    /// see `validate_peer_id`
+    InvalidPeerId(String),
+    /// a remote SDP was longer than `ControllerConfig::max_sdp_len`. contains its length in bytes
+    SdpTooLarge(usize),
+    /// the peer sent more than `ControllerConfig::max_remote_candidates` ICE candidates
+    TooManyCandidates(String),
+    /// a media source was added with a codec which isn't registered, so it can't be negotiated.
+    /// contains the codec's mime type. see `Controller::add_media_source`
+    CodecNotRegistered(String),
+    /// `accept_call` couldn't accept the remote's offer: it couldn't be applied, ex: it's not a
+    /// valid SDP, or none of its media can be received with the registered codecs. contains the
+    /// reason. see `RejectReason::Incompatible`
+    IncompatibleOffer(String),
+    /// a remote ICE candidate is malformed. contains the name of the offending field, ex: "port",
+    /// and its value, which is empty if the field is missing. see `validate_candidate` and
+    /// `validate_ice_candidate`
+    InvalidCandidate { field: &'static str, value: String },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::InvalidPeerId(id) => write!(f, "invalid peer id: {:?}", id),
+            Error::SdpTooLarge(len) => write!(f, "SDP is too large: {} bytes", len),
+            Error::TooManyCandidates(id) => write!(f, "too many ICE candidates from peer {}", id),
+            Error::CodecNotRegistered(mime_type) => {
+                write!(f, "codec is not registered: {}", mime_type)
+            }
+            Error::IncompatibleOffer(reason) => write!(f, "incompatible offer: {}", reason),
+            Error::InvalidCandidate { field, value } if value.is_empty() => {
+                write!(f, "invalid ICE candidate: missing {}", field)
+            }
+            Error::InvalidCandidate { field, value } => {
+                write!(f, "invalid ICE candidate: bad {}: {:?}", field, value)
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {}
@@ -1,7 +1,15 @@
-use crate::internal::data_types::PeerId;
+use crate::internal::data_types::{
+    DtlsFailure, HangupReason, KeyframeRequest, MediaSourceId, PeerId, PeerState, PublishedSource,
+    RejectReason,
+};
+use crate::internal::sdp::{OpusParams, TrackInfo};
+use std::collections::HashMap;
 use std::sync::Arc;
+use std::time::Duration;
 //use serde::{Serialize, Deserialize};
 use webrtc::ice_transport::ice_candidate::RTCIceCandidate;
+use webrtc::ice_transport::ice_connection_state::RTCIceConnectionState;
+use webrtc::ice_transport::ice_gatherer_state::RTCIceGathererState;
 use webrtc::peer_connection::sdp::session_description::RTCSessionDescription;
 
 use webrtc::track::track_remote::TrackRemote;
@@ -31,12 +39,17 @@ use webrtc::track::track_remote::TrackRemote;
 //     },
 // }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum EmittedEvents {
     Ice {
         dest: PeerId,
         candidate: Box<RTCIceCandidate>,
     },
+    /// emitted instead of `Ice` if `PeerOptions::ice_batch_window` is set
+    IceBatch {
+        dest: PeerId,
+        candidates: Vec<RTCIceCandidate>,
+    },
     Sdp {
         dest: PeerId,
         sdp: Box<RTCSessionDescription>,
@@ -46,16 +59,132 @@ pub enum EmittedEvents {
         dest: PeerId,
         sdp: Box<RTCSessionDescription>,
     },
-    /// unless a CallTerminated event was received, results in a reconnect
-    /// needs to be handled by the developer
+    /// the call ended, on both sides: emitted by `Controller::hang_up` and
+    /// `Controller::recv_hang_up`. if `by_remote` is false, the call was hung up locally: the
+    /// remote should be sent a CallTerminated signal, and call `Controller::recv_hang_up`. if
+    /// it's true, the remote hung up, and mustn't be signaled back
+    CallTerminated { dest: PeerId, by_remote: bool },
+    /// emitted after an answer is received if Opus was negotiated. contains the fmtp
+    /// parameters which the remote accepted. can be passed to `OpusSource::apply_remote_params`
+    RemoteOpusParams { peer: PeerId, params: OpusParams },
+    /// the ICE gathering state changed, ex: from `Gathering` to `Complete`. for diagnostics and
+    /// "connecting" UIs. emitted again when ICE is restarted. with trickle ICE, `Complete` can
+    /// be signaled to the remote, which passes it to `Controller::recv_end_of_candidates`
+    IceGatheringState {
+        peer: PeerId,
+        state: RTCIceGathererState,
+    },
+    /// ICE gathering completed without finding any candidates. the connection will never be
+    /// established; most likely there is no network connection.
+    NoCandidates { peer: PeerId },
+    /// emitted periodically for each connected peer after calling
+    /// `Controller::enable_stats_reporting`
+    StatsUpdated {
+        peer: PeerId,
+        /// bits per second sent and received since the previous update
+        bitrate: u64,
+        /// the fraction of packets lost, as reported by the remote. between 0 and 1
+        loss: f64,
+        /// the highest jitter reported by the peer for the media sources sent to it, if it
+        /// has sent a receiver report
+        jitter: Option<Duration>,
+        /// the round trip time, if it has been measured
+        rtt: Option<Duration>,
+    },
+    /// how the bandwidth set with `Controller::set_total_bandwidth` is split between the media
+    /// sources sent to the peer, in bits per second, by source. emitted again when a media
+    /// source is added or removed, or its content hint changes. webrtc-rs can't cap a sender's
+    /// bitrate, so the application must keep its encoders below these, ex: with
+    /// `OpusSource::set_bitrate`. a source is sent to every peer with the same encoding, so with
+    /// several peers its encoder should stay below the lowest allocation
+    BandwidthAllocated {
+        peer: PeerId,
+        allocation: HashMap<MediaSourceId, u32>,
+    },
+    /// the peer asked for a keyframe on a media source, ex: because it lost a frame or just
+    /// started decoding. the crate doesn't encode video, so the application must make its
+    /// encoder send a keyframe
+    KeyframeRequested {
+        peer: PeerId,
+        source: MediaSourceId,
+        request: KeyframeRequest,
+    },
+    /// the negotiation left a media source without a sending direction, ex: the remote answered
+    /// its media section with `a=inactive` or `a=sendonly`, so it isn't sent to the peer. a later
+    /// negotiation may make it active again
+    MediaInactive {
+        peer: PeerId,
+        source_id: MediaSourceId,
+    },
+    /// the remote called `hang_up_with_reason` and is closing the connection. the application
+    /// should call `recv_hang_up` to clean up
+    RemoteHangUp { peer: PeerId, reason: HangupReason },
+    /// the remote didn't accept the call. emitted by `Controller::recv_rejection`, after which
+    /// the peer is hung up
+    CallRejected { peer: PeerId, reason: RejectReason },
+    /// the remote called `Controller::hold`: it stopped sending its media, and may be sending
+    /// hold music instead. the application may stop playing the remote's tracks, which stay
+    /// open, until `Resumed`
+    OnHold { peer: PeerId },
+    /// the remote called `Controller::resume` after `OnHold`
+    Resumed { peer: PeerId },
+    /// unless a CallTerminated signal was received, results in a reconnect
+    /// needs to be handled by the developer.
+    /// if a `ReconnectPolicy` is set, this is only emitted once every attempt failed.
+    /// the peer is then `PeerState::Failed`
     Disconnected { peer: PeerId },
+    /// ICE connected to the peer, including after an ICE restart or a reconnect
+    Connected { peer: PeerId },
+    /// the peer moved to another `PeerState`. emitted for every transition
+    PeerStateChanged { peer: PeerId, state: PeerState },
+    /// the peer's ICE connection state changed, ex: to show that a call is connecting, or to
+    /// tell a transient `Disconnected` state, which ICE may recover from, from `Failed`.
+    /// emitted for every transition, before `Connected` or `Disconnected`
+    ConnectionStateChanged {
+        peer: PeerId,
+        state: RTCIceConnectionState,
+    },
+    /// ICE connected, but the DTLS handshake failed, so neither media nor data can be exchanged.
+    /// unlike `Disconnected`, this isn't a connectivity problem: reconnecting the same way is
+    /// likely to fail again
+    DtlsFailed { peer: PeerId, reason: DtlsFailure },
+    /// the connection failed and is being reestablished according to the `ReconnectPolicy`.
+    /// `attempt` counts from 1. followed by `Connected` or, once every attempt failed,
+    /// `Disconnected`
+    Reconnecting { peer: PeerId, attempt: u32 },
+    /// a connection to the peer was created, by `dial`, `accept_call` or a reconnect. emitted
+    /// before every other event of the connection, so a track can't be received from a peer
+    /// whose `PeerAdded` wasn't processed yet, even if its media arrives before `accept_call`
+    /// returns. the peer can be queried, ex: with `raw_peer_connection`, once `dial` or
+    /// `accept_call` returns
+    PeerAdded { peer: PeerId },
+    /// `Controller::join_call` found a participant of the call, which `via` is connected to,
+    /// and dialed it. `sources` are the tracks the participant sends to `via`; the ones sent to
+    /// us arrive as `TrackAdded`
+    ParticipantJoined {
+        peer: PeerId,
+        via: PeerId,
+        sources: Vec<PublishedSource>,
+    },
     /// a peer added a track. The calling application is responsible for reading from the track
-    /// and processing the output
+    /// and processing the output. always preceded by the peer's `PeerAdded`
     TrackAdded {
         peer: PeerId,
         track: Arc<TrackRemote>,
     },
-    // it appears that WebRTC doesn't emit an event for this. perhaps the track is automatically
-    // closed on the remote side when the local side calls `remove_track`
-    // TrackRemoved,
+    /// the remote started or stopped sending tracks during a renegotiation. computed from the
+    /// remote's SDP. `TrackAdded` is still emitted for each added track
+    RemoteMediaChanged {
+        peer: PeerId,
+        added: Vec<TrackInfo>,
+        removed: Vec<TrackInfo>,
+    },
+    /// the remote stopped sending a track, ex: it removed a media source. emitted after
+    /// `RemoteMediaChanged`, once for each of its `removed` tracks. `track_id` is the id of the
+    /// `TrackRemote` from `TrackAdded`.
+    /// webrtc-rs doesn't emit an event when a remote track ends, and reading the track doesn't
+    /// fail until its receiver is stopped, so this is detected from the remote's SDP: the track's
+    /// media section is removed, stops sending, or identifies another track. a track which is
+    /// muted or put on hold keeps its section and isn't removed. not emitted when the call ends
+    TrackRemoved { peer: PeerId, track_id: String },
 }
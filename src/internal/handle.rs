@@ -0,0 +1,310 @@
+use anyhow::{bail, Result};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use tokio::sync::{mpsc, oneshot};
+use webrtc::ice_transport::ice_candidate::RTCIceCandidate;
+use webrtc::peer_connection::sdp::session_description::RTCSessionDescription;
+
+use crate::internal::data_types::{PeerId, ReconnectPolicy};
+use crate::{Controller, InitArgs};
+
+// the Controller is owned by a dedicated task, which runs the commands sent by the handles one
+// at a time, in the order they were sent. a command only borrows the Controller while it runs,
+// so the application never holds a lock across its own awaits. the task keeps the Controller in
+// an Arc<tokio::sync::Mutex> only because `Controller::set_reconnect_policy` shares it with the
+// reconnect tasks.
+// when the last handle is dropped, the task calls `Controller::deinit` and ends.
+
+/// the future of a command passed to `ControllerHandle::run`, borrowing the Controller
+pub type CommandFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+type RunFn = Box<dyn for<'a> FnOnce(&'a mut Controller) -> CommandFuture<'a, ()> + Send>;
+
+enum Command {
+    Run(RunFn),
+    SetReconnectPolicy(Option<ReconnectPolicy>, oneshot::Sender<Result<()>>),
+}
+
+/// a Controller which runs on its own task, for applications which would otherwise wrap it in
+/// an `Arc<Mutex<Controller>>`. the handle is cheap to clone and its methods take `&self`, so
+/// each signaling and event handler can keep its own clone. the calls are queued and run one at
+/// a time, in order. the Controller is deinitialized when the last handle is dropped.
+/// the methods of the Controller which aren't wrapped can be called with `run`. a command must
+/// not wait for another call on a handle: it would wait for itself
+#[derive(Clone)]
+pub struct ControllerHandle {
+    commands: mpsc::UnboundedSender<Command>,
+}
+
+impl ControllerHandle {
+    /// creates the Controller with `Controller::init` and starts its task. must be called from
+    /// within a tokio runtime
+    pub fn init(args: InitArgs) -> Result<Self> {
+        let controller = Arc::new(tokio::sync::Mutex::new(Controller::init(args)?));
+        let (tx, rx) = mpsc::unbounded_channel();
+        tokio::spawn(run_commands(controller, rx));
+        Ok(Self { commands: tx })
+    }
+
+    /// runs `f` on the Controller's task, after the calls queued before it, and returns its
+    /// output. ex: `handle.run(|c| Box::pin(async move { c.hang_up(&id).await })).await`.
+    /// fails if the Controller's task has ended
+    pub async fn run<T, F>(&self, f: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: for<'a> FnOnce(&'a mut Controller) -> CommandFuture<'a, T> + Send + 'static,
+    {
+        let (tx, rx) = oneshot::channel();
+        let command: RunFn = Box::new(move |controller| {
+            Box::pin(async move {
+                // the caller may have stopped waiting
+                let _ = tx.send(f(controller).await);
+            })
+        });
+        if self.commands.send(Command::Run(command)).is_err() {
+            bail!("the Controller's task has ended");
+        }
+        match rx.await {
+            Ok(output) => Ok(output),
+            Err(_) => bail!("the Controller's task ended before running the command"),
+        }
+    }
+
+    /// see `Controller::deinit`. the handles can still be used afterwards, as the Controller can
+    pub async fn deinit(&self) -> Result<()> {
+        self.run(|c| Box::pin(c.deinit())).await?
+    }
+
+    /// see `Controller::dial`
+    pub async fn dial(&self, peer_id: &PeerId) -> Result<()> {
+        let peer_id = peer_id.clone();
+        self.run(|c| Box::pin(async move { c.dial(&peer_id).await }))
+            .await?
+    }
+
+    /// see `Controller::accept_call`
+    pub async fn accept_call(
+        &self,
+        peer_id: &PeerId,
+        remote_sdp: RTCSessionDescription,
+    ) -> Result<()> {
+        let peer_id = peer_id.clone();
+        self.run(|c| Box::pin(async move { c.accept_call(&peer_id, remote_sdp).await }))
+            .await?
+    }
+
+    /// see `Controller::hang_up`
+    pub async fn hang_up(&self, peer_id: &PeerId) -> Result<()> {
+        let peer_id = peer_id.clone();
+        self.run(|c| Box::pin(async move { c.hang_up(&peer_id).await }))
+            .await?
+    }
+
+    /// see `Controller::recv_hang_up`
+    pub async fn recv_hang_up(&self, peer_id: &PeerId) -> Result<()> {
+        let peer_id = peer_id.clone();
+        self.run(|c| Box::pin(async move { c.recv_hang_up(&peer_id).await }))
+            .await
+    }
+
+    /// see `Controller::recv_sdp`
+    pub async fn recv_sdp(&self, peer_id: &PeerId, sdp: RTCSessionDescription) -> Result<()> {
+        let peer_id = peer_id.clone();
+        self.run(|c| Box::pin(async move { c.recv_sdp(&peer_id, sdp).await }))
+            .await?
+    }
+
+    /// see `Controller::recv_ice`
+    pub async fn recv_ice(&self, peer_id: &PeerId, candidate: RTCIceCandidate) -> Result<()> {
+        let peer_id = peer_id.clone();
+        self.run(|c| Box::pin(async move { c.recv_ice(&peer_id, candidate).await }))
+            .await?
+    }
+
+    /// see `Controller::recv_end_of_candidates`
+    pub async fn recv_end_of_candidates(&self, peer_id: &PeerId) -> Result<()> {
+        let peer_id = peer_id.clone();
+        self.run(|c| Box::pin(async move { c.recv_end_of_candidates(&peer_id).await }))
+            .await?
+    }
+
+    /// see `Controller::set_reconnect_policy`. the reconnects run their attempts on the
+    /// Controller between the queued calls
+    pub async fn set_reconnect_policy(&self, policy: Option<ReconnectPolicy>) -> Result<()> {
+        let (tx, rx) = oneshot::channel();
+        if self
+            .commands
+            .send(Command::SetReconnectPolicy(policy, tx))
+            .is_err()
+        {
+            bail!("the Controller's task has ended");
+        }
+        match rx.await {
+            Ok(result) => result,
+            Err(_) => bail!("the Controller's task ended before setting the reconnect policy"),
+        }
+    }
+}
+
+async fn run_commands(
+    controller: Arc<tokio::sync::Mutex<Controller>>,
+    mut commands: mpsc::UnboundedReceiver<Command>,
+) {
+    while let Some(command) = commands.recv().await {
+        match command {
+            Command::Run(f) => {
+                let mut controller = controller.lock().await;
+                f(&mut controller).await;
+            }
+            Command::SetReconnectPolicy(policy, reply) => {
+                let result = Controller::set_reconnect_policy(&controller, policy).await;
+                let _ = reply.send(result);
+            }
+        }
+    }
+    log::debug!("every ControllerHandle was dropped");
+    if let Err(e) = controller.lock().await.deinit().await {
+        log::error!("failed to deinit Controller: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::loopback::{wait_for_event, A, B};
+    use crate::EmittedEvents;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    const TIMEOUT: Duration = Duration::from_secs(10);
+
+    fn init(id: &str) -> Result<(ControllerHandle, mpsc::UnboundedReceiver<EmittedEvents>)> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        Ok((ControllerHandle::init(InitArgs::new(id.into(), tx))?, rx))
+    }
+
+    /// applies the signals emitted by a Controller to the remote's handle, like
+    /// `testing::loopback` does with a locked Controller. every event is also sent to `observer`
+    async fn forward_signals(
+        me: PeerId,
+        mut events: mpsc::UnboundedReceiver<EmittedEvents>,
+        remote: ControllerHandle,
+        observer: mpsc::UnboundedSender<EmittedEvents>,
+    ) {
+        while let Some(event) = events.recv().await {
+            let result = match &event {
+                EmittedEvents::CallInitiated { sdp, .. } => {
+                    remote.accept_call(&me, (**sdp).clone()).await
+                }
+                EmittedEvents::Sdp { sdp, .. } => remote.recv_sdp(&me, (**sdp).clone()).await,
+                EmittedEvents::Ice { candidate, .. } => {
+                    remote.recv_ice(&me, (**candidate).clone()).await
+                }
+                EmittedEvents::IceBatch { candidates, .. } => {
+                    let (me, candidates) = (me.clone(), candidates.clone());
+                    remote
+                        .run(|c| Box::pin(async move { c.recv_ice_batch(&me, candidates).await }))
+                        .await
+                        .and_then(|result| result)
+                }
+                EmittedEvents::CallTerminated {
+                    by_remote: false, ..
+                } => remote.recv_hang_up(&me).await,
+                _ => Ok(()),
+            };
+            if let Err(e) = result {
+                log::error!("failed to forward a signal from {}: {}", me, e);
+            }
+            let _ = observer.send(event);
+        }
+    }
+
+    #[tokio::test]
+    async fn two_handles_connect_without_locking() -> Result<()> {
+        let (a, a_rx) = init(A)?;
+        let (b, b_rx) = init(B)?;
+        let (a_tx, mut a_events) = mpsc::unbounded_channel();
+        let (b_tx, mut b_events) = mpsc::unbounded_channel();
+        let forwarders = [
+            tokio::spawn(forward_signals(A.into(), a_rx, b.clone(), a_tx)),
+            tokio::spawn(forward_signals(B.into(), b_rx, a.clone(), b_tx)),
+        ];
+        a.dial(&B.into()).await?;
+        for (events, peer_id) in [(&mut a_events, B), (&mut b_events, A)] {
+            wait_for_event(events, TIMEOUT, |event| match event {
+                EmittedEvents::Connected { peer } if peer == peer_id => Some(()),
+                _ => None,
+            })
+            .await?;
+        }
+
+        a.hang_up(&B.into()).await?;
+        wait_for_event(&mut b_events, TIMEOUT, |event| match event {
+            EmittedEvents::CallTerminated { dest, by_remote } if dest == A => {
+                by_remote.then_some(())
+            }
+            _ => None,
+        })
+        .await?;
+        let has_peer = b
+            .run(|c| Box::pin(async move { c.raw_peer_connection(&A.into()).is_some() }))
+            .await?;
+        assert!(!has_peer);
+        for forwarder in forwarders {
+            forwarder.abort();
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn the_commands_run_one_at_a_time() -> Result<()> {
+        let (handle, _events) = init(A)?;
+        let running = Arc::new(AtomicBool::new(false));
+        let ran = Arc::new(AtomicUsize::new(0));
+        let mut tasks = vec![];
+        for _ in 0..10 {
+            let (handle, running, ran) = (handle.clone(), running.clone(), ran.clone());
+            tasks.push(tokio::spawn(async move {
+                handle
+                    .run(move |_| {
+                        Box::pin(async move {
+                            assert!(!running.swap(true, Ordering::SeqCst));
+                            tokio::time::sleep(Duration::from_millis(5)).await;
+                            running.store(false, Ordering::SeqCst);
+                            ran.fetch_add(1, Ordering::SeqCst);
+                        })
+                    })
+                    .await
+            }));
+        }
+        for task in tasks {
+            task.await??;
+        }
+        assert_eq!(ran.load(Ordering::SeqCst), 10);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn dropping_the_last_handle_deinits_the_controller() -> Result<()> {
+        let (handle, mut events) = init(A)?;
+        handle.dial(&B.into()).await?;
+        let clone = handle.clone();
+        drop(handle);
+        // the Controller is still running
+        let state = clone
+            .run(|c| Box::pin(async move { c.peer_state(&B.into()) }))
+            .await?;
+        assert!(state.is_some());
+        drop(clone);
+        wait_for_event(&mut events, TIMEOUT, |event| match event {
+            EmittedEvents::CallTerminated { dest, .. } if dest == B => Some(()),
+            _ => None,
+        })
+        .await?;
+        // the Controller, and every clone of its event sender, were dropped
+        let closed = async { while events.recv().await.is_some() {} };
+        tokio::time::timeout(TIMEOUT, closed).await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,88 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+// a measurement which hasn't been updated for this long is stale, ex: the sink stopped
+const MAX_AGE: Duration = Duration::from_secs(5);
+
+/// collects the receive side of the audio latency for one peer. obtained with
+/// `Controller::latency_probe` and passed to a sink via `SinkOptions::latency_probe`, which
+/// updates it as frames are decoded. see `Controller::measured_latency`
+#[derive(Debug, Clone)]
+pub struct LatencyProbe {
+    // when the remote's capture timestamps are compared with the local clock. see
+    // `PeerOptions::measure_capture_latency`
+    use_capture_time: bool,
+    state: Arc<Mutex<State>>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    // capture to decode, smoothed
+    capture_to_decode: Option<(Duration, Instant)>,
+    // time spent in the jitter buffer, smoothed
+    buffered: Option<(Duration, Instant)>,
+}
+
+impl LatencyProbe {
+    pub(crate) fn new(use_capture_time: bool) -> Self {
+        Self {
+            use_capture_time,
+            state: Arc::default(),
+        }
+    }
+
+    /// a frame captured at `capture_time` (the sender's clock) was just decoded
+    #[cfg_attr(not(feature = "media"), allow(dead_code))]
+    pub(crate) fn record_capture_time(&self, capture_time: SystemTime) {
+        if !self.use_capture_time {
+            return;
+        }
+        // the sender's clock is ahead, so the measurement is meaningless
+        let latency = match SystemTime::now().duration_since(capture_time) {
+            Ok(l) => l,
+            Err(_) => return,
+        };
+        match self.state.lock() {
+            Ok(mut s) => {
+                s.capture_to_decode = Some((smooth(s.capture_to_decode, latency), Instant::now()))
+            }
+            Err(e) => log::error!("failed to lock latency probe: {}", e),
+        }
+    }
+
+    /// a frame which waited `buffered` in the jitter buffer was just decoded
+    #[cfg_attr(not(feature = "media"), allow(dead_code))]
+    pub(crate) fn record_buffered(&self, buffered: Duration) {
+        match self.state.lock() {
+            Ok(mut s) => s.buffered = Some((smooth(s.buffered, buffered), Instant::now())),
+            Err(e) => log::error!("failed to lock latency probe: {}", e),
+        }
+    }
+
+    /// the smoothed capture to decode latency, if it's being measured
+    pub(crate) fn capture_to_decode(&self) -> Option<Duration> {
+        self.state
+            .lock()
+            .ok()
+            .and_then(|s| fresh(s.capture_to_decode))
+    }
+
+    /// the smoothed time frames spend in the jitter buffer, if a sink reported it
+    pub(crate) fn buffered(&self) -> Option<Duration> {
+        self.state.lock().ok().and_then(|s| fresh(s.buffered))
+    }
+}
+
+// an exponential moving average, with the gain used for the RTP interarrival jitter (RFC 3550)
+fn smooth(previous: Option<(Duration, Instant)>, sample: Duration) -> Duration {
+    match fresh(previous) {
+        Some(prev) => prev + sample / 16 - prev / 16,
+        None => sample,
+    }
+}
+
+fn fresh(value: Option<(Duration, Instant)>) -> Option<Duration> {
+    value
+        .filter(|(_, updated)| updated.elapsed() < MAX_AGE)
+        .map(|(v, _)| v)
+}
@@ -1,2 +1,21 @@
+pub mod abs_capture_time;
+pub mod bandwidth;
+pub mod certificate;
+pub mod control;
+pub mod counters;
 pub mod data_types;
+pub mod emitter;
+pub mod error;
 pub mod events;
+pub mod handle;
+pub mod latency;
+pub mod pacer;
+pub mod peer_state;
+pub mod reconnect;
+pub mod recorder;
+pub mod red;
+pub mod roster;
+pub mod sdp;
+pub mod splice;
+pub mod stats;
+pub mod webm;
@@ -0,0 +1,129 @@
+use async_trait::async_trait;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::time::Instant;
+use webrtc::interceptor::stream_info::StreamInfo;
+use webrtc::interceptor::{
+    self, Attributes, Interceptor, InterceptorBuilder, RTCPReader, RTCPWriter, RTPReader, RTPWriter,
+};
+use webrtc::rtp::packet::Packet;
+use webrtc::util::MarshalSize;
+
+// the pacer is an interceptor which wraps the RTP writer of every local stream, so that each
+// sender is paced separately for each peer. a write waits for the packet's turn instead of being
+// queued: the writer of the track is slowed down rather than packets piling up in memory.
+
+/// spaces out the RTP packets sent to a peer, instead of sending the packets of a frame back to
+/// back, which causes bursts that increase the jitter on slow links. see `ControllerConfig::pacer`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PacerConfig {
+    /// the rate at which each media source is sent, in bits per second. it must be above the
+    /// bitrate of the media, or the writes fall further and further behind. browsers pace at
+    /// 2.5 times the target bitrate
+    pub bitrate: u64,
+}
+
+/// builds the pacing interceptor of every connection. the bitrate is shared by all of them, so
+/// that it can be changed during calls
+#[derive(Clone, Default)]
+pub(crate) struct Pacer {
+    bitrate: Arc<AtomicU64>,
+}
+
+impl Pacer {
+    /// applies to the connections which are already paced too
+    pub fn set_bitrate(&self, bitrate: u64) {
+        self.bitrate.store(bitrate, Ordering::Relaxed);
+    }
+}
+
+impl InterceptorBuilder for Pacer {
+    fn build(&self, _id: &str) -> Result<Arc<dyn Interceptor + Send + Sync>, interceptor::Error> {
+        Ok(Arc::new(PacingInterceptor {
+            bitrate: self.bitrate.clone(),
+        }))
+    }
+}
+
+struct PacingInterceptor {
+    bitrate: Arc<AtomicU64>,
+}
+
+#[async_trait]
+impl Interceptor for PacingInterceptor {
+    async fn bind_rtcp_reader(
+        &self,
+        reader: Arc<dyn RTCPReader + Send + Sync>,
+    ) -> Arc<dyn RTCPReader + Send + Sync> {
+        reader
+    }
+
+    async fn bind_rtcp_writer(
+        &self,
+        writer: Arc<dyn RTCPWriter + Send + Sync>,
+    ) -> Arc<dyn RTCPWriter + Send + Sync> {
+        writer
+    }
+
+    async fn bind_local_stream(
+        &self,
+        _info: &StreamInfo,
+        writer: Arc<dyn RTPWriter + Send + Sync>,
+    ) -> Arc<dyn RTPWriter + Send + Sync> {
+        Arc::new(PacedWriter {
+            inner: writer,
+            bitrate: self.bitrate.clone(),
+            next_send: Mutex::new(Instant::now()),
+        })
+    }
+
+    async fn unbind_local_stream(&self, _info: &StreamInfo) {}
+
+    async fn bind_remote_stream(
+        &self,
+        _info: &StreamInfo,
+        reader: Arc<dyn RTPReader + Send + Sync>,
+    ) -> Arc<dyn RTPReader + Send + Sync> {
+        reader
+    }
+
+    async fn unbind_remote_stream(&self, _info: &StreamInfo) {}
+
+    async fn close(&self) -> Result<(), interceptor::Error> {
+        Ok(())
+    }
+}
+
+struct PacedWriter {
+    inner: Arc<dyn RTPWriter + Send + Sync>,
+    bitrate: Arc<AtomicU64>,
+    // when the next packet may be sent
+    next_send: Mutex<Instant>,
+}
+
+#[async_trait]
+impl RTPWriter for PacedWriter {
+    async fn write(
+        &self,
+        pkt: &Packet,
+        attributes: &Attributes,
+    ) -> Result<usize, interceptor::Error> {
+        let send_at = match self.next_send.lock() {
+            Ok(mut next_send) => {
+                // a stream which was idle doesn't get to send a burst
+                let send_at = (*next_send).max(Instant::now());
+                let bitrate = self.bitrate.load(Ordering::Relaxed).max(1);
+                let duration = pkt.marshal_size() as f64 * 8.0 / bitrate as f64;
+                *next_send = send_at + Duration::from_secs_f64(duration);
+                send_at
+            }
+            Err(e) => {
+                log::error!("failed to lock pacer: {}", e);
+                Instant::now()
+            }
+        };
+        tokio::time::sleep_until(send_at).await;
+        self.inner.write(pkt, attributes).await
+    }
+}
@@ -0,0 +1,175 @@
+use std::sync::{Arc, Mutex};
+use webrtc::ice_transport::ice_connection_state::RTCIceConnectionState;
+use webrtc::peer_connection::signaling_state::RTCSignalingState;
+
+use crate::internal::data_types::{PeerId, PeerState};
+use crate::internal::emitter::EventEmitter;
+use crate::internal::events::EmittedEvents;
+
+// the state is driven by the ICE and signaling state callbacks of the peer's connection, which
+// can't borrow the Controller, so it's shared with them. every transition is emitted as
+// `EmittedEvents::PeerStateChanged`.
+
+/// the `PeerState` of one connection
+#[derive(Clone)]
+pub(crate) struct PeerStateTracker {
+    peer: PeerId,
+    state: Arc<Mutex<PeerState>>,
+    emitter: EventEmitter,
+}
+
+impl PeerStateTracker {
+    pub fn new(peer: &PeerId, initial: PeerState, emitter: EventEmitter) -> Self {
+        Self {
+            peer: peer.clone(),
+            state: Arc::new(Mutex::new(initial)),
+            emitter,
+        }
+    }
+
+    pub fn get(&self) -> PeerState {
+        match self.state.lock() {
+            Ok(state) => *state,
+            Err(e) => {
+                log::error!("failed to lock peer state: {}", e);
+                PeerState::Disconnected
+            }
+        }
+    }
+
+    /// emits `PeerStateChanged` if the state changes
+    pub fn set(&self, new_state: PeerState) {
+        self.update(|_| Some(new_state));
+    }
+
+    /// a failed connection is set to Reconnecting or Failed by the caller, which knows whether a
+    /// `ReconnectPolicy` takes over
+    pub fn on_ice_state(&self, ice_state: RTCIceConnectionState) {
+        self.update(|state| match ice_state {
+            RTCIceConnectionState::Checking if state == PeerState::WaitingForSdp => {
+                Some(PeerState::WaitingForIce)
+            }
+            RTCIceConnectionState::Connected | RTCIceConnectionState::Completed => {
+                Some(PeerState::Connected)
+            }
+            // ICE may recover on its own. while reconnecting, the state only changes once ICE
+            // connects or every attempt failed
+            RTCIceConnectionState::Disconnected if state != PeerState::Reconnecting => {
+                Some(PeerState::Interrupted)
+            }
+            RTCIceConnectionState::Closed => Some(PeerState::Disconnected),
+            _ => None,
+        });
+    }
+
+    pub fn on_signaling_state(&self, signaling_state: RTCSignalingState) {
+        self.update(|state| match (signaling_state, state) {
+            // the first offer/answer exchange completed
+            (RTCSignalingState::Stable, PeerState::WaitingForSdp) => Some(PeerState::WaitingForIce),
+            (RTCSignalingState::Stable, PeerState::Negotiating) => Some(PeerState::Connected),
+            (
+                RTCSignalingState::HaveLocalOffer
+                | RTCSignalingState::HaveRemoteOffer
+                | RTCSignalingState::HaveLocalPranswer
+                | RTCSignalingState::HaveRemotePranswer,
+                PeerState::Connected,
+            ) => Some(PeerState::Negotiating),
+            _ => None,
+        });
+    }
+
+    fn update(&self, transition: impl FnOnce(PeerState) -> Option<PeerState>) {
+        let new_state = match self.state.lock() {
+            Ok(mut state) => match transition(*state) {
+                Some(new_state) if new_state != *state => {
+                    *state = new_state;
+                    new_state
+                }
+                _ => return,
+            },
+            Err(e) => {
+                log::error!("failed to lock peer state: {}", e);
+                return;
+            }
+        };
+        log::debug!("peer {} is {:?}", &self.peer, new_state);
+        if let Err(e) = self.emitter.send(EmittedEvents::PeerStateChanged {
+            peer: self.peer.clone(),
+            state: new_state,
+        }) {
+            log::error!(
+                "failed to send peer state change for peer {}: {}",
+                &self.peer,
+                e
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::internal::emitter::OnEventChanClosed;
+    use tokio::sync::mpsc;
+
+    fn tracker(initial: PeerState) -> (PeerStateTracker, mpsc::UnboundedReceiver<EmittedEvents>) {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let emitter = EventEmitter::new(tx, OnEventChanClosed::Ignore, 0);
+        (PeerStateTracker::new(&"peer".into(), initial, emitter), rx)
+    }
+
+    fn emitted(rx: &mut mpsc::UnboundedReceiver<EmittedEvents>) -> Vec<PeerState> {
+        let mut states = vec![];
+        while let Ok(event) = rx.try_recv() {
+            if let EmittedEvents::PeerStateChanged { state, .. } = event {
+                states.push(state);
+            }
+        }
+        states
+    }
+
+    #[test]
+    fn lost_connectivity_is_interrupted_until_ice_recovers() {
+        let (tracker, mut rx) = tracker(PeerState::Connected);
+        tracker.on_ice_state(RTCIceConnectionState::Disconnected);
+        assert_eq!(tracker.get(), PeerState::Interrupted);
+        tracker.on_ice_state(RTCIceConnectionState::Connected);
+        assert_eq!(tracker.get(), PeerState::Connected);
+        assert_eq!(
+            emitted(&mut rx),
+            [PeerState::Interrupted, PeerState::Connected]
+        );
+    }
+
+    #[test]
+    fn lost_connectivity_while_reconnecting_stays_reconnecting() {
+        let (tracker, mut rx) = tracker(PeerState::Reconnecting);
+        tracker.on_ice_state(RTCIceConnectionState::Disconnected);
+        assert_eq!(tracker.get(), PeerState::Reconnecting);
+        assert!(emitted(&mut rx).is_empty());
+    }
+
+    #[test]
+    fn failed_ice_is_left_to_the_caller() {
+        let (tracker, mut rx) = tracker(PeerState::Connected);
+        tracker.on_ice_state(RTCIceConnectionState::Failed);
+        assert_eq!(tracker.get(), PeerState::Connected);
+        tracker.set(PeerState::Failed);
+        assert_eq!(emitted(&mut rx), [PeerState::Failed]);
+    }
+
+    #[test]
+    fn a_closed_connection_is_disconnected() {
+        let (tracker, mut rx) = tracker(PeerState::Failed);
+        tracker.on_ice_state(RTCIceConnectionState::Closed);
+        assert_eq!(tracker.get(), PeerState::Disconnected);
+        assert_eq!(emitted(&mut rx), [PeerState::Disconnected]);
+    }
+
+    #[test]
+    fn setting_the_same_state_emits_nothing() {
+        let (tracker, mut rx) = tracker(PeerState::WaitingForIce);
+        tracker.set(PeerState::WaitingForIce);
+        assert!(emitted(&mut rx).is_empty());
+    }
+}
@@ -0,0 +1,146 @@
+use std::collections::HashMap;
+use std::sync::Weak;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use webrtc::ice_transport::ice_connection_state::RTCIceConnectionState;
+
+use crate::internal::data_types::{PeerId, PeerState, ReconnectPolicy};
+use crate::internal::emitter::EventEmitter;
+use crate::internal::events::EmittedEvents;
+use crate::Controller;
+
+// executes a `ReconnectPolicy`. the ICE state handler of every peer sends the peer's id to
+// `failed` when its connection fails. the Controller is only locked to start an attempt, so the
+// application can keep using it while waiting for the attempt to connect.
+// the task holds a Weak reference, so that it doesn't keep the Controller alive.
+
+// how often an attempt checks whether ICE connected
+const CONNECTED_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// reconnects the peers received from `failed`, one task per peer. a peer which fails again
+/// while it's being reconnected is ignored. the reconnects are aborted when this future is dropped
+pub(crate) async fn run(
+    controller: Weak<tokio::sync::Mutex<Controller>>,
+    emitter: EventEmitter,
+    policy: ReconnectPolicy,
+    mut failed: mpsc::UnboundedReceiver<PeerId>,
+) {
+    let mut reconnects = Reconnects::default();
+    while let Some(peer_id) = failed.recv().await {
+        reconnects.0.retain(|_, task| !task.is_finished());
+        if reconnects.0.contains_key(&peer_id) {
+            log::debug!("peer {} is already reconnecting", &peer_id);
+            continue;
+        }
+        let task = tokio::spawn(reconnect(
+            controller.clone(),
+            emitter.clone(),
+            policy,
+            peer_id.clone(),
+        ));
+        reconnects.0.insert(peer_id, task);
+    }
+}
+
+// aborts the reconnects when dropped
+#[derive(Default)]
+struct Reconnects(HashMap<PeerId, JoinHandle<()>>);
+
+impl Drop for Reconnects {
+    fn drop(&mut self) {
+        for task in self.0.values() {
+            task.abort();
+        }
+    }
+}
+
+async fn reconnect(
+    controller: Weak<tokio::sync::Mutex<Controller>>,
+    emitter: EventEmitter,
+    policy: ReconnectPolicy,
+    peer_id: PeerId,
+) {
+    for attempt in 1..=policy.max_attempts {
+        if let Err(e) = emitter.send(EmittedEvents::Reconnecting {
+            peer: peer_id.clone(),
+            attempt,
+        }) {
+            log::error!("failed to send reconnecting event: {}", e);
+        }
+        tokio::time::sleep(policy.backoff(attempt)).await;
+
+        let pc = {
+            let controller = match controller.upgrade() {
+                Some(c) => c,
+                None => return,
+            };
+            let mut controller = controller.lock().await;
+            let options = match controller.peers.get(&peer_id) {
+                Some(peer) => peer.options.clone(),
+                // hung up while waiting
+                None => return,
+            };
+            let result = if attempt <= policy.ice_restarts {
+                controller.restart_ice(&peer_id).await
+            } else {
+                controller.dial_with_options(&peer_id, options).await
+            };
+            if let Err(e) = result {
+                log::warn!(
+                    "reconnect attempt {} for peer {} failed: {}",
+                    attempt,
+                    &peer_id,
+                    e
+                );
+                continue;
+            }
+            match controller.raw_peer_connection(&peer_id) {
+                Some(pc) => pc,
+                None => return,
+            }
+        };
+
+        // Failed isn't a reason to stop waiting: an ICE restart leaves the state at Failed until
+        // the remote answers
+        let connected = async {
+            let mut ticker = tokio::time::interval(CONNECTED_POLL_INTERVAL);
+            loop {
+                ticker.tick().await;
+                if matches!(
+                    pc.ice_connection_state(),
+                    RTCIceConnectionState::Connected | RTCIceConnectionState::Completed
+                ) {
+                    break;
+                }
+            }
+        };
+        // the Connected event is emitted by the ICE state handler
+        if tokio::time::timeout(policy.attempt_timeout, connected)
+            .await
+            .is_ok()
+        {
+            log::info!(
+                "reconnected to peer {} after {} attempts",
+                &peer_id,
+                attempt
+            );
+            return;
+        }
+        log::warn!(
+            "reconnect attempt {} for peer {} timed out",
+            attempt,
+            &peer_id
+        );
+    }
+
+    log::warn!("failed to reconnect to peer {}", &peer_id);
+    if let Some(controller) = controller.upgrade() {
+        if let Some(peer) = controller.lock().await.peers.get(&peer_id) {
+            peer.state_tracker.set(PeerState::Failed);
+        }
+    }
+    if let Err(e) = emitter.send(EmittedEvents::Disconnected { peer: peer_id }) {
+        log::error!("failed to send disconnect event: {}", e);
+    }
+}
@@ -0,0 +1,639 @@
+use anyhow::{bail, Result};
+use async_trait::async_trait;
+use bytes::Bytes;
+use std::collections::{HashMap, VecDeque};
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+use webrtc::interceptor::stream_info::StreamInfo;
+use webrtc::interceptor::{
+    self, Attributes, Interceptor, InterceptorBuilder, RTCPReader, RTCPWriter, RTPReader, RTPWriter,
+};
+use webrtc::media::io::sample_builder::SampleBuilder;
+use webrtc::media::Sample;
+use webrtc::rtcp::sender_report::SenderReport;
+use webrtc::rtp::codecs::{opus::OpusPacket, vp8::Vp8Packet, vp9::Vp9Packet};
+use webrtc::rtp::packet::Packet;
+use webrtc::rtp_transceiver::rtp_receiver::RTCRtpReceiver;
+use webrtc::util::Unmarshal;
+
+use crate::internal::webm::{vp8_keyframe_size, vp9_keyframe_size, WebmCodec, WebmWriter};
+
+// records the tracks of a peer to a WebM file. see `Controller::record_peer`.
+// the RTP packets are copied by an interceptor as the application reads the tracks, so that
+// recording doesn't compete with playback for the packets. the tracks are reassembled into
+// frames, and each track's RTP timestamps are mapped to a common timeline: the remote's clock,
+// from its RTCP sender reports, or the arrival times when it didn't send any. a video track
+// starts at its first key frame. the frames of the tracks are then interleaved by time.
+
+/// how long the tracks which haven't started yet, and the sender reports, are waited for before
+/// the file's header is written. the tracks which haven't started by then aren't recorded
+const START_TIMEOUT: Duration = Duration::from_secs(3);
+/// how long a frame waits for the frames of the other tracks, so that they're written in order.
+/// a track which stopped, ex: the remote stopped sending it, holds up the others that long
+const MAX_INTERLEAVE_DELAY: Duration = Duration::from_secs(1);
+/// how often the frames which waited long enough are written
+const FLUSH_INTERVAL: Duration = Duration::from_millis(100);
+/// the number of late packets allowed. a video frame spans many packets
+const MAX_LATE: u16 = 256;
+
+/// the codecs which can be recorded
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum RecordedCodec {
+    Vp8,
+    Vp9,
+    Opus,
+}
+
+/// a remote track to record
+#[derive(Clone)]
+pub(crate) struct RecordedTrack {
+    pub ssrc: u32,
+    pub codec: RecordedCodec,
+    pub clock_rate: u32,
+    pub channels: u16,
+    /// the sender reports are read from the track's receiver
+    pub receiver: Arc<RTCRtpReceiver>,
+}
+
+enum TapEvent {
+    Packet {
+        track: usize,
+        packet: Packet,
+        arrival: Instant,
+    },
+    SenderReport {
+        track: usize,
+        ntp_time: u64,
+        rtp_time: u32,
+    },
+    /// the remote stream was unbound, ex: the remote removed the track
+    Ended { track: usize },
+}
+
+struct Tap {
+    track: usize,
+    tx: mpsc::UnboundedSender<TapEvent>,
+}
+
+/// the remote streams being recorded, by SSRC. shared by the Controller and the interceptor of
+/// every connection
+#[derive(Clone, Default)]
+pub(crate) struct RecordingTaps {
+    taps: Arc<Mutex<HashMap<u32, Tap>>>,
+}
+
+impl RecordingTaps {
+    fn tap(&self, ssrc: u32, track: usize, tx: mpsc::UnboundedSender<TapEvent>) {
+        match self.taps.lock() {
+            Ok(mut taps) => {
+                taps.insert(ssrc, Tap { track, tx });
+            }
+            Err(e) => log::error!("failed to lock recording taps: {}", e),
+        }
+    }
+
+    /// removes the tap. the recording is told that the track ended if `ended`
+    fn untap(&self, ssrc: u32, ended: bool) {
+        let tap = match self.taps.lock() {
+            Ok(mut taps) => taps.remove(&ssrc),
+            Err(e) => {
+                log::error!("failed to lock recording taps: {}", e);
+                None
+            }
+        };
+        if let Some(tap) = tap.filter(|_| ended) {
+            let _ = tap.tx.send(TapEvent::Ended { track: tap.track });
+        }
+    }
+
+    /// copies the packet to the recording, if the stream is recorded
+    fn forward(&self, ssrc: u32, mut buf: &[u8]) {
+        let taps = match self.taps.lock() {
+            Ok(t) => t,
+            Err(e) => {
+                log::error!("failed to lock recording taps: {}", e);
+                return;
+            }
+        };
+        let tap = match taps.get(&ssrc) {
+            Some(tap) => tap,
+            None => return,
+        };
+        match Packet::unmarshal(&mut buf) {
+            Ok(packet) => {
+                let _ = tap.tx.send(TapEvent::Packet {
+                    track: tap.track,
+                    packet,
+                    arrival: Instant::now(),
+                });
+            }
+            Err(e) => log::warn!("failed to unmarshal recorded packet: {}", e),
+        }
+    }
+}
+
+impl InterceptorBuilder for RecordingTaps {
+    fn build(&self, _id: &str) -> Result<Arc<dyn Interceptor + Send + Sync>, interceptor::Error> {
+        Ok(Arc::new(RecordingInterceptor { taps: self.clone() }))
+    }
+}
+
+struct RecordingInterceptor {
+    taps: RecordingTaps,
+}
+
+#[async_trait]
+impl Interceptor for RecordingInterceptor {
+    async fn bind_rtcp_reader(
+        &self,
+        reader: Arc<dyn RTCPReader + Send + Sync>,
+    ) -> Arc<dyn RTCPReader + Send + Sync> {
+        reader
+    }
+
+    async fn bind_rtcp_writer(
+        &self,
+        writer: Arc<dyn RTCPWriter + Send + Sync>,
+    ) -> Arc<dyn RTCPWriter + Send + Sync> {
+        writer
+    }
+
+    async fn bind_local_stream(
+        &self,
+        _info: &StreamInfo,
+        writer: Arc<dyn RTPWriter + Send + Sync>,
+    ) -> Arc<dyn RTPWriter + Send + Sync> {
+        writer
+    }
+
+    async fn unbind_local_stream(&self, _info: &StreamInfo) {}
+
+    async fn bind_remote_stream(
+        &self,
+        info: &StreamInfo,
+        reader: Arc<dyn RTPReader + Send + Sync>,
+    ) -> Arc<dyn RTPReader + Send + Sync> {
+        Arc::new(TapReader {
+            inner: reader,
+            ssrc: info.ssrc,
+            taps: self.taps.clone(),
+        })
+    }
+
+    async fn unbind_remote_stream(&self, info: &StreamInfo) {
+        self.taps.untap(info.ssrc, true);
+    }
+
+    async fn close(&self) -> Result<(), interceptor::Error> {
+        Ok(())
+    }
+}
+
+struct TapReader {
+    inner: Arc<dyn RTPReader + Send + Sync>,
+    ssrc: u32,
+    taps: RecordingTaps,
+}
+
+#[async_trait]
+impl RTPReader for TapReader {
+    async fn read(
+        &self,
+        buf: &mut [u8],
+        attributes: &Attributes,
+    ) -> Result<(usize, Attributes), interceptor::Error> {
+        let (n, attributes) = self.inner.read(buf, attributes).await?;
+        self.taps.forward(self.ssrc, &buf[..n]);
+        Ok((n, attributes))
+    }
+}
+
+/// a recording in progress. `stop` finishes the file
+pub(crate) struct Recording {
+    taps: RecordingTaps,
+    ssrcs: Vec<u32>,
+    stop_tx: oneshot::Sender<()>,
+    task: JoinHandle<Result<()>>,
+    rtcp_tasks: Vec<JoinHandle<()>>,
+}
+
+impl Recording {
+    /// creates the file and starts recording the tracks
+    pub fn start(path: &Path, tracks: Vec<RecordedTrack>, taps: &RecordingTaps) -> Result<Self> {
+        if tracks.is_empty() {
+            bail!("no track to record");
+        }
+        let out = BufWriter::new(File::create(path)?);
+        let (tx, rx) = mpsc::unbounded_channel();
+        let mut rtcp_tasks = vec![];
+        let mut receivers: Vec<&Arc<RTCRtpReceiver>> = vec![];
+        for (i, track) in tracks.iter().enumerate() {
+            taps.tap(track.ssrc, i, tx.clone());
+            // one reader per receiver, for all of its tracks
+            if receivers.iter().any(|r| Arc::ptr_eq(r, &track.receiver)) {
+                continue;
+            }
+            receivers.push(&track.receiver);
+            let ssrcs: Vec<(u32, usize)> = tracks
+                .iter()
+                .enumerate()
+                .filter(|(_, t)| Arc::ptr_eq(&t.receiver, &track.receiver))
+                .map(|(i, t)| (t.ssrc, i))
+                .collect();
+            rtcp_tasks.push(tokio::spawn(read_sender_reports(
+                track.receiver.clone(),
+                ssrcs,
+                tx.clone(),
+            )));
+        }
+        let ssrcs = tracks.iter().map(|t| t.ssrc).collect();
+        let (stop_tx, stop_rx) = oneshot::channel();
+        let recorder = Recorder::new(out, tracks);
+        let task = tokio::spawn(recorder.run(rx, stop_rx));
+
+        Ok(Self {
+            taps: taps.clone(),
+            ssrcs,
+            stop_tx,
+            task,
+            rtcp_tasks,
+        })
+    }
+
+    /// writes the frames which are still buffered and finishes the file. fails if writing the
+    /// file failed, or if nothing was recorded
+    pub async fn stop(self) -> Result<()> {
+        for ssrc in &self.ssrcs {
+            self.taps.untap(*ssrc, false);
+        }
+        for task in &self.rtcp_tasks {
+            task.abort();
+        }
+        let _ = self.stop_tx.send(());
+        match self.task.await {
+            Ok(result) => result,
+            Err(e) => bail!("recording task failed: {}", e),
+        }
+    }
+}
+
+/// reads the RTCP packets of the receiver, which nothing else reads, and forwards the sender
+/// reports of the recorded tracks. `ssrcs` maps the SSRCs to the tracks
+async fn read_sender_reports(
+    receiver: Arc<RTCRtpReceiver>,
+    ssrcs: Vec<(u32, usize)>,
+    tx: mpsc::UnboundedSender<TapEvent>,
+) {
+    while let Ok((packets, _)) = receiver.read_rtcp().await {
+        for packet in packets {
+            let report = match packet.as_any().downcast_ref::<SenderReport>() {
+                Some(r) => r,
+                None => continue,
+            };
+            for (ssrc, track) in &ssrcs {
+                if *ssrc == report.ssrc {
+                    let event = TapEvent::SenderReport {
+                        track: *track,
+                        ntp_time: report.ntp_time,
+                        rtp_time: report.rtp_time,
+                    };
+                    if tx.send(event).is_err() {
+                        return;
+                    }
+                }
+            }
+        }
+    }
+}
+
+enum Depacketizer {
+    Vp8(SampleBuilder<Vp8Packet>),
+    Vp9(SampleBuilder<Vp9Packet>),
+    Opus(SampleBuilder<OpusPacket>),
+}
+
+impl Depacketizer {
+    fn push(&mut self, packet: Packet) {
+        match self {
+            Depacketizer::Vp8(b) => b.push(packet),
+            Depacketizer::Vp9(b) => b.push(packet),
+            Depacketizer::Opus(b) => b.push(packet),
+        }
+    }
+
+    fn pop(&mut self) -> Option<Sample> {
+        match self {
+            Depacketizer::Vp8(b) => b.pop(),
+            Depacketizer::Vp9(b) => b.pop(),
+            Depacketizer::Opus(b) => b.pop(),
+        }
+    }
+}
+
+struct Frame {
+    // clock ticks since the track's first frame
+    ticks: i64,
+    keyframe: bool,
+    data: Bytes,
+    arrival: Instant,
+}
+
+struct TrackState {
+    codec: RecordedCodec,
+    clock_rate: u32,
+    channels: u16,
+    depacketizer: Depacketizer,
+    // the codec in the file, known once the track starts, at its first key frame
+    webm_codec: Option<WebmCodec>,
+    // the RTP timestamp and arrival time of the first frame
+    first: Option<(u32, Instant)>,
+    last_timestamp: u32,
+    ticks: i64,
+    // the last sender report: NTP time and RTP timestamp
+    sender_report: Option<(u64, u32)>,
+    frames: VecDeque<Frame>,
+    ended: bool,
+    // set once the header is written. None if the track isn't in the file
+    file_track: Option<usize>,
+    // where the track starts in the file, in milliseconds
+    offset: i64,
+}
+
+impl TrackState {
+    fn new(track: &RecordedTrack) -> Self {
+        let depacketizer = match track.codec {
+            RecordedCodec::Vp8 => Depacketizer::Vp8(SampleBuilder::new(
+                MAX_LATE,
+                Vp8Packet::default(),
+                track.clock_rate,
+            )),
+            RecordedCodec::Vp9 => Depacketizer::Vp9(SampleBuilder::new(
+                MAX_LATE,
+                Vp9Packet::default(),
+                track.clock_rate,
+            )),
+            RecordedCodec::Opus => {
+                Depacketizer::Opus(SampleBuilder::new(MAX_LATE, OpusPacket, track.clock_rate))
+            }
+        };
+        Self {
+            codec: track.codec,
+            clock_rate: track.clock_rate.max(1),
+            channels: track.channels,
+            depacketizer,
+            webm_codec: None,
+            first: None,
+            last_timestamp: 0,
+            ticks: 0,
+            sender_report: None,
+            frames: VecDeque::new(),
+            ended: false,
+            file_track: None,
+            offset: 0,
+        }
+    }
+
+    fn push(&mut self, packet: Packet, arrival: Instant) {
+        self.depacketizer.push(packet);
+        while let Some(sample) = self.depacketizer.pop() {
+            let keyframe_codec = match self.codec {
+                RecordedCodec::Vp8 => vp8_keyframe_size(&sample.data)
+                    .map(|(width, height)| WebmCodec::Vp8 { width, height }),
+                RecordedCodec::Vp9 => vp9_keyframe_size(&sample.data)
+                    .map(|(width, height)| WebmCodec::Vp9 { width, height }),
+                RecordedCodec::Opus => Some(WebmCodec::Opus {
+                    channels: self.channels,
+                }),
+            };
+            let timestamp = sample.packet_timestamp;
+            match self.first {
+                Some(_) => {
+                    self.ticks += timestamp.wrapping_sub(self.last_timestamp) as i32 as i64;
+                }
+                None => {
+                    // the frames before the first key frame can't be decoded
+                    let codec = match keyframe_codec {
+                        Some(c) => c,
+                        None => continue,
+                    };
+                    self.webm_codec = Some(codec);
+                    self.first = Some((timestamp, arrival));
+                }
+            }
+            self.last_timestamp = timestamp;
+            self.frames.push_back(Frame {
+                ticks: self.ticks,
+                keyframe: keyframe_codec.is_some(),
+                data: sample.data,
+                arrival,
+            });
+        }
+    }
+
+    /// when the first frame was captured, in seconds, by the remote's clock if it sent a sender
+    /// report, otherwise by the time it arrived since `start`
+    fn start_time(&self, start: Instant, use_sender_report: bool) -> f64 {
+        let (timestamp, arrival) = match self.first {
+            Some(f) => f,
+            None => return 0.0,
+        };
+        match self.sender_report.filter(|_| use_sender_report) {
+            Some((ntp_time, rtp_time)) => {
+                // NTP time is a 32.32 fixed point number of seconds
+                let seconds =
+                    (ntp_time >> 32) as f64 + (ntp_time & 0xFFFF_FFFF) as f64 / 2f64.powi(32);
+                let since_report = timestamp.wrapping_sub(rtp_time) as i32 as f64;
+                seconds + since_report / self.clock_rate as f64
+            }
+            None => arrival.duration_since(start).as_secs_f64(),
+        }
+    }
+
+    /// the time of the frame in the file, in milliseconds
+    fn frame_time(&self, frame: &Frame) -> u64 {
+        (self.offset + frame.ticks * 1000 / self.clock_rate as i64).max(0) as u64
+    }
+}
+
+struct Recorder {
+    // until the header is written
+    out: Option<BufWriter<File>>,
+    writer: Option<WebmWriter<BufWriter<File>>>,
+    tracks: Vec<TrackState>,
+    start: Instant,
+}
+
+impl Recorder {
+    fn new(out: BufWriter<File>, tracks: Vec<RecordedTrack>) -> Self {
+        Self {
+            out: Some(out),
+            writer: None,
+            tracks: tracks.iter().map(TrackState::new).collect(),
+            start: Instant::now(),
+        }
+    }
+
+    async fn run(
+        mut self,
+        mut rx: mpsc::UnboundedReceiver<TapEvent>,
+        mut stop_rx: oneshot::Receiver<()>,
+    ) -> Result<()> {
+        let mut interval = tokio::time::interval(FLUSH_INTERVAL);
+        loop {
+            tokio::select! {
+                _ = &mut stop_rx => break,
+                event = rx.recv() => match event {
+                    Some(event) => self.handle(event),
+                    None => break,
+                },
+                _ = interval.tick() => {}
+            }
+            self.write_header(false)?;
+            self.write_frames(false)?;
+        }
+        self.finish()
+    }
+
+    fn handle(&mut self, event: TapEvent) {
+        match event {
+            TapEvent::Packet {
+                track,
+                packet,
+                arrival,
+            } => {
+                if let Some(t) = self.tracks.get_mut(track) {
+                    // a track which didn't start in time isn't in the file
+                    if self.writer.is_none() || t.file_track.is_some() {
+                        t.push(packet, arrival);
+                    }
+                }
+            }
+            TapEvent::SenderReport {
+                track,
+                ntp_time,
+                rtp_time,
+            } => {
+                if let Some(t) = self.tracks.get_mut(track) {
+                    t.sender_report = Some((ntp_time, rtp_time));
+                }
+            }
+            TapEvent::Ended { track } => {
+                if let Some(t) = self.tracks.get_mut(track) {
+                    t.ended = true;
+                }
+            }
+        }
+    }
+
+    /// writes the header once every track started and can be synchronized, or after
+    /// `START_TIMEOUT`. `force` writes it with the tracks which started so far
+    fn write_header(&mut self, force: bool) -> Result<()> {
+        let out = match self.out.take() {
+            Some(out) => out,
+            None => return Ok(()),
+        };
+        let started: Vec<usize> = (0..self.tracks.len())
+            .filter(|i| self.tracks[*i].first.is_some())
+            .collect();
+        let waiting = self.tracks.iter().any(|t| {
+            t.first.is_none() && !t.ended || t.first.is_some() && t.sender_report.is_none()
+        });
+        let timed_out = force || self.start.elapsed() >= START_TIMEOUT;
+        if started.is_empty() || waiting && !timed_out {
+            self.out = Some(out);
+            return Ok(());
+        }
+
+        // the remote's clock can't be compared to the arrival times
+        let use_sender_reports = started
+            .iter()
+            .all(|i| self.tracks[*i].sender_report.is_some());
+        let start_times: Vec<f64> = started
+            .iter()
+            .map(|i| self.tracks[*i].start_time(self.start, use_sender_reports))
+            .collect();
+        let first_start = start_times.iter().cloned().fold(f64::INFINITY, f64::min);
+        let mut codecs = vec![];
+        for (file_track, (i, start_time)) in started.iter().zip(start_times).enumerate() {
+            let track = &mut self.tracks[*i];
+            track.file_track = Some(file_track);
+            track.offset = ((start_time - first_start) * 1000.0) as i64;
+            codecs.extend(track.webm_codec);
+        }
+        for track in self.tracks.iter_mut().filter(|t| t.file_track.is_none()) {
+            log::warn!(
+                "a {:?} track didn't start in time to be recorded",
+                track.codec
+            );
+            track.frames.clear();
+        }
+        log::debug!(
+            "recording {} tracks, synchronized by {}",
+            codecs.len(),
+            if use_sender_reports {
+                "sender reports"
+            } else {
+                "arrival times"
+            }
+        );
+        self.writer = Some(WebmWriter::new(out, codecs)?);
+        Ok(())
+    }
+
+    /// writes the buffered frames in the order of their time, as long as the other tracks
+    /// have a frame to compare to, or the frame waited for `MAX_INTERLEAVE_DELAY`. `all` writes
+    /// every frame
+    fn write_frames(&mut self, all: bool) -> Result<()> {
+        let writer = match self.writer.as_mut() {
+            Some(w) => w,
+            None => return Ok(()),
+        };
+        loop {
+            let next = self
+                .tracks
+                .iter()
+                .enumerate()
+                .filter_map(|(i, t)| t.frames.front().map(|f| (i, t.frame_time(f))))
+                .min_by_key(|(_, time)| *time);
+            let (i, time) = match next {
+                Some(n) => n,
+                None => return Ok(()),
+            };
+            let others_ready = self
+                .tracks
+                .iter()
+                .all(|t| t.file_track.is_none() || t.ended || !t.frames.is_empty());
+            let track = &mut self.tracks[i];
+            let waited = track
+                .frames
+                .front()
+                .map(|f| f.arrival.elapsed() >= MAX_INTERLEAVE_DELAY)
+                .unwrap_or(false);
+            if !all && !others_ready && !waited {
+                return Ok(());
+            }
+            let frame = match track.frames.pop_front() {
+                Some(f) => f,
+                None => return Ok(()),
+            };
+            if let Some(file_track) = track.file_track {
+                writer.write_frame(file_track, time, frame.keyframe, &frame.data)?;
+            }
+        }
+    }
+
+    fn finish(mut self) -> Result<()> {
+        self.write_header(true)?;
+        self.write_frames(true)?;
+        match self.writer.take() {
+            Some(writer) => writer.finish(),
+            None => bail!("nothing was recorded: no frame was received"),
+        }
+    }
+}
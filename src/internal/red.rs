@@ -0,0 +1,27 @@
+use webrtc::rtp_transceiver::rtp_codec::RTCRtpCodecCapability;
+
+// RED (RFC 2198) sends a copy of the previous frame along with each frame, so that a single lost
+// packet can be recovered by the receiver. it's negotiated as a separate codec, "audio/red",
+// whose fmtp line lists the payload types of the frames it carries.
+
+/// the MIME type of RED. webrtc-rs doesn't define it
+pub const MIME_TYPE_RED: &str = "audio/red";
+/// the payload type RED is registered with when `ControllerConfig::red` is set. the same as
+/// browsers use
+pub const RED_PAYLOAD_TYPE: u8 = 63;
+/// the payload type of Opus in the webrtc-rs default codecs
+pub const OPUS_PAYLOAD_TYPE: u8 = 111;
+
+/// the codec for an Opus media source whose frames are sent with RED. pass it to
+/// `Controller::add_media_source` and to the source. requires `ControllerConfig::red` on both
+/// sides: the negotiation fails if the remote doesn't support RED. as with Opus, set `channels`
+/// to 1 for a mono source
+pub fn red_codec() -> RTCRtpCodecCapability {
+    RTCRtpCodecCapability {
+        mime_type: MIME_TYPE_RED.into(),
+        clock_rate: 48000,
+        channels: 2,
+        sdp_fmtp_line: format!("{}/{}", OPUS_PAYLOAD_TYPE, OPUS_PAYLOAD_TYPE),
+        rtcp_feedback: vec![],
+    }
+}
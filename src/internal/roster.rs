@@ -0,0 +1,99 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use crate::internal::data_types::{MediaKind, Participant, PeerId, PublishedSource};
+use crate::internal::sdp::TrackInfo;
+
+// a late joiner of a group call only knows the peer which invited it. it asks that peer, over
+// the control channel, who else is in the call and what they send, then dials them. see
+// `Controller::join_call`. the answer comes from the roster, which the Controller keeps up to
+// date because the control channel can't reach the Controller.
+
+/// the members of the call and their media sources, as known locally. shared by the Controller
+/// with the control channel of every peer, which answers the participants queries from it
+#[derive(Clone)]
+pub(crate) struct Roster {
+    local_id: PeerId,
+    state: Arc<Mutex<State>>,
+}
+
+#[derive(Default)]
+struct State {
+    local_sources: Vec<PublishedSource>,
+    // by peer, from the tracks of its last negotiated SDP
+    remote_sources: HashMap<PeerId, Vec<PublishedSource>>,
+}
+
+impl Roster {
+    pub fn new(local_id: PeerId) -> Self {
+        Self {
+            local_id,
+            state: Arc::default(),
+        }
+    }
+
+    pub fn set_local_sources(&self, sources: Vec<PublishedSource>) {
+        match self.state.lock() {
+            Ok(mut state) => state.local_sources = sources,
+            Err(e) => log::error!("failed to lock roster: {}", e),
+        }
+    }
+
+    pub fn set_remote_tracks(&self, peer_id: &PeerId, tracks: &[TrackInfo]) {
+        let sources = tracks
+            .iter()
+            .filter_map(|track| {
+                let kind = match track.kind.as_str() {
+                    "audio" => MediaKind::Audio,
+                    "video" => MediaKind::Video,
+                    _ => return None,
+                };
+                Some(PublishedSource {
+                    id: track.track_id.clone(),
+                    kind,
+                })
+            })
+            .collect();
+        match self.state.lock() {
+            Ok(mut state) => {
+                state.remote_sources.insert(peer_id.clone(), sources);
+            }
+            Err(e) => log::error!("failed to lock roster: {}", e),
+        }
+    }
+
+    pub fn remove_peer(&self, peer_id: &PeerId) {
+        match self.state.lock() {
+            Ok(mut state) => {
+                state.remote_sources.remove(peer_id);
+            }
+            Err(e) => log::error!("failed to lock roster: {}", e),
+        }
+    }
+
+    /// the local participant first, then the peers, ordered by id
+    pub fn participants(&self) -> Vec<Participant> {
+        let state = match self.state.lock() {
+            Ok(s) => s,
+            Err(e) => {
+                log::error!("failed to lock roster: {}", e);
+                return vec![];
+            }
+        };
+        let mut remotes: Vec<Participant> = state
+            .remote_sources
+            .iter()
+            .map(|(peer, sources)| Participant {
+                peer: peer.clone(),
+                sources: sources.clone(),
+            })
+            .collect();
+        remotes.sort_by(|a, b| a.peer.cmp(&b.peer));
+        let mut participants = vec![Participant {
+            peer: self.local_id.clone(),
+            sources: state.local_sources.clone(),
+        }];
+        participants.extend(remotes);
+        participants
+    }
+}
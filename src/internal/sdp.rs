@@ -0,0 +1,411 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+/// the Opus format parameters (RFC 7587) found in an `a=fmtp` line
+/// parameters which aren't present in the SDP take their default value from the RFC
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct OpusParams {
+    /// the remote prefers to receive stereo
+    pub stereo: bool,
+    /// the remote is likely to send stereo
+    pub sprop_stereo: bool,
+    /// the remote can decode in-band forward error correction
+    pub use_inband_fec: bool,
+    /// the remote prefers discontinuous transmission
+    pub use_dtx: bool,
+    /// the maximum average bitrate the remote wishes to receive, in bits per second
+    pub max_average_bitrate: Option<u32>,
+    /// the maximum output sample rate of the remote's decoder
+    pub max_playback_rate: Option<u32>,
+    /// the length of audio the remote prefers in each packet, from `a=ptime` (RFC 4566)
+    pub ptime: Option<Duration>,
+    /// the shortest packet the remote wants to receive, from the `minptime` parameter
+    pub min_ptime: Option<Duration>,
+    /// the longest packet the remote can receive, from `a=maxptime` (RFC 4566)
+    pub max_ptime: Option<Duration>,
+}
+
+impl OpusParams {
+    /// parses the value of an `a=fmtp:<pt>` line, without the payload type.
+    /// ex: `minptime=10;useinbandfec=1`
+    /// unknown parameters are ignored
+    pub fn from_fmtp(fmtp: &str) -> Self {
+        let mut params = Self::default();
+        for (key, value) in parse_fmtp(fmtp) {
+            match key.as_str() {
+                "stereo" => params.stereo = value == "1",
+                "sprop-stereo" => params.sprop_stereo = value == "1",
+                "useinbandfec" => params.use_inband_fec = value == "1",
+                "usedtx" => params.use_dtx = value == "1",
+                "maxaveragebitrate" => params.max_average_bitrate = value.parse().ok(),
+                "maxplaybackrate" => params.max_playback_rate = value.parse().ok(),
+                "minptime" => params.min_ptime = parse_ptime(&value),
+                _ => {}
+            }
+        }
+        params
+    }
+}
+
+/// parses a packet time in milliseconds, which may have a fraction, ex: "2.5"
+fn parse_ptime(ms: &str) -> Option<Duration> {
+    let ms = ms.trim().parse::<f64>().ok()?;
+    if !ms.is_finite() || ms <= 0.0 {
+        return None;
+    }
+    Some(Duration::from_micros((ms * 1000.0).round() as u64))
+}
+
+/// formats a packet time in milliseconds, for `a=ptime`
+fn format_ptime(ptime: Duration) -> String {
+    match ptime.as_micros() % 1000 {
+        0 => ptime.as_millis().to_string(),
+        _ => format!("{}", ptime.as_micros() as f64 / 1000.0),
+    }
+}
+
+/// splits a fmtp parameter string into key/value pairs
+fn parse_fmtp(fmtp: &str) -> HashMap<String, String> {
+    fmtp.split(';')
+        .filter_map(|param| {
+            let (key, value) = param.split_once('=')?;
+            Some((key.trim().to_lowercase(), value.trim().to_string()))
+        })
+        .collect()
+}
+
+/// returns the fmtp line of the first payload type which uses the given codec.
+/// `codec` is the encoding name from the rtpmap line, ex: "opus"
+pub fn find_fmtp(sdp: &str, codec: &str) -> Option<String> {
+    let mut payload_type = None;
+    for line in sdp.lines() {
+        let line = line.trim();
+        if let Some(rtpmap) = line.strip_prefix("a=rtpmap:") {
+            let (pt, encoding) = match rtpmap.split_once(' ') {
+                Some(r) => r,
+                None => continue,
+            };
+            let name = encoding.split('/').next().unwrap_or_default();
+            if payload_type.is_none() && name.eq_ignore_ascii_case(codec) {
+                payload_type = Some(pt.to_string());
+            }
+        }
+    }
+
+    let payload_type = payload_type?;
+    sdp.lines().find_map(|line| {
+        let fmtp = line.trim().strip_prefix("a=fmtp:")?;
+        let (pt, params) = fmtp.split_once(' ')?;
+        if pt == payload_type {
+            Some(params.to_string())
+        } else {
+            None
+        }
+    })
+}
+
+/// a track the remote is sending, as described by a media section of its SDP
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TrackInfo {
+    /// identifies the media section (and transceiver) within the session
+    pub mid: String,
+    /// "audio" or "video"
+    pub kind: String,
+    /// the id of the remote's media stream. sent by simple-webrtc peers as the media source's
+    /// CNAME
+    pub stream_id: String,
+    /// the id of the remote's track. sent by simple-webrtc peers as the `MediaSourceId`
+    pub track_id: String,
+}
+
+/// returns the tracks which the SDP's author is sending: media sections which aren't rejected,
+/// have a sending direction, and identify a track with `a=msid`
+pub fn remote_tracks(sdp: &str) -> Vec<TrackInfo> {
+    let mut tracks = vec![];
+    // the media sections start with the m= lines
+    for section in sdp.split("\nm=").skip(1) {
+        let mut lines = section.lines().map(str::trim);
+        let m_line: Vec<&str> = lines.next().unwrap_or_default().split(' ').collect();
+        // a port of 0 means the section was rejected or removed
+        if m_line.len() < 2 || m_line[1] == "0" {
+            continue;
+        }
+        let kind = m_line[0];
+        let mut mid = None;
+        let mut msid = None;
+        let mut sending = true;
+        for line in lines {
+            if let Some(m) = line.strip_prefix("a=mid:") {
+                mid = Some(m);
+            } else if let Some(m) = line.strip_prefix("a=msid:") {
+                msid = msid.or(Some(m));
+            } else if let Some((_, m)) = line
+                .strip_prefix("a=ssrc:")
+                .and_then(|ssrc| ssrc.split_once(" msid:"))
+            {
+                msid = msid.or(Some(m));
+            } else if line == "a=recvonly" || line == "a=inactive" {
+                sending = false;
+            }
+        }
+        let (mid, msid) = match (mid, msid) {
+            (Some(mid), Some(msid)) if sending => (mid, msid),
+            _ => continue,
+        };
+        let (stream_id, track_id) = msid.split_once(' ').unwrap_or((msid, ""));
+        tracks.push(TrackInfo {
+            mid: mid.into(),
+            kind: kind.into(),
+            stream_id: stream_id.into(),
+            track_id: track_id.into(),
+        });
+    }
+    tracks
+}
+
+/// returns the number of audio and video sections which aren't rejected
+pub fn media_sections(sdp: &str) -> usize {
+    sdp.split("\nm=")
+        .skip(1)
+        .filter(|section| {
+            let m_line: Vec<&str> = section
+                .lines()
+                .next()
+                .unwrap_or_default()
+                .split(' ')
+                .collect();
+            // a port of 0 means the section was rejected or removed
+            m_line.len() >= 2 && matches!(m_line[0], "audio" | "video") && m_line[1] != "0"
+        })
+        .count()
+}
+
+/// returns the ICE username fragment. it changes when ICE is restarted
+pub fn ice_ufrag(sdp: &str) -> Option<&str> {
+    sdp.lines()
+        .find_map(|line| line.trim().strip_prefix("a=ice-ufrag:"))
+}
+
+/// returns the DTLS role (RFC 5763): "actpass", "active" or "passive"
+pub fn dtls_setup(sdp: &str) -> Option<&str> {
+    sdp.lines()
+        .find_map(|line| line.trim().strip_prefix("a=setup:"))
+}
+
+/// returns the Opus parameters from the SDP, if Opus was negotiated
+pub fn opus_params(sdp: &str) -> Option<OpusParams> {
+    // an Opus payload without a fmtp line uses the default parameters
+    if !sdp.to_lowercase().contains(" opus/") {
+        return None;
+    }
+    let mut params = find_fmtp(sdp, "opus")
+        .map(|fmtp| OpusParams::from_fmtp(&fmtp))
+        .unwrap_or_default();
+    // the packet times are attributes of the media section
+    if let Some(section) = sdp
+        .split("\nm=")
+        .skip(1)
+        .find(|section| section.to_lowercase().contains(" opus/"))
+    {
+        for line in section.lines() {
+            let line = line.trim();
+            if let Some(ptime) = line.strip_prefix("a=ptime:") {
+                params.ptime = parse_ptime(ptime);
+            } else if let Some(ptime) = line.strip_prefix("a=maxptime:") {
+                params.max_ptime = parse_ptime(ptime);
+            }
+        }
+    }
+    Some(params)
+}
+
+/// adds `a=ptime` to the audio sections of the SDP which don't have one, except the rejected
+/// ones. see `ControllerConfig::ptime`
+pub fn with_ptime(sdp: &str, ptime: Duration) -> String {
+    let attribute = format!("a=ptime:{}\r\n", format_ptime(ptime));
+    let mut out = String::with_capacity(sdp.len() + 64);
+    // the attribute is added at the end of each section which needs it
+    let mut needs_ptime = false;
+    for line in sdp.lines() {
+        if line.starts_with("m=") {
+            if needs_ptime {
+                out.push_str(&attribute);
+            }
+            // a port of 0 means the section was rejected or removed
+            needs_ptime = line.starts_with("m=audio ") && line.split(' ').nth(1) != Some("0");
+        } else if line.starts_with("a=ptime:") {
+            needs_ptime = false;
+        }
+        out.push_str(line);
+        out.push_str("\r\n");
+    }
+    if needs_ptime {
+        out.push_str(&attribute);
+    }
+    out
+}
+
+/// returns the bandwidth limit from the SDP's `b=AS` lines (RFC 4566), in bits per second.
+/// a session-level limit applies to all of the media. without one, the limits of the media
+/// sections which aren't rejected are added up. None if there are no `b=AS` lines
+pub fn bandwidth_limit(sdp: &str) -> Option<u32> {
+    let as_kbps = |section: &str| {
+        section
+            .lines()
+            .find_map(|line| line.trim().strip_prefix("b=AS:"))
+            .and_then(|kbps| kbps.trim().parse::<u32>().ok())
+    };
+    let mut sections = sdp.split("\nm=");
+    let session = sections.next().unwrap_or_default();
+    let kbps = match as_kbps(session) {
+        Some(kbps) => Some(kbps),
+        None => sections
+            // a port of 0 means the section was rejected or removed
+            .filter(|section| section.split(' ').nth(1) != Some("0"))
+            .filter_map(as_kbps)
+            .reduce(u32::saturating_add),
+    };
+    kbps.map(|kbps| kbps.saturating_mul(1000))
+}
+
+/// the line types of RFC 4566, in the order they appear
+const SDP_LINE_TYPES: &str = "vosiuepcbtrzkam";
+
+/// returns the SDP without the lines which aren't of the form `<type>=<value>` with a known
+/// type, and the removed lines. see `SdpParsing::Tolerant`
+pub fn drop_unparsable_lines(sdp: &str) -> (String, Vec<&str>) {
+    let mut kept = String::with_capacity(sdp.len());
+    let mut dropped = vec![];
+    for line in sdp.lines() {
+        let line = line.trim_end_matches('\r');
+        if line.is_empty() {
+            continue;
+        }
+        let known =
+            matches!(line.as_bytes(), [typ, b'=', ..] if SDP_LINE_TYPES.as_bytes().contains(typ));
+        if known {
+            kept.push_str(line);
+            kept.push_str("\r\n");
+        } else {
+            dropped.push(line);
+        }
+    }
+    (kept, dropped)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ANSWER: &str = "v=0\r\n\
+        o=- 1 2 IN IP4 0.0.0.0\r\n\
+        s=-\r\n\
+        t=0 0\r\n\
+        m=audio 9 UDP/TLS/RTP/SAVPF 111\r\n\
+        a=mid:0\r\n\
+        a=rtpmap:111 opus/48000/2\r\n\
+        a=fmtp:111 minptime=10;useinbandfec=1;stereo=1\r\n";
+
+    #[test]
+    fn from_fmtp_parses_known_params() {
+        let params = OpusParams::from_fmtp(
+            "useinbandfec=1; STEREO=1;sprop-stereo=0;usedtx=1;\
+             maxaveragebitrate=20000;maxplaybackrate=16000",
+        );
+        assert_eq!(
+            params,
+            OpusParams {
+                stereo: true,
+                sprop_stereo: false,
+                use_inband_fec: true,
+                use_dtx: true,
+                max_average_bitrate: Some(20000),
+                max_playback_rate: Some(16000),
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn from_fmtp_ignores_unknown_and_malformed_params() {
+        let params = OpusParams::from_fmtp("foo=bar;maxaveragebitrate=lots;stereo;cbr=1");
+        assert_eq!(params, OpusParams::default());
+    }
+
+    #[test]
+    fn opus_params_reads_the_fmtp_line() {
+        let params = opus_params(ANSWER).unwrap();
+        assert!(params.stereo);
+        assert!(params.use_inband_fec);
+        assert!(!params.use_dtx);
+    }
+
+    #[test]
+    fn opus_params_without_opus() {
+        let sdp = ANSWER.replace("opus/48000/2", "PCMU/8000");
+        assert_eq!(opus_params(&sdp), None);
+    }
+
+    #[test]
+    fn opus_params_without_fmtp_uses_the_defaults() {
+        let sdp: String = ANSWER
+            .lines()
+            .filter(|line| !line.starts_with("a=fmtp"))
+            .map(|line| format!("{}\r\n", line))
+            .collect();
+        assert_eq!(opus_params(&sdp), Some(OpusParams::default()));
+    }
+
+    #[test]
+    fn opus_params_reads_the_packet_times() {
+        let sdp = format!(
+            "{}a=ptime:40\r\na=maxptime:60\r\n",
+            ANSWER.replace("minptime=10", "minptime=2.5")
+        );
+        let params = opus_params(&sdp).unwrap();
+        assert_eq!(params.ptime, Some(Duration::from_millis(40)));
+        assert_eq!(params.min_ptime, Some(Duration::from_micros(2500)));
+        assert_eq!(params.max_ptime, Some(Duration::from_millis(60)));
+    }
+
+    #[test]
+    fn opus_params_ignores_the_packet_times_of_other_sections() {
+        let sdp = format!(
+            "{}m=audio 9 UDP/TLS/RTP/SAVPF 0\r\na=rtpmap:0 PCMU/8000\r\na=ptime:40\r\n",
+            ANSWER
+        );
+        let params = opus_params(&sdp).unwrap();
+        assert_eq!(params.ptime, None);
+        assert_eq!(params.max_ptime, None);
+    }
+
+    #[test]
+    fn opus_params_ignores_invalid_packet_times() {
+        let sdp = format!("{}a=ptime:0\r\na=maxptime:soon\r\n", ANSWER);
+        let params = opus_params(&sdp).unwrap();
+        assert_eq!(params.ptime, None);
+        assert_eq!(params.max_ptime, None);
+    }
+
+    #[test]
+    fn with_ptime_adds_the_attribute_to_the_audio_sections() {
+        let sdp = format!(
+            "{}m=video 9 UDP/TLS/RTP/SAVPF 96\r\na=mid:1\r\n\
+             m=audio 0 UDP/TLS/RTP/SAVPF 111\r\na=mid:2\r\n\
+             m=audio 9 UDP/TLS/RTP/SAVPF 111\r\na=mid:3\r\na=ptime:60\r\n",
+            ANSWER
+        );
+        let sdp = with_ptime(&sdp, Duration::from_micros(2500));
+        let ptimes: Vec<(&str, &str)> = sdp
+            .split("\nm=")
+            .skip(1)
+            .map(|section| {
+                let attribute = |name| section.lines().find_map(|l| l.trim().strip_prefix(name));
+                let (mid, ptime) = (attribute("a=mid:"), attribute("a=ptime:"));
+                (mid.unwrap_or_default(), ptime.unwrap_or_default())
+            })
+            .collect();
+        // the rejected section and the video are left alone, and an existing ptime is kept
+        assert_eq!(ptimes, [("0", "2.5"), ("1", ""), ("2", ""), ("3", "60")]);
+    }
+}
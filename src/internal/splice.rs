@@ -0,0 +1,211 @@
+use async_trait::async_trait;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::time::Instant;
+use webrtc::interceptor::stream_info::StreamInfo;
+use webrtc::interceptor::{
+    self, Attributes, Interceptor, InterceptorBuilder, RTCPReader, RTCPWriter, RTPReader, RTPWriter,
+};
+use webrtc::rtp::packet::Packet;
+
+// every track numbers its packets on its own, starting from a random sequence number and
+// timestamp. when a sender's track is replaced (ex: by the hold music, see `Controller::hold`)
+// the remote would see the sequence numbers jump, which its SRTP context takes for a replay or
+// a rollover and drops the packets. this interceptor rewrites the packets of each local stream
+// so that they continue from the last packet sent, whichever track wrote them.
+// it's registered last, so that it sees the packets first: the NACK responder and the sender
+// reports see the rewritten packets.
+
+/// the number of times each sender's track was replaced, by SSRC. shared by the Controller and
+/// the interceptor of every connection
+#[derive(Clone, Default)]
+pub(crate) struct StreamSplicer {
+    splices: Arc<Mutex<HashMap<u32, Arc<AtomicU64>>>>,
+}
+
+impl StreamSplicer {
+    /// the first packet of the next track sent with `ssrc` continues from the previous packet.
+    /// call it before the sender's track is replaced: the packets written by the old track in
+    /// the meantime are still told apart, since they continue the sequence
+    pub fn splice(&self, ssrc: u32) {
+        match self.splices.lock() {
+            Ok(mut splices) => {
+                splices
+                    .entry(ssrc)
+                    .or_default()
+                    .fetch_add(1, Ordering::Relaxed);
+            }
+            Err(e) => log::error!("failed to lock stream splices: {}", e),
+        }
+    }
+
+    fn bind(&self, ssrc: u32) -> Arc<AtomicU64> {
+        match self.splices.lock() {
+            Ok(mut splices) => splices.entry(ssrc).or_default().clone(),
+            Err(e) => {
+                log::error!("failed to lock stream splices: {}", e);
+                Arc::default()
+            }
+        }
+    }
+
+    fn unbind(&self, ssrc: u32) {
+        match self.splices.lock() {
+            Ok(mut splices) => {
+                splices.remove(&ssrc);
+            }
+            Err(e) => log::error!("failed to lock stream splices: {}", e),
+        }
+    }
+}
+
+impl InterceptorBuilder for StreamSplicer {
+    fn build(&self, _id: &str) -> Result<Arc<dyn Interceptor + Send + Sync>, interceptor::Error> {
+        Ok(Arc::new(SplicingInterceptor {
+            splicer: self.clone(),
+        }))
+    }
+}
+
+struct SplicingInterceptor {
+    splicer: StreamSplicer,
+}
+
+#[async_trait]
+impl Interceptor for SplicingInterceptor {
+    async fn bind_rtcp_reader(
+        &self,
+        reader: Arc<dyn RTCPReader + Send + Sync>,
+    ) -> Arc<dyn RTCPReader + Send + Sync> {
+        reader
+    }
+
+    async fn bind_rtcp_writer(
+        &self,
+        writer: Arc<dyn RTCPWriter + Send + Sync>,
+    ) -> Arc<dyn RTCPWriter + Send + Sync> {
+        writer
+    }
+
+    async fn bind_local_stream(
+        &self,
+        info: &StreamInfo,
+        writer: Arc<dyn RTPWriter + Send + Sync>,
+    ) -> Arc<dyn RTPWriter + Send + Sync> {
+        Arc::new(SplicingWriter {
+            inner: writer,
+            splices: self.splicer.bind(info.ssrc),
+            clock_rate: info.clock_rate,
+            state: Mutex::new(None),
+        })
+    }
+
+    async fn unbind_local_stream(&self, info: &StreamInfo) {
+        self.splicer.unbind(info.ssrc);
+    }
+
+    async fn bind_remote_stream(
+        &self,
+        _info: &StreamInfo,
+        reader: Arc<dyn RTPReader + Send + Sync>,
+    ) -> Arc<dyn RTPReader + Send + Sync> {
+        reader
+    }
+
+    async fn unbind_remote_stream(&self, _info: &StreamInfo) {}
+
+    async fn close(&self) -> Result<(), interceptor::Error> {
+        Ok(())
+    }
+}
+
+struct SplicingWriter {
+    inner: Arc<dyn RTPWriter + Send + Sync>,
+    splices: Arc<AtomicU64>,
+    clock_rate: u32,
+    // None until the first packet is sent
+    state: Mutex<Option<SpliceState>>,
+}
+
+struct SpliceState {
+    // the value of `splices` last seen
+    splices: u64,
+    // true after a splice, until a packet which doesn't follow the last one is sent
+    pending: bool,
+    // added to the packets written by the current track
+    sequence_offset: u16,
+    timestamp_offset: u32,
+    // the last packet sent, after rewriting
+    last_sequence_number: u16,
+    last_timestamp: u32,
+    last_sent: Instant,
+}
+
+#[async_trait]
+impl RTPWriter for SplicingWriter {
+    async fn write(
+        &self,
+        pkt: &Packet,
+        attributes: &Attributes,
+    ) -> Result<usize, interceptor::Error> {
+        let splices = self.splices.load(Ordering::Relaxed);
+        let offsets = match self.state.lock() {
+            Ok(mut state) => {
+                let now = Instant::now();
+                let state = state.get_or_insert(SpliceState {
+                    splices,
+                    pending: false,
+                    sequence_offset: 0,
+                    timestamp_offset: 0,
+                    last_sequence_number: pkt.header.sequence_number.wrapping_sub(1),
+                    last_timestamp: pkt.header.timestamp,
+                    last_sent: now,
+                });
+                if state.splices != splices {
+                    state.splices = splices;
+                    state.pending = true;
+                }
+                let follows = pkt
+                    .header
+                    .sequence_number
+                    .wrapping_add(state.sequence_offset)
+                    == state.last_sequence_number.wrapping_add(1);
+                if state.pending && !follows {
+                    // the new track continues after the last packet, at the time which passed
+                    // since then
+                    let elapsed = now.duration_since(state.last_sent);
+                    let ticks = (elapsed.as_secs_f64() * self.clock_rate as f64) as u32;
+                    state.pending = false;
+                    state.sequence_offset = state
+                        .last_sequence_number
+                        .wrapping_add(1)
+                        .wrapping_sub(pkt.header.sequence_number);
+                    state.timestamp_offset = state
+                        .last_timestamp
+                        .wrapping_add(ticks.max(1))
+                        .wrapping_sub(pkt.header.timestamp);
+                }
+                state.last_sequence_number = pkt
+                    .header
+                    .sequence_number
+                    .wrapping_add(state.sequence_offset);
+                state.last_timestamp = pkt.header.timestamp.wrapping_add(state.timestamp_offset);
+                state.last_sent = now;
+                (state.sequence_offset, state.timestamp_offset)
+            }
+            Err(e) => {
+                log::error!("failed to lock splice state: {}", e);
+                (0, 0)
+            }
+        };
+
+        if offsets == (0, 0) {
+            return self.inner.write(pkt, attributes).await;
+        }
+        let mut pkt = pkt.clone();
+        pkt.header.sequence_number = pkt.header.sequence_number.wrapping_add(offsets.0);
+        pkt.header.timestamp = pkt.header.timestamp.wrapping_add(offsets.1);
+        self.inner.write(&pkt, attributes).await
+    }
+}
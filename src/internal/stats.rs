@@ -0,0 +1,163 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use webrtc::peer_connection::RTCPeerConnection;
+use webrtc::stats::StatsReportType;
+
+use crate::internal::data_types::MediaSourceId;
+
+/// the network stats of a peer. see `Controller::get_peer_stats`
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct PeerStats {
+    /// bits per second sent, since the previous call to `get_peer_stats`
+    pub outbound_bitrate: u64,
+    /// bits per second received, since the previous call to `get_peer_stats`
+    pub inbound_bitrate: u64,
+    /// the packets sent to the peer which it reported lost, summed over the media sources.
+    /// cumulative since the connection was created
+    pub packets_lost: u64,
+    /// the round trip time, if it has been measured
+    pub round_trip_time: Option<Duration>,
+    /// the highest interarrival jitter of the media sources, as reported by the peer in its RTCP
+    /// receiver reports. None until a report is received
+    pub jitter: Option<Duration>,
+}
+
+/// a summary of the webrtc-rs stats report for one connection
+#[derive(Debug, Clone)]
+pub struct ConnectionStats {
+    /// bytes sent by the ICE transport
+    pub bytes_sent: u64,
+    /// bytes received by the ICE transport
+    pub bytes_received: u64,
+    pub timestamp: Instant,
+    /// the highest fraction of packets lost, as reported by the remote for each stream
+    pub loss: f64,
+    /// the packets lost, as reported by the remote, summed over the streams
+    pub packets_lost: u64,
+    pub rtt: Option<Duration>,
+}
+
+impl ConnectionStats {
+    /// a sample with nothing sent or received yet, taken when a connection is created
+    pub fn start() -> Self {
+        Self {
+            bytes_sent: 0,
+            bytes_received: 0,
+            timestamp: Instant::now(),
+            loss: 0.0,
+            packets_lost: 0,
+            rtt: None,
+        }
+    }
+
+    pub async fn collect(pc: &RTCPeerConnection) -> Self {
+        let report = pc.get_stats().await;
+        let mut bytes_sent = 0;
+        let mut bytes_received = 0;
+        let mut loss: f64 = 0.0;
+        let mut packets_lost = 0;
+        // webrtc-rs 0.6 doesn't fill in the candidate pair counters, so the round trip time is
+        // only known from RTCP reports, once media is flowing
+        let mut rtt: Option<f64> = None;
+
+        for stats in report.reports.values() {
+            match stats {
+                StatsReportType::Transport(transport) => {
+                    bytes_sent += transport.bytes_sent as u64;
+                    bytes_received += transport.bytes_received as u64;
+                }
+                StatsReportType::RemoteInboundRTP(remote) => {
+                    loss = loss.max(remote.fraction_lost);
+                    // negative if packets were duplicated
+                    packets_lost += remote.packets_lost.max(0) as u64;
+                    if let Some(r) = remote.round_trip_time {
+                        rtt = Some(rtt.map_or(r, |prev| prev.max(r)));
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        Self {
+            bytes_sent,
+            bytes_received,
+            timestamp: Instant::now(),
+            loss,
+            packets_lost,
+            rtt: rtt.map(Duration::from_secs_f64),
+        }
+    }
+
+    /// the bitrate between the previous sample and this one, in bits per second
+    pub fn bitrate_since(&self, previous: &Self) -> u64 {
+        self.outbound_bitrate_since(previous) + self.inbound_bitrate_since(previous)
+    }
+
+    /// the bitrate sent between the previous sample and this one, in bits per second
+    pub fn outbound_bitrate_since(&self, previous: &Self) -> u64 {
+        self.rate_since(previous, self.bytes_sent, previous.bytes_sent)
+    }
+
+    /// the bitrate received between the previous sample and this one, in bits per second
+    pub fn inbound_bitrate_since(&self, previous: &Self) -> u64 {
+        self.rate_since(previous, self.bytes_received, previous.bytes_received)
+    }
+
+    fn rate_since(&self, previous: &Self, bytes: u64, previous_bytes: u64) -> u64 {
+        let elapsed = self
+            .timestamp
+            .duration_since(previous.timestamp)
+            .as_secs_f64();
+        if elapsed <= 0.0 {
+            return 0;
+        }
+        let bits = bytes.saturating_sub(previous_bytes) * 8;
+        (bits as f64 / elapsed) as u64
+    }
+}
+
+/// the jitter of each media source sent to a peer, from the peer's RTCP reports. webrtc-rs 0.6
+/// doesn't include it in the stats report. shared by the peer, its RTCP readers and the stats
+/// reporting task
+#[derive(Clone, Default)]
+pub(crate) struct JitterReports {
+    by_source: Arc<Mutex<HashMap<MediaSourceId, Duration>>>,
+}
+
+impl JitterReports {
+    pub fn record(&self, source_id: &MediaSourceId, jitter: Duration) {
+        match self.by_source.lock() {
+            Ok(mut by_source) => {
+                by_source.insert(source_id.clone(), jitter);
+            }
+            Err(e) => log::error!("failed to lock jitter reports: {}", e),
+        }
+    }
+
+    /// called when the source is no longer sent, so that a stale report isn't returned
+    pub fn remove(&self, source_id: &MediaSourceId) {
+        match self.by_source.lock() {
+            Ok(mut by_source) => {
+                by_source.remove(source_id);
+            }
+            Err(e) => log::error!("failed to lock jitter reports: {}", e),
+        }
+    }
+
+    /// the number of clones, held by the peer, its RTCP readers and the Controller
+    #[cfg(test)]
+    pub fn holders(&self) -> usize {
+        Arc::strong_count(&self.by_source)
+    }
+
+    pub fn max(&self) -> Option<Duration> {
+        match self.by_source.lock() {
+            Ok(by_source) => by_source.values().max().copied(),
+            Err(e) => {
+                log::error!("failed to lock jitter reports: {}", e);
+                None
+            }
+        }
+    }
+}
@@ -0,0 +1,601 @@
+use anyhow::{bail, Result};
+use std::io::{Seek, SeekFrom, Write};
+
+// a minimal WebM muxer (https://www.webmproject.org/docs/container/), for
+// `Controller::record_peer`. the file is written as the frames arrive: the segment's size is
+// unknown until `finish`, which seeks back to write it and the duration. a file which wasn't
+// finished can still be played. there are no cues, so players seek by scanning the clusters.
+
+const EBML: u32 = 0x1A45DFA3;
+const EBML_VERSION: u32 = 0x4286;
+const EBML_READ_VERSION: u32 = 0x42F7;
+const EBML_MAX_ID_LENGTH: u32 = 0x42F2;
+const EBML_MAX_SIZE_LENGTH: u32 = 0x42F3;
+const DOC_TYPE: u32 = 0x4282;
+const DOC_TYPE_VERSION: u32 = 0x4287;
+const DOC_TYPE_READ_VERSION: u32 = 0x4285;
+const SEGMENT: u32 = 0x18538067;
+const INFO: u32 = 0x1549A966;
+const TIMESTAMP_SCALE: u32 = 0x2AD7B1;
+const DURATION: u32 = 0x4489;
+const MUXING_APP: u32 = 0x4D80;
+const WRITING_APP: u32 = 0x5741;
+const TRACKS: u32 = 0x1654AE6B;
+const TRACK_ENTRY: u32 = 0xAE;
+const TRACK_NUMBER: u32 = 0xD7;
+const TRACK_UID: u32 = 0x73C5;
+const TRACK_TYPE: u32 = 0x83;
+const FLAG_LACING: u32 = 0x9C;
+const CODEC_ID: u32 = 0x86;
+const CODEC_PRIVATE: u32 = 0x63A2;
+const CODEC_DELAY: u32 = 0x56AA;
+const SEEK_PRE_ROLL: u32 = 0x56BB;
+const VIDEO: u32 = 0xE0;
+const PIXEL_WIDTH: u32 = 0xB0;
+const PIXEL_HEIGHT: u32 = 0xBA;
+const AUDIO: u32 = 0xE1;
+const SAMPLING_FREQUENCY: u32 = 0xB5;
+const CHANNELS: u32 = 0x9F;
+const CLUSTER: u32 = 0x1F43B675;
+const TIMESTAMP: u32 = 0xE7;
+const SIMPLE_BLOCK: u32 = 0xA3;
+
+/// an 8 byte size whose value is unknown, until `finish` overwrites it
+const UNKNOWN_SIZE: [u8; 8] = [0x01, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF];
+/// the timestamps are in milliseconds
+const TIMESTAMP_SCALE_NS: u64 = 1_000_000;
+/// a block's timestamp is a 16 bit offset from its cluster's. clusters also start at video key
+/// frames, so that players can seek to them
+const MAX_CLUSTER_DURATION_MS: u64 = 5000;
+/// the WebM spec asks for 80ms of Opus to be decoded before a seek target
+const OPUS_SEEK_PRE_ROLL_NS: u64 = 80_000_000;
+const OPUS_SAMPLE_RATE: u32 = 48000;
+
+/// the codec of a track, with what the header needs to describe it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum WebmCodec {
+    Vp8 { width: u16, height: u16 },
+    Vp9 { width: u16, height: u16 },
+    Opus { channels: u16 },
+}
+
+impl WebmCodec {
+    fn is_video(&self) -> bool {
+        !matches!(self, WebmCodec::Opus { .. })
+    }
+}
+
+pub(crate) struct WebmWriter<W: Write + Seek> {
+    out: W,
+    // the codec of each track. the track numbers start at 1
+    tracks: Vec<WebmCodec>,
+    // where the segment's size and the duration's value are written
+    segment_size_pos: u64,
+    duration_pos: u64,
+    // the blocks of the current cluster, which is written once it's complete
+    cluster: Vec<u8>,
+    cluster_timestamp: Option<u64>,
+    last_timestamp: u64,
+}
+
+impl<W: Write + Seek> WebmWriter<W> {
+    /// writes the header, which describes the tracks
+    pub fn new(mut out: W, tracks: Vec<WebmCodec>) -> Result<Self> {
+        if tracks.is_empty() {
+            bail!("a WebM file needs at least one track");
+        }
+        let mut header = vec![];
+        let mut ebml = vec![];
+        uint_element(&mut ebml, EBML_VERSION, 1);
+        uint_element(&mut ebml, EBML_READ_VERSION, 1);
+        uint_element(&mut ebml, EBML_MAX_ID_LENGTH, 4);
+        uint_element(&mut ebml, EBML_MAX_SIZE_LENGTH, 8);
+        string_element(&mut ebml, DOC_TYPE, "webm");
+        uint_element(&mut ebml, DOC_TYPE_VERSION, 4);
+        uint_element(&mut ebml, DOC_TYPE_READ_VERSION, 2);
+        element(&mut header, EBML, &ebml);
+
+        write_id(&mut header, SEGMENT);
+        let segment_size_pos = out.stream_position()? + header.len() as u64;
+        header.extend_from_slice(&UNKNOWN_SIZE);
+
+        let mut info = vec![];
+        uint_element(&mut info, TIMESTAMP_SCALE, TIMESTAMP_SCALE_NS);
+        string_element(&mut info, MUXING_APP, "simple-webrtc");
+        string_element(&mut info, WRITING_APP, "simple-webrtc");
+        // the duration is the last element, so that its value ends the info element
+        float_element(&mut info, DURATION, 0.0);
+        element(&mut header, INFO, &info);
+        let duration_pos = out.stream_position()? + header.len() as u64 - 8;
+
+        let mut entries = vec![];
+        for (i, codec) in tracks.iter().enumerate() {
+            element(&mut entries, TRACK_ENTRY, &track_entry(i as u64 + 1, codec));
+        }
+        element(&mut header, TRACKS, &entries);
+        out.write_all(&header)?;
+
+        Ok(Self {
+            out,
+            tracks,
+            segment_size_pos,
+            duration_pos,
+            cluster: vec![],
+            cluster_timestamp: None,
+            last_timestamp: 0,
+        })
+    }
+
+    /// `track` is the index of the track passed to `new`. the frames must be written in the
+    /// order of their timestamps, in milliseconds; an earlier timestamp is moved up to the
+    /// previous frame's
+    pub fn write_frame(
+        &mut self,
+        track: usize,
+        timestamp: u64,
+        keyframe: bool,
+        data: &[u8],
+    ) -> Result<()> {
+        let codec = match self.tracks.get(track) {
+            Some(c) => *c,
+            None => bail!("invalid track: {}", track),
+        };
+        let timestamp = timestamp.max(self.last_timestamp);
+        let new_cluster = match self.cluster_timestamp {
+            None => true,
+            Some(t) => timestamp - t >= MAX_CLUSTER_DURATION_MS || codec.is_video() && keyframe,
+        };
+        if new_cluster {
+            self.write_cluster()?;
+            self.cluster_timestamp = Some(timestamp);
+        }
+        let offset = timestamp - self.cluster_timestamp.unwrap_or(timestamp);
+
+        let mut block = Vec::with_capacity(data.len() + 4);
+        // the track numbers are below 127, so they fit in a 1 byte size
+        write_size(&mut block, track as u64 + 1);
+        block.extend_from_slice(&(offset as i16).to_be_bytes());
+        // every audio frame can be decoded on its own
+        let flags = if keyframe || !codec.is_video() {
+            0x80
+        } else {
+            0
+        };
+        block.push(flags);
+        block.extend_from_slice(data);
+        element(&mut self.cluster, SIMPLE_BLOCK, &block);
+        self.last_timestamp = timestamp;
+        Ok(())
+    }
+
+    /// writes the last cluster, then the segment's size and the duration
+    pub fn finish(mut self) -> Result<()> {
+        self.write_cluster()?;
+        let end = self.out.stream_position()?;
+        let segment_size = end - (self.segment_size_pos + UNKNOWN_SIZE.len() as u64);
+        self.out.seek(SeekFrom::Start(self.segment_size_pos))?;
+        // an 8 byte size: the length marker, followed by 7 bytes
+        let mut size = segment_size.to_be_bytes();
+        size[0] = 0x01;
+        self.out.write_all(&size)?;
+        self.out.seek(SeekFrom::Start(self.duration_pos))?;
+        self.out
+            .write_all(&(self.last_timestamp as f64).to_be_bytes())?;
+        self.out.seek(SeekFrom::Start(end))?;
+        self.out.flush()?;
+        Ok(())
+    }
+
+    fn write_cluster(&mut self) -> Result<()> {
+        let timestamp = match self.cluster_timestamp.take() {
+            Some(t) => t,
+            None => return Ok(()),
+        };
+        let mut cluster = vec![];
+        uint_element(&mut cluster, TIMESTAMP, timestamp);
+        cluster.append(&mut self.cluster);
+        let mut buf = vec![];
+        element(&mut buf, CLUSTER, &cluster);
+        self.out.write_all(&buf)?;
+        Ok(())
+    }
+}
+
+fn track_entry(number: u64, codec: &WebmCodec) -> Vec<u8> {
+    let mut entry = vec![];
+    uint_element(&mut entry, TRACK_NUMBER, number);
+    uint_element(&mut entry, TRACK_UID, number);
+    uint_element(&mut entry, FLAG_LACING, 0);
+    match *codec {
+        WebmCodec::Vp8 { width, height } | WebmCodec::Vp9 { width, height } => {
+            let codec_id = match codec {
+                WebmCodec::Vp8 { .. } => "V_VP8",
+                _ => "V_VP9",
+            };
+            uint_element(&mut entry, TRACK_TYPE, 1);
+            string_element(&mut entry, CODEC_ID, codec_id);
+            let mut video = vec![];
+            uint_element(&mut video, PIXEL_WIDTH, width as u64);
+            uint_element(&mut video, PIXEL_HEIGHT, height as u64);
+            element(&mut entry, VIDEO, &video);
+        }
+        WebmCodec::Opus { channels } => {
+            uint_element(&mut entry, TRACK_TYPE, 2);
+            string_element(&mut entry, CODEC_ID, "A_OPUS");
+            element(&mut entry, CODEC_PRIVATE, &opus_head(channels));
+            // the frames are recorded as sent, without a pre-skip
+            uint_element(&mut entry, CODEC_DELAY, 0);
+            uint_element(&mut entry, SEEK_PRE_ROLL, OPUS_SEEK_PRE_ROLL_NS);
+            let mut audio = vec![];
+            float_element(&mut audio, SAMPLING_FREQUENCY, OPUS_SAMPLE_RATE as f64);
+            uint_element(&mut audio, CHANNELS, channels as u64);
+            element(&mut entry, AUDIO, &audio);
+        }
+    }
+    entry
+}
+
+/// RFC 7845 5.1: the identification header, with channel mapping family 0 (mono or stereo)
+fn opus_head(channels: u16) -> Vec<u8> {
+    let mut head = b"OpusHead".to_vec();
+    head.push(1);
+    head.push(channels as u8);
+    // pre-skip
+    head.extend_from_slice(&0u16.to_le_bytes());
+    head.extend_from_slice(&OPUS_SAMPLE_RATE.to_le_bytes());
+    // output gain
+    head.extend_from_slice(&0i16.to_le_bytes());
+    head.push(0);
+    head
+}
+
+/// the ids include their length marker, so they're written as is
+fn write_id(buf: &mut Vec<u8>, id: u32) {
+    let bytes = id.to_be_bytes();
+    let skip = (id.leading_zeros() / 8) as usize;
+    buf.extend_from_slice(&bytes[skip.min(3)..]);
+}
+
+/// a variable length integer: the number of leading zeroes of the first byte is the number of
+/// bytes which follow it
+fn write_size(buf: &mut Vec<u8>, size: u64) {
+    // all ones means unknown, so a size needs a longer encoding when it would be all ones
+    let len = (1..8).find(|len| size < (1 << (7 * len)) - 1).unwrap_or(8);
+    let marked = size | (1 << (7 * len));
+    buf.extend_from_slice(&marked.to_be_bytes()[8 - len..]);
+}
+
+fn element(buf: &mut Vec<u8>, id: u32, payload: &[u8]) {
+    write_id(buf, id);
+    write_size(buf, payload.len() as u64);
+    buf.extend_from_slice(payload);
+}
+
+fn uint_element(buf: &mut Vec<u8>, id: u32, value: u64) {
+    let bytes = value.to_be_bytes();
+    // at least 1 byte, even for 0
+    let skip = ((value.leading_zeros() / 8) as usize).min(7);
+    element(buf, id, &bytes[skip..]);
+}
+
+fn float_element(buf: &mut Vec<u8>, id: u32, value: f64) {
+    element(buf, id, &value.to_be_bytes());
+}
+
+fn string_element(buf: &mut Vec<u8>, id: u32, value: &str) {
+    element(buf, id, value.as_bytes());
+}
+
+/// the dimensions of a VP8 key frame. None for other frames.
+/// RFC 6386 9.1: the frame tag is followed by a start code and the dimensions, whose upper 2
+/// bits are the scaling
+pub(crate) fn vp8_keyframe_size(frame: &[u8]) -> Option<(u16, u16)> {
+    if frame.len() < 10 || frame[0] & 0x01 != 0 || frame[3..6] != [0x9d, 0x01, 0x2a] {
+        return None;
+    }
+    let width = u16::from_le_bytes([frame[6], frame[7]]) & 0x3fff;
+    let height = u16::from_le_bytes([frame[8], frame[9]]) & 0x3fff;
+    Some((width, height))
+}
+
+/// the dimensions of a VP9 key frame. None for other frames.
+/// VP9 bitstream spec 6.2: the uncompressed header of a key frame has a sync code and the color
+/// config, followed by the frame size
+pub(crate) fn vp9_keyframe_size(frame: &[u8]) -> Option<(u16, u16)> {
+    let mut bits = BitReader {
+        data: frame,
+        pos: 0,
+    };
+    if bits.read(2)? != 2 {
+        return None;
+    }
+    let profile_low = bits.read(1)?;
+    let profile = (bits.read(1)? << 1) | profile_low;
+    if profile == 3 {
+        bits.read(1)?;
+    }
+    // show_existing_frame
+    if bits.read(1)? == 1 {
+        return None;
+    }
+    // frame_type
+    if bits.read(1)? != 0 {
+        return None;
+    }
+    // show_frame, error_resilient_mode
+    bits.read(2)?;
+    if bits.read(24)? != 0x498342 {
+        return None;
+    }
+    if profile >= 2 {
+        // ten_or_twelve_bit
+        bits.read(1)?;
+    }
+    let color_space = bits.read(3)?;
+    // CS_RGB
+    if color_space != 7 {
+        // color_range
+        bits.read(1)?;
+        if profile == 1 || profile == 3 {
+            // subsampling_x, subsampling_y, reserved_zero
+            bits.read(3)?;
+        }
+    } else if profile == 1 || profile == 3 {
+        bits.read(1)?;
+    }
+    let width = bits.read(16)? + 1;
+    let height = bits.read(16)? + 1;
+    Some((width as u16, height as u16))
+}
+
+struct BitReader<'a> {
+    data: &'a [u8],
+    // in bits
+    pos: usize,
+}
+
+impl BitReader<'_> {
+    /// reads up to 32 bits, most significant first
+    fn read(&mut self, bits: usize) -> Option<u32> {
+        let mut value = 0;
+        for _ in 0..bits {
+            let byte = self.data.get(self.pos / 8)?;
+            let bit = (byte >> (7 - self.pos % 8)) & 0x01;
+            value = (value << 1) | bit as u32;
+            self.pos += 1;
+        }
+        Some(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    /// the elements of `buf`, with their ids and payloads
+    fn elements(mut buf: &[u8]) -> Vec<(u32, &[u8])> {
+        let mut elements = vec![];
+        while !buf.is_empty() {
+            let id_len = buf[0].leading_zeros() as usize + 1;
+            let id = buf[..id_len]
+                .iter()
+                .fold(0u32, |id, b| (id << 8) | *b as u32);
+            buf = &buf[id_len..];
+            let size_len = buf[0].leading_zeros() as usize + 1;
+            let size = buf[..size_len]
+                .iter()
+                .fold(0u64, |size, b| (size << 8) | *b as u64)
+                & ((1 << (7 * size_len)) - 1);
+            buf = &buf[size_len..];
+            let (payload, rest) = buf.split_at(size as usize);
+            elements.push((id, payload));
+            buf = rest;
+        }
+        elements
+    }
+
+    fn child(payload: &[u8], id: u32) -> &[u8] {
+        match elements(payload).into_iter().find(|(i, _)| *i == id) {
+            Some((_, payload)) => payload,
+            None => panic!("no element {:#x}", id),
+        }
+    }
+
+    fn uint(payload: &[u8]) -> u64 {
+        payload.iter().fold(0, |value, b| (value << 8) | *b as u64)
+    }
+
+    /// the segment of a WebM file, which must be its second element
+    fn segment(file: &[u8]) -> &[u8] {
+        let top = elements(file);
+        assert_eq!(top.len(), 2);
+        assert_eq!(top[0].0, EBML);
+        assert_eq!(child(top[0].1, DOC_TYPE), b"webm");
+        assert_eq!(top[1].0, SEGMENT);
+        top[1].1
+    }
+
+    /// the track number, timestamp offset and flags of a block
+    type Block = (u8, i16, u8);
+
+    /// the timestamp of each cluster, with its blocks
+    fn clusters(segment: &[u8]) -> Vec<(u64, Vec<Block>)> {
+        elements(segment)
+            .into_iter()
+            .filter(|(id, _)| *id == CLUSTER)
+            .map(|(_, cluster)| {
+                let blocks = elements(cluster)
+                    .into_iter()
+                    .filter(|(id, _)| *id == SIMPLE_BLOCK)
+                    .map(|(_, b)| (b[0] & 0x7f, i16::from_be_bytes([b[1], b[2]]), b[3]))
+                    .collect();
+                (uint(child(cluster, TIMESTAMP)), blocks)
+            })
+            .collect()
+    }
+
+    fn write(tracks: Vec<WebmCodec>, frames: &[(usize, u64, bool)]) -> Result<Vec<u8>> {
+        let mut file = vec![];
+        let mut writer = WebmWriter::new(Cursor::new(&mut file), tracks)?;
+        for (track, timestamp, keyframe) in frames {
+            writer.write_frame(*track, *timestamp, *keyframe, &[0; 10])?;
+        }
+        writer.finish()?;
+        Ok(file)
+    }
+
+    #[test]
+    fn the_header_describes_the_tracks() -> Result<()> {
+        let tracks = vec![
+            WebmCodec::Vp8 {
+                width: 320,
+                height: 240,
+            },
+            WebmCodec::Opus { channels: 2 },
+        ];
+        let file = write(tracks, &[])?;
+        let segment = segment(&file);
+        let info = child(segment, INFO);
+        assert_eq!(uint(child(info, TIMESTAMP_SCALE)), TIMESTAMP_SCALE_NS);
+        let entries = elements(child(segment, TRACKS));
+        assert_eq!(entries.len(), 2);
+
+        let video = entries[0].1;
+        assert_eq!(uint(child(video, TRACK_NUMBER)), 1);
+        assert_eq!(child(video, CODEC_ID), b"V_VP8");
+        assert_eq!(uint(child(child(video, VIDEO), PIXEL_WIDTH)), 320);
+        assert_eq!(uint(child(child(video, VIDEO), PIXEL_HEIGHT)), 240);
+
+        let audio = entries[1].1;
+        assert_eq!(uint(child(audio, TRACK_NUMBER)), 2);
+        assert_eq!(child(audio, CODEC_ID), b"A_OPUS");
+        let head = child(audio, CODEC_PRIVATE);
+        assert_eq!(&head[..8], b"OpusHead");
+        assert_eq!(head[9], 2);
+        assert_eq!(uint(child(child(audio, AUDIO), CHANNELS)), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn finish_writes_the_segment_size_and_the_duration() -> Result<()> {
+        let file = write(
+            vec![WebmCodec::Opus { channels: 1 }],
+            &[(0, 0, true), (0, 20, true), (0, 1500, true)],
+        )?;
+        // `segment` fails if the size doesn't end the segment at the end of the file
+        let segment = segment(&file);
+        let duration = child(child(segment, INFO), DURATION);
+        assert_eq!(f64::from_be_bytes(duration.try_into()?), 1500.0);
+        Ok(())
+    }
+
+    #[test]
+    fn clusters_start_at_video_key_frames_and_every_5s() -> Result<()> {
+        let tracks = vec![
+            WebmCodec::Vp8 {
+                width: 320,
+                height: 240,
+            },
+            WebmCodec::Opus { channels: 1 },
+        ];
+        let file = write(
+            tracks,
+            &[
+                (0, 0, true),
+                (1, 10, false),
+                (0, 33, false),
+                (0, 100, true),
+                (1, 110, false),
+                (1, 5100, false),
+            ],
+        )?;
+        let clusters = clusters(segment(&file));
+        assert_eq!(
+            clusters,
+            [
+                (0, vec![(1, 0, 0x80), (2, 10, 0x80), (1, 33, 0)]),
+                (100, vec![(1, 0, 0x80), (2, 10, 0x80)]),
+                (5100, vec![(2, 0, 0x80)]),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn a_frame_earlier_than_the_previous_one_is_moved_up() -> Result<()> {
+        let file = write(
+            vec![WebmCodec::Opus { channels: 1 }],
+            &[(0, 100, true), (0, 40, true)],
+        )?;
+        let clusters = clusters(segment(&file));
+        assert_eq!(clusters, [(100, vec![(1, 0, 0x80), (1, 0, 0x80)])]);
+        Ok(())
+    }
+
+    #[test]
+    fn invalid_tracks_are_rejected() -> Result<()> {
+        let mut file = vec![];
+        assert!(WebmWriter::new(Cursor::new(&mut file), vec![]).is_err());
+        let tracks = vec![WebmCodec::Opus { channels: 1 }];
+        let mut writer = WebmWriter::new(Cursor::new(&mut file), tracks)?;
+        assert!(writer.write_frame(1, 0, true, &[0]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn a_size_of_all_ones_takes_another_byte() {
+        let mut buf = vec![];
+        write_size(&mut buf, 126);
+        assert_eq!(buf, [0xfe]);
+        buf.clear();
+        write_size(&mut buf, 127);
+        assert_eq!(buf, [0x40, 0x7f]);
+    }
+
+    #[test]
+    fn vp8_key_frames_have_a_size() {
+        // a key frame tag, the start code, 640x480 without scaling
+        let mut frame = vec![0x10, 0x02, 0x00, 0x9d, 0x01, 0x2a, 0x80, 0x02, 0xe0, 0x01];
+        assert_eq!(vp8_keyframe_size(&frame), Some((640, 480)));
+        // the P bit is set on the other frames
+        frame[0] |= 0x01;
+        assert_eq!(vp8_keyframe_size(&frame), None);
+        assert_eq!(vp8_keyframe_size(&[0x10, 0x02]), None);
+    }
+
+    /// the uncompressed header of a profile 0 VP9 frame
+    fn vp9_header(keyframe: bool, width: u16, height: u16) -> Vec<u8> {
+        let fields: [(u64, u32); 11] = [
+            // frame_marker, profile, show_existing_frame
+            (2, 2),
+            (0, 2),
+            (0, 1),
+            (!keyframe as u64, 1),
+            // show_frame, error_resilient_mode
+            (1, 1),
+            (0, 1),
+            (0x498342, 24),
+            // color_space: BT 709, color_range
+            (2, 3),
+            (0, 1),
+            (width as u64 - 1, 16),
+            (height as u64 - 1, 16),
+        ];
+        let (bits, len) = fields.iter().fold((0u128, 0), |(bits, len), (value, n)| {
+            ((bits << n) | *value as u128, len + n)
+        });
+        let bytes = (bits << (128 - len)).to_be_bytes();
+        bytes[..(len as usize).div_ceil(8)].to_vec()
+    }
+
+    #[test]
+    fn vp9_key_frames_have_a_size() {
+        assert_eq!(
+            vp9_keyframe_size(&vp9_header(true, 1280, 720)),
+            Some((1280, 720))
+        );
+        assert_eq!(vp9_keyframe_size(&vp9_header(false, 1280, 720)), None);
+        assert_eq!(vp9_keyframe_size(&vp9_header(true, 1280, 720)[..4]), None);
+    }
+}
@@ -1,39 +1,100 @@
 use anyhow::{bail, Context, Result};
-use std::collections::HashMap;
-use std::sync::Arc;
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::net::IpAddr;
+use std::ops::RangeInclusive;
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU8, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use tokio::sync::mpsc;
-use webrtc::api::interceptor_registry::register_default_interceptors;
-use webrtc::api::media_engine::MediaEngine;
+use tokio::task::JoinHandle;
+use webrtc::api::interceptor_registry::{
+    configure_nack, configure_twcc_receiver_only, register_default_interceptors,
+};
+use webrtc::api::media_engine::{
+    MediaEngine, MIME_TYPE_G722, MIME_TYPE_H264, MIME_TYPE_OPUS, MIME_TYPE_PCMA, MIME_TYPE_PCMU,
+    MIME_TYPE_VP8, MIME_TYPE_VP9,
+};
+use webrtc::api::setting_engine::SettingEngine;
 use webrtc::api::APIBuilder;
+use webrtc::dtls_transport::dtls_transport_state::RTCDtlsTransportState;
+use webrtc::ice::network_type::NetworkType;
 use webrtc::ice_transport::ice_candidate::{RTCIceCandidate, RTCIceCandidateInit};
-use webrtc::ice_transport::ice_connection_state::RTCIceConnectionState;
-use webrtc::ice_transport::ice_server::RTCIceServer;
 use webrtc::interceptor::registry::Registry;
+use webrtc::interceptor::report::{receiver::ReceiverReport, sender::SenderReport};
 use webrtc::peer_connection::configuration::RTCConfiguration;
+use webrtc::peer_connection::offer_answer_options::RTCOfferOptions;
+use webrtc::peer_connection::peer_connection_state::RTCPeerConnectionState;
 use webrtc::peer_connection::RTCPeerConnection;
+use webrtc::rtcp::payload_feedbacks::full_intra_request::{FirEntry, FullIntraRequest};
+use webrtc::rtcp::payload_feedbacks::picture_loss_indication::PictureLossIndication;
+use webrtc::rtcp::reception_report::ReceptionReport;
+use webrtc::rtcp::{receiver_report, sender_report};
 
+use webrtc::peer_connection::sdp::sdp_type::RTCSdpType;
 use webrtc::peer_connection::sdp::session_description::RTCSessionDescription;
+use webrtc::peer_connection::signaling_state::RTCSignalingState;
 
+use webrtc::rtp_transceiver::rtp_codec::{RTCRtpHeaderExtensionCapability, RTPCodecType};
 use webrtc::rtp_transceiver::rtp_receiver::RTCRtpReceiver;
 use webrtc::track::track_local::track_local_static_rtp::TrackLocalStaticRTP;
+use webrtc::track::track_local::TrackLocal;
 
 use webrtc::track::track_remote::TrackRemote;
 
 mod internal;
+#[cfg(test)]
+mod tests;
 
-use crate::internal::data_types::*;
+use crate::internal::bandwidth::StreamClass;
+use crate::internal::control::{ControlChannel, ControlMessage};
+use crate::internal::counters::SendCounters;
+use crate::internal::emitter::EventEmitter;
+use crate::internal::pacer::Pacer;
+use crate::internal::peer_state::PeerStateTracker;
+use crate::internal::recorder::{RecordedCodec, RecordedTrack, Recording, RecordingTaps};
+use crate::internal::roster::Roster;
+use crate::internal::splice::StreamSplicer;
+use crate::internal::stats::{ConnectionStats, JitterReports};
 
 // public exports
+#[cfg(feature = "media")]
 pub mod media;
-pub use internal::data_types::{MediaSourceId, MimeType, PeerId};
+pub use internal::abs_capture_time::{AbsCaptureTime, ABS_CAPTURE_TIME_URI};
+pub use internal::bandwidth::MAX_AUDIO_BANDWIDTH;
+pub use internal::certificate::{certificate_from_pem, certificate_to_pem, generate_certificate};
+pub use internal::counters::SourceCounters;
+pub use internal::data_types::{
+    normalize_peer_id, validate_candidate, validate_ice_candidate, validate_peer_id, AddressFamily,
+    ContentHint, DtlsFailure, HangupReason, KeyframeRequest, LivenessCheck, MediaKind, MediaSource,
+    MediaSourceId, MimeType, Participant, PeerId, PeerOptions, PeerState, PublishedSource,
+    ReconnectPolicy, RejectReason, RemoteTrackInfo, SdpParsing, TurnConfig, MAX_CANDIDATE_LEN,
+    MAX_PEER_ID_LEN,
+};
+pub use internal::emitter::OnEventChanClosed;
+pub use internal::error::Error;
 pub use internal::events::EmittedEvents;
-pub use webrtc::rtp_transceiver::rtp_codec::RTCRtpCodecCapability;
+pub use internal::handle::{CommandFuture, ControllerHandle};
+pub use internal::latency::LatencyProbe;
+pub use internal::pacer::PacerConfig;
+pub use internal::red::{red_codec, MIME_TYPE_RED, OPUS_PAYLOAD_TYPE, RED_PAYLOAD_TYPE};
+pub use internal::sdp::{OpusParams, TrackInfo};
+pub use internal::stats::PeerStats;
+pub use webrtc::ice_transport::ice_connection_state::RTCIceConnectionState;
+pub use webrtc::ice_transport::ice_gatherer_state::RTCIceGathererState;
+pub use webrtc::ice_transport::ice_server::RTCIceServer;
+pub use webrtc::peer_connection::certificate::RTCCertificate;
+pub use webrtc::rtp_transceiver::rtp_codec::{RTCRtpCodecCapability, RTCRtpCodecParameters};
 use webrtc::rtp_transceiver::rtp_sender::RTCRtpSender;
+pub use webrtc::rtp_transceiver::rtp_transceiver_direction::RTCRtpTransceiverDirection;
 
-#[cfg(feature = "test-server")]
+// the crate's tests use the in-process signaling of `testing`
+#[cfg(any(test, feature = "test-server"))]
 pub mod testing;
 
-#[cfg(feature = "test-server")]
+#[cfg(any(test, feature = "test-server"))]
 #[macro_use]
 extern crate lazy_static;
 
@@ -51,83 +112,408 @@ extern crate lazy_static;
 /// connections.. Writing a packet to the `TrackLocalWriter` will cause the packet to be forwarded
 /// to all connected peers.
 ///
+/// media sources are optional: a connection without any only carries data channels, created with
+/// `raw_peer_connection`. if only the callee has media sources, it sends an offer for them after
+/// answering the call, as long as `ControllerConfig::auto_renegotiate` is set
+///
 /// WebRTC requires out of band signalling. The `SimpleWebRtc` accepts a callback for transmitting
 /// signals which must be forwarded to the specified peer
 ///
-
+/// every peer connection contains a pre-negotiated data channel which is reserved for
+/// simple-webrtc (id 1000, label "simple-webrtc-control"). it's used for things like `ping`.
+/// don't create a data channel with that id.
+///
+/// to share a Controller between tasks without locking it, see `ControllerHandle`
 pub struct Controller {
     api: webrtc::api::API,
     /// client's id
     id: PeerId,
+    config: ControllerConfig,
     /// list of peers
     peers: HashMap<PeerId, Peer>,
     /// used to emit events
-    emitted_event_chan: mpsc::UnboundedSender<EmittedEvents>,
+    emitter: EventEmitter,
     /// attach these to every PeerConnection
     media_sources: HashMap<MediaSourceId, Arc<TrackLocalStaticRTP>>,
+    /// the order in which media sources are attached to new connections. see
+    /// `set_media_source_order`
+    source_order: Vec<MediaSourceId>,
+    /// see `set_content_hint`
+    content_hints: HashMap<MediaSourceId, ContentHint>,
+    /// sources muted with `mute_media_source`. they stay muted after `unmute_all`
+    muted_sources: HashSet<MediaSourceId>,
+    /// see `mute_all`
+    all_muted: bool,
+    /// see `enable_stats_reporting`
+    stats_task: Option<JoinHandle<()>>,
+    /// the jitter reports of each peer, shared with the stats reporting task
+    peer_jitter: Arc<Mutex<HashMap<PeerId, JitterReports>>>,
+    /// see `prefer_local_address`. read by the SettingEngine's IP filter whenever candidates
+    /// are gathered
+    preferred_address: Arc<Mutex<Option<IpAddr>>>,
+    /// see `set_reconnect_policy`. shared with the ICE state handler of every peer, which sends
+    /// the id of a peer whose connection failed
+    reconnect_tx: Arc<Mutex<Option<mpsc::UnboundedSender<PeerId>>>>,
+    reconnect_task: Option<JoinHandle<()>>,
+    /// see `source_counters`. shared with the interceptor of every connection
+    send_counters: SendCounters,
+    /// see `ControllerConfig::pacer`. shared with the interceptor of every connection
+    pacer: Pacer,
+    /// see `InitArgs::ice_servers`
+    ice_servers: Vec<RTCIceServer>,
+    /// see `set_codec_preferences`. the mime types, by peer
+    codec_preferences: HashMap<PeerId, Vec<String>>,
+    /// keeps the packets of each sender contiguous when its track is replaced. see `hold`.
+    /// shared with the interceptor of every connection
+    splicer: StreamSplicer,
+    /// copies the packets of the recorded tracks. see `record_peer`. shared with the interceptor
+    /// of every connection
+    recording_taps: RecordingTaps,
+    /// the recordings in progress, by peer
+    recordings: HashMap<PeerId, Recording>,
+    /// answers the participants queries of the peers. see `join_call`. shared with the control
+    /// channel of every connection
+    roster: Roster,
 }
 
 // a lazy version of the builder pattern
 pub struct InitArgs {
     pub id: PeerId,
     pub emitted_event_chan: mpsc::UnboundedSender<EmittedEvents>,
+    /// the STUN/TURN servers of every connection. if empty, the default STUN server is used.
+    /// `ControllerConfig::ice_servers` replaces them when set
+    pub ice_servers: Vec<RTCIceServer>,
+    pub config: ControllerConfig,
+}
+
+impl InitArgs {
+    /// uses the default `ControllerConfig`
+    pub fn new(id: PeerId, emitted_event_chan: mpsc::UnboundedSender<EmittedEvents>) -> Self {
+        Self {
+            id,
+            emitted_event_chan,
+            ice_servers: vec![],
+            config: ControllerConfig::default(),
+        }
+    }
+}
+
+/// the tunables of a Controller. the defaults are suitable for most applications
+#[derive(Clone)]
+pub struct ControllerConfig {
+    /// a persistent DTLS certificate, allowing the remote to recognize this client across
+    /// sessions by its fingerprint. see `generate_certificate`
+    pub certificate: Option<RTCCertificate>,
+    /// how often RTCP sender and receiver reports are sent. shorter intervals give faster
+    /// feedback about loss and jitter at the cost of bandwidth. must be within
+    /// `RTCP_REPORT_INTERVAL_RANGE`. defaults to the webrtc-rs interval (1 second)
+    pub rtcp_report_interval: Option<Duration>,
+    /// if false, the webrtc-rs interceptors aren't registered: lost packets aren't
+    /// retransmitted or requested (NACK; the default video codecs still advertise it, so the
+    /// remote's NACKs go unanswered), no RTCP sender or receiver reports are sent, and
+    /// transport-wide congestion control feedback isn't negotiated. only for relays which forward
+    /// RTP without terminating the media, and leave the feedback to the endpoints. the round
+    /// trip time and remote loss in the stats, which come from the reports, are missing, and
+    /// `Controller::record_peer` synchronizes the tracks by their arrival times. must be true if
+    /// `rtcp_report_interval` is set. recommended: true
+    pub default_interceptors: bool,
+    /// when media sources are added or removed, send a new offer to the connected peers.
+    /// if false, the application must renegotiate itself. recommended: true
+    pub auto_renegotiate: bool,
+    /// what to do if `emitted_event_chan`'s receiver is dropped. recommended: `Shutdown`
+    pub on_event_chan_closed: OnEventChanClosed,
+    /// the number of recent events kept to be replayed to a channel attached with
+    /// `Controller::attach_event_chan`, ex: when the application's event loop starts after
+    /// `init`, or replaces its receiver. 0 keeps none
+    pub event_replay_len: usize,
+    /// the RTCP canonical name (CNAME) of every media source, used by recording and analytics
+    /// pipelines to correlate streams. stays the same across reconnects. defaults to `id`
+    pub cname: Option<String>,
+    /// which IP versions to gather ICE candidates for. the default, `Any`, gathers both and lets
+    /// ICE choose. the others restrict ICE to one version. see `AddressFamily`
+    pub address_family: AddressFamily,
+    /// remote SDPs longer than this are rejected with `Error::SdpTooLarge`, before they are
+    /// parsed. guards against peers sending enormous SDPs
+    pub max_sdp_len: usize,
+    /// how remote SDPs with lines webrtc-rs can't parse are handled. unknown `a=` attributes
+    /// are always ignored. see `SdpParsing`
+    pub sdp_parsing: SdpParsing,
+    /// if true, the local and remote SDPs of every negotiation are logged at debug level, with
+    /// the peer id and direction, ex: to debug a failed negotiation. off by default: SDPs are
+    /// verbose and contain the peers' IP addresses
+    pub log_sdp: bool,
+    /// the number of ICE candidates accepted via `recv_ice` from each peer. further candidates
+    /// are rejected with `Error::TooManyCandidates`. the count is reset when the remote restarts
+    /// ICE
+    pub max_remote_candidates: usize,
+    /// called before each connection is created (`dial`, `accept_call`) to obtain the STUN/TURN
+    /// servers, ex: to fetch time-limited TURN credentials. the returned servers replace
+    /// `InitArgs::ice_servers`. if None, or if the list is empty, `InitArgs::ice_servers` are
+    /// used.
+    /// if the callback returns an error, it's logged and the connection falls back to
+    /// `InitArgs::ice_servers`, so peers which can be reached without TURN can still connect.
+    /// the dial waits for the callback, so it should time out on its own.
+    /// webrtc-rs can't change the servers of an existing connection, so `restart_ice` keeps using
+    /// the servers obtained when the connection was created
+    pub ice_servers: Option<IceServersFn>,
+    /// TURN servers which are used in addition to the servers from `ice_servers`, or the
+    /// default STUN server. see `TurnConfig`
+    pub turn_servers: Vec<TurnConfig>,
+    /// generates the mid of each new media section in the offers this side creates, for
+    /// gateways which expect a fixed scheme, ex: "a0". it's passed the greatest numeric mid in use
+    /// (-1 if there is none) and must not return a mid which is in use. webrtc-rs only tracks
+    /// numeric mids, so a generator of other mids must count them itself. the generator is shared
+    /// by every connection. if None, webrtc-rs numbers the sections from 0.
+    /// see `Controller::set_media_source_order`
+    pub mid_generator: Option<MidGeneratorFn>,
+    /// if true, RED (RFC 2198) is offered for audio, which sends a copy of the previous Opus
+    /// frame with each packet, so that the remote can recover from losing a packet. it costs
+    /// about twice the audio bitrate. only the media sources created with `red_codec` are sent
+    /// with it, and only if the remote accepts it: the remote must enable it too. received RED
+    /// tracks are unwrapped by the sinks in `media`
+    pub red: bool,
+    /// if set, the length of audio the remote is asked to send in each packet, advertised with
+    /// `a=ptime` in the audio sections. must be within `PTIME_RANGE`. should match the local
+    /// sources' `OpusSourceConfig::frame_duration`. the remote's is in `OpusParams::ptime`
+    pub ptime: Option<Duration>,
+    /// if set, the packets of every media source are spaced out at the configured bitrate,
+    /// separately for each peer, instead of being sent as soon as they're written. smooths the
+    /// bursts of multi-packet frames, such as video keyframes. writes to the track wait for
+    /// their turn. see `PacerConfig`
+    pub pacer: Option<PacerConfig>,
+}
+
+/// see `ControllerConfig::mid_generator`
+pub type MidGeneratorFn = Arc<dyn Fn(isize) -> String + Send + Sync>;
+
+/// see `ControllerConfig::ice_servers`
+pub type IceServersFn = Arc<
+    dyn (Fn() -> Pin<Box<dyn Future<Output = Result<Vec<RTCIceServer>>> + Send>>) + Send + Sync,
+>;
+
+/// a `ControllerConfig::ice_servers` callback which always returns `servers`. an empty list
+/// falls back to `InitArgs::ice_servers`, which are simpler for servers which don't change
+pub fn fixed_ice_servers(servers: Vec<RTCIceServer>) -> IceServersFn {
+    Arc::new(move || {
+        let servers = servers.clone();
+        Box::pin(async move { Ok(servers) })
+    })
+}
+
+impl Default for ControllerConfig {
+    fn default() -> Self {
+        Self {
+            certificate: None,
+            rtcp_report_interval: None,
+            default_interceptors: true,
+            auto_renegotiate: true,
+            on_event_chan_closed: OnEventChanClosed::default(),
+            event_replay_len: 0,
+            cname: None,
+            address_family: AddressFamily::default(),
+            max_sdp_len: DEFAULT_MAX_SDP_LEN,
+            sdp_parsing: SdpParsing::default(),
+            log_sdp: false,
+            max_remote_candidates: DEFAULT_MAX_REMOTE_CANDIDATES,
+            ice_servers: None,
+            turn_servers: vec![],
+            mid_generator: None,
+            red: false,
+            ptime: None,
+            pacer: None,
+        }
+    }
 }
 
+/// the valid `ControllerConfig::ptime`s. the Opus frame durations, up to a packet of 120ms
+/// (RFC 7587)
+pub const PTIME_RANGE: RangeInclusive<Duration> =
+    Duration::from_micros(2500)..=Duration::from_millis(120);
+
+/// the default `ControllerConfig::max_sdp_len`. a typical SDP is a few kilobytes
+pub const DEFAULT_MAX_SDP_LEN: usize = 64 * 1024;
+/// the default `ControllerConfig::max_remote_candidates`. a typical peer sends fewer than 20
+pub const DEFAULT_MAX_REMOTE_CANDIDATES: usize = 256;
+/// used when `InitArgs::ice_servers` is empty
+pub const DEFAULT_STUN_SERVER: &str = "stun:stun.l.google.com:19302";
+
+/// the allowed values for `ControllerConfig::rtcp_report_interval`
+pub const RTCP_REPORT_INTERVAL_RANGE: RangeInclusive<Duration> =
+    Duration::from_millis(100)..=Duration::from_secs(10);
+
 /// stores a PeerConnection for updating SDP and ICE candidates, adding and removing tracks
 /// also stores associated media streams
 pub struct Peer {
-    pub state: PeerState,
+    /// shared with the connection's callbacks. see `PeerState` and `Controller::peer_state`
+    state_tracker: PeerStateTracker,
     pub id: PeerId,
     pub connection: Arc<RTCPeerConnection>,
+    pub options: PeerOptions,
     /// webrtc has a remove_track function which requires passing a RTCRtpSender
     /// to a RTCPeerConnection. this is created by add_track, though the user
     /// only receives a TrackWriter
-    /// in the future, the RTCRtpSender can be used to have finer control over the stream.
-    /// it can do things like pause the stream, without disconnecting it.
+    /// the senders' tracks are detached to pause the stream without renegotiating, see
+    /// `Controller::mute_media_source` and `Controller::hold`
     pub rtp_senders: HashMap<MediaSourceId, Arc<RTCRtpSender>>,
+    /// the tasks reading RTCP packets from `rtp_senders`. aborted when the sender is removed, or
+    /// when the negotiation leaves it inactive. see `EmittedEvents::MediaInactive`
+    rtcp_readers: Mutex<HashMap<MediaSourceId, JoinHandle<()>>>,
+    /// the media sources whose senders were included in a completed negotiation. see `mute_all`
+    negotiated_sources: Mutex<HashSet<MediaSourceId>>,
+    /// reserved data channel used by simple-webrtc. see `internal::control`
+    control: Arc<ControlChannel>,
+    /// if set, remote tracks are sent here in addition to `EmittedEvents::TrackAdded`.
+    /// used by `start_audio_call`
+    #[cfg_attr(not(feature = "media"), allow(dead_code))]
+    track_forward: Arc<Mutex<Option<mpsc::UnboundedSender<Arc<TrackRemote>>>>>,
+    /// an offer which was requested while a negotiation was in progress. sent once the
+    /// negotiation completes. see `Controller::is_negotiating`
+    queued_offer: Mutex<Option<RTCOfferOptions>>,
+    /// the number of ICE candidates received via `recv_ice` since the remote's last ICE restart
+    remote_candidates: AtomicUsize,
+    /// see `PeerOptions::liveness_check`
+    liveness_task: Option<JoinHandle<()>>,
+    /// incremented for each FIR sent. see `request_keyframe`
+    fir_sequence_number: AtomicU8,
+    /// see `Controller::latency_probe`
+    latency_probe: LatencyProbe,
+    /// see `Controller::hold`
+    on_hold: bool,
+    /// sent while on hold, in place of an audio source. see `Controller::hold`
+    hold_music: Option<Arc<TrackLocalStaticRTP>>,
+    /// in bits per second. see `Controller::set_total_bandwidth`
+    total_bandwidth: Option<u32>,
+    /// the sample taken by the previous call to `Controller::get_peer_stats`
+    stats_sample: Mutex<ConnectionStats>,
+    /// updated by the RTCP readers. see `Controller::get_peer_stats`
+    jitter: JitterReports,
 }
 
+/// how long `Controller::query_participants` waits for the answer
+pub const QUERY_TIMEOUT: Duration = Duration::from_secs(5);
+/// how long `Controller::ping` waits for a response
+pub const PING_TIMEOUT: Duration = Duration::from_secs(5);
+/// how long `Controller::hang_up_with_reason` waits for the reason to be sent
+pub const HANGUP_TIMEOUT: Duration = Duration::from_millis(500);
+
 /// The following functions are driven by the UI:
 /// dial
+/// start_audio_call
 /// accept_call
 /// hang_up
+/// hang_up_with_reason
+/// hold
+/// resume
 /// add_media_source
 /// remove_media_source
+/// restart_ice
+/// on_network_change
 ///
 /// The following functions are driven by signaling
 /// recv_ice
 /// recv_sdp
 impl Controller {
     pub fn init(args: InitArgs) -> Result<Self> {
+        let preferred_address = Arc::new(Mutex::new(None));
+        let send_counters = SendCounters::default();
+        let pacer = Pacer::default();
+        let splicer = StreamSplicer::default();
+        let recording_taps = RecordingTaps::default();
+        if let Some(config) = args.config.pacer {
+            pacer.set_bitrate(config.bitrate);
+        }
         Ok(Self {
-            api: create_api()?,
+            api: create_api(
+                &args.config,
+                preferred_address.clone(),
+                send_counters.clone(),
+                pacer.clone(),
+                splicer.clone(),
+                recording_taps.clone(),
+            )?,
+            roster: Roster::new(args.id.clone()),
             id: args.id,
+            ice_servers: args.ice_servers,
             peers: HashMap::new(),
-            emitted_event_chan: args.emitted_event_chan,
+            emitter: EventEmitter::new(
+                args.emitted_event_chan,
+                args.config.on_event_chan_closed,
+                args.config.event_replay_len,
+            ),
             media_sources: HashMap::new(),
+            source_order: vec![],
+            content_hints: HashMap::new(),
+            muted_sources: HashSet::new(),
+            all_muted: false,
+            config: args.config,
+            stats_task: None,
+            peer_jitter: Arc::new(Mutex::new(HashMap::new())),
+            preferred_address,
+            reconnect_tx: Arc::new(Mutex::new(None)),
+            reconnect_task: None,
+            send_counters,
+            pacer,
+            splicer,
+            codec_preferences: HashMap::new(),
+            recording_taps,
+            recordings: HashMap::new(),
         })
     }
     /// Rust doesn't have async drop, so this function should be called when the user is
-    /// done with Controller. it will clean up all threads
+    /// done with Controller. it will clean up all threads.
+    /// the peers are hung up in the order of their ids. when it returns, the Controller's tasks
+    /// have ended and every connection is closed, even if hanging up a peer failed. returns the
+    /// first failure
     pub async fn deinit(&mut self) -> Result<()> {
-        let peer_ids: Vec<PeerId> = self.peers.keys().cloned().collect();
+        let tasks = [self.stats_task.take(), self.reconnect_task.take()];
+        self.stop_reconnecting();
+        for task in tasks.into_iter().flatten() {
+            stop_task(task).await;
+        }
+        let mut peer_ids: Vec<PeerId> = self.peers.keys().cloned().collect();
+        peer_ids.sort();
+        let mut first_error = None;
         for peer_id in peer_ids {
-            self.hang_up(&peer_id).await;
+            if let Err(e) = self.hang_up(&peer_id).await {
+                log::error!("failed to hang up peer {} on deinit: {}", peer_id, e);
+                // still closed, so that none of its tasks outlive deinit
+                self.close_peer(&peer_id).await;
+                first_error.get_or_insert(e);
+            }
         }
 
-        Ok(())
+        match first_error {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
     }
     /// creates a RTCPeerConnection, sets the local SDP object, emits a CallInitiatedEvent,
     /// which contains the SDP object
     /// continues with the following signals: Sdp, CallTerminated, CallRejected
+    /// fails with `Error::InvalidPeerId` if `peer_id` doesn't pass `normalize_peer_id`
     pub async fn dial(&mut self, peer_id: &PeerId) -> Result<()> {
-        let pc = self.connect(peer_id).await?;
+        self.dial_with_options(peer_id, PeerOptions::default())
+            .await
+    }
+    /// same as `dial`, with settings for this peer
+    pub async fn dial_with_options(
+        &mut self,
+        peer_id: &PeerId,
+        options: PeerOptions,
+    ) -> Result<()> {
+        let peer_id = &normalize_peer_id(peer_id)?;
+        let trickle = options.trickle;
+        let pc = self.connect(peer_id, options.clone()).await?;
+        restrict_receiving(&pc, &options).await;
+        order_codecs(&pc, self.codec_preferences.get(peer_id)).await;
         let local_sdp = pc.create_offer(None).await?;
         // Sets the LocalDescription, and starts our UDP listeners
         // Note: this will start the gathering of ICE candidates
-        pc.set_local_description(local_sdp.clone()).await?;
+        let local_sdp = set_local_description(&pc, local_sdp, trickle).await?;
+        let local_sdp = self.advertised_sdp(local_sdp);
+        self.log_sdp(peer_id, "local", &local_sdp);
 
-        self.emitted_event_chan.send(EmittedEvents::CallInitiated {
+        self.emitter.send(EmittedEvents::CallInitiated {
             dest: peer_id.clone(),
             sdp: Box::new(local_sdp),
         })?;
@@ -135,43 +521,127 @@ impl Controller {
         Ok(())
     }
     /// adds the remote sdp, sets own sdp, and sends own sdp to remote
+    /// fails with `Error::InvalidPeerId` if `peer_id` doesn't pass `normalize_peer_id`, and with
+    /// `Error::IncompatibleOffer` if the offer can't be accepted. on failure, the application
+    /// should hang up and send `RejectReason::from_error` back to the caller
     pub async fn accept_call(
         &mut self,
         peer_id: &PeerId,
         remote_sdp: RTCSessionDescription,
     ) -> Result<()> {
-        let pc = self
-            .connect(peer_id)
+        self.accept_call_with_options(peer_id, remote_sdp, PeerOptions::default())
             .await
-            .context(format!("{}:{}", file!(), line!()))?;
-        pc.set_remote_description(remote_sdp)
+    }
+    /// same as `accept_call`, with settings for this peer
+    pub async fn accept_call_with_options(
+        &mut self,
+        peer_id: &PeerId,
+        mut remote_sdp: RTCSessionDescription,
+        options: PeerOptions,
+    ) -> Result<()> {
+        let peer_id = &normalize_peer_id(peer_id)?;
+        self.check_remote_sdp(peer_id, &mut remote_sdp)?;
+        let trickle = options.trickle;
+        let pc = self
+            .connect(peer_id, options.clone())
             .await
             .context(format!("{}:{}", file!(), line!()))?;
+        let offered_media = internal::sdp::media_sections(&remote_sdp.sdp);
+        if let Err(e) = pc.set_remote_description(remote_sdp).await {
+            bail!(Error::IncompatibleOffer(e.to_string()));
+        }
+        restrict_receiving(&pc, &options).await;
+        order_codecs(&pc, self.codec_preferences.get(peer_id)).await;
 
         let answer = pc
             .create_answer(None)
             .await
             .context(format!("{}:{}", file!(), line!()))?;
-        pc.set_local_description(answer.clone())
+        // webrtc-rs rejects the media sections without a registered codec, which would leave a
+        // call without media
+        if offered_media > 0 && internal::sdp::media_sections(&answer.sdp) == 0 {
+            bail!(Error::IncompatibleOffer("no codec in common".into()));
+        }
+        let answer = set_local_description(&pc, answer, trickle)
             .await
             .context(format!("{}:{}", file!(), line!()))?;
 
         if let Some(p) = self.peers.get_mut(peer_id) {
-            p.state = PeerState::WaitingForIce;
+            p.state_tracker.set(PeerState::WaitingForIce);
         } else {
             bail!("peer not found");
         }
+        if let Some(p) = self.peers.get(peer_id) {
+            self.after_negotiation(p).await;
+        }
 
-        self.emitted_event_chan.send(EmittedEvents::Sdp {
+        let answer = self.advertised_sdp(answer);
+        self.log_sdp(peer_id, "local", &answer);
+        self.emitter.send(EmittedEvents::Sdp {
             dest: peer_id.clone(),
             sdp: Box::new(answer),
         })?;
+        self.offer_missing_sources(peer_id).await?;
 
         Ok(())
     }
     /// Terminates a connection
-    /// the controlling application should send a HangUp signal to the remote side
-    pub async fn hang_up(&mut self, peer_id: &PeerId) {
+    /// emits `EmittedEvents::CallTerminated`, with `by_remote` false: the controlling application
+    /// should send it to the remote side, which then calls `recv_hang_up`.
+    /// every track is removed from the connection, and the peer's tasks have ended, before the
+    /// connection is closed and the peer is dropped. fails, without doing anything, for a peer
+    /// which was already hung up or was never dialed
+    pub async fn hang_up(&mut self, peer_id: &PeerId) -> Result<()> {
+        let peer_id = &normalize_peer_id(peer_id)?;
+        if !self.peers.contains_key(peer_id) {
+            bail!("peer not found");
+        }
+        self.end_call(peer_id, false).await;
+        Ok(())
+    }
+
+    /// the remote hung up, ex: a CallTerminated signal was received. same as `hang_up`, but
+    /// `EmittedEvents::CallTerminated` has `by_remote` set, so that it isn't sent back to the
+    /// remote. also for a remote which mustn't be signaled, ex: one which was just sent a
+    /// rejection. ignored for a peer which was already hung up
+    pub async fn recv_hang_up(&mut self, peer_id: &PeerId) {
+        let peer_id = match normalize_peer_id(peer_id) {
+            Ok(peer_id) => peer_id,
+            Err(e) => {
+                log::warn!("received a hang up: {}", e);
+                return;
+            }
+        };
+        if !self.peers.contains_key(&peer_id) {
+            log::debug!(
+                "received a hang up from peer {}, which isn't connected",
+                peer_id
+            );
+            return;
+        }
+        self.end_call(&peer_id, true).await;
+    }
+
+    /// emits `EmittedEvents::CallTerminated`, on both sides of the call, then tears the peer down
+    async fn end_call(&mut self, peer_id: &PeerId, by_remote: bool) {
+        if let Err(e) = self.emitter.send(EmittedEvents::CallTerminated {
+            dest: peer_id.clone(),
+            by_remote,
+        }) {
+            log::error!("failed to send CallTerminated event: {}", e);
+        }
+        self.close_peer(peer_id).await;
+    }
+
+    /// the teardown of `hang_up`, without emitting `EmittedEvents::CallTerminated`, ex: when the
+    /// connection is replaced
+    async fn close_peer(&mut self, peer_id: &PeerId) {
+        if let Some(recording) = self.recordings.remove(peer_id) {
+            if let Err(e) = recording.stop().await {
+                log::error!("failed to finish the recording of peer {}: {}", peer_id, e);
+            }
+        }
+        let mut tasks = vec![];
         // not sure if it's necessary to remove all tracks
         if let Some(peer) = self.peers.get_mut(peer_id) {
             for (source_id, rtp_sender) in &peer.rtp_senders {
@@ -186,32 +656,162 @@ impl Controller {
                     );
                 }
             }
+            match peer.rtcp_readers.get_mut() {
+                Ok(readers) => tasks.extend(readers.drain().map(|(_, reader)| reader)),
+                Err(e) => log::error!("failed to lock rtcp readers: {}", e),
+            }
+            tasks.extend(peer.liveness_task.take());
+        }
+        for task in tasks {
+            stop_task(task).await;
+        }
+        self.emitter.unregister(peer_id);
+        match self.peer_jitter.lock() {
+            Ok(mut peer_jitter) => {
+                peer_jitter.remove(peer_id);
+            }
+            Err(e) => log::error!("failed to lock peer jitter: {}", e),
         }
+        self.roster.remove_peer(peer_id);
         match self.peers.remove(peer_id) {
-            Some(peer) => drop(peer),
+            Some(peer) => {
+                if let Err(e) = peer.connection.close().await {
+                    log::error!("failed to close the connection to {}: {}", peer_id, e);
+                }
+            }
             None => log::warn!("attempted to remove nonexistent peer"),
         }
     }
 
+    /// same as `hang_up`, but first tells the remote why, via `EmittedEvents::RemoteHangUp`.
+    /// a remote which isn't using simple-webrtc won't get the reason. waits at most
+    /// `HANGUP_TIMEOUT` for the reason to be sent
+    pub async fn hang_up_with_reason(
+        &mut self,
+        peer_id: &PeerId,
+        reason: HangupReason,
+    ) -> Result<()> {
+        let peer_id = &normalize_peer_id(peer_id)?;
+        if let Some(peer) = self.peers.get(peer_id) {
+            if let Err(e) = peer.control.send_hang_up(reason, HANGUP_TIMEOUT).await {
+                log::warn!("failed to send hang up reason to peer {}: {}", peer_id, e);
+            }
+        }
+        self.hang_up(peer_id).await
+    }
+
+    /// asks the peer who is in the call: the peer itself, first, with its media sources, then
+    /// the peers it is connected to, with the tracks they send it. only answered by a peer using
+    /// simple-webrtc, once the connection is established. waits at most `QUERY_TIMEOUT`
+    pub async fn query_participants(&self, peer_id: &PeerId) -> Result<Vec<Participant>> {
+        let peer = match self.peers.get(peer_id) {
+            Some(p) => p,
+            None => bail!("peer not found"),
+        };
+        peer.control.query_participants(QUERY_TIMEOUT).await
+    }
+
+    /// joins a group call which is already in progress, from a connection to one of its
+    /// members, ex: the peer which invited us. asks that peer who is in the call (see
+    /// `query_participants`), then dials each participant which isn't connected yet and emits
+    /// `EmittedEvents::ParticipantJoined` for it. their tracks arrive as
+    /// `EmittedEvents::TrackAdded`, and the media sources are sent to them, as for any peer.
+    /// returns the peers which were dialed. the participants' ids must be the ones used for
+    /// signaling. only the joiner dials: the participants accept the call as usual
+    pub async fn join_call(&mut self, peer_id: &PeerId) -> Result<Vec<PeerId>> {
+        let participants = self.query_participants(peer_id).await?;
+        let mut dialed = vec![];
+        for participant in participants {
+            if participant.peer == self.id || self.peers.contains_key(&participant.peer) {
+                continue;
+            }
+            if let Err(e) = self.dial(&participant.peer).await {
+                log::error!("failed to dial participant {}: {}", &participant.peer, e);
+                continue;
+            }
+            self.emitter.send(EmittedEvents::ParticipantJoined {
+                peer: participant.peer.clone(),
+                via: peer_id.clone(),
+                sources: participant.sources,
+            })?;
+            dialed.push(participant.peer);
+        }
+        Ok(dialed)
+    }
+
+    /// publishes the media sources to the participants queries. see `join_call`
+    fn update_roster(&self) {
+        let sources = self
+            .source_order
+            .iter()
+            .filter_map(|source_id| {
+                let track = self.media_sources.get(source_id)?;
+                let kind = MediaKind::from_mime_type(&track.codec().mime_type).ok()?;
+                Some(PublishedSource {
+                    id: source_id.clone(),
+                    kind,
+                })
+            })
+            .collect();
+        self.roster.set_local_sources(sources);
+    }
+
+    /// the remote rejected the call, ex: `accept_call` failed on its side. emits
+    /// `EmittedEvents::CallRejected` and hangs up. ignored if the peer was already hung up
+    pub async fn recv_rejection(&mut self, peer_id: &PeerId, reason: RejectReason) {
+        if !self.peers.contains_key(peer_id) {
+            log::warn!("rejection from unknown peer: {}", peer_id);
+            return;
+        }
+        log::debug!("call rejected by peer {}: {:?}", peer_id, reason);
+        if let Err(e) = self.emitter.send(EmittedEvents::CallRejected {
+            peer: peer_id.clone(),
+            reason,
+        }) {
+            log::error!("failed to send CallRejected event: {}", e);
+        }
+        self.close_peer(peer_id).await;
+    }
+
     /// Spawns a MediaWorker which will receive RTP packets and forward them to all peers
+    /// if `auto_renegotiate` is set, connected peers are sent a new offer via `EmittedEvents::Sdp`
     /// todo: the peers may want to agree on the MimeType
+    /// fails if `source_id` is already in use. a media source is either attached to every peer
+    /// or to none: adding and removing sources requires `&mut self`, so those calls can't
+    /// interleave, even if the Controller is shared behind a Mutex.
+    /// the returned track's `id()` is `source_id` and its `stream_id()` is the CNAME (see
+    /// `cname`). neither changes while the source exists. see `media_source_by_track`.
+    /// fails with `Error::CodecNotRegistered` if the codec's mime type isn't one the Controller
+    /// negotiates, ex: RED without `ControllerConfig::red`
     pub async fn add_media_source(
         &mut self,
         source_id: MediaSourceId,
         codec: RTCRtpCodecCapability,
     ) -> Result<Arc<TrackLocalStaticRTP>> {
-        // todo: don't allow adding duplicate source_ids
+        // webrtc-rs accepts the track, but leaves it out of the SDP
+        if !is_codec_registered(&self.config, &codec) {
+            return Err(Error::CodecNotRegistered(codec.mime_type).into());
+        }
+        // replacing the track would leave the previous track's senders attached to the peers,
+        // where remove_media_source can't find them
+        if self.media_sources.contains_key(&source_id) {
+            bail!("media source {} already exists", source_id);
+        }
+        // webrtc-rs uses the stream id as the CNAME
         let track = Arc::new(TrackLocalStaticRTP::new(
             codec,
             source_id.clone(),
-            self.id.clone(),
+            self.cname().into(),
         ));
         // save this for later, for when connections are established to new peers
         self.media_sources.insert(source_id.clone(), track.clone());
+        self.source_order.push(source_id.clone());
+        self.send_counters.add_source(&source_id);
 
         for (peer_id, peer) in &mut self.peers {
             match peer.connection.add_track(track.clone()).await {
                 Ok(rtp_sender) => {
+                    add_sender_counters(&self.send_counters, &source_id, &rtp_sender).await;
                     // returns None if the value was newly inserted.
                     if peer
                         .rtp_senders
@@ -220,14 +820,19 @@ impl Controller {
                     {
                         log::error!("duplicate rtp_sender");
                     } else {
-                        // Read incoming RTCP packets
-                        // Before these packets are returned they are processed by interceptors. For things
-                        // like NACK this needs to be called.
-                        tokio::spawn(async move {
-                            let mut rtcp_buf = vec![0u8; 1500];
-                            while let Ok((_, _)) = rtp_sender.read(&mut rtcp_buf).await {}
-                            Result::<()>::Ok(())
-                        });
+                        let reader = spawn_rtcp_reader(
+                            rtp_sender,
+                            &self.emitter,
+                            peer_id,
+                            &source_id,
+                            &peer.jitter,
+                        );
+                        match peer.rtcp_readers.get_mut() {
+                            Ok(readers) => {
+                                readers.insert(source_id.clone(), reader);
+                            }
+                            Err(e) => log::error!("failed to lock rtcp readers: {}", e),
+                        }
                     }
                 }
                 Err(e) => {
@@ -241,11 +846,85 @@ impl Controller {
             }
         }
 
+        self.reallocate_bandwidth();
+        self.update_roster();
+        self.renegotiate_all().await;
         Ok(track)
     }
+    /// a shortcut for a one-to-one audio call: adds the `AUDIO_CALL_SOURCE_ID` media source,
+    /// captures `input_device` with an `OpusSource`, dials the peer, and plays every track the
+    /// peer sends on `output_device`. `EmittedEvents` still need to be handled as for `dial`;
+    /// `TrackAdded` may be ignored. like any media source, the audio is sent to every peer.
+    /// only one audio call can be in progress at a time
+    #[cfg(feature = "media")]
+    pub async fn start_audio_call(
+        &mut self,
+        peer_id: &PeerId,
+        input_device: cpal::Device,
+        output_device: cpal::Device,
+        codec: RTCRtpCodecCapability,
+    ) -> Result<media::AudioCall> {
+        let peer_id = &normalize_peer_id(peer_id)?;
+        let source_id: MediaSourceId = media::AUDIO_CALL_SOURCE_ID.into();
+        if self.media_sources.contains_key(&source_id) {
+            bail!("an audio call is already in progress");
+        }
+
+        let track = self
+            .add_media_source(source_id.clone(), codec.clone())
+            .await?;
+        let (forward_tx, forward_rx) = mpsc::unbounded_channel();
+        let result = match media::AudioCall::start(
+            peer_id,
+            input_device,
+            output_device,
+            track,
+            codec,
+            forward_rx,
+        )
+        .await
+        {
+            Ok(call) => self.dial(peer_id).await.map(|_| call),
+            Err(e) => Err(e),
+        };
+        let call = match result {
+            Ok(call) => call,
+            Err(e) => {
+                self.remove_media_source(source_id).await?;
+                return Err(e);
+            }
+        };
+
+        // the remote tracks arrive after the answer, so it's not too late to set this
+        if let Some(peer) = self.peers.get(peer_id) {
+            match peer.track_forward.lock() {
+                Ok(mut forward) => *forward = Some(forward_tx),
+                Err(e) => log::error!("failed to lock track forward: {}", e),
+            }
+        }
+        Ok(call)
+    }
+    /// same as `add_media_source`, for a source described by a `MediaSource`
+    pub async fn add_source(&mut self, source: &MediaSource) -> Result<Arc<TrackLocalStaticRTP>> {
+        self.add_media_source(source.id().clone(), source.codec().clone())
+            .await
+    }
+
+    /// describes a media source which was added with `add_source` or `add_media_source`
+    pub fn media_source(&self, source_id: &MediaSourceId) -> Option<MediaSource> {
+        let track = self.media_sources.get(source_id)?;
+        let codec = track.codec();
+        let kind = MediaKind::from_mime_type(&codec.mime_type).ok()?;
+        MediaSource::new(source_id.clone(), kind, codec).ok()
+    }
+
     /// Removes the media track
     /// ex: stop sharing screen
     /// the user should discard the TrackLocalWriter which they received from add_media_source
+    /// if `auto_renegotiate` is set, connected peers are sent a new offer via `EmittedEvents::Sdp`
+    /// the source's transceiver stays in the SDP without a sender. webrtc-rs's add_track reuses
+    /// it for the next source of the same kind, so repeatedly adding and removing a source (ex:
+    /// starting and stopping a screen share) doesn't grow the SDP
     pub async fn remove_media_source(&mut self, source_id: MediaSourceId) -> Result<()> {
         for (peer_id, peer) in &mut self.peers {
             // if source_id isn't found, it will be logged by the next statement
@@ -260,24 +939,387 @@ impl Controller {
                 }
             }
 
+            match peer.rtcp_readers.get_mut() {
+                Ok(readers) => {
+                    if let Some(reader) = readers.remove(&source_id) {
+                        reader.abort();
+                    }
+                }
+                Err(e) => log::error!("failed to lock rtcp readers: {}", e),
+            }
+            peer.jitter.remove(&source_id);
             if peer.rtp_senders.remove(&source_id).is_none() {
                 log::warn!("media source {} not found for peer {}", &source_id, peer_id);
             }
         }
 
+        self.content_hints.remove(&source_id);
+        self.muted_sources.remove(&source_id);
+        self.source_order.retain(|id| id != &source_id);
+        self.send_counters.remove_source(&source_id);
         if self.media_sources.remove(&source_id).is_none() {
             log::warn!(
                 "media source {} not found in self.media_sources",
                 &source_id
             );
         }
+
+        self.reallocate_bandwidth();
+        self.update_roster();
+        self.renegotiate_all().await;
+        Ok(())
+    }
+
+    /// describes what a video media source contains, ex: `ContentHint::Text` for a screen share.
+    /// see `ContentHint` for how the application's encoder should use it
+    pub fn set_content_hint(&mut self, source_id: &MediaSourceId, hint: ContentHint) -> Result<()> {
+        let track = match self.media_sources.get(source_id) {
+            Some(t) => t,
+            None => bail!("media source {} not found", source_id),
+        };
+        if MediaKind::from_mime_type(&track.codec().mime_type)? != MediaKind::Video {
+            bail!("content hints only apply to video sources");
+        }
+        self.content_hints.insert(source_id.clone(), hint);
+        self.reallocate_bandwidth();
+        Ok(())
+    }
+
+    pub fn content_hint(&self, source_id: &MediaSourceId) -> Option<ContentHint> {
+        self.content_hints.get(source_id).copied()
+    }
+
+    /// stops sending the media source to every peer, without renegotiating, by detaching its
+    /// track from the peers' senders. ex: a microphone mute button. the track can still be
+    /// written to; the packets are dropped. resume with `unmute_media_source`.
+    /// fails if the source doesn't exist, or only if the track couldn't be detached for every
+    /// peer it's sent to. the failures are logged per peer; the source stays muted, and is
+    /// muted again after the next negotiation with the peers which failed
+    pub async fn mute_media_source(&mut self, source_id: &MediaSourceId) -> Result<()> {
+        if !self.media_sources.contains_key(source_id) {
+            bail!("media source {} not found", source_id);
+        }
+        self.muted_sources.insert(source_id.clone());
+        self.apply_source_mute(source_id).await
+    }
+
+    /// resumes sending a media source muted with `mute_media_source`. has no effect until
+    /// `unmute_all` if everything is muted. fails like `mute_media_source`
+    pub async fn unmute_media_source(&mut self, source_id: &MediaSourceId) -> Result<()> {
+        if !self.media_sources.contains_key(source_id) {
+            bail!("media source {} not found", source_id);
+        }
+        self.muted_sources.remove(source_id);
+        self.apply_source_mute(source_id).await
+    }
+
+    /// stops sending every media source, including ones added or peers connected while muted.
+    /// ex: a master mute button. resume with `unmute_all`
+    pub async fn mute_all(&mut self) {
+        self.all_muted = true;
+        self.apply_mute_all().await;
+    }
+
+    /// resumes sending every media source, except for those individually muted with
+    /// `mute_media_source`
+    pub async fn unmute_all(&mut self) {
+        self.all_muted = false;
+        self.apply_mute_all().await;
+    }
+
+    /// true if the media source isn't being sent, because of `mute_media_source` or `mute_all`
+    pub fn is_media_source_muted(&self, source_id: &MediaSourceId) -> bool {
+        self.all_muted || self.muted_sources.contains(source_id)
+    }
+
+    /// puts the call with the peer on hold: no media source is sent to the peer until `resume`,
+    /// without renegotiating. if `music` is set, it's sent instead of the peer's first audio
+    /// source with the same codec, ex: a track written by a `media::ToneSource`. the sources are
+    /// still sent to the other peers, so capture isn't stopped; `AudioCall::hold` stops it.
+    /// the remote is told via `EmittedEvents::OnHold`. the remote's tracks are still received;
+    /// the application may mute their sinks (see `SinkTrack::set_muted`). calling this again
+    /// replaces the music
+    pub async fn hold(
+        &mut self,
+        peer_id: &PeerId,
+        music: Option<Arc<TrackLocalStaticRTP>>,
+    ) -> Result<()> {
+        if let Some(music) = &music {
+            // apply_mute tells the tracks apart by id
+            if self.media_sources.contains_key(music.id()) {
+                bail!(
+                    "hold music can't use the id of a media source: {}",
+                    music.id()
+                );
+            }
+        }
+        let peer = match self.peers.get_mut(peer_id) {
+            Some(p) => p,
+            None => bail!("peer {} not found", peer_id),
+        };
+        let was_on_hold = peer.on_hold;
+        peer.on_hold = true;
+        peer.hold_music = music;
+        let peer = &self.peers[peer_id];
+        self.apply_mute(peer).await;
+        if !was_on_hold {
+            if let Err(e) = peer.control.send(&ControlMessage::Hold).await {
+                log::warn!("failed to tell peer {} about the hold: {}", peer_id, e);
+            }
+        }
+        Ok(())
+    }
+
+    /// takes the call off hold: stops the hold music and resumes sending the media sources,
+    /// except for muted ones. the remote is told via `EmittedEvents::Resumed`
+    pub async fn resume(&mut self, peer_id: &PeerId) -> Result<()> {
+        let peer = match self.peers.get_mut(peer_id) {
+            Some(p) => p,
+            None => bail!("peer {} not found", peer_id),
+        };
+        if !peer.on_hold {
+            return Ok(());
+        }
+        peer.on_hold = false;
+        peer.hold_music = None;
+        let peer = &self.peers[peer_id];
+        self.apply_mute(peer).await;
+        if let Err(e) = peer.control.send(&ControlMessage::Resume).await {
+            log::warn!("failed to tell peer {} about the resume: {}", peer_id, e);
+        }
+        Ok(())
+    }
+
+    pub fn is_on_hold(&self, peer_id: &PeerId) -> bool {
+        self.peers.get(peer_id).is_some_and(|p| p.on_hold)
+    }
+
+    async fn apply_mute_all(&self) {
+        for peer in self.peers.values() {
+            self.apply_mute(peer).await;
+        }
+    }
+
+    /// applies the mute state of the source to every peer. see `mute_media_source`
+    async fn apply_source_mute(&self, source_id: &MediaSourceId) -> Result<()> {
+        let mut attempted = 0;
+        let mut failed = vec![];
+        for peer in self.peers.values() {
+            if !peer.rtp_senders.contains_key(source_id) {
+                log::debug!("peer {} isn't sent media source {}", peer.id, source_id);
+                continue;
+            }
+            attempted += 1;
+            if self.apply_mute(peer).await.contains(source_id) {
+                failed.push(peer.id.as_str());
+            }
+        }
+        if attempted > 0 && failed.len() == attempted {
+            bail!(
+                "failed to change the track of media source {} for every peer: {}",
+                source_id,
+                failed.join(", ")
+            );
+        }
         Ok(())
     }
 
-    /// receive an ICE candidate from the remote side
+    /// detaches the tracks of muted sources from the peer's senders, and restores the others.
+    /// while the peer is on hold, every track is detached, except that the hold music replaces
+    /// one of them. a sender which hasn't been negotiated yet is left alone: webrtc-rs can't
+    /// start sending without a track. it's muted once the negotiation completes; see
+    /// `after_negotiation`. returns the sources whose track couldn't be changed
+    async fn apply_mute(&self, peer: &Peer) -> HashSet<MediaSourceId> {
+        let mut failed = HashSet::new();
+        let negotiated = match peer.negotiated_sources.lock() {
+            Ok(n) => n.clone(),
+            Err(e) => {
+                log::error!("failed to lock negotiated sources: {}", e);
+                return peer.rtp_senders.keys().cloned().collect();
+            }
+        };
+        let music_source = self.hold_music_source(peer, &negotiated);
+        for (source_id, rtp_sender) in &peer.rtp_senders {
+            let wanted = if peer.on_hold {
+                match music_source {
+                    Some(id) if id == source_id => peer.hold_music.as_ref(),
+                    _ => None,
+                }
+            } else if self.is_media_source_muted(source_id) {
+                None
+            } else {
+                self.media_sources.get(source_id)
+            };
+            let wanted_id = wanted.map(|t| t.id());
+            let attached = rtp_sender.track().await;
+            if attached.as_ref().map(|t| t.id()) == wanted_id {
+                continue;
+            }
+            if negotiated.contains(source_id) || wanted_id == Some(source_id.as_str()) {
+                if let Err(e) = set_sender_track(&self.splicer, rtp_sender, wanted).await {
+                    log::error!(
+                        "failed to replace the track of media source {} for peer {}: {}",
+                        source_id,
+                        peer.id,
+                        e
+                    );
+                    failed.insert(source_id.clone());
+                }
+            }
+        }
+        failed
+    }
+
+    /// the negotiated audio source which the peer's hold music is sent in place of: the first
+    /// one with the music's codec, since the sender's codec can't change without renegotiating
+    fn hold_music_source(
+        &self,
+        peer: &Peer,
+        negotiated: &HashSet<MediaSourceId>,
+    ) -> Option<&MediaSourceId> {
+        let music = peer.hold_music.as_ref()?;
+        let music_type = music.codec().mime_type.to_lowercase();
+        let source = self.source_order.iter().find(|id| {
+            negotiated.contains(*id)
+                && peer.rtp_senders.contains_key(*id)
+                && matches!(self.media_sources.get(*id),
+                    Some(t) if t.codec().mime_type.to_lowercase() == music_type)
+        });
+        if source.is_none() {
+            log::warn!(
+                "no audio source with codec {} to send the hold music to peer {}",
+                music.codec().mime_type,
+                peer.id
+            );
+        }
+        source
+    }
+
+    /// reattaches the tracks of muted sources, and of sources replaced by hold music, before an
+    /// offer or answer is created. the SDP only describes the senders which have a track, and
+    /// the remote stops receiving from the others. `apply_mute` detaches them again once the
+    /// SDP is created
+    async fn attach_muted(&self, peer: &Peer) {
+        for (source_id, rtp_sender) in &peer.rtp_senders {
+            let own_track = matches!(rtp_sender.track().await, Some(t) if t.id() == source_id);
+            if !own_track {
+                let track = self.media_sources.get(source_id);
+                if let Err(e) = set_sender_track(&self.splicer, rtp_sender, track).await {
+                    log::error!("failed to reattach media source {}: {}", source_id, e);
+                }
+            }
+        }
+    }
+
+    /// once an offer/answer exchange is complete, the senders it included are sending and can
+    /// be muted
+    async fn after_negotiation(&self, peer: &Peer) {
+        if peer.connection.signaling_state() == RTCSignalingState::Stable {
+            match peer.negotiated_sources.lock() {
+                Ok(mut n) => *n = peer.rtp_senders.keys().cloned().collect(),
+                Err(e) => log::error!("failed to lock negotiated sources: {}", e),
+            }
+            self.check_inactive_sources(peer).await;
+        }
+        if let Some(remote_sdp) = peer.connection.remote_description().await {
+            let tracks = internal::sdp::remote_tracks(&remote_sdp.sdp);
+            self.roster.set_remote_tracks(&peer.id, &tracks);
+        }
+        self.apply_mute(peer).await;
+    }
+
+    /// stops reading RTCP from the senders whose media section the negotiation left without a
+    /// sending direction (inactive or recvonly), since the remote won't send any, and emits
+    /// `EmittedEvents::MediaInactive`. restarts reading once a later negotiation makes them active
+    async fn check_inactive_sources(&self, peer: &Peer) {
+        let transceivers = peer.connection.get_transceivers().await;
+        let mut directions = HashMap::new();
+        for (source_id, rtp_sender) in &peer.rtp_senders {
+            for t in &transceivers {
+                if matches!(t.sender().await, Some(sender) if Arc::ptr_eq(&sender, rtp_sender)) {
+                    directions.insert(source_id, t.current_direction());
+                    break;
+                }
+            }
+        }
+
+        let mut readers = match peer.rtcp_readers.lock() {
+            Ok(r) => r,
+            Err(e) => {
+                log::error!("failed to lock rtcp readers: {}", e);
+                return;
+            }
+        };
+        for (source_id, direction) in directions {
+            let sending = match direction {
+                RTCRtpTransceiverDirection::Sendrecv | RTCRtpTransceiverDirection::Sendonly => true,
+                RTCRtpTransceiverDirection::Recvonly | RTCRtpTransceiverDirection::Inactive => {
+                    false
+                }
+                // not negotiated
+                RTCRtpTransceiverDirection::Unspecified => continue,
+            };
+            if sending {
+                if !readers.contains_key(source_id) {
+                    log::debug!("media source {} is active for peer {}", source_id, &peer.id);
+                    let rtp_sender = peer.rtp_senders[source_id].clone();
+                    let reader = spawn_rtcp_reader(
+                        rtp_sender,
+                        &self.emitter,
+                        &peer.id,
+                        source_id,
+                        &peer.jitter,
+                    );
+                    readers.insert(source_id.clone(), reader);
+                }
+            } else if let Some(reader) = readers.remove(source_id) {
+                reader.abort();
+                peer.jitter.remove(source_id);
+                log::info!(
+                    "media source {} is inactive for peer {}",
+                    source_id,
+                    &peer.id
+                );
+                if let Err(e) = self.emitter.send(EmittedEvents::MediaInactive {
+                    peer: peer.id.clone(),
+                    source_id: source_id.clone(),
+                }) {
+                    log::error!("failed to send media inactive event: {}", e);
+                }
+            }
+        }
+    }
+
+    /// adds the attributes webrtc-rs doesn't generate to a local SDP, before it's sent to the
+    /// remote. webrtc-rs rejects a local description which differs from the one it created, so
+    /// only the copy sent to the remote has them
+    fn advertised_sdp(&self, mut sdp: RTCSessionDescription) -> RTCSessionDescription {
+        if let Some(ptime) = self.config.ptime {
+            sdp.sdp = internal::sdp::with_ptime(&sdp.sdp, ptime);
+        }
+        sdp
+    }
+
+    /// receive an ICE candidate from the remote side. fails with `Error::InvalidCandidate` if
+    /// one of its fields is malformed, ex: its address is empty, before it's passed to the ICE
+    /// agent. see `validate_ice_candidate`.
+    /// `RTCIceCandidate::default()` stands for an empty candidate, which means the remote
+    /// finished gathering: see `recv_end_of_candidates`. any other candidate without an address
+    /// is malformed
     pub async fn recv_ice(&self, peer_id: &PeerId, candidate: RTCIceCandidate) -> Result<()> {
+        if candidate == RTCIceCandidate::default() {
+            return self.recv_end_of_candidates(peer_id).await;
+        }
+        let peer_id = &normalize_peer_id(peer_id)?;
         if let Some(peer) = self.peers.get(peer_id) {
+            validate_ice_candidate(&candidate)?;
             let candidate = candidate.to_json()?.candidate;
+            // a malformed candidate doesn't count
+            if peer.remote_candidates.fetch_add(1, Ordering::Relaxed)
+                >= self.config.max_remote_candidates
+            {
+                return Err(Error::TooManyCandidates(peer_id.clone()).into());
+            }
             peer.connection
                 .add_ice_candidate(RTCIceCandidateInit {
                     candidate,
@@ -290,10 +1332,150 @@ impl Controller {
 
         Ok(())
     }
+    /// the remote finished gathering its ICE candidates, ex: it signaled an empty candidate or
+    /// its `EmittedEvents::IceGatheringState` became `Complete`. passed to the ICE agent as the
+    /// end-of-candidates marker, an empty candidate string. doesn't count towards
+    /// `ControllerConfig::max_remote_candidates`. webrtc-rs 0.6 accepts the marker but doesn't
+    /// act on it yet, so the agent still fails on its timeouts
+    pub async fn recv_end_of_candidates(&self, peer_id: &PeerId) -> Result<()> {
+        let peer_id = &normalize_peer_id(peer_id)?;
+        match self.peers.get(peer_id) {
+            Some(peer) => {
+                log::debug!("peer {} finished gathering ice candidates", peer_id);
+                peer.connection
+                    .add_ice_candidate(RTCIceCandidateInit::default())
+                    .await?;
+                Ok(())
+            }
+            None => bail!("peer not found"),
+        }
+    }
+    /// receive the candidates from an `EmittedEvents::IceBatch`. stops at the first candidate
+    /// which can't be added
+    pub async fn recv_ice_batch(
+        &self,
+        peer_id: &PeerId,
+        candidates: Vec<RTCIceCandidate>,
+    ) -> Result<()> {
+        for candidate in candidates {
+            self.recv_ice(peer_id, candidate).await?;
+        }
+        Ok(())
+    }
     /// receive an SDP object from the remote side
-    pub async fn recv_sdp(&self, peer_id: &PeerId, sdp: RTCSessionDescription) -> Result<()> {
+    /// if the SDP is an answer, emits the accepted Opus parameters via `RemoteOpusParams`
+    /// if the SDP is an offer (renegotiation), responds with an answer via `Sdp`
+    pub async fn recv_sdp(&self, peer_id: &PeerId, mut sdp: RTCSessionDescription) -> Result<()> {
+        let peer_id = &normalize_peer_id(peer_id)?;
+        self.check_remote_sdp(peer_id, &mut sdp)?;
         if let Some(peer) = self.peers.get(peer_id) {
+            let sdp_type = sdp.sdp_type;
+            // only an answer tells us what the remote accepted
+            let params = if sdp_type == RTCSdpType::Answer {
+                internal::sdp::opus_params(&sdp.sdp)
+            } else {
+                None
+            };
+            let prev_sdp = peer.connection.remote_description().await.map(|p| p.sdp);
+            // the remote's candidates are discarded when it restarts ICE
+            let ufrag = internal::sdp::ice_ufrag(&sdp.sdp).map(String::from);
+            let prev_ufrag = prev_sdp
+                .as_deref()
+                .and_then(internal::sdp::ice_ufrag)
+                .map(String::from);
+            // after the initial negotiation, report the tracks the remote started or stopped
+            // sending
+            let media_changes = prev_sdp.as_deref().map(|prev| {
+                let before = internal::sdp::remote_tracks(prev);
+                let after = internal::sdp::remote_tracks(&sdp.sdp);
+                let added: Vec<TrackInfo> = after
+                    .iter()
+                    .filter(|t| !before.contains(t))
+                    .cloned()
+                    .collect();
+                let removed: Vec<TrackInfo> =
+                    before.into_iter().filter(|t| !after.contains(t)).collect();
+                (added, removed)
+            });
+            if sdp_type == RTCSdpType::Offer && prev_sdp.is_some() {
+                wait_for_ice_start(&peer.connection).await;
+            }
             peer.connection.set_remote_description(sdp).await?;
+            // the signaling callback runs on its own task. updates the state now, so that it's
+            // current once recv_sdp returns
+            peer.state_tracker
+                .on_signaling_state(peer.connection.signaling_state());
+            if ufrag != prev_ufrag {
+                peer.remote_candidates.store(0, Ordering::Relaxed);
+            }
+
+            if let Some((added, removed)) = media_changes {
+                if !added.is_empty() || !removed.is_empty() {
+                    let track_ids: Vec<String> =
+                        removed.iter().map(|t| t.track_id.clone()).collect();
+                    self.emitter.send(EmittedEvents::RemoteMediaChanged {
+                        peer: peer_id.clone(),
+                        added,
+                        removed,
+                    })?;
+                    for track_id in track_ids {
+                        self.emitter.send(EmittedEvents::TrackRemoved {
+                            peer: peer_id.clone(),
+                            track_id,
+                        })?;
+                    }
+                }
+            }
+
+            if let Some(params) = params {
+                self.emitter.send(EmittedEvents::RemoteOpusParams {
+                    peer: peer_id.clone(),
+                    params,
+                })?;
+            }
+
+            // the remote is renegotiating (ex: an ICE restart). it needs an answer
+            if sdp_type == RTCSdpType::Offer {
+                restrict_receiving(&peer.connection, &peer.options).await;
+                order_codecs(&peer.connection, self.codec_preferences.get(peer_id)).await;
+                self.attach_muted(peer).await;
+                let answer = match peer.connection.create_answer(None).await {
+                    Ok(answer) => {
+                        set_local_description(&peer.connection, answer, peer.options.trickle).await
+                    }
+                    Err(e) => Err(e.into()),
+                };
+                // mutes the sources again, now that the answer describes them
+                self.after_negotiation(peer).await;
+                let answer = answer?;
+                peer.state_tracker
+                    .on_signaling_state(peer.connection.signaling_state());
+                let answer = self.advertised_sdp(answer);
+                self.log_sdp(peer_id, "local", &answer);
+                self.emitter.send(EmittedEvents::Sdp {
+                    dest: peer_id.clone(),
+                    sdp: Box::new(answer),
+                })?;
+            } else {
+                self.after_negotiation(peer).await;
+            }
+
+            // the negotiation is complete. send the offer which had to wait for it
+            let queued = match peer.queued_offer.lock() {
+                Ok(mut q) => q.take(),
+                Err(e) => bail!("failed to lock queued offer: {}", e),
+            };
+            // the remote's SDP was applied, so it isn't an error for recv_sdp
+            if let Some(options) = queued {
+                if let Err(e) = self.send_offer(peer_id, Some(options)).await {
+                    log::error!("failed to send queued offer to peer {}: {}", peer_id, e);
+                }
+            }
+            if sdp_type == RTCSdpType::Offer {
+                if let Err(e) = self.offer_missing_sources(peer_id).await {
+                    log::error!("failed to offer media sources to peer {}: {}", peer_id, e);
+                }
+            }
         } else {
             bail!("peer not found");
         }
@@ -301,55 +1483,1009 @@ impl Controller {
         Ok(())
     }
 
-    /// adds a connection. called by dial and accept_call
-    /// inserts the connection into self.peers
-    /// initializes state to WaitingForSdp
-    async fn connect(&mut self, peer_id: &PeerId) -> Result<Arc<RTCPeerConnection>> {
-        // todo: ensure id is not in self.connections
-
-        // create ICE gatherer
-        let config = RTCConfiguration {
-            ice_servers: vec![RTCIceServer {
-                urls: vec!["stun:stun.l.google.com:19302".into()],
+    /// creates a new offer with fresh ICE credentials, causing ICE candidates to be gathered again.
+    /// the offer is emitted via `EmittedEvents::Sdp` and the remote responds with an answer.
+    /// media sources are not affected. queued if a negotiation is in progress; see `is_negotiating`
+    pub async fn restart_ice(&self, peer_id: &PeerId) -> Result<()> {
+        self.send_offer(
+            peer_id,
+            Some(RTCOfferOptions {
+                ice_restart: true,
                 ..Default::default()
-            }],
-            ..Default::default()
-        };
+            }),
+        )
+        .await
+    }
 
-        // Create and store a new RTCPeerConnection
-        let peer_connection = Arc::new(self.api.new_peer_connection(config).await?);
-        if self
-            .peers
-            .insert(
-                peer_id.clone(),
-                Peer {
-                    state: PeerState::WaitingForSdp,
-                    id: peer_id.clone(),
-                    connection: peer_connection.clone(),
-                    rtp_senders: HashMap::new(),
-                },
-            )
-            .is_some()
-        {
-            log::warn!("overwriting peer connection");
+    /// describes the candidate pair currently used to reach the peer, ex: for testing failover.
+    /// None if ICE hasn't selected a pair yet
+    pub async fn selected_candidate_pair(&self, peer_id: &PeerId) -> Option<String> {
+        let peer = self.peers.get(peer_id)?;
+        let pair = peer
+            .connection
+            .sctp()
+            .transport()
+            .ice_transport()
+            .get_selected_candidate_pair()
+            .await?;
+        Some(pair.to_string())
+    }
+
+    /// moves the connection to the peer onto the network interface with the given address, by
+    /// restarting ICE with only that address. `None` removes the preference.
+    /// webrtc-rs doesn't allow choosing or nominating a candidate pair, so this is the closest
+    /// equivalent. limitations:
+    /// - the preference applies to every peer, the next time it gathers candidates
+    /// - relay candidates are gathered regardless
+    /// - if the peer can't be reached from the address, ICE fails
+    pub async fn prefer_local_address(
+        &self,
+        peer_id: &PeerId,
+        address: Option<IpAddr>,
+    ) -> Result<()> {
+        match self.preferred_address.lock() {
+            Ok(mut a) => *a = address,
+            Err(e) => bail!("failed to lock preferred address: {}", e),
         }
+        self.restart_ice(peer_id).await
+    }
 
-        // configure callbacks
+    /// true while an offer/answer exchange with the peer is in progress. offers requested in the
+    /// meantime (by `restart_ice` or renegotiation) are queued instead of creating a conflicting
+    /// offer; they're combined into one offer which is sent once `recv_sdp` completes the
+    /// negotiation. this doesn't prevent glare with the remote, which may send its own offer
+    pub fn is_negotiating(&self, peer_id: &PeerId) -> bool {
+        match self.peers.get(peer_id) {
+            Some(p) => p.connection.signaling_state() != RTCSignalingState::Stable,
+            None => false,
+        }
+    }
+
+    /// creates a new offer for an existing connection and emits it via `EmittedEvents::Sdp`.
+    /// the remote applies it with `recv_sdp`, which responds with an answer.
+    /// if a negotiation is in progress, the offer is queued
+    async fn send_offer(&self, peer_id: &PeerId, options: Option<RTCOfferOptions>) -> Result<()> {
+        let peer = match self.peers.get(peer_id) {
+            Some(p) => p,
+            None => bail!("peer not found"),
+        };
+
+        if self.is_negotiating(peer_id) {
+            let options = options.unwrap_or_default();
+            match peer.queued_offer.lock() {
+                Ok(mut q) => {
+                    let queued = q.get_or_insert_with(RTCOfferOptions::default);
+                    queued.ice_restart |= options.ice_restart;
+                    queued.voice_activity_detection |= options.voice_activity_detection;
+                }
+                Err(e) => bail!("failed to lock queued offer: {}", e),
+            }
+            log::debug!("queued offer for peer {}: negotiation in progress", peer_id);
+            return Ok(());
+        }
+
+        restrict_receiving(&peer.connection, &peer.options).await;
+        order_codecs(&peer.connection, self.codec_preferences.get(peer_id)).await;
+        self.attach_muted(peer).await;
+        let offer = peer.connection.create_offer(options).await;
+        let offer = match offer {
+            Ok(offer) => set_local_description(&peer.connection, offer, peer.options.trickle).await,
+            Err(e) => Err(e.into()),
+        };
+        self.apply_mute(peer).await;
+        let offer = offer?;
+
+        let offer = self.advertised_sdp(offer);
+        self.log_sdp(peer_id, "local", &offer);
+        self.emitter.send(EmittedEvents::Sdp {
+            dest: peer_id.clone(),
+            sdp: Box::new(offer),
+        })?;
+
+        Ok(())
+    }
+
+    /// called after the tracks of existing connections change. if `auto_renegotiate` is set,
+    /// sends a new offer to every peer. for peers which are still negotiating, the offer is queued
+    async fn renegotiate_all(&self) {
+        if !self.config.auto_renegotiate {
+            return;
+        }
+        for (peer_id, peer) in &self.peers {
+            // the initial offer wasn't created yet. it will contain the change
+            if !self.is_negotiating(peer_id) && peer.connection.remote_description().await.is_none()
+            {
+                continue;
+            }
+            if let Err(e) = self.send_offer(peer_id, None).await {
+                log::error!("failed to renegotiate with peer {}: {}", peer_id, e);
+            }
+        }
+    }
+
+    /// sends an offer if the remote's offer didn't have a media section for every media source,
+    /// ex: a caller without media sources offers only the data channel. an answer can't add media
+    /// sections, so the sources would never be sent. requires `auto_renegotiate`
+    async fn offer_missing_sources(&self, peer_id: &PeerId) -> Result<()> {
+        if !self.config.auto_renegotiate || self.is_negotiating(peer_id) {
+            return Ok(());
+        }
+        let peer = match self.peers.get(peer_id) {
+            Some(p) => p,
+            None => bail!("peer not found"),
+        };
+        for t in peer.connection.get_transceivers().await {
+            let sender = match t.sender().await {
+                Some(s) => s,
+                None => continue,
+            };
+            // webrtc-rs assigns the mid when the transceiver is negotiated
+            if peer.rtp_senders.values().any(|s| Arc::ptr_eq(s, &sender))
+                && t.mid().await.is_empty()
+            {
+                log::debug!(
+                    "offering media sources missing from peer {}'s offer",
+                    peer_id
+                );
+                return self.send_offer(peer_id, None).await;
+            }
+        }
+        Ok(())
+    }
+
+    /// should be called by the application when the network changes (ex: switching from Wi-Fi to
+    /// cellular). the old ICE candidates are no longer valid, so ICE is restarted for every peer
+    /// which has started connecting.
+    pub async fn on_network_change(&self) -> Result<()> {
+        for (peer_id, peer) in &self.peers {
+            // peers which haven't started ICE will gather candidates on the new network anyway
+            match peer.connection.ice_connection_state() {
+                RTCIceConnectionState::New | RTCIceConnectionState::Closed => continue,
+                _ => {}
+            }
+            if let Err(e) = self.restart_ice(peer_id).await {
+                log::error!("failed to restart ice for peer {}: {}", peer_id, e);
+            }
+        }
+        Ok(())
+    }
+
+    /// measures the round trip time to the peer over the reserved data channel. the remote
+    /// responds automatically. works as soon as the connection is established, before any media
+    /// flows. fails if the remote doesn't respond within `PING_TIMEOUT`
+    pub async fn ping(&self, peer_id: &PeerId) -> Result<Duration> {
+        let control = match self.peers.get(peer_id) {
+            Some(p) => p.control.clone(),
+            None => bail!("peer not found"),
+        };
+        control.ping(PING_TIMEOUT).await
+    }
+
+    /// true if the Controller closed its connections because the event receiver was dropped.
+    /// see `OnEventChanClosed`. a shut down Controller can't dial or accept calls
+    pub fn is_shut_down(&self) -> bool {
+        self.emitter.is_shut_down()
+    }
+
+    /// sends the events to `chan` instead of `emitted_event_chan`, starting with the last
+    /// `ControllerConfig::event_replay_len` events, oldest first. events are always delivered in
+    /// the order they were emitted: `chan` receives the replayed events and then every later
+    /// event, none missed and none repeated. the replayed events may also have been delivered to
+    /// the previous channel.
+    /// to start the event loop after `init`, pass a channel whose receiver is dropped to `init`,
+    /// together with `OnEventChanClosed::Ignore`. the calls made before attaching, ex: `dial`,
+    /// still succeed, and their events are replayed if they fit in `event_replay_len`.
+    /// fails if the Controller shut down
+    pub fn attach_event_chan(&self, chan: mpsc::UnboundedSender<EmittedEvents>) -> Result<()> {
+        self.emitter.attach(chan)
+    }
+
+    /// emits `EmittedEvents::StatsUpdated` for every connected peer, once per `interval`.
+    /// replaces any previous reporting task. stopped by `disable_stats_reporting` and `deinit`
+    pub fn enable_stats_reporting(&mut self, interval: Duration) -> Result<()> {
+        if interval.is_zero() {
+            bail!("stats reporting interval must not be zero");
+        }
+        self.disable_stats_reporting();
+
+        let emitter = self.emitter.clone();
+        let peer_jitter = self.peer_jitter.clone();
+        self.stats_task = Some(tokio::spawn(async move {
+            // the previous sample is needed to compute the bitrate
+            let mut previous: HashMap<PeerId, ConnectionStats> = HashMap::new();
+            let mut ticker = tokio::time::interval(interval);
+            loop {
+                ticker.tick().await;
+                let connections = emitter.connections();
+                previous.retain(|id, _| connections.iter().any(|(peer_id, _)| peer_id == id));
+                for (peer_id, pc) in connections {
+                    if pc.connection_state() != RTCPeerConnectionState::Connected {
+                        continue;
+                    }
+                    let stats = ConnectionStats::collect(&pc).await;
+                    let bitrate = previous
+                        .get(&peer_id)
+                        .map(|p| stats.bitrate_since(p))
+                        .unwrap_or_default();
+                    let jitter = match peer_jitter.lock() {
+                        Ok(peer_jitter) => peer_jitter.get(&peer_id).and_then(|j| j.max()),
+                        Err(e) => {
+                            log::error!("failed to lock peer jitter: {}", e);
+                            None
+                        }
+                    };
+                    let event = EmittedEvents::StatsUpdated {
+                        peer: peer_id.clone(),
+                        bitrate,
+                        loss: stats.loss,
+                        jitter,
+                        rtt: stats.rtt,
+                    };
+                    previous.insert(peer_id.clone(), stats);
+                    if let Err(e) = emitter.send(event) {
+                        log::error!("failed to send stats for peer {}: {}", &peer_id, e);
+                    }
+                }
+            }
+        }));
+        Ok(())
+    }
+
+    pub fn disable_stats_reporting(&mut self) {
+        if let Some(task) = self.stats_task.take() {
+            task.abort();
+        }
+    }
+
+    /// if `policy` is set, a peer whose ICE connection fails is reconnected with exponential
+    /// backoff rather than `EmittedEvents::Disconnected` being emitted right away: see
+    /// `EmittedEvents::Reconnecting`. None stops reconnecting, aborting the reconnects in progress.
+    /// the reconnects need to call the Controller, so it must be shared, as in the examples. they
+    /// stop when the peer is hung up: call `recv_hang_up` when the remote ends the call.
+    /// only one side should reconnect, ex: the side which dialed. if both do, their offers collide
+    pub async fn set_reconnect_policy(
+        controller: &Arc<tokio::sync::Mutex<Controller>>,
+        policy: Option<ReconnectPolicy>,
+    ) -> Result<()> {
+        let mut this = controller.lock().await;
+        if matches!(policy, Some(p) if p.max_attempts == 0) {
+            bail!("reconnect policy must allow at least one attempt");
+        }
+        this.stop_reconnecting();
+        let policy = match policy {
+            Some(p) => p,
+            None => return Ok(()),
+        };
+
+        let (tx, rx) = mpsc::unbounded_channel();
+        match this.reconnect_tx.lock() {
+            Ok(mut reconnect_tx) => *reconnect_tx = Some(tx),
+            Err(e) => bail!("failed to lock reconnect_tx: {}", e),
+        }
+        this.reconnect_task = Some(tokio::spawn(internal::reconnect::run(
+            Arc::downgrade(controller),
+            this.emitter.clone(),
+            policy,
+            rx,
+        )));
+        Ok(())
+    }
+
+    fn stop_reconnecting(&mut self) {
+        match self.reconnect_tx.lock() {
+            Ok(mut reconnect_tx) => *reconnect_tx = None,
+            Err(e) => log::error!("failed to lock reconnect_tx: {}", e),
+        }
+        if let Some(task) = self.reconnect_task.take() {
+            task.abort();
+        }
+    }
+
+    /// the state of the connection to the peer, also emitted as `EmittedEvents::PeerStateChanged`.
+    /// None if the peer doesn't exist
+    pub fn peer_state(&self, peer_id: &PeerId) -> Option<PeerState> {
+        self.peers.get(peer_id).map(|p| p.state_tracker.get())
+    }
+
+    /// unstable: an escape hatch for webrtc-rs features which this library doesn't wrap yet.
+    /// the Controller doesn't know about changes made through the returned connection, so
+    /// adding/removing tracks or renegotiating with it may desync the Controller's state.
+    pub fn raw_peer_connection(&self, peer_id: &PeerId) -> Option<Arc<RTCPeerConnection>> {
+        self.peers.get(peer_id).map(|p| p.connection.clone())
+    }
+
+    /// the SDP this side last negotiated with the peer, as SDP text (RFC 8866) rather than the
+    /// JSON used for signaling, ex: for a SIP back-to-back user agent bridging the call. it has
+    /// the attributes sent to the remote (see `advertised_sdp`), and the local ICE candidates
+    /// once they're all gathered. it's a WebRTC SDP: the gateway must support ICE and DTLS-SRTP.
+    /// None if the peer doesn't exist or no negotiation has completed
+    pub async fn export_sdp(&self, peer_id: &PeerId) -> Option<String> {
+        let peer = self.peers.get(peer_id)?;
+        let sdp = peer.connection.current_local_description().await?;
+        Some(self.advertised_sdp(sdp).sdp)
+    }
+
+    /// the servers for a new connection. see `InitArgs::ice_servers`,
+    /// `ControllerConfig::ice_servers` and `ControllerConfig::turn_servers`
+    async fn ice_servers(&self) -> Vec<RTCIceServer> {
+        let mut servers = match &self.config.ice_servers {
+            Some(get_servers) => get_servers().await.unwrap_or_else(|e| {
+                log::error!("failed to get ICE servers, using the default: {}", e);
+                vec![]
+            }),
+            None => vec![],
+        };
+        if servers.is_empty() {
+            servers = match self.ice_servers.is_empty() {
+                true => vec![RTCIceServer {
+                    urls: vec![DEFAULT_STUN_SERVER.into()],
+                    ..Default::default()
+                }],
+                false => self.ice_servers.clone(),
+            };
+        }
+        servers.extend(
+            self.config
+                .turn_servers
+                .iter()
+                .map(TurnConfig::to_ice_server),
+        );
+        servers
+    }
+
+    /// the client's id, from `InitArgs::id`
+    pub fn id(&self) -> &PeerId {
+        &self.id
+    }
+
+    /// logs `sdp` if `ControllerConfig::log_sdp` is set. `direction` is "local" or "remote"
+    fn log_sdp(&self, peer_id: &PeerId, direction: &str, sdp: &RTCSessionDescription) {
+        if self.config.log_sdp {
+            log::debug!(
+                "{} {} for peer {}:\n{}",
+                direction,
+                sdp.sdp_type,
+                peer_id,
+                sdp.sdp
+            );
+        }
+    }
+
+    /// enforces `ControllerConfig::max_sdp_len`, then removes the lines which can't be parsed
+    /// if `ControllerConfig::sdp_parsing` is `Tolerant`
+    fn check_remote_sdp(&self, peer_id: &PeerId, sdp: &mut RTCSessionDescription) -> Result<()> {
+        if sdp.sdp.len() > self.config.max_sdp_len {
+            return Err(Error::SdpTooLarge(sdp.sdp.len()).into());
+        }
+        // logged as received, before the unparsable lines are removed
+        self.log_sdp(peer_id, "remote", sdp);
+        if self.config.sdp_parsing == SdpParsing::Tolerant {
+            let (tolerated, dropped) = internal::sdp::drop_unparsable_lines(&sdp.sdp);
+            for line in &dropped {
+                log::warn!("ignoring unparsable SDP line: {:?}", line);
+            }
+            if !dropped.is_empty() {
+                sdp.sdp = tolerated;
+            }
+        }
+        Ok(())
+    }
+
+    /// a copy of the configuration, as passed to `init` or `apply_config`
+    pub fn current_config(&self) -> ControllerConfig {
+        self.config.clone()
+    }
+
+    /// replaces the configuration without affecting the calls in progress, ex: to reload it.
+    /// the settings take effect:
+    /// - immediately: `auto_renegotiate`, `on_event_chan_closed`, `event_replay_len`,
+    ///   `max_sdp_len`, `log_sdp`, `max_remote_candidates` and the pacer's bitrate, which also
+    ///   changes the rate of the connections which are already paced
+    /// - for the connections created afterwards, by `dial` or `accept_call`: `certificate`,
+    ///   `ice_servers`, `turn_servers`, `rtcp_report_interval`, `default_interceptors`,
+    ///   `address_family`, `mid_generator`, `red`, and turning the pacer on or off
+    /// - for the media sources added afterwards: `cname`
+    ///
+    /// nothing is renegotiated. fails if `config` is invalid, leaving the configuration unchanged
+    pub fn apply_config(&mut self, config: ControllerConfig) -> Result<()> {
+        // webrtc-rs fixes the codecs, interceptors and network settings when the API is built.
+        // existing connections keep the API they were created with
+        self.api = create_api(
+            &config,
+            self.preferred_address.clone(),
+            self.send_counters.clone(),
+            self.pacer.clone(),
+            self.splicer.clone(),
+            self.recording_taps.clone(),
+        )?;
+        if let Some(pacer) = config.pacer {
+            self.pacer.set_bitrate(pacer.bitrate);
+        }
+        self.emitter
+            .set_options(config.on_event_chan_closed, config.event_replay_len);
+        self.config = config;
+        Ok(())
+    }
+
+    /// the CNAME of the media sources. see `ControllerConfig::cname`
+    pub fn cname(&self) -> &str {
+        self.config.cname.as_deref().unwrap_or(&self.id)
+    }
+
+    /// the id of the media source which `track` was returned for by `add_media_source`. None if
+    /// the source was removed, or the track belongs to another Controller
+    pub fn media_source_by_track(&self, track: &TrackLocalStaticRTP) -> Option<MediaSourceId> {
+        let (source_id, source) = self.media_sources.get_key_value(track.id())?;
+        if std::ptr::eq(source.as_ref(), track) {
+            Some(source_id.clone())
+        } else {
+            None
+        }
+    }
+
+    /// asks the peer to send a keyframe on the track with the given SSRC (`TrackRemote::ssrc`),
+    /// ex: after the video decoder lost a frame. `Pli` is understood by most endpoints; use `Fir`
+    /// for endpoints which require it
+    pub async fn request_keyframe(
+        &self,
+        peer_id: &PeerId,
+        media_ssrc: u32,
+        request: KeyframeRequest,
+    ) -> Result<()> {
+        let peer = match self.peers.get(peer_id) {
+            Some(p) => p,
+            None => bail!("peer not found"),
+        };
+        let packet: Box<dyn webrtc::rtcp::packet::Packet + Send + Sync> = match request {
+            KeyframeRequest::Pli => Box::new(PictureLossIndication {
+                sender_ssrc: 0,
+                media_ssrc,
+            }),
+            KeyframeRequest::Fir => Box::new(FullIntraRequest {
+                sender_ssrc: 0,
+                media_ssrc,
+                fir: vec![FirEntry {
+                    ssrc: media_ssrc,
+                    // the remote ignores a FIR whose sequence number it has already seen
+                    sequence_number: peer.fir_sequence_number.fetch_add(1, Ordering::Relaxed),
+                }],
+            }),
+        };
+        peer.connection.write_rtcp(&[packet]).await?;
+        Ok(())
+    }
+
+    /// the SSRC used to send the media source to the peer. webrtc-rs picks a random SSRC for
+    /// each connection and doesn't allow setting it, so the CNAME should be used to correlate
+    /// streams across reconnects
+    pub async fn ssrc(&self, peer_id: &PeerId, source_id: &MediaSourceId) -> Option<u32> {
+        let sender = self.peers.get(peer_id)?.rtp_senders.get(source_id)?;
+        let params = sender.get_parameters().await;
+        params.encodings.first().map(|e| e.ssrc)
+    }
+
+    /// returns the Opus parameters from the remote's current SDP, if Opus was negotiated
+    pub async fn remote_opus_params(&self, peer_id: &PeerId) -> Option<OpusParams> {
+        let peer = self.peers.get(peer_id)?;
+        let remote_sdp = peer.connection.remote_description().await?;
+        internal::sdp::opus_params(&remote_sdp.sdp)
+    }
+
+    /// sets the order of the media sections in the offers sent by `dial`, for gateways which
+    /// expect them in a specific order, ex: audio before video. the sources in `order` come
+    /// first, followed by the others in the order they were added. by default, sources are in
+    /// the order they were added. fails if a source doesn't exist or is listed twice.
+    /// webrtc-rs only allows limited control:
+    /// - only connections created afterwards are affected. once negotiated, media sections
+    ///   can't be reordered, and a source added during a call gets a new section at the end, or
+    ///   reuses the section of a removed source of the same kind
+    /// - when answering, the order and the mids are chosen by the offer
+    /// - the reserved data channel's section always comes after the media, and its mid is
+    ///   numbered by webrtc-rs
+    /// - the mids can only be chosen with `ControllerConfig::mid_generator`, which doesn't know
+    ///   which source a mid is for
+    pub fn set_media_source_order(&mut self, order: &[MediaSourceId]) -> Result<()> {
+        for (idx, source_id) in order.iter().enumerate() {
+            if !self.media_sources.contains_key(source_id) {
+                bail!("media source {} not found", source_id);
+            }
+            if order[..idx].contains(source_id) {
+                bail!("media source {} is listed twice", source_id);
+            }
+        }
+        let rest = self
+            .source_order
+            .iter()
+            .filter(|id| !order.contains(id))
+            .cloned();
+        self.source_order = order.iter().cloned().chain(rest).collect();
+        Ok(())
+    }
+
+    /// the RTP packets sent so far for the media source, for every peer. cheap enough to be
+    /// called for every frame, unlike the webrtc-rs stats behind `enable_stats_reporting`: it
+    /// only reads counters which are updated as packets are sent. cumulative since
+    /// `add_media_source`. None if there's no such source
+    pub fn source_counters(&self, source_id: &MediaSourceId) -> Option<SourceCounters> {
+        self.send_counters.get(source_id)
+    }
+
+    /// the probe which a sink for the peer's audio updates with the time frames spend being
+    /// received. pass it to `media::SinkOptions::latency_probe`. see `measured_latency`
+    pub fn latency_probe(&self, peer_id: &PeerId) -> Option<LatencyProbe> {
+        self.peers.get(peer_id).map(|p| p.latency_probe.clone())
+    }
+
+    /// the latency of the audio received from the peer, from capture on the remote to decoding
+    /// on this side. requires a sink with the peer's `latency_probe`. the time samples spend in
+    /// the output device's buffer isn't included.
+    /// if `PeerOptions::measure_capture_latency` is set and the remote sends capture timestamps
+    /// (an `OpusSource` does), they are compared with the local clock. otherwise the latency is
+    /// estimated as half the round trip time plus the time frames spend in the jitter buffer.
+    /// the round trip time comes from the RTCP reports if media is being sent to the peer, and
+    /// is measured with `ping` otherwise. None if neither works
+    pub async fn measured_latency(&self, peer_id: &PeerId) -> Option<Duration> {
+        let peer = self.peers.get(peer_id)?;
+        if let Some(latency) = peer.latency_probe.capture_to_decode() {
+            return Some(latency);
+        }
+        let rtt = match ConnectionStats::collect(&peer.connection).await.rtt {
+            Some(rtt) => rtt,
+            None => peer.control.ping(PING_TIMEOUT).await.ok()?,
+        };
+        Some(rtt / 2 + peer.latency_probe.buffered().unwrap_or_default())
+    }
+
+    /// the peer's network stats, ex: for a call quality indicator. the bitrates are averaged
+    /// since the previous call, or since the connection was created for the first call, so
+    /// this should be called at a regular interval. the loss, jitter and round trip time come
+    /// from the peer's RTCP reports, so they are only known once media is being sent to it. the
+    /// round trip time may stay None: see `measured_latency`, which falls back to `ping`.
+    /// see `enable_stats_reporting` to receive stats for every peer as events
+    pub async fn get_peer_stats(&self, peer_id: &PeerId) -> Result<PeerStats> {
+        let peer = match self.peers.get(peer_id) {
+            Some(p) => p,
+            None => bail!("peer {} not found", peer_id),
+        };
+        let stats = ConnectionStats::collect(&peer.connection).await;
+        let previous = match peer.stats_sample.lock() {
+            Ok(mut sample) => std::mem::replace(&mut *sample, stats.clone()),
+            Err(e) => bail!("failed to lock stats sample: {}", e),
+        };
+        Ok(PeerStats {
+            outbound_bitrate: stats.outbound_bitrate_since(&previous),
+            inbound_bitrate: stats.inbound_bitrate_since(&previous),
+            packets_lost: stats.packets_lost,
+            round_trip_time: stats.rtt,
+            jitter: peer.jitter.max(),
+        })
+    }
+
+    /// the bandwidth limit from the `b=AS` lines of the remote's current SDP, in bits per second.
+    /// a session-level limit is returned as is; otherwise the limits of the media sections are
+    /// added up. None if the remote didn't set a limit.
+    /// the crate doesn't encode video and webrtc-rs can't cap a sender's bitrate, so the
+    /// application should keep its encoders below the limit
+    pub async fn remote_bandwidth_limit(&self, peer_id: &PeerId) -> Option<u32> {
+        let peer = self.peers.get(peer_id)?;
+        let remote_sdp = peer.connection.remote_description().await?;
+        internal::sdp::bandwidth_limit(&remote_sdp.sdp)
+    }
+
+    /// caps the total bitrate of the media sources sent to the peer, in bits per second, and
+    /// emits how it is split between them with `EmittedEvents::BandwidthAllocated`. a peer which
+    /// receives audio, video and a screen share may not have the bandwidth for all of them at
+    /// full quality. the audio sources are served first, up to `MAX_AUDIO_BANDWIDTH` each. the
+    /// rest goes to the video sources, a camera getting twice the share of a screen share (see
+    /// `set_content_hint`). None removes the cap. the cap is dropped when the peer hangs up
+    pub fn set_total_bandwidth(&mut self, peer_id: &PeerId, bitrate: Option<u32>) -> Result<()> {
+        let peer = match self.peers.get_mut(peer_id) {
+            Some(p) => p,
+            None => bail!("peer not found"),
+        };
+        if bitrate == Some(0) {
+            bail!("the total bandwidth must be positive");
+        }
+        peer.total_bandwidth = bitrate;
+        self.emit_bandwidth_allocation(peer_id);
+        Ok(())
+    }
+
+    /// the split of the bandwidth set with `set_total_bandwidth`, in bits per second, by media
+    /// source. None if no bandwidth is set for the peer
+    pub fn bandwidth_allocation(&self, peer_id: &PeerId) -> Option<HashMap<MediaSourceId, u32>> {
+        let peer = self.peers.get(peer_id)?;
+        let total = peer.total_bandwidth?;
+        let sources: Vec<(MediaSourceId, StreamClass)> = peer
+            .rtp_senders
+            .keys()
+            .filter_map(|source_id| {
+                let track = self.media_sources.get(source_id)?;
+                let kind = MediaKind::from_mime_type(&track.codec().mime_type).ok()?;
+                let hint = self.content_hints.get(source_id).copied();
+                Some((source_id.clone(), StreamClass::new(kind, hint)))
+            })
+            .collect();
+        Some(internal::bandwidth::allocate(total, &sources))
+    }
+
+    fn emit_bandwidth_allocation(&self, peer_id: &PeerId) {
+        let allocation = match self.bandwidth_allocation(peer_id) {
+            Some(a) => a,
+            None => return,
+        };
+        if let Err(e) = self.emitter.send(EmittedEvents::BandwidthAllocated {
+            peer: peer_id.clone(),
+            allocation,
+        }) {
+            log::error!(
+                "failed to send bandwidth allocation for peer {}: {}",
+                peer_id,
+                e
+            );
+        }
+    }
+
+    /// emits the new split of the bandwidth of every peer it's set for, after the media sources
+    /// changed
+    fn reallocate_bandwidth(&self) {
+        for (peer_id, peer) in &self.peers {
+            if peer.total_bandwidth.is_some() {
+                self.emit_bandwidth_allocation(peer_id);
+            }
+        }
+    }
+
+    /// sets the order in which the codecs are listed in the offers and answers sent to the peer,
+    /// for each media section. the remote usually picks the first codec it supports, ex: G722
+    /// over Opus for a peer which is known to be a VoIP phone. the other codecs follow those in
+    /// `preferences`. can be called before `dial` or `accept_call`, and applies from the next
+    /// negotiation; nothing is renegotiated. the preferences are kept after the peer hangs up.
+    /// an empty list restores the default order
+    pub fn set_codec_preferences(&mut self, peer_id: &PeerId, preferences: Vec<MimeType>) {
+        let preferences = preferences.iter().map(MimeType::to_string).collect();
+        self.codec_preferences.insert(peer_id.clone(), preferences);
+    }
+
+    /// lists the codecs negotiated with the peer, for each transceiver.
+    /// returns (mid, direction, codec). a transceiver which both sends and receives may list
+    /// more than one codec
+    /// this is meant for debugging; it doesn't affect the connection
+    pub async fn negotiated_codecs(
+        &self,
+        peer_id: &PeerId,
+    ) -> Result<Vec<(String, RTCRtpTransceiverDirection, RTCRtpCodecParameters)>> {
+        let peer = match self.peers.get(peer_id) {
+            Some(p) => p,
+            None => bail!("peer not found"),
+        };
+
+        let mut negotiated = vec![];
+        for transceiver in peer.connection.get_transceivers().await {
+            let mid = transceiver.mid().await;
+            let direction = transceiver.current_direction();
+            let mut codecs: Vec<RTCRtpCodecParameters> = vec![];
+
+            // the sender's parameters are filtered down to the codecs the remote accepted
+            if direction.has_send() {
+                if let Some(sender) = transceiver.sender().await {
+                    codecs.extend(sender.get_parameters().await.rtp_parameters.codecs);
+                }
+            }
+            // the remote track knows which codec is actually being received
+            if direction.has_recv() {
+                if let Some(receiver) = transceiver.receiver().await {
+                    for track in receiver.tracks().await {
+                        let codec = track.codec().await;
+                        if !codecs.iter().any(|c| c.payload_type == codec.payload_type) {
+                            codecs.push(codec);
+                        }
+                    }
+                }
+            }
+
+            for codec in codecs {
+                negotiated.push((mid.clone(), direction, codec));
+            }
+        }
+
+        Ok(negotiated)
+    }
+
+    /// records the audio and video the peer sends to a WebM file at `path`, until
+    /// `stop_record_peer` or `hang_up`. the tracks with a VP8, VP9 or Opus codec are recorded;
+    /// WebM can't hold H264. the application must keep reading the tracks, ex: to play them, as
+    /// the packets are copied as they're read. the tracks are synchronized by the remote's sender
+    /// reports. a video track starts at its next key frame, which is requested. tracks which
+    /// start later than `record_peer`, or which the remote adds afterwards, aren't recorded
+    pub async fn record_peer(&mut self, peer_id: &PeerId, path: &Path) -> Result<()> {
+        if self.recordings.contains_key(peer_id) {
+            bail!("peer {} is already being recorded", peer_id);
+        }
+        let peer = match self.peers.get(peer_id) {
+            Some(p) => p,
+            None => bail!("peer not found"),
+        };
+
+        let mut tracks = vec![];
+        for transceiver in peer.connection.get_transceivers().await {
+            if !transceiver.current_direction().has_recv() {
+                continue;
+            }
+            let receiver = match transceiver.receiver().await {
+                Some(r) => r,
+                None => continue,
+            };
+            for track in receiver.tracks().await {
+                // the codec is set from the payload type of the first packet
+                let codec = track.codec().await.capability;
+                if codec.mime_type.is_empty() {
+                    continue;
+                }
+                let recorded_codec = if codec.mime_type.eq_ignore_ascii_case(MIME_TYPE_VP8) {
+                    RecordedCodec::Vp8
+                } else if codec.mime_type.eq_ignore_ascii_case(MIME_TYPE_VP9) {
+                    RecordedCodec::Vp9
+                } else if codec.mime_type.eq_ignore_ascii_case(MIME_TYPE_OPUS) {
+                    RecordedCodec::Opus
+                } else {
+                    log::warn!(
+                        "can't record track {} of peer {}: {} isn't supported",
+                        track.ssrc(),
+                        peer_id,
+                        codec.mime_type
+                    );
+                    continue;
+                };
+                tracks.push(RecordedTrack {
+                    ssrc: track.ssrc(),
+                    codec: recorded_codec,
+                    clock_rate: codec.clock_rate,
+                    channels: codec.channels,
+                    receiver: receiver.clone(),
+                });
+            }
+        }
+        if tracks.is_empty() {
+            bail!("peer {} has no track which can be recorded", peer_id);
+        }
+
+        let recording = Recording::start(path, tracks.clone(), &self.recording_taps)?;
+        self.recordings.insert(peer_id.clone(), recording);
+        for track in tracks.iter().filter(|t| t.codec != RecordedCodec::Opus) {
+            if let Err(e) = self
+                .request_keyframe(peer_id, track.ssrc, KeyframeRequest::Pli)
+                .await
+            {
+                log::warn!("failed to request a key frame to record: {}", e);
+            }
+        }
+        Ok(())
+    }
+
+    /// stops recording the peer and finishes the file. fails if nothing could be recorded, ex:
+    /// no frame was received, or if writing the file failed
+    pub async fn stop_record_peer(&mut self, peer_id: &PeerId) -> Result<()> {
+        match self.recordings.remove(peer_id) {
+            Some(recording) => recording.stop().await,
+            None => bail!("peer {} isn't being recorded", peer_id),
+        }
+    }
+
+    /// lists the tracks the peer sends, from its transceivers, ex: to catch up after missing
+    /// `EmittedEvents::TrackAdded`. tracks the remote removed aren't listed
+    pub async fn remote_tracks(&self, peer_id: &PeerId) -> Result<Vec<RemoteTrackInfo>> {
+        let peer = match self.peers.get(peer_id) {
+            Some(p) => p,
+            None => bail!("peer not found"),
+        };
+
+        let mut tracks = vec![];
+        for transceiver in peer.connection.get_transceivers().await {
+            let mid = transceiver.mid().await;
+            // not negotiated yet
+            if mid.is_empty() {
+                continue;
+            }
+            let receiver = match transceiver.receiver().await {
+                Some(r) => r,
+                None => continue,
+            };
+            let kind = match transceiver.kind() {
+                RTPCodecType::Audio => MediaKind::Audio,
+                RTPCodecType::Video => MediaKind::Video,
+                _ => continue,
+            };
+            let active = transceiver.current_direction().has_recv();
+            // webrtc-rs creates the receiver's tracks once the remote describes them
+            for track in receiver.tracks().await {
+                // the codec is set from the payload type of the first packet
+                let codec = Some(track.codec().await.capability)
+                    .filter(|codec| !codec.mime_type.is_empty());
+                tracks.push(RemoteTrackInfo {
+                    mid: mid.clone(),
+                    kind,
+                    track_id: track.id().await,
+                    stream_id: track.stream_id().await,
+                    ssrc: track.ssrc(),
+                    codec,
+                    active,
+                    track,
+                });
+            }
+        }
+        Ok(tracks)
+    }
+
+    /// adds a connection. called by dial and accept_call
+    /// inserts the connection into self.peers
+    /// initializes state to WaitingForSdp
+    async fn connect(
+        &mut self,
+        peer_id: &PeerId,
+        options: PeerOptions,
+    ) -> Result<Arc<RTCPeerConnection>> {
+        if self.emitter.is_shut_down() {
+            bail!("the controller shut down because the event receiver was dropped");
+        }
+        if matches!(options.liveness_check, Some(check) if check.interval.is_zero()) {
+            bail!("liveness check interval must not be zero");
+        }
+        // a reconnect redials the peer, which stays Reconnecting until the new connection
+        // connects
+        let initial_state = match self.peers.get(peer_id) {
+            Some(p) if p.state_tracker.get() == PeerState::Reconnecting => PeerState::Reconnecting,
+            _ => PeerState::WaitingForSdp,
+        };
+        // ex: a call which was declined is still being torn down when the next one arrives.
+        // the old connection is closed, rather than leaked, before it's replaced
+        if self.peers.contains_key(peer_id) {
+            log::warn!("replacing the existing connection to peer {}", peer_id);
+            self.close_peer(peer_id).await;
+        }
+
+        // create ICE gatherer
+        let config = RTCConfiguration {
+            ice_servers: self.ice_servers().await,
+            // if empty, webrtc-rs generates a new certificate for each connection
+            certificates: self.config.certificate.iter().cloned().collect(),
+            ..Default::default()
+        };
+
+        // Create and store a new RTCPeerConnection
+        let peer_connection = Arc::new(self.api.new_peer_connection(config).await?);
+        // must be created before the SDP, so that the SDP includes the data channel
+        let control = ControlChannel::new(
+            &peer_connection,
+            peer_id,
+            self.emitter.clone(),
+            self.roster.clone(),
+        )
+        .await?;
+        self.emitter.register(peer_id, &peer_connection);
+        let track_forward = Arc::new(Mutex::new(None));
+        let jitter = JitterReports::default();
+        match self.peer_jitter.lock() {
+            Ok(mut peer_jitter) => {
+                peer_jitter.insert(peer_id.clone(), jitter.clone());
+            }
+            Err(e) => log::error!("failed to lock peer jitter: {}", e),
+        }
+        let state = PeerStateTracker::new(peer_id, initial_state, self.emitter.clone());
+        let liveness_task = options
+            .liveness_check
+            .map(|check| control.spawn_liveness_check(check));
+        let peer = Peer {
+            state_tracker: state.clone(),
+            id: peer_id.clone(),
+            connection: peer_connection.clone(),
+            options: options.clone(),
+            rtp_senders: HashMap::new(),
+            rtcp_readers: Mutex::new(HashMap::new()),
+            negotiated_sources: Mutex::new(HashSet::new()),
+            control,
+            track_forward: track_forward.clone(),
+            queued_offer: Mutex::new(None),
+            remote_candidates: AtomicUsize::new(0),
+            liveness_task,
+            fir_sequence_number: AtomicU8::new(0),
+            latency_probe: LatencyProbe::new(options.measure_capture_latency),
+            on_hold: false,
+            hold_music: None,
+            total_bandwidth: None,
+            stats_sample: Mutex::new(ConnectionStats::start()),
+            jitter: jitter.clone(),
+        };
+        // an existing connection was closed and removed above
+        let replaced = self.peers.insert(peer_id.clone(), peer);
+        debug_assert!(replaced.is_none());
+        // sent before the callbacks are registered, so that it precedes every other event of
+        // this connection
+        if let Err(e) = self.emitter.send(EmittedEvents::PeerAdded {
+            peer: peer_id.clone(),
+        }) {
+            log::error!(
+                "failed to send peer added event for peer {}: {}",
+                peer_id,
+                e
+            );
+        }
+
+        // configure callbacks
 
         // send discovered ice candidates (for self) to remote peer
         // the next 2 lines is some nonsense to satisfy the (otherwise excellent) rust compiler
-        let tx = self.emitted_event_chan.clone();
+        let tx = self.emitter.clone();
         let dest = peer_id.clone();
+        // counts the candidates found during the current round of gathering
+        let num_candidates = Arc::new(AtomicUsize::new(0));
+        let trickle = options.trickle;
+        let batch_window = options.ice_batch_window;
+        // candidates waiting to be emitted as an IceBatch
+        let batch: Arc<Mutex<Vec<RTCIceCandidate>>> = Arc::new(Mutex::new(vec![]));
         peer_connection.on_ice_candidate(Box::new(move |c: Option<RTCIceCandidate>| {
             let tx = tx.clone();
             let dest = dest.clone();
+            let num_candidates = num_candidates.clone();
+            let batch = batch.clone();
             Box::pin(async move {
-                if let Some(candidate) = c {
-                    if let Err(e) = tx.send(EmittedEvents::Ice {
-                        dest: dest.clone(),
-                        candidate: Box::new(candidate),
-                    }) {
-                        log::error!("failed to send ice candidate to peer {}: {}", &dest, e);
+                match c {
+                    Some(candidate) => {
+                        num_candidates.fetch_add(1, Ordering::Relaxed);
+                        // without trickle ICE, the candidates are sent in the SDP
+                        if !trickle {
+                            return;
+                        }
+                        let window = match batch_window {
+                            Some(w) => w,
+                            None => {
+                                if let Err(e) = tx.send(EmittedEvents::Ice {
+                                    dest: dest.clone(),
+                                    candidate: Box::new(candidate),
+                                }) {
+                                    log::error!(
+                                        "failed to send ice candidate to peer {}: {}",
+                                        &dest,
+                                        e
+                                    );
+                                }
+                                return;
+                            }
+                        };
+                        // the first candidate of a batch starts the timer
+                        let is_first = match batch.lock() {
+                            Ok(mut b) => {
+                                b.push(candidate);
+                                b.len() == 1
+                            }
+                            Err(e) => {
+                                log::error!("failed to lock ice batch: {}", e);
+                                return;
+                            }
+                        };
+                        if is_first {
+                            tokio::spawn(async move {
+                                tokio::time::sleep(window).await;
+                                send_ice_batch(&tx, &dest, &batch);
+                            });
+                        }
+                    }
+                    // gathering is complete. reset the count in case ICE is restarted
+                    None => {
+                        // don't wait for the timer. an empty batch isn't sent
+                        send_ice_batch(&tx, &dest, &batch);
+                        if num_candidates.swap(0, Ordering::Relaxed) == 0 {
+                            log::warn!("no ice candidates were gathered for peer {}", &dest);
+                            if let Err(e) =
+                                tx.send(EmittedEvents::NoCandidates { peer: dest.clone() })
+                            {
+                                log::error!(
+                                    "failed to send no candidates event for peer {}: {}",
+                                    &dest,
+                                    e
+                                );
+                            }
+                        }
                     }
                 }
             })
@@ -358,8 +2494,10 @@ impl Controller {
         // Set the handler for ICE connection state
         // This will notify you when the peer has connected/disconnected
         // the next 2 lines is some nonsense to satisfy the (otherwise excellent) rust compiler
-        let tx = self.emitted_event_chan.clone();
+        let tx = self.emitter.clone();
         let dest = peer_id.clone();
+        let reconnect_tx = self.reconnect_tx.clone();
+        let ice_peer_state = state.clone();
         peer_connection.on_ice_connection_state_change(Box::new(
             move |connection_state: RTCIceConnectionState| {
                 let tx = tx.clone();
@@ -369,24 +2507,129 @@ impl Controller {
                     &dest,
                     connection_state
                 );
+                if let Err(e) = tx.send(EmittedEvents::ConnectionStateChanged {
+                    peer: dest.clone(),
+                    state: connection_state,
+                }) {
+                    log::error!("failed to send connection state for peer {}: {}", &dest, e);
+                }
+                ice_peer_state.on_ice_state(connection_state);
+                if connection_state == RTCIceConnectionState::Connected {
+                    if let Err(e) = tx.send(EmittedEvents::Connected { peer: dest.clone() }) {
+                        log::error!("failed to send connected event for peer {}: {}", &dest, e);
+                    }
+                }
                 if connection_state == RTCIceConnectionState::Failed {
-                    if let Err(e) = tx.send(EmittedEvents::Disconnected { peer: dest.clone() }) {
-                        log::error!("failed to send disconnect event for peer {}: {}", &dest, e);
+                    // with a ReconnectPolicy, the peer is Failed and Disconnected is emitted once
+                    // reconnecting fails
+                    let reconnecting = match reconnect_tx.lock() {
+                        Ok(reconnect_tx) => match reconnect_tx.as_ref() {
+                            Some(reconnect_tx) => {
+                                // before the reconnect starts, so that it precedes its events
+                                ice_peer_state.set(PeerState::Reconnecting);
+                                reconnect_tx.send(dest.clone()).is_ok()
+                            }
+                            None => false,
+                        },
+                        Err(e) => {
+                            log::error!("failed to lock reconnect_tx: {}", e);
+                            false
+                        }
+                    };
+                    if !reconnecting {
+                        ice_peer_state.set(PeerState::Failed);
+                        if let Err(e) = tx.send(EmittedEvents::Disconnected { peer: dest.clone() })
+                        {
+                            log::error!(
+                                "failed to send disconnect event for peer {}: {}",
+                                &dest,
+                                e
+                            );
+                        }
                     }
                 }
                 Box::pin(async {})
             },
         ));
 
+        let signaling_peer_state = state.clone();
+        peer_connection.on_signaling_state_change(Box::new(
+            move |signaling_state: RTCSignalingState| {
+                signaling_peer_state.on_signaling_state(signaling_state);
+                Box::pin(async {})
+            },
+        ));
+
+        // a failed handshake leaves ICE connected, so it's reported separately
+        let tx = self.emitter.clone();
+        let dest = peer_id.clone();
+        let weak_pc = Arc::downgrade(&peer_connection);
+        peer_connection.sctp().transport().on_state_change(Box::new(
+            move |state: RTCDtlsTransportState| {
+                if state != RTCDtlsTransportState::Failed {
+                    return Box::pin(async {});
+                }
+                let tx = tx.clone();
+                let dest = dest.clone();
+                let weak_pc = weak_pc.clone();
+                Box::pin(async move {
+                    let reason = match weak_pc.upgrade() {
+                        Some(pc) => dtls_failure(&pc).await,
+                        None => DtlsFailure::Handshake,
+                    };
+                    log::warn!("DTLS handshake with peer {} failed: {:?}", &dest, reason);
+                    if let Err(e) = tx.send(EmittedEvents::DtlsFailed {
+                        peer: dest.clone(),
+                        reason,
+                    }) {
+                        log::error!("failed to send DTLS failed event for peer {}: {}", &dest, e);
+                    }
+                })
+            },
+        ));
+
+        let tx = self.emitter.clone();
+        let dest = peer_id.clone();
+        peer_connection.on_ice_gathering_state_change(Box::new(
+            move |state: RTCIceGathererState| {
+                log::debug!("ICE gathering state for peer {} is {}", &dest, state);
+                if let Err(e) = tx.send(EmittedEvents::IceGatheringState {
+                    peer: dest.clone(),
+                    state,
+                }) {
+                    log::error!(
+                        "failed to send gathering state event for peer {}: {}",
+                        &dest,
+                        e
+                    );
+                }
+                Box::pin(async {})
+            },
+        ));
+
         // store media tracks when created
         // the next 2 lines is some nonsense to satisfy the (otherwise excellent) rust compiler
-        let tx = self.emitted_event_chan.clone();
+        let tx = self.emitter.clone();
         let dest = peer_id.clone();
         peer_connection.on_track(Box::new(
             move |track: Option<Arc<TrackRemote>>, _receiver: Option<Arc<RTCRtpReceiver>>| {
                 let tx = tx.clone();
                 let dest = dest.clone();
                 if let Some(track) = track {
+                    match track_forward.lock() {
+                        Ok(forward) => {
+                            if let Some(forward) = forward.as_ref() {
+                                if let Err(e) = forward.send(track.clone()) {
+                                    log::error!(
+                                        "failed to forward track for peer {}: {}",
+                                        &dest,
+                                        e
+                                    );
+                                }
+                            }
+                        }
+                        Err(e) => log::error!("failed to lock track forward: {}", e),
+                    }
                     if let Err(e) = tx.send(EmittedEvents::TrackAdded {
                         peer: dest.clone(),
                         track,
@@ -400,18 +2643,20 @@ impl Controller {
 
         // attach all media sources to the peer
         let mut rtp_senders = HashMap::new();
-        for (source_id, track) in &self.media_sources {
+        let mut rtcp_readers = HashMap::new();
+        // the media sections are created in this order
+        for source_id in &self.source_order {
+            let track = match self.media_sources.get(source_id) {
+                Some(t) => t,
+                None => continue,
+            };
             match peer_connection.add_track(track.clone()).await {
                 Ok(rtp_sender) => {
+                    add_sender_counters(&self.send_counters, source_id, &rtp_sender).await;
                     rtp_senders.insert(source_id.clone(), rtp_sender.clone());
-                    // Read incoming RTCP packets
-                    // Before these packets are returned they are processed by interceptors. For things
-                    // like NACK this needs to be called.
-                    tokio::spawn(async move {
-                        let mut rtcp_buf = vec![0u8; 1500];
-                        while let Ok((_, _)) = rtp_sender.read(&mut rtcp_buf).await {}
-                        Result::<()>::Ok(())
-                    });
+                    let reader =
+                        spawn_rtcp_reader(rtp_sender, &self.emitter, peer_id, source_id, &jitter);
+                    rtcp_readers.insert(source_id.clone(), reader);
                 }
                 Err(e) => {
                     log::error!(
@@ -424,7 +2669,10 @@ impl Controller {
             }
         }
         match self.peers.get_mut(peer_id) {
-            Some(p) => p.rtp_senders = rtp_senders,
+            Some(p) => {
+                p.rtp_senders = rtp_senders;
+                p.rtcp_readers = Mutex::new(rtcp_readers);
+            }
             None => {
                 log::error!(
                     "failed to set rtp senders when connecting to peer {}",
@@ -436,23 +2684,410 @@ impl Controller {
     }
 }
 
+/// sets the local description. with trickle ICE, the SDP is returned unchanged and the candidates
+/// are emitted separately. otherwise, waits for ICE gathering to complete and returns the local
+/// description, which then contains all of the candidates.
+async fn set_local_description(
+    pc: &RTCPeerConnection,
+    sdp: RTCSessionDescription,
+    trickle: bool,
+) -> Result<RTCSessionDescription> {
+    if trickle {
+        pc.set_local_description(sdp.clone()).await?;
+        return Ok(sdp);
+    }
+
+    // must be created before gathering starts, or the event may be missed
+    let mut gathering_complete = pc.gathering_complete_promise().await;
+    pc.set_local_description(sdp).await?;
+    let _ = gathering_complete.recv().await;
+    match pc.local_description().await {
+        Some(sdp) => Ok(sdp),
+        None => bail!("local description not set"),
+    }
+}
+
+// how long `wait_for_ice_start` waits, and how often it checks
+const ICE_START_TIMEOUT: Duration = Duration::from_secs(5);
+const ICE_START_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// waits until ICE leaves the New state. webrtc-rs starts ICE asynchronously after the first
+/// answer is applied, and until then it treats every remote offer as an ICE restart, which fails
+/// while candidates are being gathered. ex: the offer sent by `offer_missing_sources` right after
+/// answering can arrive before the caller's ICE started
+async fn wait_for_ice_start(pc: &RTCPeerConnection) {
+    let started = async {
+        while pc.ice_connection_state() == RTCIceConnectionState::New {
+            tokio::time::sleep(ICE_START_POLL_INTERVAL).await;
+        }
+    };
+    if tokio::time::timeout(ICE_START_TIMEOUT, started)
+        .await
+        .is_err()
+    {
+        log::warn!("ICE didn't start before applying a remote offer");
+    }
+}
+
+/// works out why the DTLS handshake failed. webrtc-rs only reports that it did
+async fn dtls_failure(pc: &RTCPeerConnection) -> DtlsFailure {
+    // the remote's certificate is saved right before its fingerprint is checked
+    if !pc
+        .sctp()
+        .transport()
+        .get_remote_certificate()
+        .await
+        .is_empty()
+    {
+        return DtlsFailure::FingerprintMismatch;
+    }
+    let local = pc.local_description().await;
+    let remote = pc.remote_description().await;
+    let local_setup = local
+        .as_ref()
+        .and_then(|d| internal::sdp::dtls_setup(&d.sdp));
+    let remote_setup = remote
+        .as_ref()
+        .and_then(|d| internal::sdp::dtls_setup(&d.sdp));
+    match (local_setup, remote_setup) {
+        (Some(l), Some(r)) if l == r => DtlsFailure::RoleConflict,
+        _ => DtlsFailure::Handshake,
+    }
+}
+
+/// stops receiving the media kinds which `options` doesn't allow. must be called before creating
+/// an offer or answer, which then tells the remote not to send them. must be called again after
+/// a remote offer, which may add transceivers
+async fn restrict_receiving(pc: &RTCPeerConnection, options: &PeerOptions) {
+    for transceiver in pc.get_transceivers().await {
+        let allowed = match transceiver.kind() {
+            RTPCodecType::Audio => options.receive_audio,
+            RTPCodecType::Video => options.receive_video,
+            RTPCodecType::Unspecified => true,
+        };
+        if allowed {
+            continue;
+        }
+        let direction = match transceiver.direction() {
+            RTCRtpTransceiverDirection::Sendrecv => RTCRtpTransceiverDirection::Sendonly,
+            RTCRtpTransceiverDirection::Recvonly => RTCRtpTransceiverDirection::Inactive,
+            _ => continue,
+        };
+        transceiver.set_direction(direction).await;
+    }
+}
+
+/// lists the codecs of each transceiver in the order of `preferences`, a list of mime types,
+/// followed by the other codecs in their usual order. see `Controller::set_codec_preferences`
+async fn order_codecs(pc: &RTCPeerConnection, preferences: Option<&Vec<String>>) {
+    let preferences = match preferences {
+        Some(p) => p,
+        None => return,
+    };
+    for transceiver in pc.get_transceivers().await {
+        let codecs = match preferences.is_empty() {
+            // restores the MediaEngine's order
+            true => vec![],
+            false => {
+                let mut codecs = match transceiver.receiver().await {
+                    Some(receiver) => receiver.get_parameters().await.codecs,
+                    None => continue,
+                };
+                let rank = |codec: &RTCRtpCodecParameters| {
+                    preferences
+                        .iter()
+                        .position(|m| m.eq_ignore_ascii_case(&codec.capability.mime_type))
+                        .unwrap_or(preferences.len())
+                };
+                // stable, so the codecs with the same mime type keep their order
+                codecs.sort_by_key(rank);
+                codecs
+            }
+        };
+        if let Err(e) = transceiver.set_codec_preferences(codecs).await {
+            log::error!("failed to set codec preferences: {}", e);
+        }
+    }
+}
+
+/// counts the packets the sender sends for the media source. see `Controller::source_counters`
+async fn add_sender_counters(
+    counters: &SendCounters,
+    source_id: &MediaSourceId,
+    rtp_sender: &RTCRtpSender,
+) {
+    // webrtc-rs creates a single encoding per sender
+    if let Some(encoding) = rtp_sender.get_parameters().await.encodings.first() {
+        counters.add_sender(source_id, encoding.ssrc);
+    }
+}
+
+/// the SSRC of the sender and the clock rate of its negotiated codec
+async fn sender_clock_rate(rtp_sender: &RTCRtpSender) -> Option<(u32, u32)> {
+    let params = rtp_sender.get_parameters().await;
+    // webrtc-rs creates a single encoding per sender
+    let encoding = params.encodings.first()?;
+    let codecs = &params.rtp_parameters.codecs;
+    let codec = codecs
+        .iter()
+        .find(|c| c.payload_type == encoding.payload_type)
+        .or_else(|| codecs.first())?;
+    match codec.capability.clock_rate {
+        0 => None,
+        clock_rate => Some((encoding.ssrc, clock_rate)),
+    }
+}
+
+/// aborts the task and waits for it to end, so that it no longer holds on to the Controller's
+/// state
+async fn stop_task(task: JoinHandle<()>) {
+    task.abort();
+    if let Err(e) = task.await {
+        if !e.is_cancelled() {
+            log::error!("task failed: {}", e);
+        }
+    }
+}
+
+/// replaces the sender's track. None stops sending without renegotiating
+async fn set_sender_track(
+    splicer: &StreamSplicer,
+    rtp_sender: &RTCRtpSender,
+    track: Option<&Arc<TrackLocalStaticRTP>>,
+) -> Result<()> {
+    if track.is_some() {
+        // webrtc-rs creates a single encoding per sender
+        if let Some(encoding) = rtp_sender.get_parameters().await.encodings.first() {
+            splicer.splice(encoding.ssrc);
+        }
+    }
+    let track = track.map(|t| t.clone() as Arc<dyn TrackLocal + Send + Sync>);
+    rtp_sender.replace_track(track).await?;
+    Ok(())
+}
+
+/// reads the RTCP packets sent by the remote for a media source. the interceptors need them to
+/// be read. keyframe requests are emitted as `EmittedEvents::KeyframeRequested`
+fn spawn_rtcp_reader(
+    rtp_sender: Arc<RTCRtpSender>,
+    emitter: &EventEmitter,
+    peer_id: &PeerId,
+    source_id: &MediaSourceId,
+    jitter: &JitterReports,
+) -> JoinHandle<()> {
+    let emitter = emitter.clone();
+    let peer_id = peer_id.clone();
+    let source_id = source_id.clone();
+    let jitter = jitter.clone();
+    tokio::spawn(async move {
+        // the SSRC and clock rate of the sender. looked up when the first report is received,
+        // since the codec isn't known before the negotiation
+        let mut stream: Option<(u32, u32)> = None;
+        while let Ok((packets, _)) = rtp_sender.read_rtcp().await {
+            for packet in packets {
+                let packet = packet.as_any();
+                let reports: Option<&[ReceptionReport]> =
+                    if let Some(rr) = packet.downcast_ref::<receiver_report::ReceiverReport>() {
+                        Some(&rr.reports)
+                    } else {
+                        packet
+                            .downcast_ref::<sender_report::SenderReport>()
+                            .map(|sr| sr.reports.as_slice())
+                    };
+                if let Some(reports) = reports {
+                    if stream.is_none() {
+                        stream = sender_clock_rate(&rtp_sender).await;
+                    }
+                    if let Some((ssrc, clock_rate)) = stream {
+                        // the jitter is in units of the RTP timestamp
+                        let report = reports.iter().find(|r| r.ssrc == ssrc);
+                        if let Some(r) = report {
+                            jitter.record(
+                                &source_id,
+                                Duration::from_secs_f64(r.jitter as f64 / clock_rate as f64),
+                            );
+                        }
+                    }
+                    continue;
+                }
+
+                let request = if packet.is::<PictureLossIndication>() {
+                    KeyframeRequest::Pli
+                } else if packet.is::<FullIntraRequest>() {
+                    KeyframeRequest::Fir
+                } else {
+                    continue;
+                };
+                if let Err(e) = emitter.send(EmittedEvents::KeyframeRequested {
+                    peer: peer_id.clone(),
+                    source: source_id.clone(),
+                    request,
+                }) {
+                    log::error!("failed to send keyframe request event: {}", e);
+                }
+            }
+        }
+    })
+}
+
+/// emits the batched ICE candidates, if there are any
+fn send_ice_batch(tx: &EventEmitter, dest: &PeerId, batch: &Mutex<Vec<RTCIceCandidate>>) {
+    let candidates = match batch.lock() {
+        Ok(mut b) => std::mem::take(&mut *b),
+        Err(e) => {
+            log::error!("failed to lock ice batch: {}", e);
+            return;
+        }
+    };
+    if candidates.is_empty() {
+        return;
+    }
+    if let Err(e) = tx.send(EmittedEvents::IceBatch {
+        dest: dest.clone(),
+        candidates,
+    }) {
+        log::error!("failed to send ice candidates to peer {}: {}", dest, e);
+    }
+}
+
+/// the mime types of the codecs registered by `MediaEngine::register_default_codecs`, which
+/// `create_api` calls. webrtc-rs doesn't expose the MediaEngine's codecs, so a test checks that
+/// these are the ones negotiated
+const DEFAULT_CODECS: [&str; 7] = [
+    MIME_TYPE_OPUS,
+    MIME_TYPE_G722,
+    MIME_TYPE_PCMU,
+    MIME_TYPE_PCMA,
+    MIME_TYPE_VP8,
+    MIME_TYPE_VP9,
+    MIME_TYPE_H264,
+];
+
+/// the mime types `create_api` registers with the MediaEngine
+fn registered_codecs(config: &ControllerConfig) -> Vec<&'static str> {
+    let mut registered = DEFAULT_CODECS.to_vec();
+    if config.red {
+        registered.push(MIME_TYPE_RED);
+    }
+    registered
+}
+
+/// true if `create_api` registers the codec's mime type with the MediaEngine
+fn is_codec_registered(config: &ControllerConfig, codec: &RTCRtpCodecCapability) -> bool {
+    // webrtc-rs compares mime types case insensitively
+    registered_codecs(config)
+        .iter()
+        .any(|m| m.eq_ignore_ascii_case(&codec.mime_type))
+}
+
 // todo: add support for more codecs. perhaps make it configurable
-fn create_api() -> Result<webrtc::api::API> {
+fn create_api(
+    config: &ControllerConfig,
+    preferred_address: Arc<Mutex<Option<IpAddr>>>,
+    send_counters: SendCounters,
+    pacer: Pacer,
+    splicer: StreamSplicer,
+    recording_taps: RecordingTaps,
+) -> Result<webrtc::api::API> {
     let mut media = MediaEngine::default();
     media.register_default_codecs()?;
+    if config.red {
+        media.register_codec(
+            RTCRtpCodecParameters {
+                capability: red_codec(),
+                payload_type: RED_PAYLOAD_TYPE,
+                stats_id: String::new(),
+            },
+            RTPCodecType::Audio,
+        )?;
+    }
+    for typ in [RTPCodecType::Audio, RTPCodecType::Video] {
+        media.register_header_extension(
+            RTCRtpHeaderExtensionCapability {
+                uri: ABS_CAPTURE_TIME_URI.into(),
+            },
+            typ,
+            None,
+        )?;
+    }
 
     // Create a InterceptorRegistry. This is the user configurable RTP/RTCP Pipeline.
     // This provides NACKs, RTCP Reports and other features. If you use `webrtc.NewPeerConnection`
     // this is enabled by default. If you are manually managing You MUST create a InterceptorRegistry
     // for each PeerConnection.
     let mut registry = Registry::new();
+    // first, so that it counts the packets the other interceptors send. see `source_counters`
+    registry.add(Box::new(send_counters));
+    // after the counters, so that a packet is counted once it's sent rather than when it's
+    // written. before the other interceptors, so that retransmissions are paced too
+    if let Some(pacer_config) = config.pacer {
+        if pacer_config.bitrate == 0 {
+            bail!("the pacer's bitrate must be positive");
+        }
+        registry.add(Box::new(pacer));
+    }
+
+    for turn in &config.turn_servers {
+        turn.validate()?;
+    }
+    if let Some(ptime) = config.ptime {
+        if !PTIME_RANGE.contains(&ptime) {
+            bail!(
+                "ptime must be between {:?} and {:?}",
+                PTIME_RANGE.start(),
+                PTIME_RANGE.end()
+            );
+        }
+    }
+
+    match (config.default_interceptors, config.rtcp_report_interval) {
+        // a relay only has the interceptors of this crate, which pass the packets through
+        (false, None) => {}
+        (false, Some(_)) => bail!("rtcp report interval requires the default interceptors"),
+        // Use the default set of Interceptors
+        (true, None) => registry = register_default_interceptors(registry, &mut media)?,
+        // same as register_default_interceptors, but with custom RTCP reports
+        (true, Some(interval)) => {
+            if !RTCP_REPORT_INTERVAL_RANGE.contains(&interval) {
+                bail!(
+                    "rtcp report interval must be between {:?} and {:?}",
+                    RTCP_REPORT_INTERVAL_RANGE.start(),
+                    RTCP_REPORT_INTERVAL_RANGE.end()
+                );
+            }
+            registry = configure_nack(registry, &mut media);
+            registry.add(Box::new(ReceiverReport::builder().with_interval(interval)));
+            registry.add(Box::new(SenderReport::builder().with_interval(interval)));
+            registry = configure_twcc_receiver_only(registry, &mut media)?;
+        }
+    }
+    // sees the packets once the NACK responder reordered them
+    registry.add(Box::new(recording_taps));
+    // last, so that the NACK responder and the sender reports see the rewritten packets
+    registry.add(Box::new(splicer));
 
-    // Use the default set of Interceptors
-    registry = register_default_interceptors(registry, &mut media)?;
+    let mut settings = SettingEngine::default();
+    match config.address_family {
+        AddressFamily::Any => {}
+        AddressFamily::Ipv4Only => settings.set_network_types(vec![NetworkType::Udp4]),
+        AddressFamily::Ipv6Only => settings.set_network_types(vec![NetworkType::Udp6]),
+    }
+    settings.set_ip_filter(Box::new(move |ip| match preferred_address.lock() {
+        Ok(preferred) => match *preferred {
+            Some(p) => p == ip,
+            None => true,
+        },
+        Err(_) => true,
+    }));
+
+    if let Some(mid_generator) = config.mid_generator.clone() {
+        settings.set_mid_generator(move |greatest| mid_generator(greatest));
+    }
 
     // Create the API object with the MediaEngine
     Ok(APIBuilder::new()
         .with_media_engine(media)
         .with_interceptor_registry(registry)
+        .with_setting_engine(settings)
         .build())
 }
@@ -0,0 +1,226 @@
+use anyhow::{bail, Result};
+use cpal::traits::{DeviceTrait, HostTrait};
+use std::sync::Arc;
+use tokio::sync::{mpsc, oneshot};
+use webrtc::{
+    rtp_transceiver::rtp_codec::RTCRtpCodecCapability,
+    track::{track_local::track_local_static_rtp::TrackLocalStaticRTP, track_remote::TrackRemote},
+};
+
+use crate::media::{create_sink_track, create_source_track, SinkTrack, SourceTrack};
+use crate::{MediaSourceId, PeerId};
+
+/// the media source used by `Controller::start_audio_call`
+pub const AUDIO_CALL_SOURCE_ID: &str = "audio-call";
+
+enum Command {
+    AddSink {
+        track: Arc<TrackRemote>,
+        codec: RTCRtpCodecCapability,
+    },
+    SetMuted(bool),
+    SetOnHold(bool),
+}
+
+/// controls a call created by `Controller::start_audio_call`.
+/// cpal streams can't be sent between threads, so the source and the sinks live on a dedicated
+/// thread. dropping the AudioCall stops them, but doesn't hang up; use `hang_up` for that.
+pub struct AudioCall {
+    peer_id: PeerId,
+    commands: mpsc::UnboundedSender<Command>,
+    muted: bool,
+    on_hold: bool,
+}
+
+impl AudioCall {
+    /// starts capturing from `input_device` and writing to `track`. every track received via
+    /// `remote_tracks` is played on `output_device`
+    pub(crate) async fn start(
+        peer_id: &PeerId,
+        input_device: cpal::Device,
+        output_device: cpal::Device,
+        track: Arc<TrackLocalStaticRTP>,
+        codec: RTCRtpCodecCapability,
+        mut remote_tracks: mpsc::UnboundedReceiver<Arc<TrackRemote>>,
+    ) -> Result<Self> {
+        let (command_tx, mut command_rx) = mpsc::unbounded_channel::<Command>();
+        let (init_tx, init_rx) = oneshot::channel::<Result<()>>();
+        // the source and sinks spawn tokio tasks
+        let runtime = tokio::runtime::Handle::current();
+        // each sink takes ownership of a device, but cpal::Device can't be cloned
+        let output_device_name = output_device.name()?;
+        let mut output_device = Some(output_device);
+        std::thread::spawn(move || {
+            let _guard = runtime.enter();
+            let source = match create_source_track(input_device, track, codec) {
+                Ok(s) => s,
+                Err(e) => {
+                    let _ = init_tx.send(Err(e));
+                    return;
+                }
+            };
+            if let Err(e) = source.play() {
+                let _ = init_tx.send(Err(e));
+                return;
+            }
+            let _ = init_tx.send(Ok(()));
+
+            let mut sinks: Vec<Box<dyn SinkTrack>> = vec![];
+            let mut muted = false;
+            let mut on_hold = false;
+            while let Some(command) = command_rx.blocking_recv() {
+                match command {
+                    Command::AddSink { track, codec } => {
+                        let device = match output_device.take() {
+                            Some(d) => d,
+                            None => match find_output_device(&output_device_name) {
+                                Ok(d) => d,
+                                Err(e) => {
+                                    log::error!("failed to find output device: {}", e);
+                                    continue;
+                                }
+                            },
+                        };
+                        let sink = match create_sink_track(device, track, codec) {
+                            Ok(s) => s,
+                            Err(e) => {
+                                log::error!("failed to create sink track: {}", e);
+                                continue;
+                            }
+                        };
+                        if let Err(e) = sink.play() {
+                            log::error!("failed to play sink track: {}", e);
+                        }
+                        if on_hold {
+                            if let Err(e) = sink.set_muted(true) {
+                                log::error!("failed to mute sink track: {}", e);
+                            }
+                        }
+                        // release the output streams of tracks which have been closed
+                        sinks.retain(|s| s.is_decoding());
+                        sinks.push(sink);
+                    }
+                    Command::SetMuted(m) => {
+                        muted = m;
+                        set_capturing(source.as_ref(), !muted && !on_hold);
+                    }
+                    Command::SetOnHold(h) => {
+                        on_hold = h;
+                        set_capturing(source.as_ref(), !muted && !on_hold);
+                        for sink in &sinks {
+                            if let Err(e) = sink.set_muted(on_hold) {
+                                log::error!("failed to set sink muted to {}: {}", on_hold, e);
+                            }
+                        }
+                    }
+                }
+            }
+            log::debug!("audio call thread quitting");
+        });
+
+        match init_rx.await {
+            Ok(Ok(_)) => {}
+            Ok(Err(e)) => return Err(e),
+            Err(_) => bail!("audio call thread quit unexpectedly"),
+        }
+
+        // the codec can only be read from an async context. the weak sender lets the thread quit
+        // when the AudioCall is dropped
+        let tx = command_tx.downgrade();
+        tokio::spawn(async move {
+            while let Some(track) = remote_tracks.recv().await {
+                let codec = track.codec().await.capability;
+                let tx = match tx.upgrade() {
+                    Some(tx) => tx,
+                    None => break,
+                };
+                if tx.send(Command::AddSink { track, codec }).is_err() {
+                    break;
+                }
+            }
+        });
+
+        Ok(Self {
+            peer_id: peer_id.clone(),
+            commands: command_tx,
+            muted: false,
+            on_hold: false,
+        })
+    }
+
+    pub fn peer_id(&self) -> &PeerId {
+        &self.peer_id
+    }
+
+    pub fn is_muted(&self) -> bool {
+        self.muted
+    }
+
+    /// stops sending audio without renegotiating
+    pub fn set_muted(&mut self, muted: bool) -> Result<()> {
+        if self.commands.send(Command::SetMuted(muted)).is_err() {
+            bail!("audio call thread quit unexpectedly");
+        }
+        self.muted = muted;
+        Ok(())
+    }
+
+    pub fn is_on_hold(&self) -> bool {
+        self.on_hold
+    }
+
+    /// puts the call on hold: stops capturing and playing the audio, and sends `music` instead,
+    /// if set. see `Controller::hold`. `set_muted` still applies once the call is resumed
+    pub async fn hold(
+        &mut self,
+        controller: &mut crate::Controller,
+        music: Option<Arc<TrackLocalStaticRTP>>,
+    ) -> Result<()> {
+        controller.hold(&self.peer_id, music).await?;
+        if self.commands.send(Command::SetOnHold(true)).is_err() {
+            bail!("audio call thread quit unexpectedly");
+        }
+        self.on_hold = true;
+        Ok(())
+    }
+
+    /// takes the call off hold. see `Controller::resume`
+    pub async fn resume(&mut self, controller: &mut crate::Controller) -> Result<()> {
+        controller.resume(&self.peer_id).await?;
+        if self.commands.send(Command::SetOnHold(false)).is_err() {
+            bail!("audio call thread quit unexpectedly");
+        }
+        self.on_hold = false;
+        Ok(())
+    }
+
+    /// stops the audio, disconnects from the peer and removes the media source
+    pub async fn hang_up(self, controller: &mut crate::Controller) -> Result<()> {
+        // the source is removed even if the remote hung up first
+        let hung_up = controller.hang_up(&self.peer_id).await;
+        let source_id: MediaSourceId = AUDIO_CALL_SOURCE_ID.into();
+        controller.remove_media_source(source_id).await?;
+        hung_up
+    }
+}
+
+fn set_capturing(source: &dyn SourceTrack, capturing: bool) {
+    let r = if capturing {
+        source.play()
+    } else {
+        source.pause()
+    };
+    if let Err(e) = r {
+        log::error!("failed to set capturing to {}: {}", capturing, e);
+    }
+}
+
+fn find_output_device(name: &str) -> Result<cpal::Device> {
+    let host = cpal::default_host();
+    for device in host.output_devices()? {
+        if device.name()? == name {
+            return Ok(device);
+        }
+    }
+    bail!("output device {} not found", name)
+}
@@ -0,0 +1,172 @@
+use anyhow::{bail, Result};
+use std::sync::Arc;
+use tokio::{
+    sync::mpsc::{self, error::TrySendError},
+    task::JoinHandle,
+};
+use webrtc::{rtp_transceiver::rtp_codec::RTCRtpCodecCapability, track::track_remote::TrackRemote};
+
+use super::opus_sink::{decode_media_stream, opus_sample_builder, OpusDecoder};
+use crate::media::{SinkOptions, SinkTrack};
+use crate::MimeType;
+
+/// decodes a remote Opus track and sends each decoded frame of PCM samples to a channel, instead
+/// of playing it on an output device. for applications with their own audio pipeline, such as a
+/// game engine or a transcription service.
+/// if the receiver falls behind and the channel is full, frames are dropped.
+/// the frames have the codec's sample rate and number of channels, interleaved if there are 2.
+/// the codec of a remote Opus track always has 2 channels: pass a codec with 1 channel for mono
+pub struct ChannelSink {
+    decoder_handle: JoinHandle<()>,
+}
+
+impl Drop for ChannelSink {
+    fn drop(&mut self) {
+        // this is a failsafe in case the caller doesn't close the associated TrackRemote
+        self.decoder_handle.abort();
+    }
+}
+
+impl ChannelSink {
+    /// starts decoding immediately. `play` isn't needed
+    pub fn new(
+        samples: mpsc::Sender<Vec<i16>>,
+        track: Arc<TrackRemote>,
+        codec: RTCRtpCodecCapability,
+    ) -> Result<Self> {
+        Self::with_options(samples, track, codec, SinkOptions::default())
+    }
+
+    /// same as `new`, with the given `SinkOptions`
+    pub fn with_options(
+        samples: mpsc::Sender<Vec<i16>>,
+        track: Arc<TrackRemote>,
+        codec: RTCRtpCodecCapability,
+        options: SinkOptions,
+    ) -> Result<Self> {
+        if !matches!(
+            MimeType::from_string(&codec.mime_type)?,
+            MimeType::OPUS | MimeType::RED
+        ) {
+            bail!("unhandled mime type: {}", &codec.mime_type);
+        }
+        let sample_rate = codec.clock_rate;
+        let decoder = OpusDecoder::new(&codec)?;
+        let sample_builder = opus_sample_builder(&options, sample_rate);
+        let latency_probe = options.latency_probe;
+
+        let on_samples = move |frame: &[i16]| match samples.try_send(frame.to_vec()) {
+            Ok(_) => {}
+            Err(TrySendError::Full(_)) => log::warn!("ChannelSink receiver fell behind"),
+            Err(TrySendError::Closed(_)) => log::error!("ChannelSink receiver was dropped"),
+        };
+        let decoder_handle = tokio::spawn(async move {
+            let depacketizer = webrtc::rtp::codecs::opus::OpusPacket;
+            if let Err(e) = decode_media_stream(
+                track,
+                sample_builder,
+                depacketizer,
+                decoder,
+                latency_probe,
+                on_samples,
+            )
+            .await
+            {
+                log::error!("error decoding media stream: {}", e);
+            }
+            log::debug!("stopping decode_media_stream thread");
+        });
+
+        Ok(Self { decoder_handle })
+    }
+}
+
+impl SinkTrack for ChannelSink {
+    /// a ChannelSink has no output device. use `ChannelSink::new` or `create_sink_track`
+    fn init(
+        _output_device: cpal::Device,
+        _track: Arc<TrackRemote>,
+        _codec: RTCRtpCodecCapability,
+    ) -> Result<Self> {
+        bail!("ChannelSink requires a channel: use ChannelSink::new")
+    }
+    fn play(&self) -> Result<()> {
+        Ok(())
+    }
+    fn change_output_device(&mut self, _output_device: cpal::Device) {
+        log::warn!("ChannelSink has no output device");
+    }
+    fn is_decoding(&self) -> bool {
+        !self.decoder_handle.is_finished()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::media::ToneSource;
+    use crate::testing::loopback::{wait_for_event, Loopback, A};
+    use crate::EmittedEvents;
+    use std::time::Duration;
+
+    const TIMEOUT: Duration = Duration::from_secs(10);
+
+    fn opus(channels: u16) -> RTCRtpCodecCapability {
+        RTCRtpCodecCapability {
+            mime_type: MimeType::OPUS.to_string(),
+            clock_rate: 48000,
+            channels,
+            ..Default::default()
+        }
+    }
+
+    /// a call in which `A` sends a tone, and the `B` side of the tone's track
+    async fn tone_call() -> Result<(Loopback, ToneSource, Arc<TrackRemote>)> {
+        let mut call = Loopback::new()?;
+        call.call().await?;
+        let track = call
+            .a
+            .lock()
+            .await
+            .add_media_source("tone".into(), opus(2))
+            .await?;
+        // the tone is mono. an OpusFramer's frame size doesn't allow for a second channel
+        let tone = ToneSource::new(track, opus(1), 440.0, 0.5)?;
+        let remote = wait_for_event(&mut call.b_events, TIMEOUT, |event| match event {
+            EmittedEvents::TrackAdded { peer, track } if peer == A => Some(track),
+            _ => None,
+        })
+        .await?;
+        Ok((call, tone, remote))
+    }
+
+    #[tokio::test]
+    async fn dropping_a_sink_ends_its_decode_task() -> Result<()> {
+        let (_call, _tone, remote) = tone_call().await?;
+        let (tx, mut rx) = mpsc::channel(100);
+        let sink = ChannelSink::new(tx, remote, opus(1))?;
+        assert!(tokio::time::timeout(TIMEOUT, rx.recv()).await?.is_some());
+        assert!(sink.is_decoding());
+        drop(sink);
+        // the decode task owns the only sender
+        let ended = async { while rx.recv().await.is_some() {} };
+        tokio::time::timeout(TIMEOUT, ended).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn a_sink_stops_decoding_once_its_track_is_closed() -> Result<()> {
+        let (call, _tone, remote) = tone_call().await?;
+        let (tx, mut rx) = mpsc::channel(100);
+        let sink = ChannelSink::new(tx, remote, opus(1))?;
+        assert!(tokio::time::timeout(TIMEOUT, rx.recv()).await?.is_some());
+        call.b.lock().await.hang_up(&A.into()).await?;
+        let stopped = async {
+            while sink.is_decoding() {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+        };
+        tokio::time::timeout(TIMEOUT, stopped).await?;
+        Ok(())
+    }
+}
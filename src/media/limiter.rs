@@ -0,0 +1,55 @@
+use anyhow::{bail, Result};
+
+/// soft-clips samples which exceed the threshold, rather than letting them wrap around or hard
+/// clip. this matters when several streams are summed together, or when a single peer is very loud.
+/// samples are passed as i32 so that the sum of multiple i16 streams can be limited.
+pub struct SoftLimiter {
+    // fraction of full scale at which the limiter starts compressing. 0.0 < threshold <= 1.0
+    threshold: f32,
+}
+
+impl Default for SoftLimiter {
+    fn default() -> Self {
+        Self { threshold: 0.9 }
+    }
+}
+
+impl SoftLimiter {
+    pub fn new(threshold: f32) -> Result<Self> {
+        let mut limiter = Self::default();
+        limiter.set_threshold(threshold)?;
+        Ok(limiter)
+    }
+
+    pub fn threshold(&self) -> f32 {
+        self.threshold
+    }
+
+    /// a threshold of 1.0 disables the soft knee, leaving only a hard clip at full scale
+    pub fn set_threshold(&mut self, threshold: f32) -> Result<()> {
+        if !(threshold > 0.0 && threshold <= 1.0) {
+            bail!("invalid limiter threshold: {}", threshold);
+        }
+        self.threshold = threshold;
+        Ok(())
+    }
+
+    pub fn limit(&self, sample: i32) -> i16 {
+        let full_scale = i16::MAX as f32;
+        let x = sample as f32 / full_scale;
+        let magnitude = x.abs();
+        if magnitude <= self.threshold {
+            return sample as i16;
+        }
+
+        // compress everything above the threshold into the remaining headroom. tanh approaches
+        // 1.0 so the output never exceeds full scale.
+        let headroom = 1.0 - self.threshold;
+        let limited = if headroom > 0.0 {
+            self.threshold + headroom * ((magnitude - self.threshold) / headroom).tanh()
+        } else {
+            1.0
+        };
+        (limited.copysign(x) * full_scale) as i16
+    }
+}
@@ -0,0 +1,366 @@
+use anyhow::{bail, Result};
+use cpal::traits::{DeviceTrait, StreamTrait};
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use tokio::task::JoinHandle;
+use webrtc::{
+    rtp_transceiver::rtp_codec::RTCRtpCodecCapability,
+    track::track_local::track_local_static_rtp::TrackLocalStaticRTP,
+};
+
+use super::opus_source::{
+    opus_frame_size, red_encoder, spawn_packetizer, FrameProducer, OpusFramer,
+};
+use super::{OpusSourceConfig, SoftLimiter, SourceTrack};
+use crate::OpusParams;
+
+/// the input id used for the device passed to `SourceTrack::init`
+pub const MIXER_DEVICE_INPUT_ID: &str = "device";
+
+// an input which is more than this many frames ahead of the mixer is trimmed, which bounds the
+// latency added when an input's clock runs faster than the mixer's
+const MAX_BUFFERED_FRAMES: usize = 10;
+
+/// mixes several PCM inputs, such as a microphone and the audio of a shared video, and sends the
+/// result as a single Opus track. inputs can be added and removed during the call without
+/// renegotiating.
+/// every input must have the codec's sample rate and channel count. a frame is mixed every frame
+/// duration; an input which hasn't buffered a full frame yet is left out of that frame. the sum
+/// is passed through a `SoftLimiter`. no packets are sent while there are no inputs, and nothing
+/// is encoded while the track has no sender
+pub struct MixedOpusSource {
+    // holding on to the track so that it outlives the packetizer
+    _track: Arc<TrackLocalStaticRTP>,
+    // the samples waiting to be mixed, by input id. shared with the inputs
+    inputs: Arc<Mutex<HashMap<String, VecDeque<i16>>>>,
+    // the inputs captured from a device. they are kept here because cpal streams can't be sent
+    // between threads
+    streams: HashMap<String, cpal::Stream>,
+    // see `play` and `pause`
+    playing: Arc<AtomicBool>,
+    // shared with the mixing task. allows the encoder to be reconfigured during the call
+    framer: Arc<Mutex<OpusFramer>>,
+    limiter: Arc<Mutex<SoftLimiter>>,
+    frame_size: usize,
+    mixer_handle: JoinHandle<()>,
+    _packetizer_handle: JoinHandle<()>,
+}
+
+impl Drop for MixedOpusSource {
+    fn drop(&mut self) {
+        // the packetizer stops once the mixer drops its sender
+        self.mixer_handle.abort();
+    }
+}
+
+/// feeds samples to one of the inputs of a `MixedOpusSource`. see `MixedOpusSource::add_input`
+#[derive(Clone)]
+pub struct MixerInput {
+    id: String,
+    inputs: Arc<Mutex<HashMap<String, VecDeque<i16>>>>,
+    max_buffered: usize,
+}
+
+impl MixerInput {
+    /// queues samples to be mixed. fails if the input was removed
+    pub fn push(&self, samples: &[i16]) -> Result<()> {
+        push_samples(&self.inputs, &self.id, samples, self.max_buffered)
+    }
+
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+}
+
+impl SourceTrack for MixedOpusSource {
+    /// mixes only `input_device`, under the id `MIXER_DEVICE_INPUT_ID`. more inputs can be added
+    /// with `add_input` and `add_device_input`
+    fn init(
+        input_device: cpal::Device,
+        track: Arc<TrackLocalStaticRTP>,
+        codec: RTCRtpCodecCapability,
+    ) -> Result<Self>
+    where
+        Self: Sized,
+    {
+        let mut source = Self::new(track, codec, OpusSourceConfig::default())?;
+        source.add_device_input(MIXER_DEVICE_INPUT_ID, input_device)?;
+        Ok(source)
+    }
+
+    fn play(&self) -> Result<()> {
+        for stream in self.streams.values() {
+            stream.play()?;
+        }
+        self.playing.store(true, Ordering::Relaxed);
+        Ok(())
+    }
+    fn pause(&self) -> Result<()> {
+        for stream in self.streams.values() {
+            stream.pause()?;
+        }
+        self.playing.store(false, Ordering::Relaxed);
+        Ok(())
+    }
+    /// replaces the input `MIXER_DEVICE_INPUT_ID`
+    fn change_input_device(&mut self, input_device: cpal::Device) {
+        self.remove_input(MIXER_DEVICE_INPUT_ID);
+        if let Err(e) = self.add_device_input(MIXER_DEVICE_INPUT_ID, input_device) {
+            log::error!("failed to change input device: {}", e);
+        }
+    }
+}
+
+impl MixedOpusSource {
+    /// creates a source with no inputs, paused. call `play` to start sending
+    pub fn new(
+        track: Arc<TrackLocalStaticRTP>,
+        codec: RTCRtpCodecCapability,
+        config: OpusSourceConfig,
+    ) -> Result<Self> {
+        let sample_rate = codec.clock_rate;
+        let frame_duration = config.frame_duration;
+        let frame_size = opus_frame_size(sample_rate, frame_duration)?;
+        let framer = OpusFramer::from_config(frame_size, &codec, &config)?;
+        let framer = Arc::new(Mutex::new(framer));
+        let (producer, packetizer_handle) = spawn_packetizer(
+            track.clone(),
+            sample_rate,
+            config.mtu,
+            red_encoder(&codec, config.mtu),
+        );
+
+        let inputs: Arc<Mutex<HashMap<String, VecDeque<i16>>>> = Arc::default();
+        let playing = Arc::new(AtomicBool::new(false));
+        let limiter = Arc::new(Mutex::new(SoftLimiter::default()));
+        let mixer_handle = tokio::spawn(mix(
+            inputs.clone(),
+            playing.clone(),
+            framer.clone(),
+            limiter.clone(),
+            producer,
+            frame_size,
+            frame_duration,
+        ));
+
+        Ok(Self {
+            _track: track,
+            inputs,
+            streams: HashMap::new(),
+            playing,
+            framer,
+            limiter,
+            frame_size,
+            mixer_handle,
+            _packetizer_handle: packetizer_handle,
+        })
+    }
+
+    /// adds an input which the application feeds with `MixerInput::push`, ex: the decoded audio
+    /// of a media file. fails if `id` is in use
+    pub fn add_input(&self, id: &str) -> Result<MixerInput> {
+        match self.inputs.lock() {
+            Ok(mut inputs) => {
+                if inputs.contains_key(id) {
+                    bail!("mixer input already exists: {}", id);
+                }
+                inputs.insert(id.into(), VecDeque::new());
+            }
+            Err(e) => bail!("failed to lock mixer inputs: {}", e),
+        }
+        Ok(MixerInput {
+            id: id.into(),
+            inputs: self.inputs.clone(),
+            max_buffered: self.frame_size * MAX_BUFFERED_FRAMES,
+        })
+    }
+
+    /// adds an input which captures from `input_device`, ex: a microphone. the device is
+    /// captured while the source is playing. fails if `id` is in use
+    pub fn add_device_input(&mut self, id: &str, input_device: cpal::Device) -> Result<()> {
+        let config = input_device.default_input_config()?;
+        let input = self.add_input(id)?;
+        let input_data_fn = move |data: &[i16], _: &cpal::InputCallbackInfo| {
+            if let Err(e) = input.push(data) {
+                log::error!("MixedOpusSource failed to push samples: {}", e);
+            }
+        };
+        let stream = match input_device.build_input_stream(&config.into(), input_data_fn, err_fn) {
+            Ok(s) => s,
+            Err(e) => {
+                self.remove_input(id);
+                return Err(e.into());
+            }
+        };
+        if self.playing.load(Ordering::Relaxed) {
+            stream.play()?;
+        }
+        self.streams.insert(id.into(), stream);
+        Ok(())
+    }
+
+    /// removes an input, discarding its buffered samples. returns false if there was no such
+    /// input
+    pub fn remove_input(&mut self, id: &str) -> bool {
+        // dropping the stream stops capturing from the device
+        self.streams.remove(id);
+        match self.inputs.lock() {
+            Ok(mut inputs) => inputs.remove(id).is_some(),
+            Err(e) => {
+                log::error!("failed to lock mixer inputs: {}", e);
+                false
+            }
+        }
+    }
+
+    /// the ids of the current inputs
+    pub fn inputs(&self) -> Vec<String> {
+        match self.inputs.lock() {
+            Ok(inputs) => inputs.keys().cloned().collect(),
+            Err(e) => {
+                log::error!("failed to lock mixer inputs: {}", e);
+                vec![]
+            }
+        }
+    }
+
+    /// sets the fraction of full scale above which the mix is soft-clipped. see `SoftLimiter`
+    pub fn set_limiter_threshold(&self, threshold: f32) -> Result<()> {
+        match self.limiter.lock() {
+            Ok(mut l) => l.set_threshold(threshold),
+            Err(e) => bail!("failed to lock limiter: {}", e),
+        }
+    }
+
+    /// see `OpusSource::apply_remote_params`
+    pub fn apply_remote_params(&self, params: &OpusParams) -> Result<()> {
+        match self.framer.lock() {
+            Ok(mut f) => f.apply_remote_params(params),
+            Err(e) => bail!("failed to lock framer: {}", e),
+        }
+    }
+
+    /// see `OpusSource::set_bandwidth`
+    pub fn set_bandwidth(&self, bandwidth: opus::Bandwidth) -> Result<()> {
+        match self.framer.lock() {
+            Ok(mut f) => f.set_bandwidth(bandwidth),
+            Err(e) => bail!("failed to lock framer: {}", e),
+        }
+    }
+
+    /// see `OpusSource::set_bitrate`
+    pub fn set_bitrate(&self, bitrate: Option<u32>) -> Result<()> {
+        match self.framer.lock() {
+            Ok(mut f) => f.set_bitrate(bitrate),
+            Err(e) => bail!("failed to lock framer: {}", e),
+        }
+    }
+
+    /// see `OpusSource::set_expected_packet_loss`
+    pub fn set_expected_packet_loss(&self, percent: u8) -> Result<()> {
+        match self.framer.lock() {
+            Ok(mut f) => f.set_expected_packet_loss(percent),
+            Err(e) => bail!("failed to lock framer: {}", e),
+        }
+    }
+}
+
+fn push_samples(
+    inputs: &Mutex<HashMap<String, VecDeque<i16>>>,
+    id: &str,
+    samples: &[i16],
+    max_buffered: usize,
+) -> Result<()> {
+    let mut inputs = match inputs.lock() {
+        Ok(i) => i,
+        Err(e) => bail!("failed to lock mixer inputs: {}", e),
+    };
+    let buffer = match inputs.get_mut(id) {
+        Some(b) => b,
+        None => bail!("mixer input was removed: {}", id),
+    };
+    buffer.extend(samples);
+    if buffer.len() > max_buffered {
+        let excess = buffer.len() - max_buffered;
+        buffer.drain(..excess);
+    }
+    Ok(())
+}
+
+/// every frame duration, sums a frame from each input, encodes it and sends it to the packetizer.
+/// while the track has no sender, the inputs are still consumed but nothing is encoded
+async fn mix(
+    inputs: Arc<Mutex<HashMap<String, VecDeque<i16>>>>,
+    playing: Arc<AtomicBool>,
+    framer: Arc<Mutex<OpusFramer>>,
+    limiter: Arc<Mutex<SoftLimiter>>,
+    producer: FrameProducer,
+    frame_size: usize,
+    frame_duration: Duration,
+) {
+    let mut interval = tokio::time::interval(frame_duration);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    let mut mixed = vec![0i32; frame_size];
+    loop {
+        interval.tick().await;
+        if !playing.load(Ordering::Relaxed) {
+            continue;
+        }
+        let num_mixed = match inputs.lock() {
+            Ok(mut inputs) => {
+                mixed.iter_mut().for_each(|s| *s = 0);
+                let mut num_mixed = 0;
+                for buffer in inputs.values_mut() {
+                    if buffer.len() < frame_size {
+                        continue;
+                    }
+                    for (sum, sample) in mixed.iter_mut().zip(buffer.drain(..frame_size)) {
+                        *sum += sample as i32;
+                    }
+                    num_mixed += 1;
+                }
+                num_mixed
+            }
+            Err(e) => {
+                log::error!("MixedOpusSource failed to lock inputs: {}", e);
+                continue;
+            }
+        };
+        // draining the inputs while there's no sender keeps them from buffering stale audio
+        if num_mixed == 0 || !producer.has_sender() {
+            continue;
+        }
+
+        let limiter = match limiter.lock() {
+            Ok(l) => l,
+            Err(e) => {
+                log::error!("MixedOpusSource failed to lock limiter: {}", e);
+                continue;
+            }
+        };
+        let mut framer = match framer.lock() {
+            Ok(f) => f,
+            Err(e) => {
+                log::error!("MixedOpusSource failed to lock framer: {}", e);
+                continue;
+            }
+        };
+        for sample in &mixed {
+            if let Some(bytes) = framer.frame(limiter.limit(*sample)) {
+                // the frame started capturing one frame duration ago. the framer's duration
+                // differs from the mixing interval if the remote asked for another ptime
+                let frame_duration = framer.frame_duration();
+                let capture_time = SystemTime::now() - frame_duration;
+                if let Err(e) = producer.send((bytes, capture_time, frame_duration)) {
+                    log::error!("MixedOpusSource failed to send frame: {}", e);
+                }
+            }
+        }
+    }
+}
+
+fn err_fn(err: cpal::StreamError) {
+    log::error!("an error occurred on stream: {}", err);
+}
@@ -1,15 +1,34 @@
 use anyhow::{bail, Result};
 use std::sync::Arc;
+use tokio::sync::mpsc;
 use webrtc::{
     rtp_transceiver::rtp_codec::RTCRtpCodecCapability,
     track::{track_local::track_local_static_rtp::TrackLocalStaticRTP, track_remote::TrackRemote},
 };
 
-use crate::MimeType;
+use crate::{LatencyProbe, MimeType};
+mod audio_call;
+mod channel_sink;
+mod limiter;
+mod mixed_source;
 mod opus_sink;
 mod opus_source;
+mod red;
+mod tone_source;
+mod video_sink;
+mod vp8_source;
+pub use audio_call::{AudioCall, AUDIO_CALL_SOURCE_ID};
+pub use channel_sink::ChannelSink;
+pub use limiter::SoftLimiter;
+pub use mixed_source::{MixedOpusSource, MixerInput, MIXER_DEVICE_INPUT_ID};
 pub use opus_sink::OpusSink;
-pub use opus_source::OpusSource;
+pub use opus_source::{
+    OpusSource, OpusSourceConfig, DEFAULT_MTU, MAX_OPUS_BITRATE, MIN_OPUS_BITRATE,
+    OPUS_FRAME_DURATIONS,
+};
+pub use tone_source::ToneSource;
+pub use video_sink::{VideoFrame, VideoSink};
+pub use vp8_source::Vp8Source;
 
 pub trait SourceTrack {
     fn init(
@@ -21,6 +40,8 @@ pub trait SourceTrack {
         Self: Sized;
 
     fn play(&self) -> Result<()>;
+    /// stops capturing. resume with `play`
+    fn pause(&self) -> Result<()>;
     // should not require RTP renegotiation
     fn change_input_device(&mut self, input_device: cpal::Device);
 }
@@ -35,28 +56,136 @@ pub trait SinkTrack {
         Self: Sized;
     fn play(&self) -> Result<()>;
     fn change_output_device(&mut self, output_device: cpal::Device);
+    /// stops playing the track's media, without stopping the decode task, so that playback
+    /// resumes immediately when unmuted. only supported by sinks which play on a device
+    fn set_muted(&self, _muted: bool) -> Result<()> {
+        bail!("muting isn't supported by this sink")
+    }
+    /// false once the decode task has ended, which happens when the track is closed. the sink
+    /// can then be dropped. dropping a sink always stops its decode task
+    fn is_decoding(&self) -> bool;
+}
+
+/// where a source track gets its media
+pub enum SourceInput {
+    /// capture it from an input device
+    Device(cpal::Device),
+    /// receive encoded video frames from a channel. see `Vp8Source`
+    VideoFrames(mpsc::Receiver<VideoFrame>),
+}
+
+impl From<cpal::Device> for SourceInput {
+    fn from(device: cpal::Device) -> Self {
+        Self::Device(device)
+    }
+}
+
+impl From<mpsc::Receiver<VideoFrame>> for SourceInput {
+    fn from(frames: mpsc::Receiver<VideoFrame>) -> Self {
+        Self::VideoFrames(frames)
+    }
 }
 
+/// `input` is a `cpal::Device` for audio, or a `mpsc::Receiver<VideoFrame>` for VP8 video
 pub fn create_source_track(
-    output_device: cpal::Device,
+    input: impl Into<SourceInput>,
     track: Arc<TrackLocalStaticRTP>,
     codec: RTCRtpCodecCapability,
 ) -> Result<Box<dyn SourceTrack>> {
-    match MimeType::from_string(&codec.mime_type)? {
-        MimeType::OPUS => Ok(Box::new(OpusSource::init(output_device, track, codec)?)),
+    create_source_track_with_config(input, track, codec, OpusSourceConfig::default())
+}
+
+/// same as `create_source_track`, with the given `OpusSourceConfig`. the config only applies
+/// to audio
+pub fn create_source_track_with_config(
+    input: impl Into<SourceInput>,
+    track: Arc<TrackLocalStaticRTP>,
+    codec: RTCRtpCodecCapability,
+    config: OpusSourceConfig,
+) -> Result<Box<dyn SourceTrack>> {
+    match (MimeType::from_string(&codec.mime_type)?, input.into()) {
+        (MimeType::OPUS | MimeType::RED, SourceInput::Device(device)) => Ok(Box::new(
+            OpusSource::init_with_config(device, track, codec, config)?,
+        )),
+        (MimeType::VP8, SourceInput::VideoFrames(frames)) => {
+            Ok(Box::new(Vp8Source::new(frames, track, codec)?))
+        }
         _ => {
             bail!("unhandled mime type: {}", &codec.mime_type);
         }
     }
 }
 
+/// where a sink track sends the decoded media
+pub enum SinkOutput {
+    /// play it on an output device
+    Device(cpal::Device),
+    /// send it to a channel. see `ChannelSink`
+    Channel(mpsc::Sender<Vec<i16>>),
+    /// send the encoded video frames to a channel. see `VideoSink`
+    VideoFrames(mpsc::Sender<VideoFrame>),
+}
+
+impl From<cpal::Device> for SinkOutput {
+    fn from(device: cpal::Device) -> Self {
+        Self::Device(device)
+    }
+}
+
+impl From<mpsc::Sender<Vec<i16>>> for SinkOutput {
+    fn from(samples: mpsc::Sender<Vec<i16>>) -> Self {
+        Self::Channel(samples)
+    }
+}
+
+impl From<mpsc::Sender<VideoFrame>> for SinkOutput {
+    fn from(frames: mpsc::Sender<VideoFrame>) -> Self {
+        Self::VideoFrames(frames)
+    }
+}
+
+/// settings for a sink track. passed to `create_sink_track_with_options`
+#[derive(Debug, Clone, Default)]
+pub struct SinkOptions {
+    /// if true, each RTP packet is decoded as soon as it arrives, without waiting in the jitter
+    /// buffer, which removes about a frame of latency. packets which arrive out of order are
+    /// decoded out of order, and late ones aren't waited for, so the audio will glitch whenever
+    /// the network reorders or delays packets. only for controlled networks, such as LAN or
+    /// loopback monitoring
+    pub no_jitter_buffer: bool,
+    /// if set, the sink reports how long decoded frames took, for `Controller::measured_latency`.
+    /// see `Controller::latency_probe`
+    pub latency_probe: Option<LatencyProbe>,
+}
+
+/// `output` is a `cpal::Device` or a `mpsc::Sender<Vec<i16>>` for audio, or a
+/// `mpsc::Sender<VideoFrame>` for video
 pub fn create_sink_track(
-    output_device: cpal::Device,
+    output: impl Into<SinkOutput>,
+    track: Arc<TrackRemote>,
+    codec: RTCRtpCodecCapability,
+) -> Result<Box<dyn SinkTrack>> {
+    create_sink_track_with_options(output, track, codec, SinkOptions::default())
+}
+
+/// same as `create_sink_track`, with the given `SinkOptions`
+pub fn create_sink_track_with_options(
+    output: impl Into<SinkOutput>,
     track: Arc<TrackRemote>,
     codec: RTCRtpCodecCapability,
+    options: SinkOptions,
 ) -> Result<Box<dyn SinkTrack>> {
-    match MimeType::from_string(&codec.mime_type)? {
-        MimeType::OPUS => Ok(Box::new(OpusSink::init(output_device, track, codec)?)),
+    match (MimeType::from_string(&codec.mime_type)?, output.into()) {
+        (MimeType::OPUS | MimeType::RED, SinkOutput::Device(device)) => Ok(Box::new(
+            OpusSink::with_options(device, track, codec, options)?,
+        )),
+        (MimeType::OPUS | MimeType::RED, SinkOutput::Channel(samples)) => Ok(Box::new(
+            ChannelSink::with_options(samples, track, codec, options)?,
+        )),
+        // the jitter buffer can't be bypassed: a video frame spans several packets
+        (MimeType::VP8 | MimeType::VP9 | MimeType::H264, SinkOutput::VideoFrames(frames)) => {
+            Ok(Box::new(VideoSink::new(frames, track, codec)?))
+        }
         _ => {
             bail!("unhandled mime type: {}", &codec.mime_type);
         }
@@ -1,6 +1,9 @@
 use anyhow::{bail, Result};
 use cpal::traits::{DeviceTrait, StreamTrait};
-use std::sync::Arc;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
 use tokio::{
     sync::mpsc::{self, error::TryRecvError},
     task::JoinHandle,
@@ -11,13 +14,19 @@ use webrtc::{
     util::Unmarshal,
 };
 
-use crate::media::SinkTrack;
+use crate::media::red::{is_red, RedDecoder};
+use crate::media::{SinkOptions, SinkTrack, SoftLimiter};
+use crate::{AbsCaptureTime, LatencyProbe};
 pub struct OpusSink {
     // may not need this but am saving it here because it's related to the `stream`, which needs to be kept in scope.
     _device: cpal::Device,
     // want to keep this from getting dropped so it will continue to be read from
     stream: cpal::Stream,
     decoder_handle: JoinHandle<()>,
+    // applied to the output before it's written to the device
+    limiter: Arc<Mutex<SoftLimiter>>,
+    // shared with the output stream. see `set_muted`
+    muted: Arc<AtomicBool>,
 }
 
 impl Drop for OpusSink {
@@ -27,39 +36,89 @@ impl Drop for OpusSink {
     }
 }
 
-// todo: ensure no zombie threads
 impl SinkTrack for OpusSink {
     fn init(
         output_device: cpal::Device,
         track: Arc<TrackRemote>,
         codec: RTCRtpCodecCapability,
     ) -> Result<Self> {
-        // number of late samples allowed (for RTP)
-        let max_late = 480;
-        let sample_rate = codec.clock_rate;
-        let channels = match codec.channels {
-            _ => opus::Channels::Mono,
-            /*1 => opus::Channels::Mono,
-            2 => opus::Channels::Stereo,
-            _ => bail!("invalid number of channels"),*/
-        };
+        Self::with_options(output_device, track, codec, SinkOptions::default())
+    }
 
-        let decoder = opus::Decoder::new(sample_rate, channels)?;
+    fn play(&self) -> Result<()> {
+        if let Err(e) = self.stream.play() {
+            return Err(e.into());
+        }
+        Ok(())
+    }
+    fn change_output_device(&mut self, _output_device: cpal::Device) {
+        todo!()
+    }
+    /// plays silence. the decoded samples are still consumed, so they don't pile up
+    fn set_muted(&self, muted: bool) -> Result<()> {
+        self.muted.store(muted, Ordering::Relaxed);
+        Ok(())
+    }
+    fn is_decoding(&self) -> bool {
+        !self.decoder_handle.is_finished()
+    }
+}
+
+impl OpusSink {
+    /// same as `init`, with the given `SinkOptions`
+    pub fn with_options(
+        output_device: cpal::Device,
+        track: Arc<TrackRemote>,
+        codec: RTCRtpCodecCapability,
+        options: SinkOptions,
+    ) -> Result<Self> {
+        let sample_rate = codec.clock_rate;
+        let decoder = OpusDecoder::new(&codec)?;
         let (producer, mut consumer) = mpsc::unbounded_channel::<i16>();
-        let depacketizer = webrtc::rtp::codecs::opus::OpusPacket::default();
-        let sample_builder = SampleBuilder::new(max_late, depacketizer, sample_rate as u32);
+        let sample_builder = opus_sample_builder(&options, sample_rate);
+        let latency_probe = options.latency_probe;
+        let on_samples = move |samples: &[i16]| {
+            for audio_sample in samples {
+                if let Err(e) = producer.send(*audio_sample) {
+                    log::error!("failed to send sample: {}", e);
+                }
+            }
+        };
         let join_handle = tokio::spawn(async move {
-            if let Err(e) = decode_media_stream(track, sample_builder, producer, decoder).await {
+            let depacketizer = webrtc::rtp::codecs::opus::OpusPacket;
+            if let Err(e) = decode_media_stream(
+                track,
+                sample_builder,
+                depacketizer,
+                decoder,
+                latency_probe,
+                on_samples,
+            )
+            .await
+            {
                 log::error!("error decoding media stream: {}", e);
             }
             log::debug!("stopping decode_media_stream thread");
         });
 
+        let limiter = Arc::new(Mutex::new(SoftLimiter::default()));
+        let limiter2 = limiter.clone();
+        let muted = Arc::new(AtomicBool::new(false));
+        let muted2 = muted.clone();
         let output_data_fn = move |data: &mut [i16], _: &cpal::OutputCallbackInfo| {
+            let limiter = match limiter2.lock() {
+                Ok(l) => l,
+                Err(e) => {
+                    log::error!("failed to lock limiter: {}", e);
+                    return;
+                }
+            };
             let mut input_fell_behind = false;
+            let muted = muted2.load(Ordering::Relaxed);
             for sample in data {
                 *sample = match consumer.try_recv() {
-                    Ok(s) => s,
+                    Ok(_) if muted => 0,
+                    Ok(s) => limiter.limit(s as i32),
                     Err(TryRecvError::Empty) => {
                         input_fell_behind = true;
                         0
@@ -83,30 +142,56 @@ impl SinkTrack for OpusSink {
             _device: output_device,
             stream: output_stream,
             decoder_handle: join_handle,
+            limiter,
+            muted,
         })
     }
 
-    fn play(&self) -> Result<()> {
-        if let Err(e) = self.stream.play() {
-            return Err(e.into());
+    /// sets the fraction of full scale above which the output is soft-clipped. see `SoftLimiter`
+    pub fn set_limiter_threshold(&self, threshold: f32) -> Result<()> {
+        match self.limiter.lock() {
+            Ok(mut l) => l.set_threshold(threshold),
+            Err(e) => bail!("failed to lock limiter: {}", e),
         }
-        Ok(())
     }
-    fn change_output_device(&mut self, _output_device: cpal::Device) {
-        todo!()
+}
+
+/// the jitter buffer for an Opus track, or None if `SinkOptions::no_jitter_buffer` is set
+pub(crate) fn opus_sample_builder(
+    options: &SinkOptions,
+    sample_rate: u32,
+) -> Option<SampleBuilder<webrtc::rtp::codecs::opus::OpusPacket>> {
+    if options.no_jitter_buffer {
+        return None;
     }
+    // number of late samples allowed (for RTP)
+    let max_late = 480;
+    let depacketizer = webrtc::rtp::codecs::opus::OpusPacket;
+    Some(SampleBuilder::new(max_late, depacketizer, sample_rate))
 }
 
-async fn decode_media_stream<T>(
+/// reads RTP packets from the track and passes each decoded frame to `on_samples`.
+/// if `sample_builder` is None, each packet is depacketized with `depacketizer` and decoded as
+/// soon as it arrives. RED packets are unwrapped first. if `latency_probe` is set, it's updated as
+/// frames are decoded
+pub(crate) async fn decode_media_stream<T, F>(
     track: Arc<TrackRemote>,
-    mut sample_builder: SampleBuilder<T>,
-    producer: mpsc::UnboundedSender<i16>,
-    mut decoder: opus::Decoder,
+    mut sample_builder: Option<SampleBuilder<T>>,
+    mut depacketizer: T,
+    mut decoder: OpusDecoder,
+    latency_probe: Option<LatencyProbe>,
+    mut on_samples: F,
 ) -> Result<()>
 where
     T: Depacketizer,
+    F: FnMut(&[i16]),
 {
-    let mut decoder_output_buf = [0; 4096];
+    let codec = track.codec().await.capability;
+    let mut red = is_red(&codec).then(RedDecoder::default);
+    let mut latency = match latency_probe {
+        Some(probe) => Some(LatencyTracker::new(probe, codec.clock_rate)),
+        None => None,
+    };
     // read RTP packets, convert to samples, and send samples via channel
     let mut b = [0u8; 4096];
     loop {
@@ -129,24 +214,44 @@ where
 
                 // todo: send the RTP packet somewhere else if needed (such as something which is writing the media to an MP4 file)
 
-                // turn RTP packets into samples via SampleBuilder.push
-                sample_builder.push(rtp_packet);
-                // check if a sample can be created
-                while let Some(media_sample) = sample_builder.pop() {
-                    match decoder.decode(media_sample.data.as_ref(), &mut decoder_output_buf, false)
-                    {
-                        Ok(siz) => {
-                            let to_send = decoder_output_buf.iter().take(siz);
-                            for audio_sample in to_send {
-                                if let Err(e) = producer.send(*audio_sample) {
-                                    log::error!("failed to send sample: {}", e);
-                                }
-                            }
-                        }
+                if let Some(latency) = latency.as_mut() {
+                    latency.on_packet(&track, &rtp_packet).await;
+                }
+
+                // a RED packet may also carry the frame of a lost packet
+                let rtp_packets = match red.as_mut() {
+                    Some(red) => match red.decode(rtp_packet) {
+                        Ok(p) => p,
                         Err(e) => {
-                            log::error!("decode error: {}", e);
+                            log::warn!("invalid RED packet: {}", e);
                             continue;
                         }
+                    },
+                    None => vec![rtp_packet],
+                };
+
+                for rtp_packet in rtp_packets {
+                    let sample_builder = match sample_builder.as_mut() {
+                        Some(s) => s,
+                        None => {
+                            match depacketizer.depacketize(&rtp_packet.payload) {
+                                Ok(frame) => decoder.decode(&frame, &mut on_samples),
+                                Err(e) => log::error!("depacketize error: {}", e),
+                            }
+                            if let Some(latency) = latency.as_mut() {
+                                latency.on_decoded(rtp_packet.header.timestamp);
+                            }
+                            continue;
+                        }
+                    };
+                    // turn RTP packets into samples via SampleBuilder.push
+                    sample_builder.push(rtp_packet);
+                    // check if a sample can be created
+                    while let Some(media_sample) = sample_builder.pop() {
+                        decoder.decode(&media_sample.data, &mut on_samples);
+                        if let Some(latency) = latency.as_mut() {
+                            latency.on_decoded(media_sample.packet_timestamp);
+                        }
                     }
                 }
             }
@@ -160,6 +265,200 @@ where
     Ok(())
 }
 
+// the packets whose capture times are remembered until their frame is decoded. more than the
+// jitter buffer holds
+const MAX_CAPTURE_TIMES: usize = 64;
+
+/// feeds a `LatencyProbe`. the time a frame spent in the jitter buffer is the difference
+/// between the RTP timestamp of the newest packet and that of the decoded frame
+struct LatencyTracker {
+    probe: LatencyProbe,
+    clock_rate: u32,
+    newest_timestamp: Option<u32>,
+    // the capture time of recent packets, by RTP timestamp, from the absolute-capture-time
+    // extension
+    capture_times: VecDeque<(u32, SystemTime)>,
+}
+
+impl LatencyTracker {
+    fn new(probe: LatencyProbe, clock_rate: u32) -> Self {
+        Self {
+            probe,
+            clock_rate,
+            newest_timestamp: None,
+            capture_times: VecDeque::new(),
+        }
+    }
+
+    async fn on_packet(&mut self, track: &TrackRemote, packet: &webrtc::rtp::packet::Packet) {
+        let timestamp = packet.header.timestamp;
+        // ignores reordered packets. the difference is signed because timestamps wrap
+        let newer = self
+            .newest_timestamp
+            .map(|newest| (timestamp.wrapping_sub(newest) as i32) > 0)
+            .unwrap_or(true);
+        if newer {
+            self.newest_timestamp = Some(timestamp);
+        }
+        if let Some(abs_capture_time) = AbsCaptureTime::from_packet(track, packet).await {
+            if self.capture_times.len() == MAX_CAPTURE_TIMES {
+                self.capture_times.pop_front();
+            }
+            self.capture_times
+                .push_back((timestamp, abs_capture_time.capture_time()));
+        }
+    }
+
+    fn on_decoded(&mut self, timestamp: u32) {
+        if let Some(newest) = self.newest_timestamp {
+            if self.clock_rate > 0 {
+                let ticks = newest.wrapping_sub(timestamp) as i32;
+                let buffered = ticks.max(0) as f64 / self.clock_rate as f64;
+                self.probe
+                    .record_buffered(Duration::from_secs_f64(buffered));
+            }
+        }
+        let capture_time = self
+            .capture_times
+            .iter()
+            .find(|(ts, _)| *ts == timestamp)
+            .map(|(_, capture_time)| *capture_time);
+        if let Some(capture_time) = capture_time {
+            self.probe.record_capture_time(capture_time);
+        }
+    }
+}
+
+// the longest Opus frame (RFC 6716)
+const MAX_FRAME_DURATION_MS: usize = 120;
+
+/// an Opus decoder for the codec's clock rate and number of channels. the samples of a stereo
+/// frame are interleaved.
+/// webrtc-rs negotiates Opus as "opus/48000/2" (RFC 7587), whether or not the remote sends
+/// stereo, so the codec of a remote track has 2 channels: pass a codec with 1 channel for mono
+/// output. a mono stream decoded as stereo has the same samples in both channels, and a stereo
+/// stream decoded as mono is downmixed
+pub(crate) struct OpusDecoder {
+    decoder: opus::Decoder,
+    channels: usize,
+    // fits the longest frame
+    output: Vec<i16>,
+}
+
+impl OpusDecoder {
+    pub fn new(codec: &RTCRtpCodecCapability) -> Result<Self> {
+        let (opus_channels, channels) = match codec.channels {
+            // unspecified
+            0 | 1 => (opus::Channels::Mono, 1),
+            2 => (opus::Channels::Stereo, 2),
+            n => bail!("invalid number of channels: {}", n),
+        };
+        let decoder = opus::Decoder::new(codec.clock_rate, opus_channels)?;
+        let frame_len = codec.clock_rate as usize * MAX_FRAME_DURATION_MS / 1000;
+        Ok(Self {
+            decoder,
+            channels,
+            output: vec![0; frame_len * channels],
+        })
+    }
+
+    fn decode<F: FnMut(&[i16])>(&mut self, frame: &[u8], on_samples: &mut F) {
+        match self.decoder.decode(frame, &mut self.output, false) {
+            // the number of samples per channel
+            Ok(siz) => on_samples(&self.output[..siz * self.channels]),
+            Err(e) => log::error!("decode error: {}", e),
+        }
+    }
+}
+
 fn err_fn(err: cpal::StreamError) {
     log::error!("an error occurred on stream: {}", err);
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f32::consts::PI;
+
+    fn opus(clock_rate: u32, channels: u16) -> RTCRtpCodecCapability {
+        RTCRtpCodecCapability {
+            mime_type: "audio/opus".into(),
+            clock_rate,
+            channels,
+            ..Default::default()
+        }
+    }
+
+    /// a 20ms frame of a 440Hz tone, in the left channel only if `channels` is 2
+    fn encode(sample_rate: u32, channels: opus::Channels) -> Result<Vec<u8>> {
+        let mut encoder = opus::Encoder::new(sample_rate, channels, opus::Application::Audio)?;
+        let frame_size = sample_rate as usize / 50;
+        let count = channels as usize;
+        let mut samples = vec![0i16; frame_size * count];
+        for (i, frame) in samples.chunks_mut(count).enumerate() {
+            let t = i as f32 / sample_rate as f32;
+            frame[0] = ((t * 440.0 * 2.0 * PI).sin() * 16000.0) as i16;
+        }
+        Ok(encoder.encode_vec(&samples, 4000)?)
+    }
+
+    /// decodes `packets` and returns the output of the last one
+    fn decode(decoder: &mut OpusDecoder, packets: &[Vec<u8>]) -> Vec<i16> {
+        let mut output = vec![];
+        for packet in packets {
+            decoder.decode(packet, &mut |samples: &[i16]| output = samples.to_vec());
+        }
+        output
+    }
+
+    fn energy(samples: impl Iterator<Item = i16>) -> f64 {
+        samples.map(|s| (s as f64).powi(2)).sum()
+    }
+
+    #[test]
+    fn stereo_frames_decode_to_interleaved_samples() -> Result<()> {
+        let packet = encode(48000, opus::Channels::Stereo)?;
+        let mut decoder = OpusDecoder::new(&opus(48000, 2))?;
+        // the encoder needs a few frames to settle
+        let output = decode(&mut decoder, &vec![packet; 5]);
+        assert_eq!(output.len(), 960 * 2);
+        let left = energy(output.iter().step_by(2).copied());
+        let right = energy(output.iter().skip(1).step_by(2).copied());
+        assert!(left > right * 100.0, "left {} right {}", left, right);
+        Ok(())
+    }
+
+    #[test]
+    fn the_decoder_follows_the_clock_rate_and_the_channels() -> Result<()> {
+        let packet = encode(48000, opus::Channels::Mono)?;
+        for (codec, len) in [
+            (opus(48000, 1), 960),
+            // unspecified is mono
+            (opus(48000, 0), 960),
+            (opus(16000, 1), 320),
+            (opus(16000, 2), 640),
+        ] {
+            let mut decoder = OpusDecoder::new(&codec)?;
+            assert_eq!(
+                decode(&mut decoder, std::slice::from_ref(&packet)).len(),
+                len
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn a_mono_stream_decoded_as_stereo_has_the_same_channels() -> Result<()> {
+        let packet = encode(48000, opus::Channels::Mono)?;
+        let mut decoder = OpusDecoder::new(&opus(48000, 2))?;
+        let output = decode(&mut decoder, &vec![packet; 5]);
+        assert!(output.chunks(2).all(|frame| frame[0] == frame[1]));
+        assert!(energy(output.iter().copied()) > 0.0);
+        Ok(())
+    }
+
+    #[test]
+    fn more_than_two_channels_are_rejected() {
+        assert!(OpusDecoder::new(&opus(48000, 3)).is_err());
+    }
+}
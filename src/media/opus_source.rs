@@ -3,15 +3,27 @@ use bytes::Bytes;
 use cpal::traits::{DeviceTrait, StreamTrait};
 
 use rand::Rng;
-use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
 use tokio::{sync::mpsc, task::JoinHandle};
 use webrtc::{
-    rtp::{self, packetizer::Packetizer},
+    rtp::{self, extension::HeaderExtension, packetizer::Packetizer},
     rtp_transceiver::rtp_codec::RTCRtpCodecCapability,
-    track::track_local::{track_local_static_rtp::TrackLocalStaticRTP, TrackLocalWriter},
+    track::track_local::track_local_static_rtp::TrackLocalStaticRTP,
 };
 
+use super::red::{is_red, RedEncoder, RED_OVERHEAD};
 use super::SourceTrack;
+use crate::{AbsCaptureTime, OpusParams, ABS_CAPTURE_TIME_URI};
+
+// frames arrive in bursts, one input buffer at a time. if the gap between two frames exceeds the
+// frame duration by more than this, the source was paused
+const MAX_FRAME_GAP: Duration = Duration::from_millis(100);
+
+// how often the packetizer checks whether the track has a sender. webrtc-rs has no event for a
+// sender being attached or detached
+const SENDER_POLL_INTERVAL: Duration = Duration::from_millis(100);
 
 pub struct OpusSource {
     // holding on to the track in case the input device is changed. in that case a new track is needed.
@@ -22,8 +34,82 @@ pub struct OpusSource {
     stream: cpal::Stream,
     // used to cancel the current packetizer when the input device is changed.
     _packetizer_handle: JoinHandle<()>,
+    // shared with the input stream. allows the encoder to be reconfigured during the call
+    framer: Arc<Mutex<OpusFramer>>,
+}
+
+/// settings for an `OpusSource` which aren't part of the codec capability
+#[derive(Debug, Clone)]
+pub struct OpusSourceConfig {
+    /// forces the encoder to the given audio bandwidth, which caps the bitrate. ex: `Narrowband`
+    /// for voice over a poor link. `Auto` lets the encoder choose. can be changed mid-call with
+    /// `OpusSource::set_bandwidth`.
+    /// the remote's maxplaybackrate (see `apply_remote_params`) still applies: a forced
+    /// bandwidth above it is lowered. DTX and FEC work at any bandwidth, but are most effective
+    /// at wideband and below, where the encoder uses its speech (SILK) mode
+    pub bandwidth: opus::Bandwidth,
+    /// the length of audio in each packet. must be one of `OPUS_FRAME_DURATIONS`. the number of
+    /// samples per frame, and the RTP timestamp increment, are derived from it using the codec's
+    /// clock rate. longer frames have less overhead but add latency. the remote's ptime,
+    /// minptime and maxptime (see `apply_remote_params`) replace it with the closest duration
+    /// they allow
+    pub frame_duration: Duration,
+    /// the maximum size of an RTP packet in bytes, including the RTP header and header
+    /// extensions. Opus frames can't be split across packets, so the encoder is limited to
+    /// frames which fit. defaults to `DEFAULT_MTU`
+    pub mtu: usize,
+    /// the bitrate the encoder starts at, in bits per second, between `MIN_OPUS_BITRATE` and
+    /// `MAX_OPUS_BITRATE`. None lets libopus pick one from the sample rate and channels.
+    /// webrtc-rs doesn't estimate the bandwidth, so nothing ramps the bitrate up or down: on a
+    /// network known to be good, a high start bitrate gives full quality from the first packet,
+    /// but overshooting on a bad network causes loss from the start of the call. the remote's
+    /// maxaveragebitrate (see `apply_remote_params`) still applies. can be changed mid-call with
+    /// `OpusSource::set_bitrate`
+    pub start_bitrate: Option<u32>,
+    /// what the encoder is tuned for. `Voip` favors the intelligibility of speech, `Audio` the
+    /// fidelity of music, and `LowDelay` drops the speech (SILK) mode, and with it 4ms of
+    /// lookahead, for the lowest latency. can't be changed once the source is created. see
+    /// `OpusSource::application`
+    pub application: opus::Application,
+}
+
+impl Default for OpusSourceConfig {
+    fn default() -> Self {
+        Self {
+            bandwidth: opus::Bandwidth::default(),
+            frame_duration: Duration::from_micros(2500),
+            mtu: DEFAULT_MTU,
+            start_bitrate: None,
+            application: opus::Application::Voip,
+        }
+    }
 }
 
+/// the default `OpusSourceConfig::mtu`. leaves room for the UDP, IP and SRTP overhead within
+/// the smallest common path MTU, avoiding IP fragmentation
+pub const DEFAULT_MTU: usize = 1200;
+
+/// the lowest `OpusSourceConfig::start_bitrate` accepted by libopus, in bits per second
+pub const MIN_OPUS_BITRATE: u32 = 500;
+/// the highest `OpusSourceConfig::start_bitrate` accepted by libopus, in bits per second
+pub const MAX_OPUS_BITRATE: u32 = 512_000;
+
+// the fixed RTP header, without CSRCs
+const RTP_HEADER_LEN: usize = 12;
+// the abs-capture-time extension sent with every packet: a 4 byte extension header and a 9 byte
+// element, padded to a multiple of 4
+const ABS_CAPTURE_TIME_EXT_LEN: usize = 16;
+
+/// the frame durations supported by Opus
+pub const OPUS_FRAME_DURATIONS: [Duration; 6] = [
+    Duration::from_micros(2500),
+    Duration::from_millis(5),
+    Duration::from_millis(10),
+    Duration::from_millis(20),
+    Duration::from_millis(40),
+    Duration::from_millis(60),
+];
+
 impl SourceTrack for OpusSource {
     fn init(
         input_device: cpal::Device,
@@ -33,65 +119,66 @@ impl SourceTrack for OpusSource {
     where
         Self: Sized,
     {
-        // number of samples to send in a RTP packet
-        let frame_size = 120;
-        let sample_rate = codec.clock_rate;
-        let channels = match codec.channels {
-            1 => opus::Channels::Mono,
-            2 => opus::Channels::Stereo,
-            _ => bail!("invalid number of channels"),
-        };
+        Self::init_with_config(input_device, track, codec, OpusSourceConfig::default())
+    }
 
-        // create the ssrc for the RTP packets. ssrc serves to uniquely identify the sender
-        let mut rng = rand::thread_rng();
-        let ssrc: u32 = rng.gen();
-
-        let (producer, mut consumer) = mpsc::unbounded_channel::<Bytes>();
-
-        let mut framer = OpusFramer::init(frame_size, sample_rate, channels)?;
-        let opus = Box::new(rtp::codecs::opus::OpusPayloader {});
-        let seq = Box::new(rtp::sequence::new_random_sequencer());
-
-        let mut packetizer = rtp::packetizer::new_packetizer(
-            // i16 is 2 bytes
-            // frame size is number of i16 samles
-            // 12 is for the header, though there may be an additional 4*csrc bytes in the header.
-            (frame_size * 2 + 12) as usize,
-            // payload type means nothing
-            // https://en.wikipedia.org/wiki/RTP_payload_formats
-            // todo: use an enum for this
-            98,
-            // randomly generated and uniquely identifies the source
-            ssrc,
-            opus,
-            seq,
+    fn play(&self) -> Result<()> {
+        if let Err(e) = self.stream.play() {
+            return Err(e.into());
+        }
+        Ok(())
+    }
+    fn pause(&self) -> Result<()> {
+        if let Err(e) = self.stream.pause() {
+            return Err(e.into());
+        }
+        Ok(())
+    }
+    // should not require RTP renegotiation
+    fn change_input_device(&mut self, _input_device: cpal::Device) {
+        todo!()
+    }
+}
+
+impl OpusSource {
+    /// same as `SourceTrack::init`, with settings for the encoder
+    pub fn init_with_config(
+        input_device: cpal::Device,
+        track: Arc<TrackLocalStaticRTP>,
+        codec: RTCRtpCodecCapability,
+        config: OpusSourceConfig,
+    ) -> Result<Self> {
+        let sample_rate = codec.clock_rate;
+        let frame_duration = config.frame_duration;
+        let frame_size = opus_frame_size(sample_rate, frame_duration)?;
+        let framer = OpusFramer::from_config(frame_size, &codec, &config)?;
+        let framer = Arc::new(Mutex::new(framer));
+        let (producer, join_handle) = spawn_packetizer(
+            track.clone(),
             sample_rate,
+            config.mtu,
+            red_encoder(&codec, config.mtu),
         );
 
-        // todo: when the input device changes, this needs to change too.
-        let track2 = track.clone();
-        let join_handle = tokio::spawn(async move {
-            while let Some(bytes) = consumer.recv().await {
-                // todo: figure out how many samples were actually created
-                match packetizer.packetize(&bytes, frame_size as u32).await {
-                    Ok(packets) => {
-                        for packet in &packets {
-                            if let Err(e) = track2.write_rtp(packet).await {
-                                log::error!("failed to send RTP packet: {}", e);
-                            }
-                        }
-                    }
-                    Err(e) => {
-                        log::error!("failed to packetize for opus: {}", e);
-                    }
-                }
-            }
-            log::debug!("SourceTrack packetizer thread quitting");
-        });
+        let framer2 = framer.clone();
         let input_data_fn = move |data: &[i16], _: &cpal::InputCallbackInfo| {
+            // nothing would send the frames, ex: after the only peer hung up
+            if !producer.has_sender() {
+                return;
+            }
+            let mut framer = match framer2.lock() {
+                Ok(f) => f,
+                Err(e) => {
+                    log::error!("SourceTrack failed to lock framer: {}", e);
+                    return;
+                }
+            };
             for sample in data {
                 if let Some(bytes) = framer.frame(*sample) {
-                    if let Err(e) = producer.send(bytes) {
+                    // the frame started capturing one frame duration ago
+                    let frame_duration = framer.frame_duration();
+                    let capture_time = SystemTime::now() - frame_duration;
+                    if let Err(e) = producer.send((bytes, capture_time, frame_duration)) {
                         log::error!("SourceTrack failed to send sample: {}", e);
                     }
                 }
@@ -107,21 +194,234 @@ impl SourceTrack for OpusSource {
             _device: input_device,
             stream: input_stream,
             _packetizer_handle: join_handle,
+            framer,
         })
     }
 
-    fn play(&self) -> Result<()> {
-        if let Err(e) = self.stream.play() {
-            return Err(e.into());
+    /// configures the encoder to match the parameters the remote accepted during negotiation.
+    /// see `Controller::remote_opus_params` and `EmittedEvents::RemoteOpusParams`
+    pub fn apply_remote_params(&self, params: &OpusParams) -> Result<()> {
+        let mut framer = match self.framer.lock() {
+            Ok(f) => f,
+            Err(e) => bail!("failed to lock framer: {}", e),
+        };
+        framer.apply_remote_params(params)
+    }
+
+    /// forces the audio bandwidth, with immediate effect. see `OpusSourceConfig::bandwidth`
+    pub fn set_bandwidth(&self, bandwidth: opus::Bandwidth) -> Result<()> {
+        match self.framer.lock() {
+            Ok(mut f) => f.set_bandwidth(bandwidth),
+            Err(e) => bail!("failed to lock framer: {}", e),
+        }
+    }
+
+    pub fn bandwidth(&self) -> Result<opus::Bandwidth> {
+        match self.framer.lock() {
+            Ok(f) => Ok(f.bandwidth()),
+            Err(e) => bail!("failed to lock framer: {}", e),
+        }
+    }
+
+    /// the encoder's application, as reported by libopus. see `OpusSourceConfig::application`
+    pub fn application(&self) -> Result<opus::Application> {
+        match self.framer.lock() {
+            Ok(mut f) => f.application(),
+            Err(e) => bail!("failed to lock framer: {}", e),
+        }
+    }
+
+    /// sets the encoder's bitrate, with immediate effect, ex: to lower it when
+    /// `EmittedEvents::StatsUpdated` reports loss. None lets libopus pick. see
+    /// `OpusSourceConfig::start_bitrate`
+    pub fn set_bitrate(&self, bitrate: Option<u32>) -> Result<()> {
+        match self.framer.lock() {
+            Ok(mut f) => f.set_bitrate(bitrate),
+            Err(e) => bail!("failed to lock framer: {}", e),
+        }
+    }
+
+    /// tells the encoder what percentage of packets (0 to 100) are expected to be lost, which
+    /// sets how much of the bitrate goes to in-band FEC. only has an effect if the remote
+    /// accepted FEC (see `apply_remote_params`). to adapt to the network, call this with the
+    /// `loss` reported by `EmittedEvents::StatsUpdated`, ex: `(loss * 100.0).round() as u8`
+    pub fn set_expected_packet_loss(&self, percent: u8) -> Result<()> {
+        match self.framer.lock() {
+            Ok(mut f) => f.set_expected_packet_loss(percent),
+            Err(e) => bail!("failed to lock framer: {}", e),
+        }
+    }
+}
+
+/// the number of samples in a frame of the given duration. fails if the duration isn't one of
+/// `OPUS_FRAME_DURATIONS`
+pub(crate) fn opus_frame_size(sample_rate: u32, frame_duration: Duration) -> Result<usize> {
+    if !OPUS_FRAME_DURATIONS.contains(&frame_duration) {
+        bail!("invalid frame duration for opus: {:?}", frame_duration);
+    }
+    Ok((sample_rate as u128 * frame_duration.as_micros() / 1_000_000) as usize)
+}
+
+/// the frame duration to use for a remote which sent `params`: the longest Opus frame duration
+/// up to its ptime, within its minptime and maxptime. `current` replaces the ptime if it has none.
+/// the shortest duration if none fits
+fn negotiated_frame_duration(current: Duration, params: &OpusParams) -> Duration {
+    let mut wanted = params.ptime.unwrap_or(current);
+    if let Some(min) = params.min_ptime {
+        wanted = wanted.max(min);
+    }
+    if let Some(max) = params.max_ptime {
+        wanted = wanted.min(max);
+    }
+    OPUS_FRAME_DURATIONS
+        .iter()
+        .rev()
+        .find(|d| **d <= wanted)
+        .copied()
+        .unwrap_or(OPUS_FRAME_DURATIONS[0])
+}
+
+/// a RED encoder if the codec is RED. see `ControllerConfig::red`
+pub(crate) fn red_encoder(codec: &RTCRtpCodecCapability, mtu: usize) -> Option<RedEncoder> {
+    if !is_red(codec) {
+        return None;
+    }
+    let max_payload = mtu.saturating_sub(RTP_HEADER_LEN + ABS_CAPTURE_TIME_EXT_LEN);
+    Some(RedEncoder::new(codec, max_payload))
+}
+
+/// sends encoded frames to the packetizer. see `spawn_packetizer`
+pub(crate) struct FrameProducer {
+    frames: mpsc::UnboundedSender<(Bytes, SystemTime, Duration)>,
+    sending: Arc<AtomicBool>,
+}
+
+impl FrameProducer {
+    /// sends a frame with its capture time and duration. fails if the packetizer quit
+    pub(crate) fn send(&self, frame: (Bytes, SystemTime, Duration)) -> Result<()> {
+        if self.frames.send(frame).is_err() {
+            bail!("the packetizer quit");
         }
         Ok(())
     }
-    // should not require RTP renegotiation
-    fn change_input_device(&mut self, _input_device: cpal::Device) {
-        todo!()
+
+    /// false while the track has no active sender, ex: before the first peer connects or after
+    /// every peer hung up. the sources don't encode until a peer is attached to the track again
+    pub(crate) fn has_sender(&self) -> bool {
+        self.sending.load(Ordering::Relaxed)
     }
 }
 
+/// spawns a task which packetizes the encoded frames sent to the returned channel, each with its
+/// capture time and duration, and writes them to the track. the task ends when the sender is
+/// dropped. if `red` is set, the packets are wrapped in RED
+pub(crate) fn spawn_packetizer(
+    track: Arc<TrackLocalStaticRTP>,
+    sample_rate: u32,
+    mtu: usize,
+    mut red: Option<RedEncoder>,
+) -> (FrameProducer, JoinHandle<()>) {
+    // create the ssrc for the RTP packets. ssrc serves to uniquely identify the sender
+    let mut rng = rand::thread_rng();
+    let ssrc: u32 = rng.gen();
+
+    // each frame is sent with its capture time and duration. the duration changes if the remote
+    // asks for another ptime
+    let (producer, mut consumer) = mpsc::unbounded_channel::<(Bytes, SystemTime, Duration)>();
+
+    let opus = Box::new(rtp::codecs::opus::OpusPayloader {});
+    let seq = Box::new(rtp::sequence::new_random_sequencer());
+
+    let mut packetizer = rtp::packetizer::new_packetizer(
+        // the packetizer subtracts the RTP header. the track adds the header extensions
+        // afterwards, and the encoder's output was limited to leave room for them
+        mtu,
+        // payload type means nothing
+        // https://en.wikipedia.org/wiki/RTP_payload_formats
+        // todo: use an enum for this
+        98,
+        // randomly generated and uniquely identifies the source
+        ssrc,
+        opus,
+        seq,
+        sample_rate,
+    );
+
+    let sending = Arc::new(AtomicBool::new(false));
+    let sending2 = sending.clone();
+
+    // todo: when the input device changes, this needs to change too.
+    let join_handle = tokio::spawn(async move {
+        let mut last_capture_time: Option<SystemTime> = None;
+        let mut sender_poll = tokio::time::interval(SENDER_POLL_INTERVAL);
+        loop {
+            let (bytes, capture_time, frame_duration) = tokio::select! {
+                frame = consumer.recv() => match frame {
+                    Some(frame) => frame,
+                    None => break,
+                },
+                _ = sender_poll.tick() => {
+                    // true if there are no bindings, or if every sender is paused
+                    let has_sender = !track.all_binding_paused().await;
+                    if sending2.swap(has_sender, Ordering::Relaxed) != has_sender {
+                        if has_sender {
+                            log::debug!("SourceTrack resuming encoding: the track has a sender");
+                        } else {
+                            log::debug!("SourceTrack pausing encoding: the track has no sender");
+                        }
+                    }
+                    continue;
+                }
+            };
+            let frame_size = (sample_rate as u128 * frame_duration.as_micros() / 1_000_000) as u32;
+            // after a pause (ex: while muted) no packets are sent. the sequence numbers stay
+            // contiguous, which keeps the remote's SRTP rollover counter in sync, but the
+            // RTP timestamp has to jump by the length of the pause or the remote's jitter
+            // buffer will think the resumed audio is late.
+            let skipped_frames = last_capture_time
+                .and_then(|last| capture_time.duration_since(last).ok())
+                .filter(|gap| *gap > frame_duration + MAX_FRAME_GAP)
+                .map(|gap| (gap.as_secs_f64() / frame_duration.as_secs_f64()) as u32 - 1);
+            last_capture_time = Some(capture_time);
+            if let Some(frames) = skipped_frames {
+                log::debug!("SourceTrack resuming after {} skipped frames", frames);
+                packetizer.skip_samples(frames.saturating_mul(frame_size));
+            }
+
+            // todo: figure out how many samples were actually created
+            match packetizer.packetize(&bytes, frame_size).await {
+                Ok(packets) => {
+                    for mut packet in packets {
+                        if let Some(red) = red.as_mut() {
+                            red.encode(&mut packet);
+                        }
+                        // the track sets the extension id negotiated with each peer
+                        let abs_capture_time = HeaderExtension::Custom {
+                            uri: ABS_CAPTURE_TIME_URI.into(),
+                            extension: Box::new(AbsCaptureTime::new(capture_time)),
+                        };
+                        if let Err(e) = track
+                            .write_rtp_with_extensions(&packet, &[abs_capture_time])
+                            .await
+                        {
+                            log::error!("failed to send RTP packet: {}", e);
+                        }
+                    }
+                }
+                Err(e) => {
+                    log::error!("failed to packetize for opus: {}", e);
+                }
+            }
+        }
+        log::debug!("SourceTrack packetizer thread quitting");
+    });
+    let producer = FrameProducer {
+        frames: producer,
+        sending,
+    };
+    (producer, join_handle)
+}
+
 pub struct OpusFramer {
     // encodes groups of samples (frames)
     encoder: opus::Encoder,
@@ -131,14 +431,20 @@ pub struct OpusFramer {
     opus_out: Vec<u8>,
     // number of samples in a frame
     frame_size: usize,
+    sample_rate: u32,
+    // the size of the largest packet which fits in the MTU
+    max_payload: usize,
+    // the forced bandwidth. the encoder only reports the bandwidth of the last packet
+    bandwidth: opus::Bandwidth,
+    // the bitrate set by the user, and the remote's maxaveragebitrate, which bounds it
+    bitrate: Option<u32>,
+    max_bitrate: Option<u32>,
 }
 
 impl OpusFramer {
     pub fn init(frame_size: usize, sample_rate: u32, channels: opus::Channels) -> Result<Self> {
-        let mut buf = Vec::new();
-        buf.reserve(frame_size as usize);
-        let mut opus_out = Vec::new();
-        opus_out.resize(frame_size, 0);
+        let buf = Vec::with_capacity(frame_size);
+        let opus_out = vec![0; frame_size];
         let encoder = opus::Encoder::new(sample_rate, channels, opus::Application::Voip)?;
 
         Ok(Self {
@@ -146,18 +452,140 @@ impl OpusFramer {
             raw_samples: buf,
             opus_out,
             frame_size,
+            sample_rate,
+            max_payload: usize::MAX,
+            bandwidth: opus::Bandwidth::Auto,
+            bitrate: None,
+            max_bitrate: None,
         })
     }
 
+    /// an encoder for the codec's channels, with the config's bandwidth
+    pub(crate) fn from_config(
+        frame_size: usize,
+        codec: &RTCRtpCodecCapability,
+        config: &OpusSourceConfig,
+    ) -> Result<Self> {
+        let channels = match codec.channels {
+            1 => opus::Channels::Mono,
+            2 => opus::Channels::Stereo,
+            _ => bail!("invalid number of channels"),
+        };
+        // RED adds a header to every packet
+        let overhead = if is_red(codec) { RED_OVERHEAD } else { 0 };
+        let max_payload = match config
+            .mtu
+            .checked_sub(RTP_HEADER_LEN + ABS_CAPTURE_TIME_EXT_LEN + overhead)
+        {
+            Some(m) if m > 0 => m,
+            _ => bail!("mtu is too small: {}", config.mtu),
+        };
+        let mut framer = Self::init(frame_size, codec.clock_rate, channels)?;
+        // libopus only allows switching to LowDelay before the first frame is encoded
+        framer.encoder.set_application(config.application)?;
+        framer.set_bandwidth(config.bandwidth)?;
+        framer.set_bitrate(config.start_bitrate)?;
+        // the encoder lowers the bitrate of a frame to fit in the output buffer
+        framer.max_payload = max_payload;
+        framer.opus_out.truncate(max_payload);
+        Ok(framer)
+    }
+
+    pub fn apply_remote_params(&mut self, params: &OpusParams) -> Result<()> {
+        // if the remote didn't ask for stereo, don't send it.
+        let channels = if params.stereo {
+            None
+        } else {
+            Some(opus::Channels::Mono)
+        };
+        self.encoder.set_force_channels(channels)?;
+        self.encoder.set_inband_fec(params.use_inband_fec)?;
+        self.encoder.set_dtx(params.use_dtx)?;
+        let frame_duration = negotiated_frame_duration(self.frame_duration(), params);
+        if frame_duration != self.frame_duration() {
+            log::debug!("using the remote's frame duration: {:?}", frame_duration);
+            self.set_frame_duration(frame_duration)?;
+        }
+        self.max_bitrate = params.max_average_bitrate;
+        self.apply_bitrate()?;
+        if let Some(rate) = params.max_playback_rate {
+            let bandwidth = match rate {
+                0..=8000 => opus::Bandwidth::Narrowband,
+                8001..=12000 => opus::Bandwidth::Mediumband,
+                12001..=16000 => opus::Bandwidth::Wideband,
+                16001..=24000 => opus::Bandwidth::Superwideband,
+                _ => opus::Bandwidth::Fullband,
+            };
+            self.encoder.set_max_bandwidth(bandwidth)?;
+        }
+        Ok(())
+    }
+
+    /// changes the length of the frames, starting with the frame being built. must be one of
+    /// `OPUS_FRAME_DURATIONS`
+    pub fn set_frame_duration(&mut self, frame_duration: Duration) -> Result<()> {
+        self.frame_size = opus_frame_size(self.sample_rate, frame_duration)?;
+        self.opus_out
+            .resize(self.frame_size.min(self.max_payload), 0);
+        Ok(())
+    }
+
+    pub fn frame_duration(&self) -> Duration {
+        Duration::from_micros((self.frame_size as u64 * 1_000_000) / self.sample_rate as u64)
+    }
+
+    pub fn set_bandwidth(&mut self, bandwidth: opus::Bandwidth) -> Result<()> {
+        self.encoder.set_bandwidth(bandwidth)?;
+        self.bandwidth = bandwidth;
+        Ok(())
+    }
+
+    pub fn bandwidth(&self) -> opus::Bandwidth {
+        self.bandwidth
+    }
+
+    pub fn application(&mut self) -> Result<opus::Application> {
+        Ok(self.encoder.get_application()?)
+    }
+
+    pub fn set_bitrate(&mut self, bitrate: Option<u32>) -> Result<()> {
+        if let Some(b) = bitrate {
+            if !(MIN_OPUS_BITRATE..=MAX_OPUS_BITRATE).contains(&b) {
+                bail!("invalid bitrate for opus: {}", b);
+            }
+        }
+        self.bitrate = bitrate;
+        self.apply_bitrate()
+    }
+
+    fn apply_bitrate(&mut self) -> Result<()> {
+        let bitrate = match (self.bitrate, self.max_bitrate) {
+            (Some(b), Some(max)) => opus::Bitrate::Bits(b.min(max) as i32),
+            (Some(b), None) | (None, Some(b)) => opus::Bitrate::Bits(b as i32),
+            (None, None) => opus::Bitrate::Auto,
+        };
+        self.encoder.set_bitrate(bitrate)?;
+        Ok(())
+    }
+
+    pub fn set_expected_packet_loss(&mut self, percent: u8) -> Result<()> {
+        if percent > 100 {
+            bail!("invalid packet loss percentage: {}", percent);
+        }
+        self.encoder.set_packet_loss_perc(percent as i32)?;
+        Ok(())
+    }
+
     pub fn frame(&mut self, sample: i16) -> Option<Bytes> {
         self.raw_samples.push(sample);
-        if self.raw_samples.len() == self.frame_size {
+        // more than a frame is buffered if the frame size was lowered
+        if self.raw_samples.len() >= self.frame_size {
             match self.encoder.encode(
-                self.raw_samples.as_mut_slice(),
+                &self.raw_samples[..self.frame_size],
                 self.opus_out.as_mut_slice(),
             ) {
                 Ok(size) => {
-                    self.raw_samples.clear();
+                    self.raw_samples.drain(..self.frame_size);
                     let slice = self.opus_out.as_slice();
                     let bytes = bytes::Bytes::copy_from_slice(&slice[0..size]);
                     Some(bytes)
@@ -176,3 +604,326 @@ impl OpusFramer {
 fn err_fn(err: cpal::StreamError) {
     log::error!("an error occurred on stream: {}", err);
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::loopback::{wait_for_event, Loopback, A, B};
+    use crate::EmittedEvents;
+    use webrtc::track::track_remote::TrackRemote;
+    use webrtc::util::MarshalSize;
+
+    const TIMEOUT: Duration = Duration::from_secs(10);
+
+    fn opus(channels: u16) -> RTCRtpCodecCapability {
+        RTCRtpCodecCapability {
+            mime_type: "audio/opus".into(),
+            clock_rate: 48000,
+            channels,
+            ..Default::default()
+        }
+    }
+
+    /// encodes `frames` frames of a loud signal with content up to 20kHz, and returns the
+    /// packets
+    fn encode(framer: &mut OpusFramer, frames: usize) -> Vec<Bytes> {
+        let mut rng = rand::thread_rng();
+        let samples = frames * framer.frame_size;
+        (0..samples)
+            .filter_map(|_| framer.frame(rng.gen_range(-16000..16000)))
+            .collect()
+    }
+
+    /// the audio bandwidth of the packets, after the encoder has settled
+    fn packet_bandwidths(packets: &[Bytes]) -> Vec<opus::Bandwidth> {
+        packets
+            .iter()
+            .skip(5)
+            .map(|p| opus::packet::get_bandwidth(p).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn from_config_forces_the_bandwidth() -> Result<()> {
+        let config = OpusSourceConfig {
+            bandwidth: opus::Bandwidth::Narrowband,
+            ..Default::default()
+        };
+        let mut framer = OpusFramer::from_config(960, &opus(1), &config)?;
+        assert_eq!(framer.bandwidth(), opus::Bandwidth::Narrowband);
+        let bandwidths = packet_bandwidths(&encode(&mut framer, 20));
+        assert!(!bandwidths.is_empty());
+        assert!(bandwidths.iter().all(|b| *b == opus::Bandwidth::Narrowband));
+        Ok(())
+    }
+
+    #[test]
+    fn set_bandwidth_applies_to_the_next_frames() -> Result<()> {
+        let config = OpusSourceConfig {
+            bandwidth: opus::Bandwidth::Narrowband,
+            start_bitrate: Some(64000),
+            ..Default::default()
+        };
+        let mut framer = OpusFramer::from_config(960, &opus(1), &config)?;
+        encode(&mut framer, 10);
+        framer.set_bandwidth(opus::Bandwidth::Fullband)?;
+        assert_eq!(framer.bandwidth(), opus::Bandwidth::Fullband);
+        let bandwidths = packet_bandwidths(&encode(&mut framer, 20));
+        assert!(bandwidths.iter().all(|b| *b == opus::Bandwidth::Fullband));
+        Ok(())
+    }
+
+    /// reads the next packet's sequence number, timestamp and payload
+    async fn read(track: &TrackRemote) -> Result<(u16, u32, Bytes)> {
+        let (packet, _) = tokio::time::timeout(TIMEOUT, track.read_rtp()).await??;
+        Ok((
+            packet.header.sequence_number,
+            packet.header.timestamp,
+            packet.payload,
+        ))
+    }
+
+    #[tokio::test]
+    async fn the_remote_decodes_the_audio_resumed_after_a_long_pause() -> Result<()> {
+        const PAUSE: Duration = Duration::from_secs(3600);
+        let mut call = Loopback::new()?;
+        call.call().await?;
+        let track = call
+            .a
+            .lock()
+            .await
+            .add_media_source("mic".into(), opus(2))
+            .await?;
+        let (producer, _packetizer) = spawn_packetizer(track, 48000, DEFAULT_MTU, None);
+        let resumed = Arc::new(AtomicBool::new(false));
+        let resumed2 = resumed.clone();
+        // the pause is simulated by the capture times, so that the test doesn't wait for it
+        let writer = tokio::spawn(async move {
+            let frame_duration = Duration::from_millis(20);
+            let mut framer = OpusFramer::from_config(960, &opus(1), &Default::default()).unwrap();
+            let mut capture_time = SystemTime::now();
+            let mut interval = tokio::time::interval(frame_duration);
+            let mut paused = false;
+            loop {
+                interval.tick().await;
+                if !paused && resumed2.load(Ordering::Relaxed) {
+                    paused = true;
+                    capture_time += PAUSE;
+                }
+                for frame in encode(&mut framer, 1) {
+                    producer
+                        .send((frame, capture_time, frame_duration))
+                        .unwrap();
+                }
+                capture_time += frame_duration;
+            }
+        });
+
+        let remote = wait_for_event(&mut call.b_events, TIMEOUT, |event| match event {
+            EmittedEvents::TrackAdded { peer, track } if peer == A => Some(track),
+            _ => None,
+        })
+        .await?;
+        let mut last = read(&remote).await?;
+        for _ in 0..10 {
+            let next = read(&remote).await?;
+            assert_eq!(next.0, last.0.wrapping_add(1));
+            assert_eq!(next.1, last.1.wrapping_add(960));
+            last = next;
+        }
+
+        resumed.store(true, Ordering::Relaxed);
+        // the first packet which doesn't follow on from the previous one was captured after the
+        // pause. the packets already queued arrive first
+        let mut queued = 0;
+        let mut next = read(&remote).await?;
+        while next.1 == last.1.wrapping_add(960) {
+            assert_eq!(next.0, last.0.wrapping_add(1));
+            queued += 1;
+            assert!(queued < 50, "the timestamp didn't jump");
+            last = next;
+            next = read(&remote).await?;
+        }
+        // the sequence numbers stay contiguous, which keeps SRTP in sync, and the timestamp
+        // jumps by the length of the pause, give or take a frame
+        assert_eq!(next.0, last.0.wrapping_add(1));
+        let pause = (PAUSE.as_secs() * 48000) as u32;
+        let elapsed = next.1.wrapping_sub(last.1);
+        assert!(elapsed.abs_diff(pause) <= 960 * 2, "{}", elapsed);
+
+        let mut decoder = opus::Decoder::new(48000, opus::Channels::Mono)?;
+        let mut output = vec![0i16; 960];
+        for _ in 0..10 {
+            assert_eq!(decoder.decode(&next.2, &mut output, false)?, 960);
+            last = next;
+            next = read(&remote).await?;
+            assert_eq!(next.0, last.0.wrapping_add(1));
+            assert_eq!(next.1, last.1.wrapping_add(960));
+        }
+        writer.abort();
+        Ok(())
+    }
+
+    /// 60ms frames at the highest bitrate, which are several kilobytes without a limit
+    fn large_frames(mtu: usize) -> OpusSourceConfig {
+        OpusSourceConfig {
+            frame_duration: Duration::from_millis(60),
+            start_bitrate: Some(MAX_OPUS_BITRATE),
+            mtu,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn from_config_limits_the_frames_to_the_mtu() -> Result<()> {
+        let headers = RTP_HEADER_LEN + ABS_CAPTURE_TIME_EXT_LEN;
+        let mut framer = OpusFramer::from_config(2880, &opus(1), &large_frames(200))?;
+        let frames = encode(&mut framer, 20);
+        assert_eq!(frames.len(), 20);
+        assert!(frames.iter().all(|f| f.len() + headers <= 200));
+
+        let mut framer = OpusFramer::from_config(2880, &opus(1), &large_frames(DEFAULT_MTU))?;
+        assert!(encode(&mut framer, 20)
+            .iter()
+            .any(|f| f.len() + headers > 200));
+        Ok(())
+    }
+
+    #[test]
+    fn from_config_rejects_an_mtu_without_room_for_a_frame() {
+        let headers = RTP_HEADER_LEN + ABS_CAPTURE_TIME_EXT_LEN;
+        assert!(OpusFramer::from_config(2880, &opus(1), &large_frames(headers)).is_err());
+        assert!(OpusFramer::from_config(2880, &opus(1), &large_frames(headers + 1)).is_ok());
+    }
+
+    #[tokio::test]
+    async fn the_packets_sent_fit_in_the_mtu() -> Result<()> {
+        const MTU: usize = 200;
+        let mut call = Loopback::new()?;
+        call.call().await?;
+        let track = call
+            .a
+            .lock()
+            .await
+            .add_media_source("mic".into(), opus(2))
+            .await?;
+        let (producer, _packetizer) = spawn_packetizer(track, 48000, MTU, None);
+        let writer = tokio::spawn(async move {
+            let config = large_frames(MTU);
+            let mut framer = OpusFramer::from_config(2880, &opus(1), &config).unwrap();
+            let mut interval = tokio::time::interval(config.frame_duration);
+            loop {
+                interval.tick().await;
+                for frame in encode(&mut framer, 1) {
+                    let capture_time = SystemTime::now();
+                    producer
+                        .send((frame, capture_time, config.frame_duration))
+                        .unwrap();
+                }
+            }
+        });
+
+        let remote = wait_for_event(&mut call.b_events, TIMEOUT, |event| match event {
+            EmittedEvents::TrackAdded { peer, track } if peer == A => Some(track),
+            _ => None,
+        })
+        .await?;
+        for _ in 0..10 {
+            let (packet, _) = tokio::time::timeout(TIMEOUT, remote.read_rtp()).await??;
+            assert!(packet.marshal_size() <= MTU, "{}", packet.marshal_size());
+        }
+        writer.abort();
+        Ok(())
+    }
+
+    fn ptimes(ptime: Option<u64>, min_ptime: Option<u64>, max_ptime: Option<u64>) -> OpusParams {
+        OpusParams {
+            ptime: ptime.map(Duration::from_millis),
+            min_ptime: min_ptime.map(Duration::from_millis),
+            max_ptime: max_ptime.map(Duration::from_millis),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn the_frame_duration_follows_the_remote_ptime() {
+        let current = Duration::from_millis(20);
+        let negotiated = |params| negotiated_frame_duration(current, &params);
+        assert_eq!(negotiated(ptimes(None, None, None)), current);
+        assert_eq!(
+            negotiated(ptimes(Some(40), None, None)),
+            Duration::from_millis(40)
+        );
+        // rounded down to a duration Opus supports
+        assert_eq!(
+            negotiated(ptimes(Some(30), None, None)),
+            Duration::from_millis(20)
+        );
+        assert_eq!(
+            negotiated(ptimes(Some(1), None, None)),
+            Duration::from_micros(2500)
+        );
+        assert_eq!(
+            negotiated(ptimes(Some(120), None, None)),
+            Duration::from_millis(60)
+        );
+    }
+
+    #[test]
+    fn the_frame_duration_stays_within_the_remote_limits() {
+        let current = Duration::from_millis(20);
+        let negotiated = |params| negotiated_frame_duration(current, &params);
+        assert_eq!(
+            negotiated(ptimes(None, Some(40), None)),
+            Duration::from_millis(40)
+        );
+        assert_eq!(
+            negotiated(ptimes(None, None, Some(10))),
+            Duration::from_millis(10)
+        );
+        // maxptime wins over ptime
+        assert_eq!(
+            negotiated(ptimes(Some(60), None, Some(40))),
+            Duration::from_millis(40)
+        );
+        // rounded down within the limit
+        assert_eq!(
+            negotiated(ptimes(None, None, Some(15))),
+            Duration::from_millis(10)
+        );
+    }
+
+    /// waits until `producer.has_sender()` is `expected`
+    async fn wait_for_sender(producer: &FrameProducer, expected: bool) -> Result<()> {
+        let wait = async {
+            while producer.has_sender() != expected {
+                tokio::time::sleep(SENDER_POLL_INTERVAL / 2).await;
+            }
+        };
+        tokio::time::timeout(TIMEOUT, wait).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn encoding_pauses_while_the_track_has_no_sender() -> Result<()> {
+        let mut call = Loopback::new()?;
+        let track = call
+            .a
+            .lock()
+            .await
+            .add_media_source("mic".into(), opus(1))
+            .await?;
+        let (producer, _packetizer) = spawn_packetizer(track, 48000, DEFAULT_MTU, None);
+        // no peer yet
+        tokio::time::sleep(SENDER_POLL_INTERVAL * 2).await;
+        assert!(!producer.has_sender());
+
+        call.call().await?;
+        wait_for_sender(&producer, true).await?;
+        call.a.lock().await.hang_up(&B.into()).await?;
+        wait_for_sender(&producer, false).await?;
+        // resumes once a peer is attached again
+        call.call().await?;
+        wait_for_sender(&producer, true).await
+    }
+}
@@ -0,0 +1,161 @@
+use anyhow::{bail, Result};
+use bytes::{BufMut, Bytes, BytesMut};
+use std::collections::VecDeque;
+use webrtc::rtp::packet::Packet;
+use webrtc::rtp_transceiver::rtp_codec::RTCRtpCodecCapability;
+
+use crate::{MimeType, OPUS_PAYLOAD_TYPE};
+
+// RFC 2198 section 3: each redundant block has a 4 byte header: F (1 bit, set), the block's
+// payload type (7 bits), its timestamp offset (14 bits) and its length (10 bits). the primary
+// block's header is 1 byte: F (unset) and the payload type.
+const REDUNDANT_HEADER_LEN: usize = 4;
+const PRIMARY_HEADER_LEN: usize = 1;
+const MAX_TIMESTAMP_OFFSET: u32 = (1 << 14) - 1;
+const MAX_BLOCK_LEN: usize = (1 << 10) - 1;
+// the sequence numbers remembered by `RedDecoder`, to tell which packets were lost
+const MAX_SEEN: usize = 64;
+
+/// the bytes RED adds to each packet apart from the redundant frame: the primary block's header.
+/// the encoder's output has to leave room for it
+pub(crate) const RED_OVERHEAD: usize = PRIMARY_HEADER_LEN;
+
+/// true if the codec is RED, ex: `red_codec`
+pub(crate) fn is_red(codec: &RTCRtpCodecCapability) -> bool {
+    matches!(MimeType::from_string(&codec.mime_type), Ok(MimeType::RED))
+}
+
+/// wraps each packet's Opus frame in a RED payload, along with the previous frame if it fits
+pub(crate) struct RedEncoder {
+    // the payload type of the blocks, from the codec's fmtp line, ex: "111/111"
+    block_payload_type: u8,
+    // the largest payload which fits in a packet
+    max_payload: usize,
+    // the previous frame and its timestamp
+    previous: Option<(Bytes, u32)>,
+}
+
+impl RedEncoder {
+    /// `max_payload` is the space left for the RED payload after the RTP header and extensions
+    pub fn new(codec: &RTCRtpCodecCapability, max_payload: usize) -> Self {
+        let block_payload_type = codec
+            .sdp_fmtp_line
+            .split('/')
+            .next()
+            .and_then(|pt| pt.trim().parse().ok())
+            .unwrap_or(OPUS_PAYLOAD_TYPE);
+        Self {
+            block_payload_type,
+            max_payload,
+            previous: None,
+        }
+    }
+
+    pub fn encode(&mut self, packet: &mut Packet) {
+        let primary = packet.payload.clone();
+        let timestamp = packet.header.timestamp;
+        // the previous frame is left out if it's too old (after a pause) or doesn't fit
+        let redundant = self.previous.take().filter(|(frame, prev_timestamp)| {
+            let offset = timestamp.wrapping_sub(*prev_timestamp);
+            offset > 0
+                && offset <= MAX_TIMESTAMP_OFFSET
+                && frame.len() <= MAX_BLOCK_LEN
+                && REDUNDANT_HEADER_LEN + PRIMARY_HEADER_LEN + frame.len() + primary.len()
+                    <= self.max_payload
+        });
+
+        let mut payload = BytesMut::new();
+        if let Some((frame, prev_timestamp)) = &redundant {
+            let offset = timestamp.wrapping_sub(*prev_timestamp);
+            let first = (0x80 | self.block_payload_type) as u32;
+            payload.put_u32((first << 24) | (offset << 10) | frame.len() as u32);
+        }
+        payload.put_u8(self.block_payload_type);
+        if let Some((frame, _)) = &redundant {
+            payload.put_slice(frame);
+        }
+        payload.put_slice(&primary);
+
+        self.previous = Some((primary, timestamp));
+        packet.payload = payload.freeze();
+    }
+}
+
+/// unwraps RED payloads into a packet per block. a redundant block is only returned if the
+/// packet it was copied from was lost; it's given that packet's sequence number, assuming each
+/// packet carries the frame before it, as browsers and `RedEncoder` send
+#[derive(Default)]
+pub(crate) struct RedDecoder {
+    // the sequence numbers of the recently returned packets
+    seen: VecDeque<u16>,
+}
+
+impl RedDecoder {
+    pub fn decode(&mut self, packet: Packet) -> Result<Vec<Packet>> {
+        let payload = &packet.payload;
+        let mut blocks = vec![];
+        let mut pos = 0;
+        loop {
+            let first = match payload.get(pos) {
+                Some(b) => *b,
+                None => bail!("truncated RED header"),
+            };
+            if first & 0x80 == 0 {
+                pos += PRIMARY_HEADER_LEN;
+                break;
+            }
+            let header = match payload.get(pos..pos + REDUNDANT_HEADER_LEN) {
+                Some(h) => u32::from_be_bytes([h[0], h[1], h[2], h[3]]),
+                None => bail!("truncated RED header"),
+            };
+            let offset = (header >> 10) & MAX_TIMESTAMP_OFFSET;
+            let len = (header & MAX_BLOCK_LEN as u32) as usize;
+            blocks.push((offset, len));
+            pos += REDUNDANT_HEADER_LEN;
+        }
+
+        let mut packets = vec![];
+        let num_redundant = blocks.len() as u16;
+        for (idx, (offset, len)) in blocks.into_iter().enumerate() {
+            let data = match payload.get(pos..pos + len) {
+                Some(d) => payload.slice_ref(d),
+                None => bail!("truncated RED block"),
+            };
+            pos += len;
+            let sequence_number = packet
+                .header
+                .sequence_number
+                .wrapping_sub(num_redundant - idx as u16);
+            if len == 0 || self.seen.contains(&sequence_number) {
+                continue;
+            }
+            let mut recovered = Packet {
+                header: packet.header.clone(),
+                payload: data,
+            };
+            recovered.header.sequence_number = sequence_number;
+            recovered.header.timestamp = packet.header.timestamp.wrapping_sub(offset);
+            self.see(sequence_number);
+            packets.push(recovered);
+        }
+
+        let sequence_number = packet.header.sequence_number;
+        if self.seen.contains(&sequence_number) {
+            return Ok(packets);
+        }
+        self.see(sequence_number);
+        let primary = payload.slice(pos..);
+        packets.push(Packet {
+            header: packet.header,
+            payload: primary,
+        });
+        Ok(packets)
+    }
+
+    fn see(&mut self, sequence_number: u16) {
+        if self.seen.len() == MAX_SEEN {
+            self.seen.pop_front();
+        }
+        self.seen.push_back(sequence_number);
+    }
+}
@@ -0,0 +1,111 @@
+use anyhow::{bail, Result};
+use std::f32::consts::PI;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio::task::JoinHandle;
+use webrtc::{
+    rtp_transceiver::rtp_codec::RTCRtpCodecCapability,
+    track::track_local::track_local_static_rtp::TrackLocalStaticRTP,
+};
+
+use super::opus_source::{
+    opus_frame_size, red_encoder, spawn_packetizer, FrameProducer, OpusFramer,
+};
+use super::OpusSourceConfig;
+
+/// sends a sine tone as an Opus track, without an input device. ex: the music for
+/// `Controller::hold`, or a test signal. the tone is sent until the ToneSource is dropped, while
+/// the track has a sender
+pub struct ToneSource {
+    // holding on to the track so that it outlives the packetizer
+    _track: Arc<TrackLocalStaticRTP>,
+    tone_handle: JoinHandle<()>,
+    _packetizer_handle: JoinHandle<()>,
+}
+
+impl Drop for ToneSource {
+    fn drop(&mut self) {
+        // the packetizer stops once the tone task drops its sender
+        self.tone_handle.abort();
+    }
+}
+
+impl ToneSource {
+    /// starts sending a tone of `frequency` Hz to `track`. `volume` is a fraction of full scale,
+    /// from 0 to 1
+    pub fn new(
+        track: Arc<TrackLocalStaticRTP>,
+        codec: RTCRtpCodecCapability,
+        frequency: f32,
+        volume: f32,
+    ) -> Result<Self> {
+        if !(0.0..=1.0).contains(&volume) {
+            bail!("invalid volume: {}", volume);
+        }
+        let sample_rate = codec.clock_rate;
+        if frequency <= 0.0 || frequency >= sample_rate as f32 / 2.0 {
+            bail!("invalid frequency: {}", frequency);
+        }
+        let config = OpusSourceConfig::default();
+        let frame_duration = config.frame_duration;
+        let frame_size = opus_frame_size(sample_rate, frame_duration)?;
+        let framer = OpusFramer::from_config(frame_size, &codec, &config)?;
+        let (producer, packetizer_handle) = spawn_packetizer(
+            track.clone(),
+            sample_rate,
+            config.mtu,
+            red_encoder(&codec, config.mtu),
+        );
+        let tone_handle = tokio::spawn(generate(
+            framer,
+            producer,
+            frame_size,
+            frame_duration,
+            frequency / sample_rate as f32,
+            volume,
+        ));
+
+        Ok(Self {
+            _track: track,
+            tone_handle,
+            _packetizer_handle: packetizer_handle,
+        })
+    }
+}
+
+/// every frame duration, encodes a frame of the tone and sends it to the packetizer, while the
+/// track has a sender. `cycles_per_sample` is the frequency divided by the sample rate
+async fn generate(
+    mut framer: OpusFramer,
+    producer: FrameProducer,
+    frame_size: usize,
+    frame_duration: Duration,
+    cycles_per_sample: f32,
+    volume: f32,
+) {
+    let mut interval = tokio::time::interval(frame_duration);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    // kept within a cycle so that the tone doesn't lose precision over a long call
+    let mut phase = 0.0f32;
+    loop {
+        interval.tick().await;
+        if !producer.has_sender() {
+            continue;
+        }
+        for _ in 0..frame_size {
+            let sample = (phase * 2.0 * PI).sin() * volume * i16::MAX as f32;
+            phase = (phase + cycles_per_sample).fract();
+            if let Some(bytes) = framer.frame(sample as i16) {
+                // the frame started one frame duration ago
+                let capture_time = SystemTime::now() - frame_duration;
+                if producer
+                    .send((bytes, capture_time, frame_duration))
+                    .is_err()
+                {
+                    log::debug!("ToneSource packetizer quit");
+                    return;
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,180 @@
+use anyhow::{bail, Result};
+use bytes::Bytes;
+use std::sync::Arc;
+use tokio::{
+    sync::mpsc::{self, error::TrySendError},
+    task::JoinHandle,
+};
+use webrtc::{
+    media::io::sample_builder::SampleBuilder,
+    rtp::{
+        codecs::{h264::H264Packet, vp8::Vp8Packet, vp9::Vp9Packet},
+        packetizer::Depacketizer,
+    },
+    rtp_transceiver::rtp_codec::RTCRtpCodecCapability,
+    track::track_remote::TrackRemote,
+    util::Unmarshal,
+};
+
+use crate::media::SinkTrack;
+use crate::MimeType;
+
+/// a complete encoded video frame, reassembled from RTP packets
+#[derive(Debug, Clone)]
+pub struct VideoFrame {
+    /// the frame's bitstream: a VP8 or VP9 frame, or H264 NAL units in Annex B format
+    pub data: Bytes,
+    /// true if the frame can be decoded without the previous frames. a decoder which starts
+    /// mid-stream, or which lost a frame, should wait for one
+    pub keyframe: bool,
+    /// the RTP timestamp, in units of the codec's clock rate (90kHz for video)
+    pub timestamp: u32,
+    /// packets were lost before this frame. the following frames may not decode until the next
+    /// keyframe
+    pub packets_lost: bool,
+}
+
+/// reassembles the frames of a remote VP8, VP9 or H264 track and sends them to a channel, without
+/// decoding them. for applications which decode and render video themselves.
+/// if the receiver falls behind and the channel is full, frames are dropped
+pub struct VideoSink {
+    decoder_handle: JoinHandle<()>,
+}
+
+impl Drop for VideoSink {
+    fn drop(&mut self) {
+        // this is a failsafe in case the caller doesn't close the associated TrackRemote
+        self.decoder_handle.abort();
+    }
+}
+
+impl VideoSink {
+    /// starts reading immediately. `play` isn't needed
+    pub fn new(
+        frames: mpsc::Sender<VideoFrame>,
+        track: Arc<TrackRemote>,
+        codec: RTCRtpCodecCapability,
+    ) -> Result<Self> {
+        // number of late packets allowed (for RTP). a video frame spans many packets
+        let max_late = 256;
+        let sample_rate = codec.clock_rate;
+        let decoder_handle = match MimeType::from_string(&codec.mime_type)? {
+            MimeType::VP8 => {
+                let sample_builder =
+                    SampleBuilder::new(max_late, Vp8Packet::default(), sample_rate);
+                spawn_reader(track, sample_builder, vp8_is_keyframe, frames)
+            }
+            MimeType::VP9 => {
+                let sample_builder =
+                    SampleBuilder::new(max_late, Vp9Packet::default(), sample_rate);
+                spawn_reader(track, sample_builder, vp9_is_keyframe, frames)
+            }
+            MimeType::H264 => {
+                let sample_builder =
+                    SampleBuilder::new(max_late, H264Packet::default(), sample_rate);
+                spawn_reader(track, sample_builder, h264_is_keyframe, frames)
+            }
+            _ => bail!("unhandled mime type: {}", &codec.mime_type),
+        };
+        Ok(Self { decoder_handle })
+    }
+}
+
+impl SinkTrack for VideoSink {
+    /// a VideoSink has no output device. use `VideoSink::new` or `create_sink_track`
+    fn init(
+        _output_device: cpal::Device,
+        _track: Arc<TrackRemote>,
+        _codec: RTCRtpCodecCapability,
+    ) -> Result<Self> {
+        bail!("VideoSink requires a channel: use VideoSink::new")
+    }
+    fn play(&self) -> Result<()> {
+        Ok(())
+    }
+    fn change_output_device(&mut self, _output_device: cpal::Device) {
+        log::warn!("VideoSink has no output device");
+    }
+    fn is_decoding(&self) -> bool {
+        !self.decoder_handle.is_finished()
+    }
+}
+
+fn spawn_reader<T>(
+    track: Arc<TrackRemote>,
+    mut sample_builder: SampleBuilder<T>,
+    is_keyframe: fn(&[u8]) -> bool,
+    frames: mpsc::Sender<VideoFrame>,
+) -> JoinHandle<()>
+where
+    T: Depacketizer + Send + 'static,
+{
+    tokio::spawn(async move {
+        let mut b = [0u8; 4096];
+        loop {
+            let siz = match track.read(&mut b).await {
+                Ok((siz, _attr)) => siz,
+                Err(e) => {
+                    log::warn!("closing track: {}", e);
+                    break;
+                }
+            };
+            let mut buf = &b[..siz];
+            let rtp_packet = match webrtc::rtp::packet::Packet::unmarshal(&mut buf) {
+                Ok(r) => r,
+                Err(e) => {
+                    log::error!("unmarshall rtp packet failed: {}", e);
+                    break;
+                }
+            };
+            sample_builder.push(rtp_packet);
+            while let Some(sample) = sample_builder.pop() {
+                let frame = VideoFrame {
+                    keyframe: is_keyframe(&sample.data),
+                    data: sample.data,
+                    timestamp: sample.packet_timestamp,
+                    packets_lost: sample.prev_dropped_packets > 0,
+                };
+                match frames.try_send(frame) {
+                    Ok(_) => {}
+                    Err(TrySendError::Full(_)) => log::warn!("VideoSink receiver fell behind"),
+                    Err(TrySendError::Closed(_)) => {
+                        log::error!("VideoSink receiver was dropped");
+                    }
+                }
+            }
+        }
+        log::debug!("stopping VideoSink thread");
+    })
+}
+
+// RFC 6386 9.1: the lowest bit of the frame tag is 0 for key frames
+pub(crate) fn vp8_is_keyframe(frame: &[u8]) -> bool {
+    frame.first().map(|b| b & 0x01 == 0).unwrap_or(false)
+}
+
+// VP9 bitstream spec 6.2: the uncompressed header starts with a 2 bit frame marker, the profile
+// (with a reserved bit for profile 3) and show_existing_frame, followed by frame_type, which is 0
+// for key frames
+fn vp9_is_keyframe(frame: &[u8]) -> bool {
+    let b = match frame.first() {
+        Some(b) => *b,
+        None => return false,
+    };
+    let profile = ((b >> 5) & 0x01) | ((b >> 3) & 0x02);
+    // the bit after the profile (and the reserved bit)
+    let show_existing_frame = if profile == 3 { 2 } else { 3 };
+    if (b >> show_existing_frame) & 0x01 == 1 {
+        return false;
+    }
+    (b >> (show_existing_frame - 1)) & 0x01 == 0
+}
+
+// a frame which contains an IDR slice (type 5) or a sequence parameter set (type 7) lets the
+// decoder start
+fn h264_is_keyframe(frame: &[u8]) -> bool {
+    frame
+        .windows(4)
+        .filter(|w| w[..3] == [0, 0, 1])
+        .any(|w| matches!(w[3] & 0x1f, 5 | 7))
+}
@@ -0,0 +1,139 @@
+use anyhow::{bail, Result};
+use rand::Rng;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use tokio::{sync::mpsc, task::JoinHandle};
+use webrtc::{
+    rtp::{self, codecs::vp8::Vp8Payloader, packetizer::Packetizer},
+    rtp_transceiver::rtp_codec::RTCRtpCodecCapability,
+    track::track_local::{track_local_static_rtp::TrackLocalStaticRTP, TrackLocalWriter},
+};
+
+use super::video_sink::vp8_is_keyframe;
+use super::{SourceTrack, VideoFrame, DEFAULT_MTU};
+use crate::MimeType;
+
+/// sends encoded VP8 frames, ex: from a camera or screen capture encoder, as a VP8 track. the
+/// frames are read from a channel and packetized without being decoded, so the frames of a
+/// `VideoSink` can be forwarded as they are.
+/// the RTP timestamps advance by the difference between the frames' `timestamp`s, in units of
+/// the codec's clock rate (90kHz). `keyframe` and `packets_lost` are ignored.
+/// sending starts immediately. while paused, frames are dropped, and after resuming they are
+/// dropped until the next keyframe, since the remote can't decode the others. the encoder should
+/// send a keyframe when `EmittedEvents::KeyframeRequested` is received
+pub struct Vp8Source {
+    // holding on to the track so that it outlives the packetizer
+    _track: Arc<TrackLocalStaticRTP>,
+    // see `play` and `pause`
+    playing: Arc<AtomicBool>,
+    packetizer_handle: JoinHandle<()>,
+}
+
+impl Drop for Vp8Source {
+    fn drop(&mut self) {
+        // the packetizer would otherwise run until the sender of the frames is dropped
+        self.packetizer_handle.abort();
+    }
+}
+
+impl Vp8Source {
+    /// starts sending the frames received on `frames` to `track`. the task ends when the sender
+    /// is dropped
+    pub fn new(
+        frames: mpsc::Receiver<VideoFrame>,
+        track: Arc<TrackLocalStaticRTP>,
+        codec: RTCRtpCodecCapability,
+    ) -> Result<Self> {
+        if !matches!(MimeType::from_string(&codec.mime_type)?, MimeType::VP8) {
+            bail!("Vp8Source requires a VP8 codec: {}", &codec.mime_type);
+        }
+        let playing = Arc::new(AtomicBool::new(true));
+        let packetizer_handle = spawn_packetizer(frames, track.clone(), codec, playing.clone());
+        Ok(Self {
+            _track: track,
+            playing,
+            packetizer_handle,
+        })
+    }
+}
+
+impl SourceTrack for Vp8Source {
+    /// a Vp8Source has no input device. use `Vp8Source::new` or `create_source_track`
+    fn init(
+        _input_device: cpal::Device,
+        _track: Arc<TrackLocalStaticRTP>,
+        _codec: RTCRtpCodecCapability,
+    ) -> Result<Self> {
+        bail!("Vp8Source requires a channel: use Vp8Source::new")
+    }
+
+    fn play(&self) -> Result<()> {
+        self.playing.store(true, Ordering::Relaxed);
+        Ok(())
+    }
+    fn pause(&self) -> Result<()> {
+        self.playing.store(false, Ordering::Relaxed);
+        Ok(())
+    }
+    fn change_input_device(&mut self, _input_device: cpal::Device) {
+        log::warn!("Vp8Source has no input device");
+    }
+}
+
+fn spawn_packetizer(
+    mut frames: mpsc::Receiver<VideoFrame>,
+    track: Arc<TrackLocalStaticRTP>,
+    codec: RTCRtpCodecCapability,
+    playing: Arc<AtomicBool>,
+) -> JoinHandle<()> {
+    // randomly generated and uniquely identifies the source
+    let ssrc: u32 = rand::thread_rng().gen();
+    let mut packetizer = rtp::packetizer::new_packetizer(
+        DEFAULT_MTU,
+        // the track sets the payload type negotiated with each peer
+        96,
+        ssrc,
+        Box::new(Vp8Payloader::default()),
+        Box::new(rtp::sequence::new_random_sequencer()),
+        codec.clock_rate,
+    );
+
+    tokio::spawn(async move {
+        let mut last_timestamp: Option<u32> = None;
+        // the remote can't decode a frame without the ones before it, up to a keyframe
+        let mut waiting_for_keyframe = true;
+        while let Some(frame) = frames.recv().await {
+            // the RTP timestamp keeps advancing while frames are dropped
+            if let Some(last) = last_timestamp {
+                packetizer.skip_samples(frame.timestamp.wrapping_sub(last));
+            }
+            last_timestamp = Some(frame.timestamp);
+
+            if !playing.load(Ordering::Relaxed) {
+                waiting_for_keyframe = true;
+                continue;
+            }
+            if waiting_for_keyframe {
+                if !vp8_is_keyframe(&frame.data) {
+                    continue;
+                }
+                waiting_for_keyframe = false;
+            }
+
+            // the timestamp was already advanced to this frame's
+            match packetizer.packetize(&frame.data, 0).await {
+                Ok(packets) => {
+                    for packet in packets {
+                        if let Err(e) = track.write_rtp(&packet).await {
+                            log::error!("failed to send RTP packet: {}", e);
+                        }
+                    }
+                }
+                Err(e) => {
+                    log::error!("failed to packetize for vp8: {}", e);
+                }
+            }
+        }
+        log::debug!("Vp8Source packetizer thread quitting");
+    })
+}
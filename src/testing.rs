@@ -8,10 +8,16 @@ use hyper::client::HttpConnector;
 use serde::{Deserialize, Serialize};
 use std::net::SocketAddr;
 use std::str::FromStr;
+use std::sync::Arc;
 use tokio::sync::{mpsc, Mutex};
 use webrtc::ice_transport::ice_candidate::RTCIceCandidate;
 use webrtc::peer_connection::sdp::session_description::RTCSessionDescription;
 
+use crate::RejectReason;
+
+pub mod loopback;
+pub mod rtp_gen;
+
 // testing
 // simple_webrtc requires signaling to initiate the WebRTC connection and to add/remove tracks
 // a signaling server is provided for development purposes. This will allow the developers to
@@ -19,9 +25,11 @@ use webrtc::peer_connection::sdp::session_description::RTCSessionDescription;
 //
 // Hyper (the web server) doesn't have a good way to share data when the service function
 // isn't a closure so the unboudned channel, used to exchange signaling data, is stored statically.
+// the same goes for the SignalCodec.
 
 lazy_static! {
     static ref SIGNAL_CHAN: Mutex<Option<mpsc::UnboundedSender<PeerSignal>>> = Mutex::new(None);
+    static ref SIGNAL_CODEC: Mutex<Arc<dyn SignalCodec>> = Mutex::new(Arc::new(JsonCodec));
 }
 
 #[derive(Serialize, Deserialize)]
@@ -36,12 +44,93 @@ pub struct SigIce {
     pub ice: RTCIceCandidate,
 }
 
+#[derive(Serialize, Deserialize)]
+pub struct SigReject {
+    pub src: String,
+    pub reason: RejectReason,
+}
+
 pub enum PeerSignal {
     Ice(SigIce),
     Sdp(SigSdp),
     CallInitiated(SigSdp),
     CallTerminated(String),
-    CallRejected(String),
+    CallRejected(SigReject),
+}
+
+/// converts signals to and from the bodies of the signaling requests. both peers must use the
+/// same codec. implement this to use another format, such as protobuf. see `set_signal_codec`
+pub trait SignalCodec: Send + Sync {
+    /// the value of the content-type header
+    fn content_type(&self) -> &'static str;
+    fn encode_sdp(&self, sig: &SigSdp) -> Result<Vec<u8>>;
+    fn decode_sdp(&self, bytes: &[u8]) -> Result<SigSdp>;
+    fn encode_ice(&self, sig: &SigIce) -> Result<Vec<u8>>;
+    fn decode_ice(&self, bytes: &[u8]) -> Result<SigIce>;
+    fn encode_reject(&self, sig: &SigReject) -> Result<Vec<u8>>;
+    fn decode_reject(&self, bytes: &[u8]) -> Result<SigReject>;
+}
+
+/// the default codec. readable, but most of an ICE candidate is field names
+pub struct JsonCodec;
+
+impl SignalCodec for JsonCodec {
+    fn content_type(&self) -> &'static str {
+        "application/json; charset=utf-8"
+    }
+    fn encode_sdp(&self, sig: &SigSdp) -> Result<Vec<u8>> {
+        Ok(serde_json::to_vec(sig)?)
+    }
+    fn decode_sdp(&self, bytes: &[u8]) -> Result<SigSdp> {
+        Ok(serde_json::from_slice(bytes)?)
+    }
+    fn encode_ice(&self, sig: &SigIce) -> Result<Vec<u8>> {
+        Ok(serde_json::to_vec(sig)?)
+    }
+    fn decode_ice(&self, bytes: &[u8]) -> Result<SigIce> {
+        Ok(serde_json::from_slice(bytes)?)
+    }
+    fn encode_reject(&self, sig: &SigReject) -> Result<Vec<u8>> {
+        Ok(serde_json::to_vec(sig)?)
+    }
+    fn decode_reject(&self, bytes: &[u8]) -> Result<SigReject> {
+        Ok(serde_json::from_slice(bytes)?)
+    }
+}
+
+/// a compact binary codec, for mobile clients. requires the `bincode` feature
+#[cfg(feature = "bincode")]
+pub struct BincodeCodec;
+
+#[cfg(feature = "bincode")]
+impl SignalCodec for BincodeCodec {
+    fn content_type(&self) -> &'static str {
+        "application/octet-stream"
+    }
+    fn encode_sdp(&self, sig: &SigSdp) -> Result<Vec<u8>> {
+        Ok(bincode::serialize(sig)?)
+    }
+    fn decode_sdp(&self, bytes: &[u8]) -> Result<SigSdp> {
+        Ok(bincode::deserialize(bytes)?)
+    }
+    fn encode_ice(&self, sig: &SigIce) -> Result<Vec<u8>> {
+        Ok(bincode::serialize(sig)?)
+    }
+    fn decode_ice(&self, bytes: &[u8]) -> Result<SigIce> {
+        Ok(bincode::deserialize(bytes)?)
+    }
+    fn encode_reject(&self, sig: &SigReject) -> Result<Vec<u8>> {
+        Ok(bincode::serialize(sig)?)
+    }
+    fn decode_reject(&self, bytes: &[u8]) -> Result<SigReject> {
+        Ok(bincode::deserialize(bytes)?)
+    }
+}
+
+/// replaces the codec used to send and receive signals. defaults to `JsonCodec`
+pub async fn set_signal_codec(codec: Arc<dyn SignalCodec>) {
+    let mut lock = SIGNAL_CODEC.lock().await;
+    *lock = codec;
 }
 
 /// when a signal is received by the web server, it is transmitted via this channel
@@ -52,32 +141,47 @@ pub async fn set_signal_tx_chan(chan: mpsc::UnboundedSender<PeerSignal>) {
 }
 
 pub async fn send_connect(dest: &str, sig: SigSdp) -> Result<()> {
-    let payload = serde_json::to_string(&sig)?;
-    send_signal(dest, "connect", payload).await
+    let codec = SIGNAL_CODEC.lock().await.clone();
+    let payload = codec.encode_sdp(&sig)?;
+    send_signal(dest, "connect", codec.content_type(), payload).await
 }
 
 pub async fn send_disconnect(remote_host: &str, id: &str) -> Result<()> {
-    send_signal(remote_host, "disconnect", id.into()).await
+    // the payload is just the peer id
+    send_signal(remote_host, "disconnect", "text/plain", id.into()).await
+}
+
+pub async fn send_reject(remote_host: &str, sig: SigReject) -> Result<()> {
+    let codec = SIGNAL_CODEC.lock().await.clone();
+    let payload = codec.encode_reject(&sig)?;
+    send_signal(remote_host, "reject", codec.content_type(), payload).await
 }
 
 pub async fn send_ice_candidate(remote_host: &str, sig: SigIce) -> Result<()> {
-    let payload = serde_json::to_string(&sig)?;
-    send_signal(remote_host, "ice-candidate", payload).await
+    let codec = SIGNAL_CODEC.lock().await.clone();
+    let payload = codec.encode_ice(&sig)?;
+    send_signal(remote_host, "ice-candidate", codec.content_type(), payload).await
 }
 
 pub async fn send_sdp(remote_host: &str, sig: SigSdp) -> Result<()> {
-    let payload = serde_json::to_string(&sig)?;
-    send_signal(remote_host, "sdp", payload).await
+    let codec = SIGNAL_CODEC.lock().await.clone();
+    let payload = codec.encode_sdp(&sig)?;
+    send_signal(remote_host, "sdp", codec.content_type(), payload).await
 }
 
-async fn send_signal(remote_host: &str, route: &str, payload: String) -> Result<()> {
+async fn send_signal(
+    remote_host: &str,
+    route: &str,
+    content_type: &str,
+    payload: Vec<u8>,
+) -> Result<()> {
     let http = HttpConnector::new();
     let client = Client::builder().build::<_, hyper::Body>(http);
 
     let req = match Request::builder()
         .method(Method::POST)
         .uri(format!("http://{}/{}", remote_host, route))
-        .header("content-type", "application/json; charset=utf-8")
+        .header("content-type", content_type)
         .body(Body::from(payload))
     {
         Ok(req) => req,
@@ -113,17 +217,9 @@ async fn remote_handler(req: Request<Body>) -> Result<Response<Body>, hyper::Err
     *response.status_mut() = StatusCode::OK;
     match (req.method(), req.uri().path()) {
         (&Method::POST, "/connect") => {
-            let sig_str = match std::str::from_utf8(&hyper::body::to_bytes(req.into_body()).await?)
-            {
-                Ok(s) => s.to_owned(),
-                Err(err) => {
-                    log::error!(" error parsing payload: {}", err);
-                    *response.status_mut() = StatusCode::BAD_REQUEST;
-                    return Ok(response);
-                }
-            };
-
-            let sig = match serde_json::from_str::<SigSdp>(&sig_str) {
+            let body = hyper::body::to_bytes(req.into_body()).await?;
+            let codec = SIGNAL_CODEC.lock().await.clone();
+            let sig = match codec.decode_sdp(&body) {
                 Ok(s) => s,
                 Err(err) => {
                     log::error!("deserialize error: {}", err);
@@ -162,17 +258,32 @@ async fn remote_handler(req: Request<Body>) -> Result<Response<Body>, hyper::Err
             }
             Ok(response)
         }
-        (&Method::POST, "/sdp") => {
-            let sig_str = match std::str::from_utf8(&hyper::body::to_bytes(req.into_body()).await?)
-            {
-                Ok(s) => s.to_owned(),
+        (&Method::POST, "/reject") => {
+            let body = hyper::body::to_bytes(req.into_body()).await?;
+            let codec = SIGNAL_CODEC.lock().await.clone();
+            let sig = match codec.decode_reject(&body) {
+                Ok(s) => s,
                 Err(err) => {
-                    log::error!(" error parsing payload: {}", err);
+                    log::error!("deserialize error: {}", err);
                     *response.status_mut() = StatusCode::BAD_REQUEST;
                     return Ok(response);
                 }
             };
-            let sig = match serde_json::from_str::<SigSdp>(&sig_str) {
+
+            {
+                let opt = SIGNAL_CHAN.lock().await;
+                if let Some(ch) = &*opt {
+                    if let Err(e) = ch.send(PeerSignal::CallRejected(sig)) {
+                        log::error!("failed to send signal: {}", e);
+                    }
+                }
+            }
+            Ok(response)
+        }
+        (&Method::POST, "/sdp") => {
+            let body = hyper::body::to_bytes(req.into_body()).await?;
+            let codec = SIGNAL_CODEC.lock().await.clone();
+            let sig = match codec.decode_sdp(&body) {
                 Ok(s) => s,
                 Err(err) => {
                     log::error!("deserialize error: {}", err);
@@ -194,17 +305,9 @@ async fn remote_handler(req: Request<Body>) -> Result<Response<Body>, hyper::Err
         // this route was being used in the webrtc offer-answer example
         // without it, no ICE candiates were gathered. perhaps because of intermittent service from Google's STUN server
         (&Method::POST, "/ice-candidate") => {
-            let sig_str = match std::str::from_utf8(&hyper::body::to_bytes(req.into_body()).await?)
-            {
-                Ok(s) => s.to_owned(),
-                Err(err) => {
-                    log::error!(" error parsing payload: {}", err);
-                    *response.status_mut() = StatusCode::BAD_REQUEST;
-                    return Ok(response);
-                }
-            };
-
-            let sig = match serde_json::from_str::<SigIce>(&sig_str) {
+            let body = hyper::body::to_bytes(req.into_body()).await?;
+            let codec = SIGNAL_CODEC.lock().await.clone();
+            let sig = match codec.decode_ice(&body) {
                 Ok(s) => s,
                 Err(err) => {
                     log::error!("deserialize error: {}", err);
@@ -230,3 +333,38 @@ async fn remote_handler(req: Request<Body>) -> Result<Response<Body>, hyper::Err
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reject_round_trip(codec: &dyn SignalCodec) -> Result<()> {
+        let sig = SigReject {
+            src: "a".into(),
+            reason: RejectReason::Busy,
+        };
+        let decoded = codec.decode_reject(&codec.encode_reject(&sig)?)?;
+        assert_eq!(decoded.src, "a");
+        assert_eq!(decoded.reason, RejectReason::Busy);
+        Ok(())
+    }
+
+    #[test]
+    fn json_codec_round_trips_a_rejection() -> Result<()> {
+        reject_round_trip(&JsonCodec)
+    }
+
+    #[cfg(feature = "bincode")]
+    #[test]
+    fn bincode_codec_round_trips_a_rejection() -> Result<()> {
+        reject_round_trip(&BincodeCodec)?;
+        // the body isn't JSON
+        assert!(JsonCodec
+            .decode_reject(&BincodeCodec.encode_reject(&SigReject {
+                src: "a".into(),
+                reason: RejectReason::Error,
+            })?)
+            .is_err());
+        Ok(())
+    }
+}
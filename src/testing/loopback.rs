@@ -0,0 +1,215 @@
+use anyhow::{bail, Result};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{mpsc, Mutex};
+use tokio::task::JoinHandle;
+use webrtc::track::track_local::track_local_static_rtp::TrackLocalStaticRTP;
+use webrtc::track::track_local::TrackLocalWriter;
+
+use crate::testing::rtp_gen::RtpGenerator;
+use crate::{Controller, ControllerConfig, EmittedEvents, InitArgs, MimeType, PeerId};
+
+// connects two Controllers within the process, to test a call without a signaling server: the
+// signaling events emitted by each Controller are passed to the other, the way the application's
+// signaling layer would. the Controllers are locked while a signal is applied, so a test must not
+// hold a lock while it waits for an event.
+
+/// the id of the Controller which dials, by default
+pub const A: &str = "a";
+/// the id of the Controller which answers
+pub const B: &str = "b";
+
+/// how long `Loopback::call` waits for the connection
+pub const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// two Controllers, `A` and `B`, signaling each other. a received call is accepted. every event
+/// is also sent to the Controller's receiver, once it's been applied by the remote
+pub struct Loopback {
+    pub a: Arc<Mutex<Controller>>,
+    pub b: Arc<Mutex<Controller>>,
+    pub a_events: mpsc::UnboundedReceiver<EmittedEvents>,
+    pub b_events: mpsc::UnboundedReceiver<EmittedEvents>,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl Loopback {
+    /// uses the default `ControllerConfig`
+    pub fn new() -> Result<Self> {
+        Self::with_config(ControllerConfig::default(), ControllerConfig::default())
+    }
+
+    pub fn with_config(a_config: ControllerConfig, b_config: ControllerConfig) -> Result<Self> {
+        let (a, a_rx) = init(A, a_config)?;
+        let (b, b_rx) = init(B, b_config)?;
+        let (a_tx, a_events) = mpsc::unbounded_channel();
+        let (b_tx, b_events) = mpsc::unbounded_channel();
+        let tasks = vec![
+            tokio::spawn(forward_signals(A.into(), a_rx, B.into(), b.clone(), a_tx)),
+            tokio::spawn(forward_signals(B.into(), b_rx, A.into(), a.clone(), b_tx)),
+        ];
+        Ok(Self {
+            a,
+            b,
+            a_events,
+            b_events,
+            tasks,
+        })
+    }
+
+    /// `A` dials `B`, then waits until both are connected. the events emitted until then are
+    /// skipped
+    pub async fn call(&mut self) -> Result<()> {
+        self.a.lock().await.dial(&B.into()).await?;
+        wait_for_connected(&mut self.a_events, B).await?;
+        wait_for_connected(&mut self.b_events, A).await
+    }
+}
+
+impl Drop for Loopback {
+    fn drop(&mut self) {
+        for task in &self.tasks {
+            task.abort();
+        }
+    }
+}
+
+/// writes the packets of an `RtpGenerator` to a media source's track in real time, until it's
+/// dropped. a remote's `TrackAdded` is only emitted once packets arrive
+pub struct RtpWriter {
+    task: JoinHandle<()>,
+}
+
+impl RtpWriter {
+    pub fn new(track: Arc<TrackLocalStaticRTP>, mime_type: MimeType) -> Result<Self> {
+        // the track sets the SSRC and payload type of each sender
+        let mut generator = RtpGenerator::new(mime_type, 0, 0)?;
+        let task = tokio::spawn(async move {
+            let mut interval = tokio::time::interval(generator.frame_duration());
+            loop {
+                interval.tick().await;
+                let packets = match generator.next_frame().await {
+                    Ok(packets) => packets,
+                    Err(e) => {
+                        log::error!("failed to generate a frame: {}", e);
+                        return;
+                    }
+                };
+                for packet in packets {
+                    // fails while the track has no sender
+                    let _ = track.write_rtp(&packet).await;
+                }
+            }
+        });
+        Ok(Self { task })
+    }
+}
+
+impl Drop for RtpWriter {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// waits at most `timeout` for an event for which `f` returns Some, skipping the others
+pub async fn wait_for_event<T>(
+    events: &mut mpsc::UnboundedReceiver<EmittedEvents>,
+    timeout: Duration,
+    mut f: impl FnMut(EmittedEvents) -> Option<T>,
+) -> Result<T> {
+    let wait = async {
+        while let Some(event) = events.recv().await {
+            if let Some(found) = f(event) {
+                return Ok(found);
+            }
+        }
+        bail!("the Controller was dropped")
+    };
+    match tokio::time::timeout(timeout, wait).await {
+        Ok(found) => found,
+        Err(_) => bail!("the event wasn't emitted within {:?}", timeout),
+    }
+}
+
+async fn wait_for_connected(
+    events: &mut mpsc::UnboundedReceiver<EmittedEvents>,
+    peer_id: &str,
+) -> Result<()> {
+    wait_for_event(events, CONNECT_TIMEOUT, |event| match event {
+        EmittedEvents::Connected { peer } if peer == peer_id => Some(()),
+        _ => None,
+    })
+    .await
+}
+
+fn init(
+    id: &str,
+    config: ControllerConfig,
+) -> Result<(
+    Arc<Mutex<Controller>>,
+    mpsc::UnboundedReceiver<EmittedEvents>,
+)> {
+    let (tx, rx) = mpsc::unbounded_channel();
+    let mut args = InitArgs::new(id.into(), tx);
+    args.config = config;
+    Ok((Arc::new(Mutex::new(Controller::init(args)?)), rx))
+}
+
+/// the signals sent to other peers are dropped, ex: when a test dials a peer which has no
+/// Controller
+async fn forward_signals(
+    me: PeerId,
+    mut events: mpsc::UnboundedReceiver<EmittedEvents>,
+    remote_id: PeerId,
+    remote: Arc<Mutex<Controller>>,
+    observer: mpsc::UnboundedSender<EmittedEvents>,
+) {
+    while let Some(event) = events.recv().await {
+        let result = match &event {
+            _ if matches!(signal_dest(&event), Some(dest) if *dest != remote_id) => Ok(()),
+            EmittedEvents::CallInitiated { sdp, .. } => {
+                remote.lock().await.accept_call(&me, (**sdp).clone()).await
+            }
+            EmittedEvents::Sdp { sdp, .. } => {
+                remote.lock().await.recv_sdp(&me, (**sdp).clone()).await
+            }
+            EmittedEvents::Ice { candidate, .. } => {
+                remote
+                    .lock()
+                    .await
+                    .recv_ice(&me, (**candidate).clone())
+                    .await
+            }
+            EmittedEvents::IceBatch { candidates, .. } => {
+                remote
+                    .lock()
+                    .await
+                    .recv_ice_batch(&me, candidates.clone())
+                    .await
+            }
+            // the remote emits its own, which mustn't be sent back
+            EmittedEvents::CallTerminated {
+                by_remote: false, ..
+            } => {
+                remote.lock().await.recv_hang_up(&me).await;
+                Ok(())
+            }
+            _ => Ok(()),
+        };
+        if let Err(e) = result {
+            log::error!("failed to forward a signal from {}: {}", me, e);
+        }
+        // the test may not be watching this Controller's events
+        let _ = observer.send(event);
+    }
+}
+
+fn signal_dest(event: &EmittedEvents) -> Option<&PeerId> {
+    match event {
+        EmittedEvents::CallInitiated { dest, .. }
+        | EmittedEvents::Sdp { dest, .. }
+        | EmittedEvents::Ice { dest, .. }
+        | EmittedEvents::IceBatch { dest, .. }
+        | EmittedEvents::CallTerminated { dest, .. } => Some(dest),
+        _ => None,
+    }
+}
@@ -0,0 +1,238 @@
+use anyhow::{bail, Result};
+use bytes::Bytes;
+use std::time::Duration;
+use webrtc::rtp::{
+    self,
+    codecs::{g7xx::G7xxPayloader, opus::OpusPayloader, vp8::Vp8Payloader},
+    packet::Packet,
+    packetizer::{Packetizer, Payloader},
+};
+
+use crate::MimeType;
+
+// generates the RTP packets of a stream without any media, to test the send/receive pipeline:
+// the packets can be written to the TrackLocalWriter returned by `Controller::add_media_source`,
+// or fed to a forwarding logic directly. the headers, timestamps and payload headers are valid,
+// but the frames are placeholders. ex: the Opus frames are silence, and the VP8 frames don't
+// decode to an image.
+
+/// fits within the MTU of most links, like `media::DEFAULT_MTU`
+const MTU: usize = 1200;
+/// the size of the generated VP8 frames, in bytes. a key frame spans several packets
+const VP8_KEYFRAME_LEN: usize = 3000;
+const VP8_DELTA_FRAME_LEN: usize = 400;
+/// the dimensions in the header of the VP8 key frames
+const VP8_WIDTH: u16 = 320;
+const VP8_HEIGHT: u16 = 240;
+
+/// produces the packets of a stream of `mime_type`, one frame at a time. the first frame of a
+/// video stream is a key frame, followed by delta frames
+pub struct RtpGenerator {
+    packetizer: Box<dyn Packetizer + Send + Sync>,
+    mime_type: MimeType,
+    clock_rate: u32,
+    // the number of clock ticks between 2 frames
+    samples_per_frame: u32,
+    send_keyframe: bool,
+}
+
+impl RtpGenerator {
+    /// supports Opus, VP8, PCMU and PCMA. the sequence numbers start at 0 and the timestamps
+    /// at a random value, like the tracks of webrtc-rs
+    pub fn new(mime_type: MimeType, payload_type: u8, ssrc: u32) -> Result<Self> {
+        let (payloader, clock_rate, samples_per_frame): (Box<dyn Payloader + Send + Sync>, _, _) =
+            match mime_type {
+                // 20ms frames
+                MimeType::OPUS => (Box::new(OpusPayloader {}), 48000, 960),
+                MimeType::PCMU | MimeType::PCMA => (Box::new(G7xxPayloader {}), 8000, 160),
+                // 30 frames per second
+                MimeType::VP8 => {
+                    let mut payloader = Vp8Payloader::default();
+                    payloader.enable_picture_id = true;
+                    (Box::new(payloader), 90000, 3000)
+                }
+                _ => bail!("no test packets for {}", mime_type),
+            };
+        let packetizer = rtp::packetizer::new_packetizer(
+            MTU,
+            payload_type,
+            ssrc,
+            payloader,
+            Box::new(rtp::sequence::new_fixed_sequencer(0)),
+            clock_rate,
+        );
+
+        Ok(Self {
+            packetizer: Box::new(packetizer),
+            mime_type,
+            clock_rate,
+            samples_per_frame,
+            send_keyframe: true,
+        })
+    }
+
+    /// the time between 2 frames. write a frame every frame duration to send the stream in real
+    /// time
+    pub fn frame_duration(&self) -> Duration {
+        Duration::from_secs_f64(self.samples_per_frame as f64 / self.clock_rate as f64)
+    }
+
+    /// the next frame of a video stream is a key frame, ex: when the remote sent a PLI
+    pub fn request_keyframe(&mut self) {
+        self.send_keyframe = true;
+    }
+
+    /// the packets of the next frame. the marker bit is set on the last packet of a frame
+    pub async fn next_frame(&mut self) -> Result<Vec<Packet>> {
+        let frame = match self.mime_type {
+            // RFC 6716 3.1: a code 0 packet of a 20ms CELT-only fullband frame, which has no
+            // data and decodes to silence
+            MimeType::OPUS => Bytes::from_static(&[0xf8, 0xff, 0xfe]),
+            MimeType::PCMU => Bytes::from(vec![0xff; self.samples_per_frame as usize]),
+            MimeType::PCMA => Bytes::from(vec![0xd5; self.samples_per_frame as usize]),
+            MimeType::VP8 => {
+                let keyframe = std::mem::take(&mut self.send_keyframe);
+                vp8_frame(keyframe)
+            }
+            _ => unreachable!("checked by RtpGenerator::new"),
+        };
+        let packets = self
+            .packetizer
+            .packetize(&frame, self.samples_per_frame)
+            .await?;
+        Ok(packets)
+    }
+}
+
+/// RFC 6386 9.1: the frame tag holds the frame type, the version, show_frame and the size of the
+/// first partition. a key frame then has a start code and the dimensions. the partitions are
+/// zeroes
+fn vp8_frame(keyframe: bool) -> Bytes {
+    let len = match keyframe {
+        true => VP8_KEYFRAME_LEN,
+        false => VP8_DELTA_FRAME_LEN,
+    };
+    let mut frame = Vec::with_capacity(len);
+    let header_len = if keyframe { 10 } else { 3 };
+    let first_partition_len = (len - header_len) as u32;
+    // version 0, shown
+    let tag = (!keyframe as u32) | (1 << 4) | (first_partition_len << 5);
+    frame.extend_from_slice(&tag.to_le_bytes()[..3]);
+    if keyframe {
+        frame.extend_from_slice(&[0x9d, 0x01, 0x2a]);
+        // the upper 2 bits are the scaling, none
+        frame.extend_from_slice(&VP8_WIDTH.to_le_bytes());
+        frame.extend_from_slice(&VP8_HEIGHT.to_le_bytes());
+    }
+    frame.resize(len, 0);
+    Bytes::from(frame)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use webrtc::rtp::{codecs::vp8::Vp8Packet, packetizer::Depacketizer};
+
+    /// the VP8 frame carried by the packets, and the picture id of each packet
+    fn vp8_payload(packets: &[Packet]) -> Result<(Bytes, Vec<u16>)> {
+        let mut frame = vec![];
+        let mut picture_ids = vec![];
+        for packet in packets {
+            let mut vp8 = Vp8Packet::default();
+            frame.extend_from_slice(&vp8.depacketize(&packet.payload)?);
+            picture_ids.push(vp8.picture_id);
+        }
+        Ok((Bytes::from(frame), picture_ids))
+    }
+
+    #[tokio::test]
+    async fn opus_frames_are_one_packet_20ms_apart() -> Result<()> {
+        let mut generator = RtpGenerator::new(MimeType::OPUS, 111, 42)?;
+        assert_eq!(generator.frame_duration(), Duration::from_millis(20));
+        let mut previous: Option<Packet> = None;
+        for _ in 0..3 {
+            let packets = generator.next_frame().await?;
+            assert_eq!(packets.len(), 1);
+            let packet = &packets[0];
+            assert_eq!(packet.header.payload_type, 111);
+            assert_eq!(packet.header.ssrc, 42);
+            assert!(packet.header.marker);
+            assert_eq!(packet.payload, Bytes::from_static(&[0xf8, 0xff, 0xfe]));
+            if let Some(previous) = previous {
+                let header = &previous.header;
+                assert_eq!(packet.header.sequence_number, header.sequence_number + 1);
+                assert_eq!(packet.header.timestamp, header.timestamp.wrapping_add(960));
+            }
+            previous = Some(packet.clone());
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn g711_frames_hold_20ms_of_samples() -> Result<()> {
+        for (mime_type, silence) in [(MimeType::PCMU, 0xff), (MimeType::PCMA, 0xd5)] {
+            let mut generator = RtpGenerator::new(mime_type, 0, 0)?;
+            assert_eq!(generator.frame_duration(), Duration::from_millis(20));
+            let packets = generator.next_frame().await?;
+            assert_eq!(packets.len(), 1);
+            assert_eq!(packets[0].payload.len(), 160);
+            assert!(packets[0].payload.iter().all(|b| *b == silence));
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn a_vp8_stream_starts_with_a_key_frame() -> Result<()> {
+        let mut generator = RtpGenerator::new(MimeType::VP8, 96, 1)?;
+        let keyframe = generator.next_frame().await?;
+        assert!(keyframe.len() > 1);
+        assert!(keyframe.iter().all(|p| p.payload.len() <= MTU));
+        let markers: Vec<bool> = keyframe.iter().map(|p| p.header.marker).collect();
+        assert_eq!(markers.last(), Some(&true));
+        assert!(!markers[..markers.len() - 1].contains(&true));
+        assert!(keyframe
+            .windows(2)
+            .all(|p| p[0].header.timestamp == p[1].header.timestamp));
+
+        let (frame, picture_ids) = vp8_payload(&keyframe)?;
+        assert_eq!(frame.len(), VP8_KEYFRAME_LEN);
+        // the P bit of the frame tag is 0 for a key frame
+        assert_eq!(frame[0] & 1, 0);
+        assert_eq!(&frame[3..6], &[0x9d, 0x01, 0x2a]);
+        assert_eq!(u16::from_le_bytes([frame[6], frame[7]]), VP8_WIDTH);
+        assert_eq!(u16::from_le_bytes([frame[8], frame[9]]), VP8_HEIGHT);
+        assert!(picture_ids.iter().all(|id| *id == picture_ids[0]));
+
+        let delta = generator.next_frame().await?;
+        assert_eq!(delta.len(), 1);
+        assert_eq!(
+            delta[0].header.timestamp,
+            keyframe[0].header.timestamp.wrapping_add(3000)
+        );
+        let (frame, delta_ids) = vp8_payload(&delta)?;
+        assert_eq!(frame.len(), VP8_DELTA_FRAME_LEN);
+        assert_eq!(frame[0] & 1, 1);
+        assert_eq!(delta_ids[0], picture_ids[0] + 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn a_requested_key_frame_is_the_next_frame() -> Result<()> {
+        let mut generator = RtpGenerator::new(MimeType::VP8, 96, 1)?;
+        generator.next_frame().await?;
+        generator.request_keyframe();
+        let (frame, _) = vp8_payload(&generator.next_frame().await?)?;
+        assert_eq!(frame.len(), VP8_KEYFRAME_LEN);
+        assert_eq!(frame[0] & 1, 0);
+        let (frame, _) = vp8_payload(&generator.next_frame().await?)?;
+        assert_eq!(frame[0] & 1, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn other_codecs_are_rejected() {
+        for mime_type in [MimeType::H264, MimeType::G722, MimeType::RED] {
+            assert!(RtpGenerator::new(mime_type, 0, 0).is_err());
+        }
+    }
+}
@@ -0,0 +1,1164 @@
+use anyhow::Result;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use webrtc::track::track_local::{TrackLocal, TrackLocalContext};
+
+use crate::testing::loopback::{wait_for_event, Loopback, RtpWriter, A, B};
+use crate::*;
+use webrtc::ice_transport::ice_candidate_type::RTCIceCandidateType;
+use webrtc::ice_transport::ice_credential_type::RTCIceCredentialType;
+use webrtc::ice_transport::ice_protocol::RTCIceProtocol;
+
+// tests of the Controller's public API. the calls between two Controllers use
+// `testing::loopback`
+
+/// how long a test waits for an event which should be emitted
+const TIMEOUT: Duration = Duration::from_secs(10);
+/// how long a test waits for an event which shouldn't be emitted
+const QUIET_PERIOD: Duration = Duration::from_secs(2);
+
+fn opus() -> RTCRtpCodecCapability {
+    RTCRtpCodecCapability {
+        mime_type: MimeType::OPUS.to_string(),
+        clock_rate: 48000,
+        channels: 2,
+        ..Default::default()
+    }
+}
+
+/// waits for the `TrackAdded` of a track sent by `peer_id`, and returns its track id
+async fn wait_for_track(
+    events: &mut mpsc::UnboundedReceiver<EmittedEvents>,
+    peer_id: &str,
+    timeout: Duration,
+) -> Result<String> {
+    let track = wait_for_event(events, timeout, |event| match event {
+        EmittedEvents::TrackAdded { peer, track } if peer == peer_id => Some(track),
+        _ => None,
+    })
+    .await?;
+    Ok(track.id().await)
+}
+
+fn controller(id: &str) -> Result<(Controller, mpsc::UnboundedReceiver<EmittedEvents>)> {
+    controller_with(id, ControllerConfig::default())
+}
+
+fn controller_with(
+    id: &str,
+    config: ControllerConfig,
+) -> Result<(Controller, mpsc::UnboundedReceiver<EmittedEvents>)> {
+    let (tx, rx) = mpsc::unbounded_channel();
+    let mut args = InitArgs::new(id.into(), tx);
+    args.config = config;
+    Ok((Controller::init(args)?, rx))
+}
+
+/// dials the peer and returns the offer of its `CallInitiated`
+async fn dial_offer(
+    controller: &mut Controller,
+    events: &mut mpsc::UnboundedReceiver<EmittedEvents>,
+    peer_id: &str,
+) -> Result<String> {
+    controller.dial(&peer_id.into()).await?;
+    while let Some(event) = events.recv().await {
+        if let EmittedEvents::CallInitiated { dest, sdp } = event {
+            assert_eq!(dest, peer_id);
+            return Ok(sdp.sdp);
+        }
+    }
+    anyhow::bail!("no CallInitiated")
+}
+
+fn fingerprint(sdp: &str) -> Option<&str> {
+    sdp.lines()
+        .find_map(|line| line.trim().strip_prefix("a=fingerprint:"))
+}
+
+#[tokio::test]
+async fn a_closed_event_chan_is_ignored_if_configured() -> Result<()> {
+    let config = ControllerConfig {
+        on_event_chan_closed: OnEventChanClosed::Ignore,
+        ..Default::default()
+    };
+    let (mut controller, events) = controller_with("a", config)?;
+    drop(events);
+    controller.dial(&"b".into()).await?;
+    controller.dial(&"c".into()).await?;
+    assert!(!controller.is_shut_down());
+    controller.deinit().await
+}
+
+#[tokio::test]
+async fn the_events_emitted_before_attaching_are_replayed() -> Result<()> {
+    let config = ControllerConfig {
+        on_event_chan_closed: OnEventChanClosed::Ignore,
+        event_replay_len: 16,
+        ..Default::default()
+    };
+    let (mut controller, events) = controller_with("a", config)?;
+    drop(events);
+    controller.dial(&"b".into()).await?;
+    let (tx, mut events) = mpsc::unbounded_channel();
+    controller.attach_event_chan(tx)?;
+    let dest = wait_for_event(&mut events, TIMEOUT, |event| match event {
+        EmittedEvents::CallInitiated { dest, .. } => Some(dest),
+        _ => None,
+    })
+    .await?;
+    assert_eq!(dest, "b");
+    controller.deinit().await
+}
+
+#[tokio::test]
+async fn a_closed_event_chan_shuts_down_by_default() -> Result<()> {
+    let (mut controller, events) = controller("a")?;
+    drop(events);
+    assert!(controller.dial(&"b".into()).await.is_err());
+    assert!(controller.is_shut_down());
+    assert!(controller.dial(&"c".into()).await.is_err());
+    controller.deinit().await
+}
+
+#[tokio::test]
+async fn dial_normalizes_the_peer_id() -> Result<()> {
+    let (mut controller, mut events) = controller("a")?;
+    controller.dial(&" b\n".to_string()).await?;
+    assert!(controller.peer_state(&"b".to_string()).is_some());
+    loop {
+        match events.recv().await {
+            Some(EmittedEvents::CallInitiated { dest, .. }) => {
+                assert_eq!(dest, "b");
+                break;
+            }
+            Some(_) => {}
+            None => panic!("no CallInitiated"),
+        }
+    }
+    controller.deinit().await
+}
+
+#[tokio::test]
+async fn dial_rejects_invalid_peer_ids() -> Result<()> {
+    let (mut controller, _events) = controller("a")?;
+    let err = controller.dial(&"a/b".to_string()).await.unwrap_err();
+    assert!(matches!(
+        err.downcast_ref::<Error>(),
+        Some(Error::InvalidPeerId(_))
+    ));
+    assert!(controller.peer_state(&"a/b".to_string()).is_none());
+    controller.deinit().await
+}
+
+#[tokio::test]
+async fn connections_share_the_configured_certificate() -> Result<()> {
+    let config = ControllerConfig {
+        certificate: Some(generate_certificate()?),
+        ..Default::default()
+    };
+    let (mut controller, mut events) = controller_with("a", config)?;
+    let first = dial_offer(&mut controller, &mut events, "b").await?;
+    let second = dial_offer(&mut controller, &mut events, "c").await?;
+    assert!(fingerprint(&first).is_some());
+    assert_eq!(fingerprint(&first), fingerprint(&second));
+    controller.deinit().await
+}
+
+#[tokio::test]
+async fn connections_without_a_certificate_get_their_own() -> Result<()> {
+    let (mut controller, mut events) = controller("a")?;
+    let first = dial_offer(&mut controller, &mut events, "b").await?;
+    let second = dial_offer(&mut controller, &mut events, "c").await?;
+    assert_ne!(fingerprint(&first), fingerprint(&second));
+    controller.deinit().await
+}
+
+#[test]
+fn certificate_pem_round_trip_keeps_the_fingerprint() -> Result<()> {
+    let certificate = generate_certificate()?;
+    let parsed = certificate_from_pem(&certificate_to_pem(&certificate))?;
+    let values = |certificate: &RTCCertificate| -> Vec<String> {
+        certificate
+            .get_fingerprints()
+            .into_iter()
+            .map(|f| f.value)
+            .collect()
+    };
+    assert_eq!(values(&certificate), values(&parsed));
+    Ok(())
+}
+
+#[tokio::test]
+async fn a_source_added_mid_call_reaches_the_remote() -> Result<()> {
+    let mut call = Loopback::new()?;
+    call.call().await?;
+    let track = call
+        .a
+        .lock()
+        .await
+        .add_media_source("mic".into(), opus())
+        .await?;
+    let _writer = RtpWriter::new(track, MimeType::OPUS)?;
+    assert_eq!(wait_for_track(&mut call.b_events, A, TIMEOUT).await?, "mic");
+    Ok(())
+}
+
+#[tokio::test]
+async fn a_source_added_mid_call_waits_for_renegotiation_without_auto_renegotiate() -> Result<()> {
+    let config = ControllerConfig {
+        auto_renegotiate: false,
+        ..Default::default()
+    };
+    let mut call = Loopback::with_config(config, ControllerConfig::default())?;
+    call.call().await?;
+    let track = call
+        .a
+        .lock()
+        .await
+        .add_media_source("mic".into(), opus())
+        .await?;
+    let _writer = RtpWriter::new(track, MimeType::OPUS)?;
+    assert!(wait_for_track(&mut call.b_events, A, QUIET_PERIOD)
+        .await
+        .is_err());
+    call.a.lock().await.restart_ice(&B.into()).await?;
+    assert_eq!(wait_for_track(&mut call.b_events, A, TIMEOUT).await?, "mic");
+    Ok(())
+}
+
+/// how long ICE takes to fail once the remote stops responding: 5s to disconnect, then 25s
+const ICE_FAILED_TIMEOUT: Duration = Duration::from_secs(40);
+
+#[tokio::test]
+async fn a_dialed_peer_waits_for_the_answer() -> Result<()> {
+    let (mut controller, mut events) = controller("a")?;
+    dial_offer(&mut controller, &mut events, "b").await?;
+    assert_eq!(
+        controller.peer_state(&"b".into()),
+        Some(PeerState::WaitingForSdp)
+    );
+    controller.deinit().await
+}
+
+#[tokio::test]
+async fn an_accepted_call_waits_for_ice() -> Result<()> {
+    let (mut a, mut a_events) = controller("a")?;
+    let (mut b, _b_events) = controller("b")?;
+    let offer = dial_offer(&mut a, &mut a_events, "b").await?;
+    // the candidates aren't forwarded, so ICE can't connect
+    b.accept_call(&"a".into(), RTCSessionDescription::offer(offer)?)
+        .await?;
+    assert_eq!(b.peer_state(&"a".into()), Some(PeerState::WaitingForIce));
+    a.deinit().await?;
+    b.deinit().await
+}
+
+#[tokio::test]
+async fn both_peers_are_connected_once_ice_connects() -> Result<()> {
+    let mut call = Loopback::new()?;
+    call.call().await?;
+    assert_eq!(
+        call.a.lock().await.peer_state(&B.into()),
+        Some(PeerState::Connected)
+    );
+    assert_eq!(
+        call.b.lock().await.peer_state(&A.into()),
+        Some(PeerState::Connected)
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn a_peer_which_stops_responding_is_interrupted_then_disconnected() -> Result<()> {
+    let mut call = Loopback::new()?;
+    call.call().await?;
+    // closed without signaling, so that A only notices through ICE
+    let connection = call.b.lock().await.raw_peer_connection(&A.into());
+    connection.expect("no connection to A").close().await?;
+
+    let mut states = vec![];
+    wait_for_event(
+        &mut call.a_events,
+        ICE_FAILED_TIMEOUT,
+        |event| match event {
+            EmittedEvents::PeerStateChanged { peer, state } if peer == B => {
+                states.push(state);
+                (state == PeerState::Failed).then_some(())
+            }
+            _ => None,
+        },
+    )
+    .await?;
+    assert_eq!(states, [PeerState::Interrupted, PeerState::Failed]);
+    assert_eq!(
+        call.a.lock().await.peer_state(&B.into()),
+        Some(PeerState::Failed)
+    );
+    Ok(())
+}
+
+/// the ICE connection states of the peer emitted as `ConnectionStateChanged`, until `until`
+async fn ice_states(
+    events: &mut mpsc::UnboundedReceiver<EmittedEvents>,
+    peer_id: &str,
+    timeout: Duration,
+    mut until: impl FnMut(&EmittedEvents) -> bool,
+) -> Result<Vec<RTCIceConnectionState>> {
+    let mut states = vec![];
+    wait_for_event(events, timeout, |event| {
+        if let EmittedEvents::ConnectionStateChanged { peer, state } = &event {
+            if peer == peer_id {
+                states.push(*state);
+            }
+        }
+        until(&event).then_some(())
+    })
+    .await?;
+    Ok(states)
+}
+
+#[tokio::test]
+async fn every_ice_state_is_emitted_before_connected_and_disconnected() -> Result<()> {
+    let mut call = Loopback::new()?;
+    call.a.lock().await.dial(&B.into()).await?;
+    let connected =
+        |event: &EmittedEvents| matches!(event, EmittedEvents::Connected { peer } if peer == B);
+    let states = ice_states(&mut call.a_events, B, TIMEOUT, connected).await?;
+    assert_eq!(
+        states,
+        [
+            RTCIceConnectionState::Checking,
+            RTCIceConnectionState::Connected
+        ]
+    );
+
+    // closed without signaling, so that A only notices through ICE
+    let connection = call.b.lock().await.raw_peer_connection(&A.into());
+    connection.expect("no connection to A").close().await?;
+    let disconnected =
+        |event: &EmittedEvents| matches!(event, EmittedEvents::Disconnected { peer } if peer == B);
+    let states = ice_states(&mut call.a_events, B, ICE_FAILED_TIMEOUT, disconnected).await?;
+    assert_eq!(
+        states,
+        [
+            RTCIceConnectionState::Disconnected,
+            RTCIceConnectionState::Failed
+        ]
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn hanging_up_emits_the_closed_ice_state() -> Result<()> {
+    let mut call = Loopback::new()?;
+    call.call().await?;
+    call.a.lock().await.hang_up(&B.into()).await?;
+    let closed = |event: &EmittedEvents| match event {
+        EmittedEvents::ConnectionStateChanged { peer, state } => {
+            peer == B && *state == RTCIceConnectionState::Closed
+        }
+        _ => false,
+    };
+    let states = ice_states(&mut call.a_events, B, TIMEOUT, closed).await?;
+    assert_eq!(states, [RTCIceConnectionState::Closed]);
+    Ok(())
+}
+
+/// the senders of `A`'s connection to `B` which have a track
+async fn attached_tracks(call: &Loopback) -> Result<Vec<String>> {
+    let connection = call.a.lock().await.raw_peer_connection(&B.into());
+    let mut tracks = vec![];
+    for sender in connection.expect("no connection to B").get_senders().await {
+        if let Some(track) = sender.track().await {
+            tracks.push(track.id().to_string());
+        }
+    }
+    Ok(tracks)
+}
+
+#[tokio::test]
+async fn interleaved_adds_and_removes_leave_no_sender_behind() -> Result<()> {
+    let mut call = Loopback::new()?;
+    call.call().await?;
+    let tasks: Vec<_> = (0..20)
+        .map(|i| {
+            let a = call.a.clone();
+            tokio::spawn(async move {
+                if i % 2 == 0 {
+                    // fails while the source exists
+                    let _ = a.lock().await.add_media_source("mic".into(), opus()).await;
+                } else {
+                    a.lock()
+                        .await
+                        .remove_media_source("mic".into())
+                        .await
+                        .unwrap();
+                }
+            })
+        })
+        .collect();
+    for task in tasks {
+        task.await?;
+    }
+
+    let exists = call.a.lock().await.media_source(&"mic".into()).is_some();
+    let expected = if exists {
+        vec!["mic".to_string()]
+    } else {
+        vec![]
+    };
+    assert_eq!(attached_tracks(&call).await?, expected);
+    // the maps still agree, so the source can be added and removed again
+    call.a
+        .lock()
+        .await
+        .add_media_source("mic".into(), opus())
+        .await
+        .ok();
+    assert_eq!(attached_tracks(&call).await?, ["mic"]);
+    call.a
+        .lock()
+        .await
+        .remove_media_source("mic".into())
+        .await?;
+    assert!(attached_tracks(&call).await?.is_empty());
+    Ok(())
+}
+
+#[tokio::test]
+async fn adding_a_source_twice_keeps_the_first_track() -> Result<()> {
+    let mut call = Loopback::new()?;
+    call.call().await?;
+    let mut a = call.a.lock().await;
+    let first = a.add_media_source("mic".into(), opus()).await?;
+    assert!(a.add_media_source("mic".into(), opus()).await.is_err());
+    assert_eq!(a.media_source_by_track(&first), Some("mic".into()));
+    drop(a);
+    assert_eq!(attached_tracks(&call).await?, ["mic"]);
+    Ok(())
+}
+
+#[tokio::test]
+async fn a_source_added_before_dialing_reaches_the_remote() -> Result<()> {
+    let mut call = Loopback::new()?;
+    let track = call
+        .a
+        .lock()
+        .await
+        .add_media_source("mic".into(), opus())
+        .await?;
+    let _writer = RtpWriter::new(track, MimeType::OPUS)?;
+    call.call().await?;
+    assert_eq!(wait_for_track(&mut call.b_events, A, TIMEOUT).await?, "mic");
+    // the RTCP reader is stored with the peer, which stops it on hang up
+    let a = call.a.lock().await;
+    let readers = a.peers[B].rtcp_readers.lock().unwrap();
+    assert!(readers.contains_key("mic"));
+    Ok(())
+}
+
+#[tokio::test]
+async fn the_peer_is_added_before_its_first_track() -> Result<()> {
+    let mut call = Loopback::new()?;
+    let track = call
+        .a
+        .lock()
+        .await
+        .add_media_source("mic".into(), opus())
+        .await?;
+    let _writer = RtpWriter::new(track, MimeType::OPUS)?;
+    call.a.lock().await.dial(&B.into()).await?;
+    let first = wait_for_event(&mut call.a_events, TIMEOUT, Some).await?;
+    assert!(matches!(first, EmittedEvents::PeerAdded { peer } if peer == B));
+    // B receives the media as soon as it answers
+    let mut added = false;
+    let added_first = wait_for_event(&mut call.b_events, TIMEOUT, |event| match event {
+        EmittedEvents::PeerAdded { peer } if peer == A => {
+            added = true;
+            None
+        }
+        EmittedEvents::TrackAdded { peer, .. } if peer == A => Some(added),
+        _ => None,
+    })
+    .await?;
+    assert!(added_first);
+    Ok(())
+}
+
+#[tokio::test]
+async fn hang_up_removes_every_sender_then_closes_the_connection() -> Result<()> {
+    let mut call = Loopback::new()?;
+    call.call().await?;
+    let mut a = call.a.lock().await;
+    a.add_media_source("mic".into(), opus()).await?;
+    a.add_media_source("music".into(), opus()).await?;
+    let connection = a
+        .raw_peer_connection(&B.into())
+        .expect("no connection to B");
+    a.hang_up(&B.into()).await?;
+    assert!(a.raw_peer_connection(&B.into()).is_none());
+    assert!(a.peer_state(&B.into()).is_none());
+    drop(a);
+    for sender in connection.get_senders().await {
+        assert!(sender.track().await.is_none());
+    }
+    assert_eq!(
+        connection.connection_state(),
+        RTCPeerConnectionState::Closed
+    );
+    wait_for_event(&mut call.a_events, TIMEOUT, |event| match event {
+        EmittedEvents::CallTerminated { dest, .. } if dest == B => Some(()),
+        _ => None,
+    })
+    .await?;
+    // the remote was signaled
+    let b = call.b.lock().await;
+    assert!(b.raw_peer_connection(&A.into()).is_none());
+    Ok(())
+}
+
+#[tokio::test]
+async fn a_second_hang_up_does_nothing() -> Result<()> {
+    let mut call = Loopback::new()?;
+    call.call().await?;
+    call.a.lock().await.hang_up(&B.into()).await?;
+    wait_for_event(&mut call.a_events, TIMEOUT, |event| match event {
+        EmittedEvents::CallTerminated { dest, .. } if dest == B => Some(()),
+        _ => None,
+    })
+    .await?;
+    assert!(call.a.lock().await.hang_up(&B.into()).await.is_err());
+    let terminated = wait_for_event(&mut call.a_events, QUIET_PERIOD, |event| match event {
+        EmittedEvents::CallTerminated { .. } => Some(()),
+        _ => None,
+    })
+    .await;
+    assert!(terminated.is_err());
+    Ok(())
+}
+
+/// the `by_remote` of the next CallTerminated for `peer_id`
+async fn terminated_by_remote(
+    events: &mut mpsc::UnboundedReceiver<EmittedEvents>,
+    peer_id: &str,
+) -> Result<bool> {
+    wait_for_event(events, TIMEOUT, |event| match event {
+        EmittedEvents::CallTerminated { dest, by_remote } if dest == peer_id => Some(by_remote),
+        _ => None,
+    })
+    .await
+}
+
+#[tokio::test]
+async fn both_sides_of_a_hang_up_emit_call_terminated() -> Result<()> {
+    let mut call = Loopback::new()?;
+    call.call().await?;
+    call.a.lock().await.hang_up(&B.into()).await?;
+    assert!(!terminated_by_remote(&mut call.a_events, B).await?);
+    assert!(terminated_by_remote(&mut call.b_events, A).await?);
+    // B's isn't sent back to A
+    let echoed = wait_for_event(&mut call.a_events, QUIET_PERIOD, |event| match event {
+        EmittedEvents::CallTerminated { .. } => Some(()),
+        _ => None,
+    })
+    .await;
+    assert!(echoed.is_err());
+    Ok(())
+}
+
+#[tokio::test]
+async fn a_hang_up_with_a_reason_also_emits_call_terminated_on_both_sides() -> Result<()> {
+    let mut call = Loopback::new()?;
+    call.call().await?;
+    // the reason is sent over the control channel, which opens shortly after ICE connects
+    let ping = async {
+        while call.a.lock().await.ping(&B.into()).await.is_err() {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+    };
+    tokio::time::timeout(TIMEOUT, ping).await?;
+    call.a
+        .lock()
+        .await
+        .hang_up_with_reason(&B.into(), HangupReason::Busy)
+        .await?;
+    assert!(!terminated_by_remote(&mut call.a_events, B).await?);
+    let reason = wait_for_event(&mut call.b_events, TIMEOUT, |event| match event {
+        EmittedEvents::RemoteHangUp { peer, reason } if peer == A => Some(reason),
+        _ => None,
+    })
+    .await?;
+    assert_eq!(reason, HangupReason::Busy);
+    assert!(terminated_by_remote(&mut call.b_events, A).await?);
+    Ok(())
+}
+
+#[tokio::test]
+async fn a_hang_up_from_an_unknown_peer_emits_nothing() -> Result<()> {
+    let (mut controller, mut events) = controller("a")?;
+    controller.recv_hang_up(&"b".into()).await;
+    assert!(events.try_recv().is_err());
+    controller.deinit().await
+}
+
+#[tokio::test]
+async fn hang_up_fails_for_an_unknown_peer() -> Result<()> {
+    let (mut controller, _events) = controller("a")?;
+    assert!(controller.hang_up(&"nobody".into()).await.is_err());
+    let err = controller.hang_up(&"a/b".into()).await.unwrap_err();
+    assert!(matches!(
+        err.downcast_ref::<Error>(),
+        Some(Error::InvalidPeerId(_))
+    ));
+    controller.deinit().await
+}
+
+#[tokio::test]
+async fn deinit_hangs_up_the_peers_in_order() -> Result<()> {
+    let (mut controller, mut events) = controller("x")?;
+    for peer_id in ["c", "a", "b", "d"] {
+        dial_offer(&mut controller, &mut events, peer_id).await?;
+    }
+    // hang_up rejects the id, so this peer fails to hang up. the ones after it are still hung up
+    let failing = controller.peers.remove("d").unwrap();
+    let failing_connection = failing.connection.clone();
+    controller.peers.insert("a/d".into(), failing);
+    assert!(controller.deinit().await.is_err());
+    assert!(controller.peers.is_empty());
+    assert_eq!(
+        failing_connection.connection_state(),
+        RTCPeerConnectionState::Closed
+    );
+    let mut terminated = vec![];
+    while let Ok(event) = events.try_recv() {
+        if let EmittedEvents::CallTerminated { dest, .. } = event {
+            terminated.push(dest);
+        }
+    }
+    assert_eq!(terminated, ["a", "b", "c"]);
+    Ok(())
+}
+
+#[tokio::test]
+async fn no_task_outlives_deinit() -> Result<()> {
+    let mut call = Loopback::new()?;
+    call.call().await?;
+    Controller::set_reconnect_policy(&call.a, Some(ReconnectPolicy::default())).await?;
+    let mut a = call.a.lock().await;
+    let track = a.add_media_source("mic".into(), opus()).await?;
+    let _writer = RtpWriter::new(track, MimeType::OPUS)?;
+    a.enable_stats_reporting(Duration::from_millis(100))?;
+    let jitter = a.peers[B].jitter.clone();
+    a.deinit().await?;
+    assert!(a.stats_task.is_none());
+    assert!(a.reconnect_task.is_none());
+    assert!(a.peers.is_empty());
+    drop(a);
+    // the connection's handlers are dropped once webrtc-rs has closed it
+    let released = async {
+        loop {
+            let clones = call.a.lock().await.emitter.clones();
+            if clones == 1 && jitter.holders() == 1 {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    };
+    tokio::time::timeout(TIMEOUT, released).await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn the_stats_updates_include_the_jitter_reported_by_the_peer() -> Result<()> {
+    let mut call = Loopback::new()?;
+    call.call().await?;
+    let mut a = call.a.lock().await;
+    let track = a.add_media_source("mic".into(), opus()).await?;
+    let _writer = RtpWriter::new(track, MimeType::OPUS)?;
+    a.enable_stats_reporting(Duration::from_millis(100))?;
+    drop(a);
+    let track = wait_for_event(&mut call.b_events, TIMEOUT, |event| match event {
+        EmittedEvents::TrackAdded { peer, track } if peer == A => Some(track),
+        _ => None,
+    })
+    .await?;
+    // the receiver reports are only sent for the packets which are read
+    let reader = tokio::spawn(async move { while track.read_rtp().await.is_ok() {} });
+    let jitter = wait_for_event(&mut call.a_events, TIMEOUT, |event| match event {
+        EmittedEvents::StatsUpdated { peer, jitter, .. } if peer == B => jitter,
+        _ => None,
+    })
+    .await;
+    reader.abort();
+    jitter?;
+    // the same reports as get_peer_stats
+    let stats = call.a.lock().await.get_peer_stats(&B.into()).await?;
+    assert!(stats.jitter.is_some());
+    Ok(())
+}
+
+#[tokio::test]
+async fn dialing_a_peer_twice_closes_the_first_connection() -> Result<()> {
+    let (mut controller, mut events) = controller("a")?;
+    dial_offer(&mut controller, &mut events, "b").await?;
+    let first = controller.raw_peer_connection(&"b".into()).unwrap();
+    dial_offer(&mut controller, &mut events, "b").await?;
+    let second = controller.raw_peer_connection(&"b".into()).unwrap();
+    assert!(!Arc::ptr_eq(&first, &second));
+    assert_eq!(first.connection_state(), RTCPeerConnectionState::Closed);
+    assert_ne!(second.connection_state(), RTCPeerConnectionState::Closed);
+    controller.deinit().await?;
+    assert_eq!(second.connection_state(), RTCPeerConnectionState::Closed);
+    Ok(())
+}
+
+#[tokio::test]
+async fn a_call_accepted_again_replaces_the_first_one() -> Result<()> {
+    let (mut a, mut a_events) = controller("a")?;
+    let (mut b, _b_events) = controller("b")?;
+    let offer = dial_offer(&mut a, &mut a_events, "b").await?;
+    b.accept_call(&"a".into(), RTCSessionDescription::offer(offer)?)
+        .await?;
+    let first = b.raw_peer_connection(&"a".into()).unwrap();
+    let offer = dial_offer(&mut a, &mut a_events, "b").await?;
+    b.accept_call(&"a".into(), RTCSessionDescription::offer(offer)?)
+        .await?;
+    let second = b.raw_peer_connection(&"a".into()).unwrap();
+    assert!(!Arc::ptr_eq(&first, &second));
+    assert_eq!(first.connection_state(), RTCPeerConnectionState::Closed);
+    assert_eq!(b.peer_state(&"a".into()), Some(PeerState::WaitingForIce));
+    a.deinit().await?;
+    b.deinit().await
+}
+
+/// the media types of the m= lines of the connection's local SDP
+async fn media_sections(controller: &tokio::sync::Mutex<Controller>, peer_id: &str) -> Vec<String> {
+    let connection = controller.lock().await.raw_peer_connection(&peer_id.into());
+    let sdp = connection
+        .expect("no connection")
+        .local_description()
+        .await
+        .expect("no local description")
+        .sdp;
+    sdp.lines()
+        .filter_map(|line| line.strip_prefix("m="))
+        .filter_map(|media| media.split(' ').next())
+        .map(String::from)
+        .collect()
+}
+
+#[tokio::test]
+async fn a_call_without_media_only_negotiates_data_channels() -> Result<()> {
+    let mut call = Loopback::new()?;
+    call.call().await?;
+    assert_eq!(media_sections(&call.a, B).await, ["application"]);
+    assert_eq!(media_sections(&call.b, A).await, ["application"]);
+    // the control channel is a data channel. it opens shortly after ICE connects
+    let ping = async {
+        while call.a.lock().await.ping(&B.into()).await.is_err() {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+    };
+    tokio::time::timeout(TIMEOUT, ping).await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn a_callee_with_media_offers_it_after_answering() -> Result<()> {
+    let mut call = Loopback::new()?;
+    let track = call
+        .b
+        .lock()
+        .await
+        .add_media_source("mic".into(), opus())
+        .await?;
+    let _writer = RtpWriter::new(track, MimeType::OPUS)?;
+    call.call().await?;
+    assert_eq!(wait_for_track(&mut call.a_events, B, TIMEOUT).await?, "mic");
+    assert!(media_sections(&call.a, B)
+        .await
+        .contains(&"audio".to_string()));
+    Ok(())
+}
+
+fn codec(mime_type: &str, clock_rate: u32) -> RTCRtpCodecCapability {
+    RTCRtpCodecCapability {
+        mime_type: mime_type.into(),
+        clock_rate,
+        ..Default::default()
+    }
+}
+
+/// the mime type of `Error::CodecNotRegistered`
+async fn unregistered_codec(controller: &mut Controller, codec: RTCRtpCodecCapability) -> String {
+    let err = controller
+        .add_media_source("source".into(), codec)
+        .await
+        .unwrap_err();
+    match err.downcast::<Error>() {
+        Ok(Error::CodecNotRegistered(mime_type)) => mime_type,
+        other => panic!("unexpected error: {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn add_media_source_rejects_codecs_which_arent_registered() -> Result<()> {
+    let (mut controller, _events) = controller("a")?;
+    // not one of the default codecs
+    let av1 = codec("video/AV1", 90000);
+    assert_eq!(unregistered_codec(&mut controller, av1).await, "video/AV1");
+    // only registered if `ControllerConfig::red` is set
+    let red = codec(MIME_TYPE_RED, 48000);
+    assert_eq!(
+        unregistered_codec(&mut controller, red).await,
+        MIME_TYPE_RED
+    );
+    assert!(controller.media_source(&"source".into()).is_none());
+    controller.deinit().await
+}
+
+#[tokio::test]
+async fn the_registered_codecs_are_the_negotiated_ones() -> Result<()> {
+    let config = ControllerConfig {
+        red: true,
+        ..Default::default()
+    };
+    let (mut a, mut a_events) = controller_with("a", config.clone())?;
+    let (mut b, _b_events) = controller_with("b", config.clone())?;
+    a.add_media_source("mic".into(), opus()).await?;
+    a.add_media_source("cam".into(), codec(MIME_TYPE_VP8, 90000))
+        .await?;
+    let offer = dial_offer(&mut a, &mut a_events, "b").await?;
+    b.accept_call(&"a".into(), RTCSessionDescription::offer(offer)?)
+        .await?;
+    let answer = b
+        .raw_peer_connection(&"a".into())
+        .expect("no connection")
+        .local_description()
+        .await
+        .expect("no answer")
+        .sdp;
+    let mut negotiated = sdp_codecs(&answer, "audio");
+    negotiated.extend(sdp_codecs(&answer, "video"));
+    // a forward error correction scheme, not a codec a source can use
+    negotiated.retain(|encoding| encoding != "ulpfec");
+    negotiated.sort();
+    negotiated.dedup();
+    let mut registered: Vec<String> = registered_codecs(&config)
+        .iter()
+        .map(|mime_type| mime_type.split('/').nth(1).unwrap().to_lowercase())
+        .collect();
+    registered.sort();
+    assert_eq!(negotiated, registered);
+    a.deinit().await?;
+    b.deinit().await
+}
+
+#[tokio::test]
+async fn add_media_source_accepts_the_registered_codecs() -> Result<()> {
+    let config = ControllerConfig {
+        red: true,
+        ..Default::default()
+    };
+    let (mut controller, _events) = controller_with("a", config)?;
+    for (id, codec) in [
+        ("opus", codec("audio/OPUS", 48000)),
+        ("vp8", codec("video/vp8", 90000)),
+        ("red", red_codec()),
+    ] {
+        controller.add_media_source(id.into(), codec).await?;
+    }
+    controller.deinit().await
+}
+
+fn host_candidate(foundation: &str, port: u16) -> RTCIceCandidate {
+    RTCIceCandidate {
+        foundation: foundation.into(),
+        priority: 2130706431,
+        address: "127.0.0.1".into(),
+        protocol: RTCIceProtocol::Udp,
+        port,
+        typ: RTCIceCandidateType::Host,
+        component: 1,
+        ..Default::default()
+    }
+}
+
+#[tokio::test]
+async fn candidates_beyond_the_limit_are_rejected() -> Result<()> {
+    let config = ControllerConfig {
+        max_remote_candidates: 3,
+        ..Default::default()
+    };
+    let (mut a, mut a_events) = controller("a")?;
+    let (mut b, _b_events) = controller_with("b", config)?;
+    let offer = dial_offer(&mut a, &mut a_events, "b").await?;
+    b.accept_call(&"a".into(), RTCSessionDescription::offer(offer)?)
+        .await?;
+    let peer_id: PeerId = "a".into();
+    // the malformed candidates don't count
+    for _ in 0..5 {
+        let err = b
+            .recv_ice(&peer_id, host_candidate("not/a+foundation!", 9))
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<Error>(),
+            Some(Error::InvalidCandidate { .. })
+        ));
+    }
+    for port in 1..=3 {
+        b.recv_ice(&peer_id, host_candidate("1", port)).await?;
+    }
+    let err = b
+        .recv_ice(&peer_id, host_candidate("1", 4))
+        .await
+        .unwrap_err();
+    assert!(matches!(
+        err.downcast_ref::<Error>(),
+        Some(Error::TooManyCandidates(peer)) if peer == "a"
+    ));
+    a.deinit().await?;
+    b.deinit().await
+}
+
+#[tokio::test]
+async fn an_empty_candidate_ends_the_remote_candidates() -> Result<()> {
+    let config = ControllerConfig {
+        max_remote_candidates: 1,
+        ..Default::default()
+    };
+    let (mut a, mut a_events) = controller("a")?;
+    let (mut b, _b_events) = controller_with("b", config)?;
+    let peer_id: PeerId = "a".into();
+    assert!(b.recv_end_of_candidates(&peer_id).await.is_err());
+    let offer = dial_offer(&mut a, &mut a_events, "b").await?;
+    b.accept_call(&peer_id, RTCSessionDescription::offer(offer)?)
+        .await?;
+    b.recv_end_of_candidates(&peer_id).await?;
+    b.recv_ice(&peer_id, RTCIceCandidate::default()).await?;
+    // the markers don't count towards the limit
+    b.recv_ice(&peer_id, host_candidate("1", 1)).await?;
+
+    // only the default candidate is the marker
+    let no_address = RTCIceCandidate {
+        address: String::new(),
+        ..host_candidate("1", 2)
+    };
+    let err = b.recv_ice(&peer_id, no_address).await.unwrap_err();
+    assert!(matches!(
+        err.downcast_ref::<Error>(),
+        Some(Error::InvalidCandidate {
+            field: "address",
+            ..
+        })
+    ));
+    a.deinit().await?;
+    b.deinit().await
+}
+
+fn stun(url: &str) -> RTCIceServer {
+    RTCIceServer {
+        urls: vec![url.into()],
+        ..Default::default()
+    }
+}
+
+/// the ICE servers of a connection dialed with `ice_servers` and `config`
+async fn dialed_servers(
+    ice_servers: Vec<RTCIceServer>,
+    config: ControllerConfig,
+) -> Result<Vec<RTCIceServer>> {
+    let (tx, mut events) = mpsc::unbounded_channel();
+    let mut args = InitArgs::new("a".into(), tx);
+    args.ice_servers = ice_servers;
+    args.config = config;
+    let mut controller = Controller::init(args)?;
+    dial_offer(&mut controller, &mut events, "b").await?;
+    let connection = controller.raw_peer_connection(&"b".into()).unwrap();
+    let servers = connection.get_configuration().ice_servers.clone();
+    controller.deinit().await?;
+    Ok(servers)
+}
+
+/// the urls of the ICE servers of a connection dialed with `ice_servers` and `config`
+async fn dialed_ice_servers(
+    ice_servers: Vec<RTCIceServer>,
+    config: ControllerConfig,
+) -> Result<Vec<String>> {
+    let servers = dialed_servers(ice_servers, config).await?;
+    Ok(servers.into_iter().flat_map(|server| server.urls).collect())
+}
+
+#[tokio::test]
+async fn connections_use_the_ice_servers_of_init() -> Result<()> {
+    let urls = dialed_ice_servers(vec![], ControllerConfig::default()).await?;
+    assert_eq!(urls, [DEFAULT_STUN_SERVER]);
+    let servers = vec![stun("stun:stun.example.com:3478")];
+    let urls = dialed_ice_servers(servers, ControllerConfig::default()).await?;
+    assert_eq!(urls, ["stun:stun.example.com:3478"]);
+    Ok(())
+}
+
+#[tokio::test]
+async fn the_ice_servers_callback_replaces_the_ice_servers_of_init() -> Result<()> {
+    let init_servers = vec![stun("stun:init.example.com:3478")];
+    let config = ControllerConfig {
+        ice_servers: Some(fixed_ice_servers(vec![stun(
+            "stun:fetched.example.com:3478",
+        )])),
+        ..Default::default()
+    };
+    let urls = dialed_ice_servers(init_servers.clone(), config).await?;
+    assert_eq!(urls, ["stun:fetched.example.com:3478"]);
+
+    // an empty list falls back to the servers of init, then to the default
+    let config = ControllerConfig {
+        ice_servers: Some(fixed_ice_servers(vec![])),
+        ..Default::default()
+    };
+    let urls = dialed_ice_servers(init_servers, config.clone()).await?;
+    assert_eq!(urls, ["stun:init.example.com:3478"]);
+    let urls = dialed_ice_servers(vec![], config).await?;
+    assert_eq!(urls, [DEFAULT_STUN_SERVER]);
+    Ok(())
+}
+
+#[tokio::test]
+async fn the_turn_servers_are_added_to_the_connection() -> Result<()> {
+    let config = ControllerConfig {
+        turn_servers: vec![
+            TurnConfig {
+                urls: vec!["turn:turn.example.com:3478?transport=udp".into()],
+                username: "user".into(),
+                credential: "secret".into(),
+            },
+            TurnConfig {
+                urls: vec!["turns:relay.example.com:5349".into()],
+                ..Default::default()
+            },
+        ],
+        ..Default::default()
+    };
+    let servers = dialed_servers(vec![], config).await?;
+    assert_eq!(servers.len(), 3);
+    assert_eq!(servers[0].urls, [DEFAULT_STUN_SERVER]);
+    assert_eq!(
+        servers[1].urls,
+        ["turn:turn.example.com:3478?transport=udp"]
+    );
+    assert_eq!(servers[1].username, "user");
+    assert_eq!(servers[1].credential, "secret");
+    assert_eq!(servers[1].credential_type, RTCIceCredentialType::Password);
+    // an open relay gets placeholder credentials, which webrtc-rs requires
+    assert_eq!(servers[2].urls, ["turns:relay.example.com:5349"]);
+    assert!(!servers[2].username.is_empty());
+    assert!(!servers[2].credential.is_empty());
+    Ok(())
+}
+
+#[test]
+fn init_rejects_invalid_turn_servers() {
+    for urls in [vec![], vec!["stun:stun.example.com:3478".to_string()]] {
+        let config = ControllerConfig {
+            turn_servers: vec![TurnConfig {
+                urls,
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        assert!(controller_with("a", config).is_err());
+    }
+}
+
+/// the encoding names of the audio section's codecs, in the order they're listed
+/// the encoding names of the codecs in the SDP's first media section of `kind`, lowercase, in
+/// order
+fn sdp_codecs(sdp: &str, kind: &str) -> Vec<String> {
+    let prefix = format!("m={} ", kind);
+    let payload_types: Vec<&str> = sdp
+        .lines()
+        .find_map(|line| line.strip_prefix(prefix.as_str()))
+        .map(|m| m.split(' ').skip(2).collect())
+        .unwrap_or_default();
+    payload_types
+        .iter()
+        .filter_map(|pt| {
+            let prefix = format!("a=rtpmap:{} ", pt);
+            sdp.lines()
+                .find_map(|line| line.strip_prefix(prefix.as_str()))
+                .and_then(|encoding| encoding.split('/').next())
+                .map(str::to_lowercase)
+        })
+        .collect()
+}
+
+#[tokio::test]
+async fn the_offer_lists_the_preferred_codecs_first() -> Result<()> {
+    let (mut controller, mut events) = controller("a")?;
+    controller.add_media_source("mic".into(), opus()).await?;
+    let offer = dial_offer(&mut controller, &mut events, "b").await?;
+    assert_eq!(sdp_codecs(&offer, "audio")[0], "opus");
+
+    controller.set_codec_preferences(&"c".into(), vec![MimeType::PCMU, MimeType::G722]);
+    let offer = dial_offer(&mut controller, &mut events, "c").await?;
+    assert_eq!(
+        sdp_codecs(&offer, "audio"),
+        ["pcmu", "g722", "opus", "pcma"]
+    );
+    controller.deinit().await
+}
+
+/// an audio track which can't be detached from its sender
+struct UndetachableTrack;
+
+#[async_trait::async_trait]
+impl TrackLocal for UndetachableTrack {
+    async fn bind(&self, t: &TrackLocalContext) -> webrtc::error::Result<RTCRtpCodecParameters> {
+        Ok(t.codec_parameters().first().cloned().unwrap_or_default())
+    }
+
+    async fn unbind(&self, _t: &TrackLocalContext) -> webrtc::error::Result<()> {
+        Err(webrtc::Error::ErrUnbindFailed)
+    }
+
+    fn id(&self) -> &str {
+        "undetachable"
+    }
+
+    fn stream_id(&self) -> &str {
+        "undetachable"
+    }
+
+    fn kind(&self) -> RTPCodecType {
+        RTPCodecType::Audio
+    }
+
+    fn as_any(&self) -> &dyn std::any::Any {
+        self
+    }
+}
+
+#[tokio::test]
+async fn muting_fails_only_if_every_peer_fails() -> Result<()> {
+    let mut call = Loopback::new()?;
+    call.call().await?;
+    let mut a = call.a.lock().await;
+    let track = a.add_media_source("mic".into(), opus()).await?;
+    let _writer = RtpWriter::new(track, MimeType::OPUS)?;
+    drop(a);
+    wait_for_track(&mut call.b_events, A, TIMEOUT).await?;
+
+    let mut a = call.a.lock().await;
+    // not connected, so there is nothing to detach
+    a.dial(&"c".into()).await?;
+    let sender = a.peers[B].rtp_senders["mic"].clone();
+    sender
+        .replace_track(Some(Arc::new(UndetachableTrack)))
+        .await?;
+    a.mute_media_source(&"mic".into()).await?;
+    a.hang_up(&"c".into()).await?;
+    assert!(a.mute_media_source(&"mic".into()).await.is_err());
+    assert!(a.is_media_source_muted(&"mic".into()));
+    Ok(())
+}